@@ -1,9 +1,13 @@
 //! `darker build` command implementation
 
 use crate::image::build::ImageBuilder;
+use crate::image::layer::Compression;
+use crate::image::oci::Platform;
+use crate::image::progress::ProgressMode;
+use crate::image::registry::{RegistryClient, DEFAULT_MAX_CONCURRENT_DOWNLOADS};
 use crate::storage::paths::DarkerPaths;
 use clap::Args;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 /// Valid container file names in order of preference
 pub const CONTAINER_FILE_NAMES: &[&str] = &["Darkerfile", "Dockerfile", "Containerfile"];
@@ -54,10 +58,22 @@ pub struct BuildArgs {
     /// Set platform if the Dockerfile uses FROM --platform
     #[arg(long)]
     pub platform: Option<String>,
+
+    /// Set type of progress output (auto, plain, tty, json)
+    #[arg(long, value_enum, default_value_t = ProgressMode::Auto)]
+    pub progress: ProgressMode,
+
+    /// Maximum number of layers to download at the same time
+    #[arg(long, default_value_t = DEFAULT_MAX_CONCURRENT_DOWNLOADS)]
+    pub max_concurrent_downloads: usize,
+
+    /// Compression of the layers created by the build (none, gzip, zstd)
+    #[arg(long, value_enum, default_value_t = Compression::Gzip)]
+    pub compression: Compression,
 }
 
 /// Find the container file in the build context
-fn find_container_file(context_path: &PathBuf, explicit_file: Option<&str>) -> anyhow::Result<String> {
+fn find_container_file(context_path: &Path, explicit_file: Option<&str>) -> anyhow::Result<String> {
     // If explicitly specified, use that
     if let Some(file) = explicit_file {
         let path = context_path.join(file);
@@ -102,7 +118,14 @@ pub async fn execute(args: BuildArgs) -> anyhow::Result<()> {
         })
         .collect();
 
-    let mut builder = ImageBuilder::new(&paths)?;
+    let registry = RegistryClient::configured(&paths)?
+        .with_quiet(args.quiet)
+        .with_max_concurrent_downloads(args.max_concurrent_downloads)
+        .with_progress(args.progress.sink())
+        .with_platform(Platform::requested(args.platform.as_deref())?);
+    let mut builder = ImageBuilder::new(&paths)?
+        .with_registry(registry)
+        .with_compression(args.compression);
 
     if !args.quiet {
         eprintln!("Using {} as container file", container_file);
@@ -0,0 +1,78 @@
+//! `darker builder` command implementation
+
+use crate::cli::images::format_size;
+use crate::image::layer::LayerManager;
+use crate::storage::blobs::BlobStore;
+use crate::storage::build_cache::BuildCache;
+use crate::storage::paths::DarkerPaths;
+use clap::{Args, Subcommand};
+
+/// Arguments for the `builder` command
+#[derive(Args)]
+pub struct BuilderArgs {
+    #[command(subcommand)]
+    pub command: BuilderCommands,
+}
+
+/// Builder subcommands
+#[derive(Subcommand)]
+pub enum BuilderCommands {
+    /// Remove the build cache
+    Prune(BuilderPruneArgs),
+}
+
+/// Arguments for builder prune
+#[derive(Args)]
+pub struct BuilderPruneArgs {
+    /// Do not prompt for confirmation
+    #[arg(short, long)]
+    pub force: bool,
+}
+
+/// Execute the `builder` command
+pub async fn execute(args: BuilderArgs) -> anyhow::Result<()> {
+    let paths = DarkerPaths::new()?;
+
+    match args.command {
+        BuilderCommands::Prune(prune_args) => {
+            if !prune_args.force {
+                eprintln!("WARNING! This will remove all build cache.");
+                if !crate::cli::confirm()? {
+                    return Ok(());
+                }
+            }
+
+            let (removed, reclaimed) = prune_build_cache(&paths)?;
+            if !removed.is_empty() {
+                println!("Deleted build cache objects:");
+                for key in removed {
+                    println!("{}", &key[..12.min(key.len())]);
+                }
+                println!();
+            }
+            println!("Total reclaimed space: {}", format_size(reclaimed));
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove every cached build step, and the layers and blobs only they kept
+///
+/// Returns the removed cache keys and the number of bytes reclaimed.
+pub(crate) fn prune_build_cache(paths: &DarkerPaths) -> anyhow::Result<(Vec<String>, u64)> {
+    let entries = BuildCache::new(paths).clear()?;
+
+    let layers: Vec<String> = entries
+        .iter()
+        .filter_map(|(_, e)| e.layer.clone())
+        .collect();
+    let blobs: Vec<String> = entries
+        .iter()
+        .filter_map(|(_, e)| e.blob_digest.clone())
+        .collect();
+    let mut reclaimed = LayerManager::new(paths).remove_unreferenced(&layers)?;
+    reclaimed += BlobStore::new(paths).remove_unreferenced(&blobs)?;
+
+    Ok((entries.into_iter().map(|(key, _)| key).collect(), reclaimed))
+}
@@ -0,0 +1,116 @@
+//! `darker export` and `darker import` command implementations
+
+use crate::filesystem::rootfs::{write_tar, RootFs};
+use crate::image::import::import_rootfs;
+use crate::image::oci::{ImageReference, Platform};
+use crate::storage::containers::ContainerStore;
+use crate::storage::paths::DarkerPaths;
+use crate::DarkerError;
+use clap::Args;
+use std::fs::{self, File};
+use std::io::{BufWriter, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Arguments for the `export` command
+#[derive(Args)]
+pub struct ExportArgs {
+    /// Container name or ID
+    pub container: String,
+
+    /// Write to a file instead of stdout
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+/// Arguments for the `import` command
+#[derive(Args)]
+pub struct ImportArgs {
+    /// Tarball or directory of a root filesystem, or '-' for a tarball on stdin
+    pub source: String,
+
+    /// Name and tag for the new image
+    pub reference: Option<String>,
+
+    /// Apply a Dockerfile instruction to the image config (e.g. 'CMD ["/app"]')
+    #[arg(short, long)]
+    pub change: Vec<String>,
+
+    /// Platform of the image, if it isn't the host's
+    #[arg(long)]
+    pub platform: Option<String>,
+}
+
+/// Execute the `export` command
+pub async fn execute(args: ExportArgs) -> anyhow::Result<()> {
+    let paths = DarkerPaths::new()?;
+    let container_store = ContainerStore::new(&paths)?;
+
+    let container_id = container_store
+        .find(&args.container)
+        .ok_or_else(|| DarkerError::ContainerNotFound(args.container.clone()))?;
+    let rootfs = RootFs::new(&paths, &container_id)?;
+
+    match &args.output {
+        Some(path) => {
+            let mut writer = BufWriter::new(File::create(path)?);
+            rootfs.export(&mut writer)?;
+            writer.flush()?;
+        }
+        None => {
+            let stdout = std::io::stdout();
+            if stdout.is_terminal() {
+                anyhow::bail!(
+                    "Refusing to write an archive to a terminal, use -o or redirect the output"
+                );
+            }
+            rootfs.export(BufWriter::new(stdout.lock()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Execute the `import` command
+pub async fn execute_import(args: ImportArgs) -> anyhow::Result<()> {
+    let paths = DarkerPaths::new()?;
+    paths.ensure_directories()?;
+
+    let reference = args
+        .reference
+        .as_deref()
+        .map(ImageReference::parse)
+        .transpose()?;
+    let platform = args.platform.as_deref().map(Platform::parse).transpose()?;
+
+    let import = |reader: &mut dyn Read| {
+        import_rootfs(
+            &paths,
+            reader,
+            reference.as_ref(),
+            &args.change,
+            platform.as_ref(),
+        )
+    };
+    let image_id = if args.source == "-" {
+        import(&mut std::io::stdin().lock())?
+    } else if Path::new(&args.source).is_dir() {
+        // Directories are archived first, keeping symlinks as they are
+        let tar_path = paths
+            .tmp_dir()
+            .join(format!("{}.tar", uuid::Uuid::new_v4()));
+        let result = write_tar(
+            Path::new(&args.source),
+            BufWriter::new(File::create(&tar_path)?),
+            &|_, _| false,
+        )
+        .and_then(|_| import(&mut File::open(&tar_path)?));
+        fs::remove_file(&tar_path)?;
+        result?
+    } else {
+        import(&mut File::open(&args.source)?)?
+    };
+
+    println!("sha256:{}", image_id);
+
+    Ok(())
+}
@@ -0,0 +1,155 @@
+//! `darker image` command implementation
+
+use crate::cli::images::format_size;
+use crate::cli::search::{matches_filter, ListFormat};
+use crate::image::layer::LayerManager;
+use crate::image::oci::ImageReference;
+use crate::image::registry::RegistryClient;
+use crate::storage::blobs::BlobStore;
+use crate::storage::containers::ContainerStore;
+use crate::storage::images::ImageStore;
+use crate::storage::paths::DarkerPaths;
+use clap::{Args, Subcommand};
+use std::collections::HashSet;
+
+/// Arguments for the `image` command
+#[derive(Args)]
+pub struct ImageArgs {
+    #[command(subcommand)]
+    pub command: ImageCommands,
+}
+
+/// Image subcommands
+#[derive(Subcommand)]
+pub enum ImageCommands {
+    /// List the tags of a repository in a registry
+    Tags(ImageTagsArgs),
+    /// Remove unused images and the layers only they use
+    Prune(ImagePruneArgs),
+}
+
+/// Arguments for image tags
+#[derive(Args)]
+pub struct ImageTagsArgs {
+    /// Repository to list tags for (e.g. alpine, ghcr.io/org/app)
+    pub repository: String,
+
+    /// Only show tags matching a glob pattern (e.g. '3.*')
+    #[arg(short, long)]
+    pub filter: Option<String>,
+
+    /// Format the output
+    #[arg(long, value_enum, default_value_t = ListFormat::Table)]
+    pub format: ListFormat,
+}
+
+/// Arguments for image prune
+#[derive(Args)]
+pub struct ImagePruneArgs {
+    /// Remove all images not used by a container, not just dangling ones
+    #[arg(short, long)]
+    pub all: bool,
+
+    /// Do not prompt for confirmation
+    #[arg(short, long)]
+    pub force: bool,
+}
+
+/// Execute the `image` command
+pub async fn execute(args: ImageArgs) -> anyhow::Result<()> {
+    let paths = DarkerPaths::new()?;
+
+    match args.command {
+        ImageCommands::Tags(tags_args) => {
+            // A ':' in the last path component is a tag rather than a registry port
+            let name = tags_args.repository.rsplit('/').next().unwrap_or("");
+            if name.contains(':') || name.contains('@') {
+                anyhow::bail!(
+                    "Expected a repository without a tag or digest: {}",
+                    tags_args.repository
+                );
+            }
+            let reference = ImageReference::parse(&tags_args.repository)?;
+
+            let client = RegistryClient::configured(&paths)?;
+            let tags: Vec<String> = client
+                .list_tags(&reference, &paths)
+                .await?
+                .into_iter()
+                .filter(|tag| matches_filter(tags_args.filter.as_deref(), tag))
+                .collect();
+            let repository = reference.repository_with_registry();
+
+            match tags_args.format {
+                ListFormat::Json => {
+                    let output = serde_json::json!({
+                        "name": repository,
+                        "tags": tags,
+                    });
+                    println!("{}", serde_json::to_string_pretty(&output)?);
+                }
+                ListFormat::Table => {
+                    println!("{:<40} {:<20}", "REPOSITORY", "TAG");
+                    for tag in tags {
+                        println!("{:<40} {:<20}", repository, tag);
+                    }
+                }
+            }
+        }
+        ImageCommands::Prune(prune_args) => {
+            if !prune_args.force {
+                if prune_args.all {
+                    eprintln!(
+                        "WARNING! This will remove all images without at least one container \
+                         associated to them."
+                    );
+                } else {
+                    eprintln!("WARNING! This will remove all dangling images.");
+                }
+                if !crate::cli::confirm()? {
+                    return Ok(());
+                }
+            }
+
+            let (removed, reclaimed) = prune_images(&paths, prune_args.all)?;
+            for image_id in removed {
+                println!("Deleted Image: {}", &image_id[..12.min(image_id.len())]);
+            }
+
+            println!();
+            println!("Total reclaimed space: {}", format_size(reclaimed));
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove unused images, then every layer no longer referenced
+///
+/// Only images without a repository are removed, or with `all` every image
+/// no container was created from. Returns the removed image IDs and the
+/// number of bytes reclaimed.
+pub(crate) fn prune_images(paths: &DarkerPaths, all: bool) -> anyhow::Result<(Vec<String>, u64)> {
+    let image_store = ImageStore::new(paths)?;
+    let used: HashSet<String> = ContainerStore::new(paths)?
+        .list()?
+        .into_iter()
+        .map(|c| c.image_id)
+        .collect();
+
+    let mut removed = Vec::new();
+    let mut reclaimed = 0;
+    for image in image_store.list()? {
+        if used.contains(&image.id) || (!all && image.repository.is_some()) {
+            continue;
+        }
+        reclaimed += image_store.remove(&image.id, true)?;
+        removed.push(image.id);
+    }
+
+    // Layers and blobs left behind by earlier removals or removed containers
+    reclaimed += LayerManager::new(paths).prune()?;
+    reclaimed += BlobStore::new(paths).prune()?;
+
+    Ok((removed, reclaimed))
+}
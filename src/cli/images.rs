@@ -96,7 +96,11 @@ pub async fn execute(args: ImagesArgs) -> anyhow::Result<()> {
         let size = format_size(image.size);
 
         if args.digests {
-            let digest = image.digest.as_deref().unwrap_or("<none>");
+            let digest = image
+                .repo_digests
+                .iter()
+                .find_map(|d| d.strip_prefix(&format!("{}@", repo)))
+                .unwrap_or("<none>");
             println!(
                 "{:<20} {:<20} {:<72} {:<20} {:<20} {:<10}",
                 repo, tag, digest, id, created, size
@@ -135,7 +139,7 @@ fn format_time_ago(time: chrono::DateTime<chrono::Utc>) -> String {
 }
 
 /// Format a size in bytes as a human-readable string
-fn format_size(bytes: u64) -> String {
+pub(crate) fn format_size(bytes: u64) -> String {
     const KB: u64 = 1024;
     const MB: u64 = KB * 1024;
     const GB: u64 = MB * 1024;
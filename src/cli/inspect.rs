@@ -1,5 +1,6 @@
 //! `darker inspect` command implementation
 
+use crate::image::oci::Platform;
 use crate::storage::containers::ContainerStore;
 use crate::storage::images::ImageStore;
 use crate::storage::paths::DarkerPaths;
@@ -122,6 +123,11 @@ fn inspect_image(store: &ImageStore, name: &str) -> crate::Result<serde_json::Va
         .ok_or_else(|| DarkerError::ImageNotFound(name.to_string()))?;
 
     let metadata = store.load_metadata(&image_id)?;
+    let platform = metadata
+        .platform
+        .as_deref()
+        .and_then(|p| Platform::parse(p).ok())
+        .unwrap_or_else(|| Platform::new("darwin", "darwin", None));
 
     Ok(json!({
         "Id": format!("sha256:{}", metadata.id),
@@ -129,11 +135,11 @@ fn inspect_image(store: &ImageStore, name: &str) -> crate::Result<serde_json::Va
             metadata.repository.as_deref().unwrap_or("<none>"),
             metadata.tag.as_deref().unwrap_or("<none>")
         )],
-        "RepoDigests": metadata.digest.map(|d| vec![d]).unwrap_or_default(),
+        "RepoDigests": metadata.repo_digests,
         "Created": metadata.created.to_rfc3339(),
         "Size": metadata.size,
-        "Architecture": "darwin",
-        "Os": "darwin",
+        "Architecture": platform.architecture,
+        "Os": platform.os,
         "Config": {
             "Hostname": "",
             "Env": [],
@@ -0,0 +1,135 @@
+//! `darker login` and `darker logout` command implementations
+
+use crate::image::registry::RegistryClient;
+use crate::storage::credentials::{normalize_registry, CredentialStore, Credentials};
+use crate::storage::paths::DarkerPaths;
+use clap::Args;
+use std::io::{BufRead, IsTerminal, Write};
+use std::os::unix::io::AsRawFd;
+
+/// Arguments for the `login` command
+#[derive(Args)]
+pub struct LoginArgs {
+    /// Registry server (defaults to Docker Hub)
+    pub server: Option<String>,
+
+    /// Username
+    #[arg(short, long)]
+    pub username: Option<String>,
+
+    /// Password
+    #[arg(short, long)]
+    pub password: Option<String>,
+
+    /// Take the password from stdin
+    #[arg(long)]
+    pub password_stdin: bool,
+}
+
+/// Arguments for the `logout` command
+#[derive(Args)]
+pub struct LogoutArgs {
+    /// Registry server (defaults to Docker Hub)
+    pub server: Option<String>,
+}
+
+/// Execute the `login` command
+pub async fn execute(args: LoginArgs) -> anyhow::Result<()> {
+    let paths = DarkerPaths::new()?;
+    let registry = normalize_registry(args.server.as_deref().unwrap_or(""));
+
+    if args.password.is_some() && args.password_stdin {
+        anyhow::bail!("--password and --password-stdin are mutually exclusive");
+    }
+
+    let username = match args.username {
+        Some(username) => username,
+        None => prompt("Username: ")?,
+    };
+
+    let password = if args.password_stdin {
+        let mut password = String::new();
+        std::io::stdin().lock().read_line(&mut password)?;
+        password.trim_end_matches(['\n', '\r']).to_string()
+    } else {
+        match args.password {
+            Some(password) => {
+                eprintln!(
+                    "WARNING! Using --password via the CLI is insecure. Use --password-stdin."
+                );
+                password
+            }
+            None => prompt_password("Password: ")?,
+        }
+    };
+
+    if username.is_empty() || password.is_empty() {
+        anyhow::bail!("Username and password are required");
+    }
+
+    let credentials = Credentials { username, password };
+
+    let client = RegistryClient::configured(&paths)?;
+    client.login(&registry, &credentials).await?;
+
+    CredentialStore::new(&paths)?.store(&registry, &credentials)?;
+    println!("Login Succeeded");
+
+    Ok(())
+}
+
+/// Execute the `logout` command
+pub async fn execute_logout(args: LogoutArgs) -> anyhow::Result<()> {
+    let paths = DarkerPaths::new()?;
+    let registry = normalize_registry(args.server.as_deref().unwrap_or(""));
+
+    if CredentialStore::new(&paths)?.remove(&registry)? {
+        println!("Removing login credentials for {}", registry);
+    } else {
+        eprintln!("Not logged in to {}", registry);
+    }
+
+    Ok(())
+}
+
+/// Prompt for a line of input on stderr
+fn prompt(message: &str) -> anyhow::Result<String> {
+    eprint!("{}", message);
+    std::io::stderr().flush()?;
+
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+/// Prompt for a password on stderr, without echoing it on a terminal
+///
+/// Only the line ending is stripped, like with `--password-stdin`.
+fn prompt_password(message: &str) -> anyhow::Result<String> {
+    eprint!("{}", message);
+    std::io::stderr().flush()?;
+
+    let stdin = std::io::stdin();
+    let fd = stdin.as_raw_fd();
+    let mut saved = None;
+    if stdin.is_terminal() {
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(fd, &mut termios) } == 0 {
+            // Still echo the newline, so the next output starts on its own line
+            let mut silent = termios;
+            silent.c_lflag &= !libc::ECHO;
+            silent.c_lflag |= libc::ECHONL;
+            if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) } == 0 {
+                saved = Some(termios);
+            }
+        }
+    }
+
+    let mut line = String::new();
+    let read = stdin.lock().read_line(&mut line);
+    if let Some(termios) = saved {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) };
+    }
+    read?;
+    Ok(line.trim_end_matches(['\n', '\r']).to_string())
+}
@@ -55,7 +55,13 @@ pub async fn execute(args: LogsArgs) -> anyhow::Result<()> {
 
     let file = std::fs::File::open(&log_path)?;
     let reader = BufReader::new(file);
-    let lines: Vec<String> = reader.lines().filter_map(|l| l.ok()).collect();
+    // Lines that aren't valid UTF-8 are skipped, a read error ends the logs
+    let lines: Vec<String> = reader
+        .split(b'\n')
+        .map_while(|l| l.ok())
+        .filter_map(|l| String::from_utf8(l).ok())
+        .map(|l| l.strip_suffix('\r').map(str::to_string).unwrap_or(l))
+        .collect();
 
     // Handle tail parameter
     let tail_count = if args.tail == "all" {
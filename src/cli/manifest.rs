@@ -0,0 +1,240 @@
+//! `darker manifest` command implementation
+
+use crate::image::oci::{ImageReference, Platform};
+use crate::image::registry::{local_manifest_descriptor, RegistryClient};
+use crate::storage::images::ImageStore;
+use crate::storage::manifest_lists::{ManifestList, ManifestListEntry, ManifestListStore};
+use crate::storage::paths::DarkerPaths;
+use clap::{Args, Subcommand};
+
+/// Arguments for the `manifest` command
+#[derive(Args)]
+pub struct ManifestArgs {
+    #[command(subcommand)]
+    pub command: ManifestCommands,
+}
+
+/// Manifest subcommands
+#[derive(Subcommand)]
+pub enum ManifestCommands {
+    /// Create a local manifest list from images for different platforms
+    Create(ManifestCreateArgs),
+
+    /// Show a local manifest list, or any manifest or index in a registry
+    Inspect(ManifestInspectArgs),
+
+    /// Set the platform of a manifest in a local manifest list
+    Annotate(ManifestAnnotateArgs),
+
+    /// Push a local manifest list to a registry
+    Push(ManifestPushArgs),
+
+    /// Remove local manifest lists
+    Rm(ManifestRmArgs),
+}
+
+/// Arguments for manifest create
+#[derive(Args)]
+pub struct ManifestCreateArgs {
+    /// Reference the list is pushed to
+    pub list: String,
+
+    /// Local images or remote manifests to add to the list
+    #[arg(required = true)]
+    pub manifests: Vec<String>,
+
+    /// Add to an existing list instead of failing
+    #[arg(short, long)]
+    pub amend: bool,
+}
+
+/// Arguments for manifest inspect
+#[derive(Args)]
+pub struct ManifestInspectArgs {
+    /// Manifest list or image reference
+    pub name: String,
+}
+
+/// Arguments for manifest annotate
+#[derive(Args)]
+pub struct ManifestAnnotateArgs {
+    /// Manifest list to change
+    pub list: String,
+
+    /// Manifest in the list, by the reference it was added as or its digest
+    pub manifest: String,
+
+    /// Operating system (e.g. linux)
+    #[arg(long)]
+    pub os: Option<String>,
+
+    /// Architecture (e.g. amd64, arm64)
+    #[arg(long)]
+    pub arch: Option<String>,
+
+    /// Architecture variant (e.g. v7)
+    #[arg(long)]
+    pub variant: Option<String>,
+
+    /// Operating system version
+    #[arg(long)]
+    pub os_version: Option<String>,
+
+    /// Operating system features
+    #[arg(long, value_delimiter = ',')]
+    pub os_features: Vec<String>,
+}
+
+/// Arguments for manifest push
+#[derive(Args)]
+pub struct ManifestPushArgs {
+    /// Manifest list to push
+    pub list: String,
+
+    /// Remove the local list after pushing it
+    #[arg(short, long)]
+    pub purge: bool,
+
+    /// Suppress verbose output
+    #[arg(short, long)]
+    pub quiet: bool,
+}
+
+/// Arguments for manifest rm
+#[derive(Args)]
+pub struct ManifestRmArgs {
+    /// Manifest lists to remove
+    #[arg(required = true)]
+    pub lists: Vec<String>,
+}
+
+/// Execute the `manifest` command
+pub async fn execute(args: ManifestArgs) -> anyhow::Result<()> {
+    let paths = DarkerPaths::new()?;
+    let store = ManifestListStore::new(&paths)?;
+
+    match args.command {
+        ManifestCommands::Create(create_args) => {
+            let reference = ImageReference::parse(&create_args.list)?;
+            let mut list = match store.load(&reference)? {
+                Some(_) if !create_args.amend => anyhow::bail!(
+                    "Manifest list {} already exists, use --amend to add to it",
+                    reference.full_name()
+                ),
+                Some(list) => list,
+                None => ManifestList::new(&reference),
+            };
+
+            let image_store = ImageStore::new(&paths)?;
+            let client = RegistryClient::configured(&paths)?;
+            for manifest in &create_args.manifests {
+                let source = ImageReference::parse(manifest)?;
+
+                // Local images are pushed along with the list
+                let (image_id, descriptor) = match image_store.find_image(&source) {
+                    Some(image_id) => {
+                        let descriptor = local_manifest_descriptor(&paths, &image_id)?;
+                        (Some(image_id), descriptor)
+                    }
+                    None => (None, client.manifest_descriptor(&source, &paths).await?),
+                };
+                list.add(ManifestListEntry {
+                    source: source.full_name(),
+                    image_id,
+                    descriptor,
+                });
+            }
+
+            store.save(&list)?;
+            println!("Created manifest list {}", list.name);
+        }
+        ManifestCommands::Inspect(inspect_args) => {
+            let reference = ImageReference::parse(&inspect_args.name)?;
+            let value: serde_json::Value = match store.load(&reference)? {
+                Some(list) => serde_json::to_value(list.index())?,
+                None => {
+                    let client = RegistryClient::configured(&paths)?;
+                    let (_, body) = client.fetch_raw_manifest(&reference, &paths).await?;
+                    serde_json::from_slice(&body)?
+                }
+            };
+            println!("{}", serde_json::to_string_pretty(&value)?);
+        }
+        ManifestCommands::Annotate(annotate_args) => {
+            let reference = ImageReference::parse(&annotate_args.list)?;
+            let mut list = load_list(&store, &reference)?;
+            let entry = list.find_mut(&annotate_args.manifest).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Manifest {} is not in list {}",
+                    annotate_args.manifest,
+                    reference.full_name()
+                )
+            })?;
+
+            let current = entry.descriptor.platform.take();
+            let (Some(os), Some(arch)) = (
+                annotate_args
+                    .os
+                    .or_else(|| current.as_ref().map(|p| p.os.clone())),
+                annotate_args
+                    .arch
+                    .or_else(|| current.as_ref().map(|p| p.architecture.clone())),
+            ) else {
+                anyhow::bail!(
+                    "Manifest {} has no platform, set --os and --arch",
+                    annotate_args.manifest
+                );
+            };
+            let current = current.as_ref();
+            let variant = annotate_args
+                .variant
+                .or_else(|| current.and_then(|p| p.variant.clone()));
+
+            let mut platform = Platform::new(&os, &arch, variant.as_deref());
+            platform.os_version = annotate_args
+                .os_version
+                .or_else(|| current.and_then(|p| p.os_version.clone()));
+            platform.os_features = if annotate_args.os_features.is_empty() {
+                current.and_then(|p| p.os_features.clone())
+            } else {
+                Some(annotate_args.os_features)
+            };
+            platform.features = current.and_then(|p| p.features.clone());
+            entry.descriptor.platform = Some(platform);
+
+            store.save(&list)?;
+        }
+        ManifestCommands::Push(push_args) => {
+            let reference = ImageReference::parse(&push_args.list)?;
+            let list = load_list(&store, &reference)?;
+
+            let client = RegistryClient::configured(&paths)?.with_quiet(push_args.quiet);
+            let (digest, _) = client.push_manifest_list(&list, &paths).await?;
+            if push_args.purge {
+                store.remove(&reference)?;
+            }
+            println!("{}", digest);
+        }
+        ManifestCommands::Rm(rm_args) => {
+            for name in &rm_args.lists {
+                let reference = ImageReference::parse(name)?;
+                if !store.remove(&reference)? {
+                    anyhow::bail!("No such manifest list: {}", name);
+                }
+                println!("Deleted: {}", reference.full_name());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Load a local manifest list, failing if it doesn't exist
+fn load_list(
+    store: &ManifestListStore,
+    reference: &ImageReference,
+) -> anyhow::Result<ManifestList> {
+    store
+        .load(reference)?
+        .ok_or_else(|| anyhow::anyhow!("No such manifest list: {}", reference.full_name()))
+}
@@ -1,16 +1,23 @@
 //! CLI command definitions and handlers
 
 pub mod build;
+pub mod builder;
 pub mod exec;
+pub mod export;
+pub mod image;
 pub mod images;
 pub mod inspect;
+pub mod login;
 pub mod logs;
+pub mod manifest;
 pub mod network;
 pub mod ps;
 pub mod pull;
 pub mod push;
 pub mod rm;
 pub mod run;
+pub mod save;
+pub mod search;
 pub mod start;
 pub mod stop;
 pub mod system;
@@ -40,6 +47,9 @@ pub enum Commands {
     /// Build an image from a Dockerfile
     Build(build::BuildArgs),
 
+    /// Manage the build cache
+    Builder(builder::BuilderArgs),
+
     /// List images
     Images(images::ImagesArgs),
 
@@ -58,6 +68,33 @@ pub enum Commands {
     /// Push an image to a registry
     Push(push::PushArgs),
 
+    /// Save images to a tar archive
+    Save(save::SaveArgs),
+
+    /// Load images from a tar archive
+    Load(save::LoadArgs),
+
+    /// Export a container's filesystem as a tar archive
+    Export(export::ExportArgs),
+
+    /// Create an image from a tarball or directory of a root filesystem
+    Import(export::ImportArgs),
+
+    /// List the repositories in a registry
+    Search(search::SearchArgs),
+
+    /// Manage images
+    Image(image::ImageArgs),
+
+    /// Manage multi-platform manifest lists
+    Manifest(manifest::ManifestArgs),
+
+    /// Log in to a registry
+    Login(login::LoginArgs),
+
+    /// Log out from a registry
+    Logout(login::LogoutArgs),
+
     /// Fetch the logs of a container
     Logs(logs::LogsArgs),
 
@@ -88,3 +125,17 @@ pub enum Commands {
     /// Attach to a running container
     Attach(exec::AttachArgs),
 }
+
+/// Ask whether to continue with a destructive command
+///
+/// Only an answer of `y` or `yes` on stdin confirms it.
+pub(crate) fn confirm() -> std::io::Result<bool> {
+    use std::io::Write;
+
+    eprint!("Are you sure you want to continue? [y/N] ");
+    std::io::stderr().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
@@ -58,7 +58,7 @@ pub async fn execute(args: PsArgs) -> anyhow::Result<()> {
     }
 
     // Sort by creation time (newest first)
-    containers.sort_by(|a, b| b.created.cmp(&a.created));
+    containers.sort_by_key(|c| std::cmp::Reverse(c.created));
 
     // Apply --last or --latest filter
     if args.latest {
@@ -141,7 +141,7 @@ fn format_status(state: &crate::storage::containers::ContainerState) -> String {
         let exit_code = state.exit_code.unwrap_or(0);
         let finished = state
             .finished_at
-            .map(|t| format_time_ago(t))
+            .map(format_time_ago)
             .unwrap_or_else(|| "unknown".to_string());
         format!("Exited ({}) {}", exit_code, finished)
     } else {
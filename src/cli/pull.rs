@@ -1,14 +1,19 @@
 //! `darker pull` command implementation
 
-use crate::image::oci::ImageReference;
-use crate::image::registry::RegistryClient;
+use crate::cli::save::print_loaded;
+use crate::image::layout::{self, Transport};
+use crate::image::oci::{ImageReference, Platform};
+use crate::image::progress::ProgressMode;
+use crate::image::registry::{RegistryClient, DEFAULT_MAX_CONCURRENT_DOWNLOADS};
+use crate::storage::images::ImageStore;
 use crate::storage::paths::DarkerPaths;
 use clap::Args;
 
 /// Arguments for the `pull` command
 #[derive(Args)]
 pub struct PullArgs {
-    /// Image name to pull
+    /// Image name to pull, or `oci:<dir>[:<ref>]`, `oci-archive:<file>[:<ref>]`
+    /// or `docker-archive:<file>` to import from a local archive
     pub image: String,
 
     /// Download all tagged images in the repository
@@ -26,6 +31,14 @@ pub struct PullArgs {
     /// Suppress verbose output
     #[arg(short, long)]
     pub quiet: bool,
+
+    /// Set type of progress output (auto, plain, tty, json)
+    #[arg(long, value_enum, default_value_t = ProgressMode::Auto)]
+    pub progress: ProgressMode,
+
+    /// Maximum number of layers to download at the same time
+    #[arg(long, default_value_t = DEFAULT_MAX_CONCURRENT_DOWNLOADS)]
+    pub max_concurrent_downloads: usize,
 }
 
 /// Execute the `pull` command
@@ -33,6 +46,16 @@ pub async fn execute(args: PullArgs) -> anyhow::Result<()> {
     let paths = DarkerPaths::new()?;
     paths.ensure_directories()?;
 
+    // Local archives are imported rather than fetched from a registry
+    if let Some(transport) = Transport::parse(&args.image) {
+        let platform = Platform::requested(args.platform.as_deref())?;
+        let loaded = layout::load_transport(&paths, &transport, platform.as_ref())?;
+        if !args.quiet {
+            print_loaded(&loaded);
+        }
+        return Ok(());
+    }
+
     let image_ref = ImageReference::parse(&args.image)?;
 
     if !args.quiet {
@@ -42,11 +65,23 @@ pub async fn execute(args: PullArgs) -> anyhow::Result<()> {
         );
     }
 
-    let registry = RegistryClient::new()?;
+    let registry = RegistryClient::configured(&paths)?
+        .with_quiet(args.quiet)
+        .with_max_concurrent_downloads(args.max_concurrent_downloads)
+        .with_progress(args.progress.sink())
+        .with_platform(Platform::requested(args.platform.as_deref())?);
     let image_id = registry.pull(&image_ref, &paths).await?;
 
     if !args.quiet {
-        eprintln!("Digest: sha256:{}", &image_id[..12]);
+        let metadata = ImageStore::new(&paths)?.load_metadata(&image_id)?;
+        let prefix = format!("{}@", image_ref.repository_with_registry());
+        if let Some(digest) = metadata
+            .repo_digests
+            .iter()
+            .find_map(|d| d.strip_prefix(&prefix))
+        {
+            eprintln!("Digest: {}", digest);
+        }
         eprintln!(
             "Status: Downloaded newer image for {}",
             image_ref.full_name()
@@ -33,24 +33,43 @@ pub async fn execute(args: PushArgs) -> anyhow::Result<()> {
 
     let image_ref = ImageReference::parse(&args.image)?;
 
-    // Find the image locally
-    let image_id = image_store
-        .find_image(&image_ref)
-        .ok_or_else(|| DarkerError::ImageNotFound(args.image.clone()))?;
+    // Collect the tags to push
+    let targets: Vec<(ImageReference, String)> = if args.all_tags {
+        let tags = image_store.list_tags(&image_ref.repository_with_registry())?;
+        if tags.is_empty() {
+            return Err(DarkerError::ImageNotFound(image_ref.repository_with_registry()).into());
+        }
+        tags.into_iter()
+            .map(|(tag, image_id)| {
+                let mut tagged = image_ref.clone();
+                tagged.tag = tag;
+                (tagged, image_id)
+            })
+            .collect()
+    } else {
+        let image_id = image_store
+            .find_image(&image_ref)
+            .ok_or_else(|| DarkerError::ImageNotFound(args.image.clone()))?;
+        vec![(image_ref.clone(), image_id)]
+    };
 
     if !args.quiet {
-        eprintln!("The push refers to repository [{}]", image_ref.repository_with_registry());
+        eprintln!(
+            "The push refers to repository [{}/{}]",
+            image_ref.registry, image_ref.repository
+        );
     }
 
-    let registry = RegistryClient::new()?;
-    registry.push(&image_ref, &image_id, &paths).await?;
+    let registry = RegistryClient::configured(&paths)?.with_quiet(args.quiet);
 
-    if !args.quiet {
-        eprintln!(
-            "{}: digest: sha256:{} size: unknown",
-            image_ref.tag(),
-            &image_id[..12]
-        );
+    for (reference, image_id) in targets {
+        let (digest, size) = registry.push(&reference, &image_id, &paths).await?;
+
+        if args.quiet {
+            println!("{}", reference.full_name());
+        } else {
+            eprintln!("{}: digest: {} size: {}", reference.tag(), digest, size);
+        }
     }
 
     Ok(())
@@ -1,5 +1,6 @@
 //! `darker rm` and `darker rmi` command implementations
 
+use crate::cli::images::format_size;
 use crate::storage::containers::ContainerStore;
 use crate::storage::images::ImageStore;
 use crate::storage::paths::DarkerPaths;
@@ -88,6 +89,7 @@ pub async fn execute_rmi(args: RmiArgs) -> anyhow::Result<()> {
     let paths = DarkerPaths::new()?;
     let image_store = ImageStore::new(&paths)?;
     let container_store = ContainerStore::new(&paths)?;
+    let mut reclaimed = 0;
 
     for image_ref in &args.images {
         let image_id = match image_store.find(image_ref) {
@@ -114,11 +116,15 @@ pub async fn execute_rmi(args: RmiArgs) -> anyhow::Result<()> {
             continue;
         }
 
-        // Remove image
-        image_store.remove(&image_id, !args.no_prune)?;
+        // Remove image, and the layers no other image or container uses
+        reclaimed += image_store.remove(&image_id, !args.no_prune)?;
 
         println!("Deleted: {}", image_id);
     }
 
+    if reclaimed > 0 {
+        println!("Total reclaimed space: {}", format_size(reclaimed));
+    }
+
     Ok(())
 }
@@ -1,5 +1,7 @@
 //! `darker run` command implementation
 
+use crate::image::progress::ProgressMode;
+use crate::image::registry::DEFAULT_MAX_CONCURRENT_DOWNLOADS;
 use crate::runtime::container::Container;
 use crate::storage::paths::DarkerPaths;
 use crate::DarkerError;
@@ -69,6 +71,18 @@ pub struct RunArgs {
     /// Run container in read-only mode
     #[arg(long)]
     pub read_only: bool,
+
+    /// Set platform if server is multi-platform capable (os/arch[/variant])
+    #[arg(long)]
+    pub platform: Option<String>,
+
+    /// Set type of progress output (auto, plain, tty, json)
+    #[arg(long, value_enum, default_value_t = ProgressMode::Auto)]
+    pub progress: ProgressMode,
+
+    /// Maximum number of layers to download at the same time
+    #[arg(long, default_value_t = DEFAULT_MAX_CONCURRENT_DOWNLOADS)]
+    pub max_concurrent_downloads: usize,
 }
 
 /// Execute the `run` command
@@ -87,14 +101,18 @@ pub async fn execute(args: RunArgs) -> anyhow::Result<()> {
         "scratch".to_string()
     } else {
         let image_ref = crate::image::oci::ImageReference::parse(&args.image)?;
-        match image_store.find_image(&image_ref) {
+        let platform = crate::image::oci::Platform::requested(args.platform.as_deref())?;
+        match image_store.find_image_for_platform(&image_ref, platform.as_ref()) {
             Some(id) => id,
             None => {
                 eprintln!("Unable to find image '{}' locally", args.image);
                 eprintln!("Pulling from registry...");
 
                 // Pull the image
-                let registry = crate::image::registry::RegistryClient::new()?;
+                let registry = crate::image::registry::RegistryClient::configured(&paths)?
+                    .with_max_concurrent_downloads(args.max_concurrent_downloads)
+                    .with_progress(args.progress.sink())
+                    .with_platform(platform);
                 let pulled_id = registry.pull(&image_ref, &paths).await?;
                 eprintln!("Successfully pulled {}", args.image);
                 pulled_id
@@ -104,7 +122,7 @@ pub async fn execute(args: RunArgs) -> anyhow::Result<()> {
 
     // Create container
     let container_store = crate::storage::containers::ContainerStore::new(&paths)?;
-    let container_name = args.name.unwrap_or_else(|| generate_container_name());
+    let container_name = args.name.unwrap_or_else(generate_container_name);
 
     if container_store.exists(&container_name) {
         return Err(DarkerError::ContainerExists(container_name).into());
@@ -164,6 +182,11 @@ pub async fn execute(args: RunArgs) -> anyhow::Result<()> {
         read_only: args.read_only,
         auto_remove: args.rm,
         created: chrono::Utc::now(),
+        layers: if is_scratch {
+            Vec::new()
+        } else {
+            image_store.load_metadata(&image_id)?.layers
+        },
     };
 
     container_store.create(&config)?;
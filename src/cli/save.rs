@@ -0,0 +1,156 @@
+//! `darker save` and `darker load` command implementations
+
+use crate::image::archive::{self, LoadedImage};
+use crate::image::layout::{self, Transport};
+use crate::image::oci::ImageReference;
+use crate::storage::images::ImageStore;
+use crate::storage::paths::DarkerPaths;
+use crate::DarkerError;
+use clap::Args;
+use std::fs::File;
+use std::io::{BufWriter, IsTerminal, Write};
+use std::path::PathBuf;
+
+/// Arguments for the `save` command
+#[derive(Args)]
+pub struct SaveArgs {
+    /// Images to save, by name, tag or ID (a repository saves all of its tags)
+    #[arg(required = true)]
+    pub images: Vec<String>,
+
+    /// Write to a file instead of stdout, or to `oci:<dir>[:<ref>]` or
+    /// `oci-archive:<file>[:<ref>]` for an OCI image layout
+    #[arg(short, long)]
+    pub output: Option<String>,
+}
+
+/// Arguments for the `load` command
+#[derive(Args)]
+pub struct LoadArgs {
+    /// Read from a tar archive file instead of stdin, or from
+    /// `oci:<dir>[:<ref>]` or `oci-archive:<file>[:<ref>]`
+    #[arg(short, long)]
+    pub input: Option<String>,
+
+    /// Suppress the load output
+    #[arg(short, long)]
+    pub quiet: bool,
+}
+
+/// Execute the `save` command
+pub async fn execute(args: SaveArgs) -> anyhow::Result<()> {
+    let paths = DarkerPaths::new()?;
+    let image_store = ImageStore::new(&paths)?;
+
+    let mut images = Vec::new();
+    for name in &args.images {
+        images.extend(resolve(&image_store, name)?);
+    }
+
+    let output = args.output.as_deref().map(|output| {
+        Transport::parse(output).unwrap_or_else(|| Transport::DockerArchive(PathBuf::from(output)))
+    });
+    match output {
+        Some(Transport::DockerArchive(path)) => {
+            let mut writer = BufWriter::new(File::create(path)?);
+            archive::save(&paths, &images, &mut writer)?;
+            writer.flush()?;
+        }
+        Some(Transport::Oci { path, reference }) => {
+            layout::save_layout(&paths, &images, &path, reference.as_deref())?;
+        }
+        Some(Transport::OciArchive { path, reference }) => {
+            let mut writer = BufWriter::new(File::create(path)?);
+            layout::save_layout_archive(&paths, &images, reference.as_deref(), &mut writer)?;
+            writer.flush()?;
+        }
+        None => {
+            let stdout = std::io::stdout();
+            if stdout.is_terminal() {
+                anyhow::bail!(
+                    "Refusing to write an archive to a terminal, use -o or redirect the output"
+                );
+            }
+            archive::save(&paths, &images, BufWriter::new(stdout.lock()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Execute the `load` command
+pub async fn execute_load(args: LoadArgs) -> anyhow::Result<()> {
+    let paths = DarkerPaths::new()?;
+    paths.ensure_directories()?;
+
+    // Plain files may hold a docker-archive or an oci-archive
+    let loaded = match &args.input {
+        Some(input) => match Transport::parse(input) {
+            Some(transport) => layout::load_transport(&paths, &transport, None)?,
+            None => archive::load(&paths, File::open(input)?)?,
+        },
+        None => {
+            let stdin = std::io::stdin();
+            if stdin.is_terminal() {
+                anyhow::bail!("Requested load from stdin, but stdin is empty");
+            }
+            archive::load(&paths, stdin.lock())?
+        }
+    };
+
+    if !args.quiet {
+        print_loaded(&loaded);
+    }
+
+    Ok(())
+}
+
+/// Print the names of loaded images, or their IDs if they have none
+pub(crate) fn print_loaded(loaded: &[LoadedImage]) {
+    for image in loaded {
+        if image.tags.is_empty() {
+            println!("Loaded image ID: sha256:{}", image.image_id);
+        }
+        for tag in &image.tags {
+            println!("Loaded image: {}", tag);
+        }
+    }
+}
+
+/// Resolve a name given to `save` to image IDs and the tags to save them with
+fn resolve(
+    image_store: &ImageStore,
+    name: &str,
+) -> anyhow::Result<Vec<(String, Vec<ImageReference>)>> {
+    let last = name.rsplit('/').next().unwrap_or(name);
+    let reference = ImageReference::parse(name)?;
+
+    // A bare repository name saves every tag of the repository
+    if !last.contains(':') && !last.contains('@') {
+        let tags = image_store.list_tags(&reference.repository_with_registry())?;
+        if !tags.is_empty() {
+            return Ok(tags
+                .into_iter()
+                .map(|(tag, image_id)| {
+                    let mut tagged = reference.clone();
+                    tagged.tag = tag;
+                    (image_id, vec![tagged])
+                })
+                .collect());
+        }
+    }
+
+    if let Some(image_id) = image_store.find_image(&reference) {
+        let tags = match reference.digest {
+            Some(_) => Vec::new(),
+            None => vec![reference],
+        };
+        return Ok(vec![(image_id, tags)]);
+    }
+
+    // Images saved by ID are written without tags
+    let image_id = image_store
+        .find(name)
+        .ok_or_else(|| DarkerError::ImageNotFound(name.to_string()))?;
+    Ok(vec![(image_id, Vec::new())])
+}
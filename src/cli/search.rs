@@ -0,0 +1,103 @@
+//! `darker search` command implementation
+
+use crate::image::registry::RegistryClient;
+use crate::storage::paths::DarkerPaths;
+use clap::{Args, ValueEnum};
+
+/// Output format for registry listings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ListFormat {
+    /// Aligned columns with a header
+    Table,
+    /// A single JSON document
+    Json,
+}
+
+/// Arguments for the `search` command
+#[derive(Args)]
+pub struct SearchArgs {
+    /// Registry to list repositories from
+    pub registry: String,
+
+    /// Only show repositories matching a glob pattern (e.g. 'library/*')
+    #[arg(short, long)]
+    pub filter: Option<String>,
+
+    /// Format the output
+    #[arg(long, value_enum, default_value_t = ListFormat::Table)]
+    pub format: ListFormat,
+
+    /// Max number of repositories to show
+    #[arg(long)]
+    pub limit: Option<usize>,
+}
+
+/// Execute the `search` command
+pub async fn execute(args: SearchArgs) -> anyhow::Result<()> {
+    let paths = DarkerPaths::new()?;
+
+    let client = RegistryClient::configured(&paths)?;
+    let repositories: Vec<String> = client
+        .catalog(&args.registry, &paths)
+        .await?
+        .into_iter()
+        .filter(|repo| matches_filter(args.filter.as_deref(), repo))
+        .take(args.limit.unwrap_or(usize::MAX))
+        .collect();
+
+    match args.format {
+        ListFormat::Json => {
+            let output = serde_json::json!({
+                "registry": args.registry,
+                "repositories": repositories,
+            });
+            println!("{}", serde_json::to_string_pretty(&output)?);
+        }
+        ListFormat::Table => {
+            println!("NAME");
+            for repo in repositories {
+                println!("{}", repo);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check a name against an optional glob filter
+pub(crate) fn matches_filter(filter: Option<&str>, name: &str) -> bool {
+    filter
+        .map(|pattern| glob_match(pattern, name))
+        .unwrap_or(true)
+}
+
+/// Match a name against a glob pattern supporting `*` and `?`
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // Position to resume from after the last `*`: (pattern index, name index)
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -1,5 +1,7 @@
 //! `darker system` command implementation
 
+use crate::cli::builder::prune_build_cache;
+use crate::cli::image::prune_images;
 use crate::storage::containers::ContainerStore;
 use crate::storage::images::ImageStore;
 use crate::storage::paths::DarkerPaths;
@@ -111,14 +113,13 @@ pub async fn execute(args: SystemArgs) -> anyhow::Result<()> {
                 if prune_args.volumes {
                     eprintln!("  - all volumes not used by at least one container");
                 }
-                eprintln!("Are you sure you want to continue? [y/N]");
-                // In a real implementation, we'd read user input here
+                eprintln!("  - all build cache");
+                if !crate::cli::confirm()? {
+                    return Ok(());
+                }
             }
 
             let container_store = ContainerStore::new(&paths)?;
-            let image_store = ImageStore::new(&paths)?;
-
-            let mut total_space: u64 = 0;
 
             // Remove stopped containers
             let containers = container_store.list()?;
@@ -132,17 +133,18 @@ pub async fn execute(args: SystemArgs) -> anyhow::Result<()> {
                 }
             }
 
-            // Remove dangling images
-            if prune_args.all {
-                let images = image_store.list()?;
-                for image in images {
-                    if image.repository.is_none() {
-                        total_space += image.size;
-                        image_store.remove(&image.id, true)?;
-                        println!("Deleted Image: {}", &image.id[..12]);
-                    }
-                }
+            // Remove dangling images, or all unused ones, and unreferenced layers
+            let (removed, mut total_space) = prune_images(&paths, prune_args.all)?;
+            for image_id in removed {
+                println!("Deleted Image: {}", &image_id[..12.min(image_id.len())]);
+            }
+
+            // Remove the build cache and the layers only it kept
+            let (removed, reclaimed) = prune_build_cache(&paths)?;
+            if !removed.is_empty() {
+                println!("Deleted build cache objects: {}", removed.len());
             }
+            total_space += reclaimed;
 
             // Remove unused volumes
             if prune_args.volumes {
@@ -43,7 +43,7 @@ pub fn chroot_to(path: &Path) -> Result<()> {
 }
 
 #[cfg(not(target_os = "macos"))]
-pub fn chroot_to(path: &Path) -> Result<()> {
+pub fn chroot_to(_path: &Path) -> Result<()> {
     Err(DarkerError::Unsupported(
         "chroot not supported on this platform".to_string(),
     ))
@@ -65,7 +65,7 @@ impl PseudoChroot {
     /// Create a new pseudo-chroot environment
     pub fn new(rootfs: &Path) -> Result<Self> {
         let original_cwd = std::env::current_dir()
-            .map_err(|e| DarkerError::Io(e))?;
+            .map_err(DarkerError::Io)?;
 
         Ok(Self {
             rootfs: rootfs.to_path_buf(),
@@ -3,6 +3,8 @@
 use crate::darwin::chroot::can_chroot;
 use crate::{DarkerError, Result};
 use std::path::Path;
+use std::sync::Mutex;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
 
 /// High-level process spawner using posix_spawn
 pub struct ProcessSpawner;
@@ -13,6 +15,7 @@ impl ProcessSpawner {
     }
 
     /// Spawn a container process
+    #[allow(clippy::too_many_arguments)]
     pub async fn spawn_container(
         &self,
         command: &[String],
@@ -23,11 +26,139 @@ impl ProcessSpawner {
         interactive: bool,
         log_path: Option<&Path>,
     ) -> Result<i32> {
+        let mut cmd = self.command(command, rootfs, workdir, env, None)?;
+
+        // Configure I/O
+        if interactive || tty {
+            cmd.stdin(std::process::Stdio::inherit());
+            cmd.stdout(std::process::Stdio::inherit());
+            cmd.stderr(std::process::Stdio::inherit());
+
+            let mut child = cmd
+                .spawn()
+                .map_err(|e| DarkerError::Spawn(e.to_string()))?;
+
+            let status = child
+                .wait()
+                .await
+                .map_err(|e| DarkerError::Spawn(e.to_string()))?;
+
+            Ok(status.code().unwrap_or(1))
+        } else {
+            cmd.stdin(std::process::Stdio::null());
+            cmd.stdout(std::process::Stdio::piped());
+            cmd.stderr(std::process::Stdio::piped());
+
+            let mut child = cmd
+                .spawn()
+                .map_err(|e| DarkerError::Spawn(e.to_string()))?;
+
+            // Handle output logging
+            if let Some(log_path) = log_path {
+                use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+                let stdout = child.stdout.take();
+                let stderr = child.stderr.take();
+                let log_path = log_path.to_path_buf();
+
+                tokio::spawn(async move {
+                    let mut log_file = tokio::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&log_path)
+                        .await
+                        .ok();
+
+                    if let Some(stdout) = stdout {
+                        let mut reader = BufReader::new(stdout).lines();
+                        while let Ok(Some(line)) = reader.next_line().await {
+                            println!("{}", line);
+                            if let Some(ref mut f) = log_file {
+                                let _ = f.write_all(format!("{}\n", line).as_bytes()).await;
+                            }
+                        }
+                    }
+                });
+
+                tokio::spawn(async move {
+                    if let Some(stderr) = stderr {
+                        let mut reader = BufReader::new(stderr).lines();
+                        while let Ok(Some(line)) = reader.next_line().await {
+                            eprintln!("{}", line);
+                        }
+                    }
+                });
+            }
+
+            let status = child
+                .wait()
+                .await
+                .map_err(|e| DarkerError::Spawn(e.to_string()))?;
+
+            Ok(status.code().unwrap_or(1))
+        }
+    }
+
+    /// Run a process in a rootfs to completion, collecting its output
+    ///
+    /// Stdout and stderr are collected line by line as they arrive, and
+    /// echoed to stderr if `echo` is set. `user` is the uid and gid to run
+    /// as, which is an error unless running as root. Returns the exit code
+    /// and the output.
+    pub async fn spawn_captured(
+        &self,
+        command: &[String],
+        rootfs: &Path,
+        workdir: &str,
+        env: &[(String, String)],
+        user: Option<(u32, u32)>,
+        echo: bool,
+    ) -> Result<(i32, String)> {
+        let mut cmd = self.command(command, rootfs, workdir, env, user)?;
+        cmd.stdin(std::process::Stdio::null());
+        cmd.stdout(std::process::Stdio::piped());
+        cmd.stderr(std::process::Stdio::piped());
+
+        let mut child = cmd.spawn().map_err(|e| DarkerError::Spawn(e.to_string()))?;
+
+        let output = Mutex::new(String::new());
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let (_, _, status) = tokio::join!(
+            collect_lines(stdout, &output, echo),
+            collect_lines(stderr, &output, echo),
+            child.wait()
+        );
+        let status = status.map_err(|e| DarkerError::Spawn(e.to_string()))?;
+
+        let output = output.into_inner().unwrap_or_else(|e| e.into_inner());
+        Ok((status.code().unwrap_or(1), output))
+    }
+
+    /// Build the command for a process in a rootfs
+    ///
+    /// As root the process is chrooted into the rootfs, and switched to
+    /// `user` if one is given. Otherwise it runs on the host, with its
+    /// executable and working directory resolved inside the rootfs, and
+    /// giving a `user` is an error.
+    fn command(
+        &self,
+        command: &[String],
+        rootfs: &Path,
+        workdir: &str,
+        env: &[(String, String)],
+        user: Option<(u32, u32)>,
+    ) -> Result<tokio::process::Command> {
         if command.is_empty() {
             return Err(DarkerError::Spawn("No command specified".to_string()));
         }
 
         let use_chroot = can_chroot();
+        if user.is_some() && !use_chroot {
+            return Err(DarkerError::PermissionDenied(
+                "switching users requires root".to_string(),
+            ));
+        }
         let cmd_path = &command[0];
 
         // When using chroot, we use container-relative paths
@@ -111,80 +242,21 @@ impl ProcessSpawner {
                         let root_cstr = std::ffi::CString::new("/").unwrap();
                         libc::chdir(root_cstr.as_ptr());
                     }
-                    Ok(())
-                });
-            }
-        }
-
-        // Configure I/O
-        if interactive || tty {
-            cmd.stdin(std::process::Stdio::inherit());
-            cmd.stdout(std::process::Stdio::inherit());
-            cmd.stderr(std::process::Stdio::inherit());
-
-            let mut child = cmd
-                .spawn()
-                .map_err(|e| DarkerError::Spawn(e.to_string()))?;
-
-            let status = child
-                .wait()
-                .await
-                .map_err(|e| DarkerError::Spawn(e.to_string()))?;
-
-            Ok(status.code().unwrap_or(1))
-        } else {
-            cmd.stdin(std::process::Stdio::null());
-            cmd.stdout(std::process::Stdio::piped());
-            cmd.stderr(std::process::Stdio::piped());
-
-            let mut child = cmd
-                .spawn()
-                .map_err(|e| DarkerError::Spawn(e.to_string()))?;
-
-            // Handle output logging
-            if let Some(log_path) = log_path {
-                use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-
-                let stdout = child.stdout.take();
-                let stderr = child.stderr.take();
-                let log_path = log_path.to_path_buf();
-
-                tokio::spawn(async move {
-                    let mut log_file = tokio::fs::OpenOptions::new()
-                        .create(true)
-                        .append(true)
-                        .open(&log_path)
-                        .await
-                        .ok();
-
-                    if let Some(stdout) = stdout {
-                        let mut reader = BufReader::new(stdout).lines();
-                        while let Ok(Some(line)) = reader.next_line().await {
-                            println!("{}", line);
-                            if let Some(ref mut f) = log_file {
-                                let _ = f.write_all(format!("{}\n", line).as_bytes()).await;
-                            }
-                        }
-                    }
-                });
-
-                tokio::spawn(async move {
-                    if let Some(stderr) = stderr {
-                        let mut reader = BufReader::new(stderr).lines();
-                        while let Ok(Some(line)) = reader.next_line().await {
-                            eprintln!("{}", line);
+                    // Drop to the requested user, group first
+                    if let Some((uid, gid)) = user {
+                        if libc::setgroups(0, std::ptr::null()) != 0
+                            || libc::setgid(gid) != 0
+                            || libc::setuid(uid) != 0
+                        {
+                            return Err(std::io::Error::last_os_error());
                         }
                     }
+                    Ok(())
                 });
             }
-
-            let status = child
-                .wait()
-                .await
-                .map_err(|e| DarkerError::Spawn(e.to_string()))?;
-
-            Ok(status.code().unwrap_or(1))
         }
+
+        Ok(cmd)
     }
 
     /// Spawn a detached container process
@@ -298,6 +370,82 @@ impl Default for ProcessSpawner {
     }
 }
 
+/// Collect the lines of a process's output stream, echoing them if asked
+async fn collect_lines<R: AsyncRead + Unpin>(
+    stream: Option<R>,
+    output: &Mutex<String>,
+    echo: bool,
+) {
+    let Some(stream) = stream else {
+        return;
+    };
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    while matches!(reader.read_until(b'\n', &mut line).await, Ok(n) if n > 0) {
+        let text = String::from_utf8_lossy(&line);
+        if echo {
+            eprint!("{}", text);
+        }
+        let mut output = output.lock().unwrap_or_else(|e| e.into_inner());
+        output.push_str(&text);
+        if !text.ends_with('\n') {
+            output.push('\n');
+        }
+        line.clear();
+    }
+}
+
+/// Look up the uid and gid of a `user[:group]` in a rootfs
+///
+/// Names are looked up in the rootfs's /etc/passwd and /etc/group, and
+/// numeric IDs are used as they are. Without a group, the user's primary
+/// group is used, or group 0 for a uid with no passwd entry.
+pub fn resolve_user(rootfs: &Path, spec: &str) -> Result<(u32, u32)> {
+    let (user, group) = match spec.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (spec, None),
+    };
+    let read = |file: &str| std::fs::read_to_string(rootfs.join(file)).unwrap_or_default();
+    let passwd = read("etc/passwd");
+    let entry = passwd
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() > 3 && (fields[0] == user || fields[2] == user));
+
+    let uid = match (user.parse::<u32>(), &entry) {
+        (Ok(uid), _) => uid,
+        (Err(_), Some(fields)) => fields[2].parse().map_err(|_| {
+            DarkerError::Spawn(format!("Invalid uid for user {} in /etc/passwd", user))
+        })?,
+        (Err(_), None) => {
+            return Err(DarkerError::Spawn(format!(
+                "Unable to find user {}: no matching entries in passwd file",
+                user
+            )))
+        }
+    };
+
+    let gid = match group {
+        Some(group) => match group.parse::<u32>() {
+            Ok(gid) => gid,
+            Err(_) => read("etc/group")
+                .lines()
+                .map(|line| line.split(':').collect::<Vec<_>>())
+                .find(|fields| fields.len() > 2 && fields[0] == group)
+                .and_then(|fields| fields[2].parse().ok())
+                .ok_or_else(|| {
+                    DarkerError::Spawn(format!(
+                        "Unable to find group {}: no matching entries in group file",
+                        group
+                    ))
+                })?,
+        },
+        None => entry.and_then(|fields| fields[3].parse().ok()).unwrap_or(0),
+    };
+
+    Ok((uid, gid))
+}
+
 /// Escape a string for safe use in shell commands
 fn shell_escape(s: &str) -> String {
     // If the string is empty, return empty quotes
@@ -319,6 +467,10 @@ pub mod posix {
     use std::ptr;
 
     /// Spawn a process using posix_spawn
+    ///
+    /// # Safety
+    /// The spawned process inherits no file actions or attributes; callers
+    /// must ensure `path`, `args` and `envp` contain no interior NUL bytes.
     pub unsafe fn spawn_process(
         path: &str,
         args: &[&str],
@@ -364,6 +516,10 @@ pub mod posix {
 pub mod posix {
     use libc::pid_t;
 
+    /// Spawn a process using posix_spawn
+    ///
+    /// # Safety
+    /// Always returns an error on platforms without posix_spawn support.
     pub unsafe fn spawn_process(
         _path: &str,
         _args: &[&str],
@@ -375,3 +531,30 @@ pub mod posix {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_resolve_user() {
+        let tmp = TempDir::new().unwrap();
+        std::fs::create_dir_all(tmp.path().join("etc")).unwrap();
+        std::fs::write(
+            tmp.path().join("etc/passwd"),
+            "root:x:0:0:root:/root:/bin/sh\napp:x:1000:1001::/home/app:/bin/sh\n",
+        )
+        .unwrap();
+        std::fs::write(tmp.path().join("etc/group"), "root:x:0:\nstaff:x:50:app\n").unwrap();
+
+        let resolve = |spec| resolve_user(tmp.path(), spec).ok();
+        assert_eq!(resolve("app"), Some((1000, 1001)));
+        assert_eq!(resolve("1000"), Some((1000, 1001)));
+        assert_eq!(resolve("app:staff"), Some((1000, 50)));
+        assert_eq!(resolve("app:7"), Some((1000, 7)));
+        assert_eq!(resolve("4242"), Some((4242, 0)));
+        assert_eq!(resolve("nobody"), None);
+        assert_eq!(resolve("app:wheel"), None);
+    }
+}
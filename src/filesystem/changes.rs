@@ -0,0 +1,223 @@
+//! Changes made to a root filesystem, captured as a layer
+
+use crate::filesystem::rootfs::WHITEOUT_PREFIX;
+use crate::Result;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// State of a path, compared to find the paths a command changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PathState {
+    file_type: fs::FileType,
+    mode: u32,
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+    link: Option<PathBuf>,
+}
+
+impl PathState {
+    fn read(path: &Path) -> Result<Self> {
+        let metadata = fs::symlink_metadata(path)?;
+        let file_type = metadata.file_type();
+        Ok(Self {
+            file_type,
+            mode: metadata.mode(),
+            ino: metadata.ino(),
+            size: metadata.len(),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            ctime: (metadata.ctime(), metadata.ctime_nsec()),
+            link: match file_type.is_symlink() {
+                true => Some(fs::read_link(path)?),
+                false => None,
+            },
+        })
+    }
+}
+
+/// The state of every path below a directory at some point in time
+pub struct Snapshot {
+    root: PathBuf,
+    paths: HashMap<PathBuf, PathState>,
+}
+
+impl Snapshot {
+    /// Record the state of the paths below a directory, without following
+    /// symlinks
+    pub fn capture(root: &Path) -> Result<Self> {
+        let mut paths = HashMap::new();
+        walk(root, Path::new(""), &mut paths)?;
+        Ok(Self {
+            root: root.to_path_buf(),
+            paths,
+        })
+    }
+
+    /// Copy what changed below the directory since the snapshot into `dest`
+    ///
+    /// Added and modified paths are copied along with their parent
+    /// directories, and deleted paths get whiteout markers. Returns false if
+    /// nothing changed.
+    pub fn write_changes(&self, dest: &Path) -> Result<bool> {
+        let current = Self::capture(&self.root)?.paths;
+
+        let mut changed: Vec<&PathBuf> = current
+            .iter()
+            .filter(|(path, state)| self.paths.get(*path) != Some(state))
+            .map(|(path, _)| path)
+            .collect();
+        changed.sort();
+
+        // Only the topmost deleted path needs a whiteout, and none can be
+        // written below a path that is no longer a directory
+        let mut deleted: Vec<&PathBuf> = self
+            .paths
+            .keys()
+            .filter(|path| !current.contains_key(*path))
+            .filter(|path| match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => current
+                    .get(parent)
+                    .is_some_and(|state| state.file_type.is_dir()),
+                _ => true,
+            })
+            .collect();
+        deleted.sort();
+
+        if changed.is_empty() && deleted.is_empty() {
+            return Ok(false);
+        }
+
+        fs::create_dir_all(dest)?;
+        let mut dirs = Vec::new();
+        for path in changed {
+            let src = self.root.join(path);
+            let dst = dest.join(path);
+            self.create_parents(dest, path, &mut dirs)?;
+
+            let state = &current[path];
+            if state.file_type.is_dir() {
+                if !dst.is_dir() {
+                    fs::create_dir(&dst)?;
+                    dirs.push((dst, state.mode));
+                }
+            } else if let Some(target) = &state.link {
+                symlink(target, &dst)?;
+            } else if state.file_type.is_file() {
+                fs::copy(&src, &dst)?;
+                File::open(&dst)?.set_modified(fs::metadata(&src)?.modified()?)?;
+            }
+        }
+        for path in deleted {
+            self.create_parents(dest, path, &mut dirs)?;
+            if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+                let marker = format!("{}{}", WHITEOUT_PREFIX, name.to_string_lossy());
+                fs::write(dest.join(parent).join(marker), "")?;
+            }
+        }
+
+        // Directory modes are set last, as they may not allow writing
+        for (dir, mode) in dirs.iter().rev() {
+            fs::set_permissions(dir, fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+
+        Ok(true)
+    }
+
+    /// Create the parent directories of a changed path in `dest`
+    fn create_parents(
+        &self,
+        dest: &Path,
+        path: &Path,
+        dirs: &mut Vec<(PathBuf, u32)>,
+    ) -> Result<()> {
+        let mut ancestors: Vec<&Path> = path
+            .ancestors()
+            .skip(1)
+            .take_while(|parent| !parent.as_os_str().is_empty())
+            .collect();
+        ancestors.reverse();
+
+        for parent in ancestors {
+            let dst = dest.join(parent);
+            if !dst.is_dir() {
+                fs::create_dir(&dst)?;
+                dirs.push((dst, fs::metadata(self.root.join(parent))?.mode()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Record the state of the paths below a directory
+fn walk(root: &Path, relative: &Path, paths: &mut HashMap<PathBuf, PathState>) -> Result<()> {
+    for entry in fs::read_dir(root.join(relative))? {
+        let path = relative.join(entry?.file_name());
+        let state = PathState::read(&root.join(&path))?;
+        let is_dir = state.file_type.is_dir();
+        paths.insert(path.clone(), state);
+        if is_dir {
+            walk(root, &path, paths)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_write_changes() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().join("rootfs");
+        for dir in ["etc/app", "var/cache/apt", "usr/local/bin", "opt/tool"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        fs::write(root.join("etc/app/keep.conf"), "keep").unwrap();
+        fs::write(root.join("etc/app/edit.conf"), "old").unwrap();
+        fs::write(root.join("var/cache/apt/pkg.deb"), "deb").unwrap();
+        fs::write(root.join("opt/tool/run"), "tool").unwrap();
+        symlink("/bin", root.join("bin")).unwrap();
+
+        let snapshot = Snapshot::capture(&root).unwrap();
+        assert!(!snapshot.write_changes(&tmp.path().join("none")).unwrap());
+        assert!(!tmp.path().join("none").exists());
+
+        fs::write(root.join("etc/app/edit.conf"), "new contents").unwrap();
+        fs::write(root.join("usr/local/bin/app"), "app").unwrap();
+        fs::set_permissions(
+            root.join("usr/local/bin/app"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        symlink("app", root.join("usr/local/bin/app-link")).unwrap();
+        fs::remove_file(root.join("var/cache/apt/pkg.deb")).unwrap();
+        fs::remove_dir_all(root.join("opt/tool")).unwrap();
+
+        let dest = tmp.path().join("changes");
+        assert!(snapshot.write_changes(&dest).unwrap());
+
+        let read = |path: &str| fs::read_to_string(dest.join(path)).unwrap();
+        assert_eq!(read("etc/app/edit.conf"), "new contents");
+        assert_eq!(read("usr/local/bin/app"), "app");
+        assert_eq!(
+            fs::metadata(dest.join("usr/local/bin/app")).unwrap().mode() & 0o777,
+            0o755
+        );
+        assert_eq!(
+            fs::read_link(dest.join("usr/local/bin/app-link")).unwrap(),
+            Path::new("app")
+        );
+        assert!(dest.join("var/cache/apt/.wh.pkg.deb").is_file());
+        assert!(dest.join("opt/.wh.tool").is_file());
+
+        // Unchanged paths are left out
+        assert!(!dest.join("etc/app/keep.conf").exists());
+        assert!(!dest.join("bin").exists());
+        assert!(!dest.join("opt/tool").exists());
+    }
+}
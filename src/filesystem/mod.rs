@@ -1,5 +1,6 @@
 //! Filesystem operations module
 
+pub mod changes;
 pub mod mount;
 pub mod overlay;
 pub mod rootfs;
@@ -3,9 +3,23 @@
 use crate::storage::paths::DarkerPaths;
 use crate::{DarkerError, Result};
 use std::fs;
+use std::io::Write;
 use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
 
+/// Host system directories linked into every rootfs, as (container path, host path)
+const SYSTEM_SYMLINKS: [(&str, &str); 9] = [
+    ("bin", "/bin"),
+    ("sbin", "/sbin"),
+    ("usr/bin", "/usr/bin"),
+    ("usr/sbin", "/usr/sbin"),
+    ("usr/lib", "/usr/lib"),
+    ("usr/libexec", "/usr/libexec"),
+    ("usr/share", "/usr/share"),
+    ("System", "/System"),
+    ("Library/Frameworks", "/Library/Frameworks"),
+];
+
 /// Root filesystem manager for a container
 pub struct RootFs {
     paths: DarkerPaths,
@@ -49,6 +63,26 @@ impl RootFs {
         Ok(())
     }
 
+    /// Set up the root filesystem from a stack of layers, bottom first
+    pub fn setup_layers(&self, layers: &[String]) -> Result<()> {
+        fs::create_dir_all(&self.rootfs_path)?;
+        self.create_standard_dirs()?;
+        self.setup_system_symlinks()?;
+        self.extract_layers(layers)
+    }
+
+    /// Extract a stack of layers, bottom first, into the root filesystem
+    ///
+    /// Unlike `setup_layers`, nothing else is added, so the result is just the
+    /// image's files.
+    pub fn extract_layers(&self, layers: &[String]) -> Result<()> {
+        fs::create_dir_all(&self.rootfs_path)?;
+        for layer in layers {
+            self.apply_layer(layer)?;
+        }
+        Ok(())
+    }
+
     /// Create standard container directories
     fn create_standard_dirs(&self) -> Result<()> {
         let dirs = [
@@ -84,19 +118,7 @@ impl RootFs {
     /// Set up symlinks to host system directories
     fn setup_system_symlinks(&self) -> Result<()> {
         // System directories to symlink from host (read-only)
-        let symlinks = [
-            ("bin", "/bin"),
-            ("sbin", "/sbin"),
-            ("usr/bin", "/usr/bin"),
-            ("usr/sbin", "/usr/sbin"),
-            ("usr/lib", "/usr/lib"),
-            ("usr/libexec", "/usr/libexec"),
-            ("usr/share", "/usr/share"),
-            ("System", "/System"),
-            ("Library/Frameworks", "/Library/Frameworks"),
-        ];
-
-        for (container_path, host_path) in symlinks {
+        for (container_path, host_path) in SYSTEM_SYMLINKS {
             let full_container_path = self.rootfs_path.join(container_path);
 
             // Create parent directory if needed
@@ -155,7 +177,7 @@ impl RootFs {
 
         if layer_extracted.exists() {
             // Copy extracted layer contents to rootfs
-            copy_dir_contents(&layer_extracted, &self.rootfs_path)?;
+            apply_dir(&layer_extracted, &self.rootfs_path, false)?;
         } else {
             // Try to extract from tar
             let layer_tar = self.paths.layer_tar(layer_sha);
@@ -190,12 +212,6 @@ impl RootFs {
                         }
                     };
 
-                    // Skip whiteout files (used by overlayfs)
-                    let path_str = path.to_string_lossy();
-                    if path_str.contains(".wh.") {
-                        continue;
-                    }
-
                     let dest = layer_extracted.join(&path);
 
                     // Create parent directory
@@ -209,8 +225,9 @@ impl RootFs {
                     }
                 }
 
-                // Copy to rootfs
-                copy_dir_contents(&layer_extracted, &self.rootfs_path)?;
+                // Copy to rootfs; whiteout markers are kept in the extracted
+                // layer and applied here
+                apply_dir(&layer_extracted, &self.rootfs_path, false)?;
             }
         }
 
@@ -244,6 +261,24 @@ impl RootFs {
         Ok(())
     }
 
+    /// Write the rootfs to a tar stream
+    ///
+    /// Symlinks are stored as links rather than followed, and the links to
+    /// host system directories made by `setup_system_symlinks` are left out.
+    pub fn export<W: Write>(&self, writer: W) -> Result<()> {
+        if !self.rootfs_path.is_dir() {
+            return Err(DarkerError::Io(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Root filesystem not found: {}", self.rootfs_path.display()),
+            )));
+        }
+        write_tar(&self.rootfs_path, writer, &|path, target| {
+            SYSTEM_SYMLINKS
+                .iter()
+                .any(|(container, host)| path == Path::new(container) && target == Path::new(host))
+        })
+    }
+
     /// Clean up the rootfs
     pub fn cleanup(&self) -> Result<()> {
         if self.rootfs_path.exists() {
@@ -254,17 +289,99 @@ impl RootFs {
     }
 }
 
-/// Recursively copy directory contents
-fn copy_dir_contents(src: &Path, dst: &Path) -> Result<()> {
+/// Write a directory tree to a tar stream without following symlinks
+///
+/// `skip_link` is given the relative path and target of each symlink, and
+/// returns true for links to leave out.
+pub fn write_tar<W: Write>(
+    dir: &Path,
+    writer: W,
+    skip_link: &dyn Fn(&Path, &Path) -> bool,
+) -> Result<()> {
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+    append_tree(&mut builder, dir, Path::new(""), skip_link)?;
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+/// Append the entries under a directory to a tar stream, in name order
+fn append_tree<W: Write>(
+    builder: &mut tar::Builder<W>,
+    root: &Path,
+    relative: &Path,
+    skip_link: &dyn Fn(&Path, &Path) -> bool,
+) -> Result<()> {
+    let mut entries: Vec<_> = fs::read_dir(root.join(relative))?.collect::<std::io::Result<_>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = relative.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            if skip_link(&path, &fs::read_link(entry.path())?) {
+                continue;
+            }
+        } else if !file_type.is_dir() && !file_type.is_file() {
+            // Sockets and device placeholders can't be archived
+            continue;
+        }
+
+        builder.append_path_with_name(entry.path(), &path)?;
+        if file_type.is_dir() {
+            append_tree(builder, root, &path, skip_link)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Prefix of whiteout markers, which delete a path of the lower layers
+pub(crate) const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Marker that hides everything the lower layers put in its directory
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+/// Apply a directory of an extracted layer on top of the rootfs
+///
+/// Whiteouts are applied before the layer's own entries, so an opaque
+/// directory keeps what the layer itself puts in it. Nothing is deleted
+/// below a symlink, since those may point at host directories, and a
+/// whiteout that names anything but an entry of its directory is an error.
+fn apply_dir(src: &Path, dst: &Path, through_link: bool) -> Result<()> {
     if !src.is_dir() {
         return Ok(());
     }
 
-    for entry in fs::read_dir(src)? {
-        let entry = match entry {
-            Ok(e) => e,
-            Err(_) => continue,
-        };
+    let entries: Vec<fs::DirEntry> = fs::read_dir(src)?.filter_map(|e| e.ok()).collect();
+    let is_whiteout = |entry: &fs::DirEntry| {
+        entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(WHITEOUT_PREFIX))
+    };
+
+    if !through_link {
+        for entry in entries.iter().filter(|e| is_whiteout(e)) {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name == OPAQUE_WHITEOUT {
+                if let Ok(lower) = fs::read_dir(dst) {
+                    for lower in lower.filter_map(|e| e.ok()) {
+                        remove_path(&lower.path())?;
+                    }
+                }
+            } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                // A whiteout only hides an entry of its own directory
+                if matches!(hidden, "" | "." | "..") || hidden.contains('/') {
+                    return Err(DarkerError::Layer(format!("Invalid whiteout: {}", name)));
+                }
+                remove_path(&dst.join(hidden))?;
+            }
+        }
+    }
+
+    for entry in entries.iter().filter(|e| !is_whiteout(e)) {
         let src_path = entry.path();
         let dst_path = dst.join(entry.file_name());
 
@@ -275,7 +392,8 @@ fn copy_dir_contents(src: &Path, dst: &Path) -> Result<()> {
 
         if file_type.is_dir() {
             let _ = fs::create_dir_all(&dst_path);
-            let _ = copy_dir_contents(&src_path, &dst_path);
+            let link = through_link || dst_path.is_symlink();
+            apply_dir(&src_path, &dst_path, link)?;
         } else if file_type.is_file() {
             // Don't overwrite symlinks
             if dst_path.is_symlink() {
@@ -299,6 +417,16 @@ fn copy_dir_contents(src: &Path, dst: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Remove a file, symlink or directory tree, if it exists
+fn remove_path(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(_) => {}
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -318,4 +446,167 @@ mod tests {
         assert!(rootfs.path().join("tmp").exists());
         assert!(rootfs.path().join("home").exists());
     }
+
+    fn layer(entries: &[(&str, Option<&[u8]>)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, contents) in entries {
+            let mut header = tar::Header::new_gnu();
+            match contents {
+                Some(contents) => {
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_size(contents.len() as u64);
+                    header.set_mode(0o644);
+                }
+                None => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_size(0);
+                    header.set_mode(0o755);
+                }
+            }
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, contents.unwrap_or_default())
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_apply_whiteouts_and_opaque_dirs() {
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let layers = [
+            layer(&[
+                ("app/", None),
+                ("app/conf.d/", None),
+                ("app/conf.d/a.conf", Some(b"a")),
+                ("app/conf.d/b.conf", Some(b"b")),
+                ("app/cache/", None),
+                ("app/cache/x", Some(b"x")),
+                ("app/cache/sub/", None),
+                ("app/cache/sub/y", Some(b"y")),
+                ("app/keep.txt", Some(b"keep")),
+                ("app/gone.txt", Some(b"gone")),
+            ]),
+            layer(&[
+                ("app/.wh.cache", Some(b"")),
+                ("app/.wh.gone.txt", Some(b"")),
+                ("app/conf.d/.wh..wh..opq", Some(b"")),
+                ("app/conf.d/c.conf", Some(b"c")),
+                ("app/notes.wh.txt", Some(b"not a whiteout")),
+            ]),
+            layer(&[("app/cache/", None), ("app/cache/new", Some(b"new"))]),
+        ];
+
+        let layer_manager = crate::image::layer::LayerManager::new(&paths);
+        let mut digests = Vec::new();
+        for tar in &layers {
+            let digest = crate::image::layer::LayerManager::compute_digest_bytes(tar)
+                .trim_start_matches("sha256:")
+                .to_string();
+            layer_manager.store_layer_bytes(&digest, tar).unwrap();
+            digests.push(digest);
+        }
+        let image_store = crate::storage::images::ImageStore::new(&paths).unwrap();
+        image_store
+            .store("whiteouts", None, None, None, &digests, 0)
+            .unwrap();
+
+        // Apply twice, the second time from the extracted layers
+        for container in ["first", "second"] {
+            let rootfs = RootFs::new(&paths, container).unwrap();
+            rootfs.setup("whiteouts", &[]).unwrap();
+            let app = rootfs.path().join("app");
+
+            let mut conf: Vec<String> = fs::read_dir(app.join("conf.d"))
+                .unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+                .collect();
+            conf.sort();
+            assert_eq!(conf, vec!["c.conf"]);
+
+            assert!(!app.join("cache/x").exists());
+            assert!(!app.join("cache/sub").exists());
+            assert_eq!(fs::read(app.join("cache/new")).unwrap(), b"new");
+            assert!(!app.join("gone.txt").exists());
+            assert!(app.join("keep.txt").exists());
+            assert!(app.join("notes.wh.txt").exists());
+            assert!(!app.join(".wh.cache").exists());
+            assert!(!app.join("conf.d/.wh..wh..opq").exists());
+        }
+    }
+
+    #[test]
+    fn test_reject_invalid_whiteouts() {
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+        fs::write(tmp.path().join("outside"), "keep").unwrap();
+
+        let layer_manager = crate::image::layer::LayerManager::new(&paths);
+        let image_store = crate::storage::images::ImageStore::new(&paths).unwrap();
+        let base = layer(&[("app/", None), ("app/keep.txt", Some(b"keep"))]);
+        let base_digest = crate::image::layer::LayerManager::compute_digest_bytes(&base)
+            .trim_start_matches("sha256:")
+            .to_string();
+        layer_manager.store_layer_bytes(&base_digest, &base).unwrap();
+
+        // Whiteouts of the directory itself or its parent, at the top and
+        // in a subdirectory
+        for name in [".wh..", ".wh...", "app/.wh..", "app/.wh..."] {
+            let tar = layer(&[("app/", None), (name, Some(b""))]);
+            let digest = crate::image::layer::LayerManager::compute_digest_bytes(&tar)
+                .trim_start_matches("sha256:")
+                .to_string();
+            layer_manager.store_layer_bytes(&digest, &tar).unwrap();
+            let layers = [base_digest.clone(), digest.clone()];
+            image_store.store(&digest, None, None, None, &layers, 0).unwrap();
+
+            let rootfs = RootFs::new(&paths, &digest).unwrap();
+            let err = rootfs.setup(&digest, &[]).unwrap_err();
+            assert!(err.to_string().contains("Invalid whiteout"), "{}", err);
+            assert!(rootfs.path().join("app/keep.txt").exists());
+            assert!(tmp.path().join("outside").exists());
+            assert!(paths.containers_dir().exists());
+        }
+    }
+
+    #[test]
+    fn test_export_skips_host_symlinks() {
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let rootfs = RootFs::new(&paths, "export123").unwrap();
+        rootfs.setup("", &[]).unwrap();
+        fs::write(rootfs.path().join("etc/hostname"), "box").unwrap();
+        symlink("/etc/hostname", rootfs.path().join("etc/name")).unwrap();
+        if !rootfs.path().join("bin").is_symlink() {
+            symlink("/bin", rootfs.path().join("bin")).unwrap();
+        }
+
+        let mut archive = Vec::new();
+        rootfs.export(&mut archive).unwrap();
+
+        let mut entries = std::collections::HashMap::new();
+        for entry in tar::Archive::new(archive.as_slice()).entries().unwrap() {
+            let entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().to_string();
+            let link = entry.link_name().unwrap().map(|l| l.to_path_buf());
+            entries.insert(
+                path.trim_end_matches('/').to_string(),
+                (entry.header().entry_type(), link),
+            );
+        }
+
+        assert!(entries["etc/hostname"].0.is_file());
+        let (kind, link) = &entries["etc/name"];
+        assert!(kind.is_symlink());
+        assert_eq!(link.as_deref(), Some(Path::new("/etc/hostname")));
+        assert!(entries["usr/local/bin"].0.is_dir());
+        assert!(!entries.contains_key("bin"));
+        assert!(!entries.keys().any(|k| k.starts_with("usr/bin")));
+    }
 }
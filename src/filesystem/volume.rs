@@ -115,8 +115,7 @@ impl VolumeManager {
 
         // Check if volume is in use
         if self.is_in_use(name)? {
-            return Err(DarkerError::Io(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(DarkerError::Io(std::io::Error::other(
                 format!("Volume '{}' is in use", name),
             )));
         }
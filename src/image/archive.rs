@@ -0,0 +1,585 @@
+//! docker-archive tarballs, as written by `docker save` and read by `docker load`
+//!
+//! An archive holds a `manifest.json` listing each image's config, tags and
+//! layers, a legacy `repositories` file, the image configs as `<id>.json`,
+//! and the uncompressed layers as `<diff_id>/layer.tar`.
+
+use crate::image::layer::{Compression, LayerManager};
+use crate::image::layout;
+use crate::image::oci::{ImageReference, OciImageConfig, Platform};
+use crate::image::registry::push_config;
+use crate::storage::blobs::BlobStore;
+use crate::storage::images::ImageStore;
+use crate::storage::paths::DarkerPaths;
+use crate::{DarkerError, Result, GZIP_MAGIC, ZSTD_MAGIC};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// An image in the `manifest.json` of an archive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ArchiveManifestEntry {
+    /// Path of the image config in the archive
+    pub config: String,
+    /// Names the image is tagged with
+    #[serde(default)]
+    pub repo_tags: Option<Vec<String>>,
+    /// Paths of the layer tars in the archive, base layer first
+    pub layers: Vec<String>,
+}
+
+/// An image imported from an archive
+#[derive(Debug, Clone)]
+pub struct LoadedImage {
+    /// ID of the image in the store
+    pub image_id: String,
+    /// Names the image was tagged with
+    pub tags: Vec<String>,
+}
+
+/// Write images to a docker-archive tarball
+///
+/// Each image is saved with the given tags. Images listed more than once are
+/// written once with all of their tags, and layers shared between images are
+/// written once.
+pub fn save<W: Write>(
+    paths: &DarkerPaths,
+    images: &[(String, Vec<ImageReference>)],
+    writer: W,
+) -> Result<()> {
+    let image_store = ImageStore::new(paths)?;
+    let layer_manager = LayerManager::new(paths);
+    let mut builder = tar::Builder::new(writer);
+
+    // Merge the tags of images that were named more than once
+    let mut order: Vec<&str> = Vec::new();
+    let mut tags: HashMap<&str, Vec<String>> = HashMap::new();
+    for (image_id, references) in images {
+        let names = tags.entry(image_id.as_str()).or_insert_with(|| {
+            order.push(image_id);
+            Vec::new()
+        });
+        for reference in references {
+            let name = repo_tag(reference);
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+
+    let mut manifest = Vec::new();
+    let mut repositories: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+    let mut written: HashSet<String> = HashSet::new();
+    for image_id in order {
+        let metadata = image_store.load_metadata(image_id)?;
+
+        let mut layer_paths = Vec::new();
+        let mut diff_ids = Vec::new();
+        for layer in &metadata.layers {
+            let tar_path = layer_manager.layer_tar_path(layer);
+            if !tar_path.exists() {
+                return Err(DarkerError::Layer(format!(
+                    "Layer {} of image {} is missing",
+                    layer, image_id
+                )));
+            }
+
+            // Archives hold uncompressed layers, named by their diff ID
+            let (diff_id, size) = hash_stream(open_layer(&tar_path)?)?;
+            let name = format!("{}/layer.tar", diff_id.trim_start_matches("sha256:"));
+            if written.insert(name.clone()) {
+                let mut header = archive_header(size);
+                builder.append_data(&mut header, &name, open_layer(&tar_path)?)?;
+            }
+            layer_paths.push(name);
+            diff_ids.push(diff_id);
+        }
+
+        // Stored configs are saved as they are when their layers match, which
+        // keeps the image ID
+        let stored = image_store.config_bytes(image_id)?.filter(|bytes| {
+            serde_json::from_slice::<OciImageConfig>(bytes)
+                .is_ok_and(|config| config.rootfs.diff_ids == diff_ids)
+        });
+        let config_bytes = match stored {
+            Some(bytes) => bytes,
+            None => serde_json::to_vec(&push_config(&image_store, image_id, diff_ids)?)?,
+        };
+        let config_name = format!("{:x}.json", Sha256::digest(&config_bytes));
+        if written.insert(config_name.clone()) {
+            let mut header = archive_header(config_bytes.len() as u64);
+            builder.append_data(&mut header, &config_name, config_bytes.as_slice())?;
+        }
+
+        let names = tags.remove(image_id).unwrap_or_default();
+        if let Some(top) = layer_paths.last() {
+            let top = top.trim_end_matches("/layer.tar");
+            for name in &names {
+                if let Some((repository, tag)) = name.rsplit_once(':') {
+                    repositories
+                        .entry(repository.to_string())
+                        .or_default()
+                        .insert(tag.to_string(), top.to_string());
+                }
+            }
+        }
+        manifest.push(ArchiveManifestEntry {
+            config: config_name,
+            repo_tags: Some(names),
+            layers: layer_paths,
+        });
+    }
+
+    let manifest_bytes = serde_json::to_vec(&manifest)?;
+    let mut header = archive_header(manifest_bytes.len() as u64);
+    builder.append_data(&mut header, "manifest.json", manifest_bytes.as_slice())?;
+
+    let repositories_bytes = serde_json::to_vec(&repositories)?;
+    let mut header = archive_header(repositories_bytes.len() as u64);
+    builder.append_data(&mut header, "repositories", repositories_bytes.as_slice())?;
+
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+/// Import the images in a docker-archive tarball into the store
+///
+/// The tarball may be gzip or zstd compressed. Layers already in the store
+/// are not stored again, and each layer is checked against the diff ID its
+/// image config gives for it.
+pub fn load<R: Read>(paths: &DarkerPaths, reader: R) -> Result<Vec<LoadedImage>> {
+    // Entries can come in any order, so unpack the whole archive first
+    let dir = paths.tmp_dir().join(uuid::Uuid::new_v4().to_string());
+    fs::create_dir_all(&dir)?;
+    let result = unpack(reader, &dir).and_then(|_| load_unpacked(paths, &dir));
+    fs::remove_dir_all(&dir)?;
+    result
+}
+
+/// Unpack a possibly compressed tarball into a directory
+pub(crate) fn unpack<R: Read>(reader: R, dir: &Path) -> Result<()> {
+    let mut archive = tar::Archive::new(decompress(reader)?);
+    archive.unpack(dir)?;
+    Ok(())
+}
+
+/// Wrap a reader of a tarball in a decoder for its compression, if any
+pub(crate) fn decompress<'a, R: Read + 'a>(reader: R) -> Result<Box<dyn Read + 'a>> {
+    let mut reader = BufReader::new(reader);
+    let compression = {
+        let magic = reader.fill_buf()?;
+        if magic.starts_with(&GZIP_MAGIC) {
+            Compression::Gzip
+        } else if magic.starts_with(&ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    };
+    compression.decoder(reader)
+}
+
+/// Import the images of an unpacked archive
+fn load_unpacked(paths: &DarkerPaths, dir: &Path) -> Result<Vec<LoadedImage>> {
+    let manifest_path = dir.join("manifest.json");
+    if !manifest_path.exists() {
+        if layout::is_layout(dir) {
+            return layout::load_layout(paths, dir, None, None, None);
+        }
+        return Err(DarkerError::Archive(
+            "manifest.json not found, not a docker-archive tarball".to_string(),
+        ));
+    }
+    let manifest: Vec<ArchiveManifestEntry> = serde_json::from_slice(&fs::read(&manifest_path)?)?;
+
+    let image_store = ImageStore::new(paths)?;
+    let layer_manager = LayerManager::new(paths);
+    let mut loaded = Vec::new();
+    for entry in manifest {
+        let config_bytes = fs::read(archive_path(dir, &entry.config)?)?;
+        let config: OciImageConfig = serde_json::from_slice(&config_bytes)?;
+        if config.rootfs.diff_ids.len() != entry.layers.len() {
+            return Err(DarkerError::Archive(format!(
+                "Config {} lists {} layers, but the image has {}",
+                entry.config,
+                config.rootfs.diff_ids.len(),
+                entry.layers.len()
+            )));
+        }
+
+        let mut layers = Vec::new();
+        let mut total_size = 0;
+        for (layer_path, expected) in entry.layers.iter().zip(&config.rootfs.diff_ids) {
+            let path = archive_path(dir, layer_path)?;
+            let digest = expected.trim_start_matches("sha256:").to_string();
+            let size = if layer_manager.exists(&digest) {
+                let (diff_id, size) = hash_stream(open_layer(&path)?)?;
+                check_diff_id(layer_path, &diff_id, expected)?;
+                size
+            } else {
+                store_layer(paths, layer_path, open_layer(&path)?, expected)?
+            };
+            layers.push(digest);
+            total_size += size;
+        }
+
+        // The image ID is the digest of its config, as for pulled images
+        let config_digest = format!("sha256:{:x}", Sha256::digest(&config_bytes));
+        let image_id = config_digest.trim_start_matches("sha256:").to_string();
+        let references = entry
+            .repo_tags
+            .iter()
+            .flatten()
+            .map(|name| ImageReference::parse(name))
+            .collect::<Result<Vec<_>>>()?;
+
+        let first = references.first();
+        image_store.store(
+            &image_id,
+            first.map(|r| r.repository_with_registry()).as_deref(),
+            first.map(|r| r.tag()),
+            Some(&config_digest),
+            &layers,
+            total_size,
+        )?;
+        for reference in references.iter().skip(1) {
+            image_store.tag(&image_id, reference)?;
+        }
+        let platform = Platform::new(&config.os, &config.architecture, config.variant.as_deref());
+        image_store.set_platform(&image_id, &platform)?;
+        BlobStore::new(paths).write(&config_bytes)?;
+
+        loaded.push(LoadedImage {
+            image_id,
+            tags: references.iter().map(repo_tag).collect(),
+        });
+    }
+
+    Ok(loaded)
+}
+
+/// Name of an image as written to `RepoTags`, without Docker Hub's `library/`
+pub fn repo_tag(reference: &ImageReference) -> String {
+    let repository = reference.repository_with_registry();
+    let repository = match reference.registry.as_str() {
+        "docker.io" => repository
+            .strip_prefix("library/")
+            .map(String::from)
+            .unwrap_or(repository),
+        _ => repository,
+    };
+    format!("{}:{}", repository, reference.tag())
+}
+
+/// Resolve a path named in `manifest.json`, refusing any outside the archive
+fn archive_path(dir: &Path, name: &str) -> Result<PathBuf> {
+    let relative = Path::new(name);
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(DarkerError::Archive(format!(
+            "Invalid path in archive: {}",
+            name
+        )));
+    }
+    let path = dir.join(relative);
+    if !path.is_file() {
+        return Err(DarkerError::Archive(format!(
+            "{} not found in archive",
+            name
+        )));
+    }
+    Ok(path)
+}
+
+/// Open a layer tar, decompressing it if it was stored compressed
+pub(crate) fn open_layer(path: &Path) -> Result<Box<dyn Read>> {
+    let mut magic = [0u8; 4];
+    let read = File::open(path)?.read(&mut magic)?;
+    let compression = if magic[..read].starts_with(&GZIP_MAGIC) {
+        Compression::Gzip
+    } else if magic[..read].starts_with(&ZSTD_MAGIC) {
+        Compression::Zstd
+    } else {
+        Compression::None
+    };
+    compression.decoder(File::open(path)?)
+}
+
+/// Store an uncompressed layer tar under its diff ID
+///
+/// The tar is written to a staging directory and checked against `diff_id`
+/// before it's moved into the layers directory, as when pulling, so a failed
+/// load leaves no partial layer behind. `name` identifies the layer in
+/// errors. Returns the size of the tar.
+pub(crate) fn store_layer(
+    paths: &DarkerPaths,
+    name: &str,
+    mut reader: impl Read,
+    diff_id: &str,
+) -> Result<u64> {
+    let staging_dir = paths.tmp_dir().join(uuid::Uuid::new_v4().to_string());
+    fs::create_dir_all(&staging_dir)?;
+    let staged_tar = staging_dir.join("layer.tar");
+    let staged = File::create(&staged_tar)
+        .and_then(|mut file| std::io::copy(&mut reader, &mut file))
+        .map_err(DarkerError::from)
+        .and_then(|_| hash_stream(File::open(&staged_tar)?))
+        .and_then(|(actual, size)| check_diff_id(name, &actual, diff_id).map(|_| size));
+    let size = match staged {
+        Ok(size) => size,
+        Err(e) => {
+            let _ = fs::remove_dir_all(&staging_dir);
+            return Err(e);
+        }
+    };
+
+    let layer_dir = paths.layer_dir(diff_id.trim_start_matches("sha256:"));
+    fs::create_dir_all(paths.layers_dir())?;
+    if let Err(e) = fs::rename(&staging_dir, &layer_dir) {
+        let _ = fs::remove_dir_all(&staging_dir);
+        // Another load or pull may have stored the same layer concurrently
+        if !layer_dir.exists() {
+            return Err(e.into());
+        }
+    }
+    Ok(size)
+}
+
+/// Check a layer's diff ID against the one its image config expects
+fn check_diff_id(name: &str, actual: &str, expected: &str) -> Result<()> {
+    if actual != expected {
+        return Err(DarkerError::Archive(format!(
+            "Layer {} has diff ID {}, but the image config expects {}",
+            name, actual, expected
+        )));
+    }
+    Ok(())
+}
+
+/// Compute the digest and length of a stream
+pub(crate) fn hash_stream(mut reader: impl Read) -> Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(&mut reader, &mut hasher)?;
+    Ok((format!("sha256:{:x}", hasher.finalize()), size))
+}
+
+/// Header for a regular file in an archive
+pub(crate) fn archive_header(size: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    header.set_cksum();
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::images::{ImageConfig, ImageConfigDetails};
+    use tempfile::TempDir;
+
+    fn layer_tar(name: &str, contents: &[u8]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, contents).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    /// Store an image with the given layer tars, returning its ID
+    fn store_image(paths: &DarkerPaths, image_id: &str, name: &str, layers: &[Vec<u8>]) -> String {
+        let layer_manager = LayerManager::new(paths);
+        let mut digests = Vec::new();
+        for tar in layers {
+            let digest = LayerManager::compute_digest_bytes(tar)
+                .trim_start_matches("sha256:")
+                .to_string();
+            layer_manager.store_layer_bytes(&digest, tar).unwrap();
+            digests.push(digest);
+        }
+
+        let reference = ImageReference::parse(name).unwrap();
+        let image_store = ImageStore::new(paths).unwrap();
+        image_store
+            .store(
+                image_id,
+                Some(&reference.repository_with_registry()),
+                Some(reference.tag()),
+                None,
+                &digests,
+                0,
+            )
+            .unwrap();
+        image_store
+            .save_config(
+                image_id,
+                &ImageConfig {
+                    config: ImageConfigDetails {
+                        cmd: Some(vec!["/hello".to_string()]),
+                        ..Default::default()
+                    },
+                },
+            )
+            .unwrap();
+        image_id.to_string()
+    }
+
+    fn archive_entries(archive: &[u8]) -> HashMap<String, Vec<u8>> {
+        let mut entries = HashMap::new();
+        for entry in tar::Archive::new(archive).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().to_string();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            entries.insert(path, data);
+        }
+        entries
+    }
+
+    #[test]
+    fn test_save_and_load_round_trip() {
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path().join("src"));
+        paths.ensure_directories().unwrap();
+
+        let base = layer_tar("base.txt", b"base");
+        let app = store_image(&paths, "aaaa", "alpine:3.19", std::slice::from_ref(&base));
+        let tool = store_image(
+            &paths,
+            "bbbb",
+            "ghcr.io/org/tool:v1",
+            &[base, layer_tar("t", b"t")],
+        );
+
+        let images = vec![
+            (
+                app.clone(),
+                vec![ImageReference::parse("alpine:3.19").unwrap()],
+            ),
+            (
+                tool,
+                vec![ImageReference::parse("ghcr.io/org/tool:v1").unwrap()],
+            ),
+            (app, vec![ImageReference::parse("alpine:latest").unwrap()]),
+        ];
+        let mut archive = Vec::new();
+        save(&paths, &images, &mut archive).unwrap();
+
+        // The shared base layer is written once
+        let entries = archive_entries(&archive);
+        assert_eq!(
+            entries.keys().filter(|k| k.ends_with("/layer.tar")).count(),
+            2
+        );
+        let manifest: Vec<ArchiveManifestEntry> =
+            serde_json::from_slice(&entries["manifest.json"]).unwrap();
+        assert_eq!(manifest.len(), 2);
+        assert_eq!(
+            manifest[0].repo_tags.as_deref().unwrap(),
+            ["alpine:3.19", "alpine:latest"]
+        );
+        assert_eq!(manifest[1].layers.len(), 2);
+        assert_eq!(manifest[0].layers[0], manifest[1].layers[0]);
+        let repositories: serde_json::Value =
+            serde_json::from_slice(&entries["repositories"]).unwrap();
+        assert!(repositories["alpine"]["latest"].is_string());
+
+        // Load it into an empty store
+        let dest = DarkerPaths::with_root(tmp.path().join("dest"));
+        dest.ensure_directories().unwrap();
+        let loaded = load(&dest, archive.as_slice()).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[1].tags, vec!["ghcr.io/org/tool:v1"]);
+
+        let image_store = ImageStore::new(&dest).unwrap();
+        let reference = ImageReference::parse("alpine:latest").unwrap();
+        assert_eq!(
+            image_store.find_image(&reference),
+            Some(loaded[0].image_id.clone())
+        );
+        let metadata = image_store.load_metadata(&loaded[1].image_id).unwrap();
+        assert_eq!(metadata.layers.len(), 2);
+        assert!(dest.layer_tar(&metadata.layers[1]).exists());
+        let config = image_store.load_config(&loaded[1].image_id).unwrap();
+        assert_eq!(config.cmd(), Some(vec!["/hello".to_string()]));
+        assert_eq!(fs::read_dir(dest.layers_dir()).unwrap().count(), 2);
+        assert_eq!(fs::read_dir(dest.tmp_dir()).unwrap().count(), 0);
+
+        // Loading a compressed copy again reuses the stored layers
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&archive).unwrap();
+        let reloaded = load(&dest, encoder.finish().unwrap().as_slice()).unwrap();
+        assert_eq!(reloaded[0].image_id, loaded[0].image_id);
+        assert_eq!(fs::read_dir(dest.layers_dir()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_load_rejects_bad_archives() {
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let not_archive = layer_tar("hello.txt", b"hello");
+        let err = load(&paths, not_archive.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("manifest.json not found"));
+
+        // A layer whose content doesn't match the config's diff ID
+        let config = serde_json::json!({
+            "architecture": "amd64",
+            "os": "linux",
+            "rootfs": { "type": "layers", "diff_ids": [format!("sha256:{}", "0".repeat(64))] },
+        });
+        let manifest = serde_json::json!([{
+            "Config": "config.json",
+            "RepoTags": ["bad:latest"],
+            "Layers": ["layer/layer.tar"],
+        }]);
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in [
+            ("config.json", serde_json::to_vec(&config).unwrap()),
+            ("layer/layer.tar", layer_tar("x", b"x")),
+            ("manifest.json", serde_json::to_vec(&manifest).unwrap()),
+        ] {
+            builder
+                .append_data(
+                    &mut archive_header(data.len() as u64),
+                    name,
+                    data.as_slice(),
+                )
+                .unwrap();
+        }
+        let archive = builder.into_inner().unwrap();
+        let err = load(&paths, archive.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("but the image config expects"));
+        assert_eq!(fs::read_dir(paths.layers_dir()).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(paths.tmp_dir()).unwrap().count(), 0);
+        assert!(ImageStore::new(&paths)
+            .unwrap()
+            .find_image(&ImageReference::parse("bad:latest").unwrap())
+            .is_none());
+
+        // Paths outside the archive are refused
+        let manifest = serde_json::json!([{ "Config": "../config.json", "Layers": [] }]);
+        let data = serde_json::to_vec(&manifest).unwrap();
+        let mut builder = tar::Builder::new(Vec::new());
+        builder
+            .append_data(
+                &mut archive_header(data.len() as u64),
+                "manifest.json",
+                data.as_slice(),
+            )
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+        let err = load(&paths, archive.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("Invalid path in archive"));
+    }
+}
@@ -0,0 +1,128 @@
+//! Registry authentication challenges
+
+use crate::{RegistryError, Result};
+use std::collections::HashMap;
+
+/// Authentication challenge from a registry's `WWW-Authenticate` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthChallenge {
+    /// HTTP Basic authentication
+    Basic { realm: Option<String> },
+    /// Token authentication against a separate token service
+    Bearer {
+        realm: String,
+        service: Option<String>,
+        scope: Option<String>,
+    },
+}
+
+impl AuthChallenge {
+    /// Parse a `WWW-Authenticate` header value
+    pub fn parse(header: &str) -> Result<Self> {
+        let header = header.trim();
+        let (scheme, params) = header.split_once(' ').unwrap_or((header, ""));
+        let mut params = parse_params(params);
+
+        match scheme.to_ascii_lowercase().as_str() {
+            "basic" => Ok(AuthChallenge::Basic {
+                realm: params.remove("realm"),
+            }),
+            "bearer" => {
+                let realm = params.remove("realm").ok_or_else(|| {
+                    RegistryError::Other(format!("Bearer challenge without realm: {}", header))
+                })?;
+                Ok(AuthChallenge::Bearer {
+                    realm,
+                    service: params.remove("service"),
+                    scope: params.remove("scope"),
+                })
+            }
+            _ => Err(RegistryError::Other(format!(
+                "Unsupported authentication scheme: {}",
+                scheme
+            ))
+            .into()),
+        }
+    }
+}
+
+/// Parse comma-separated `key=value` or `key="value"` challenge parameters
+fn parse_params(input: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut chars = input.chars().peekable();
+
+    loop {
+        // Skip separators
+        while matches!(chars.peek(), Some(c) if *c == ',' || c.is_whitespace()) {
+            chars.next();
+        }
+
+        let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+        if key.is_empty() {
+            break;
+        }
+
+        let mut value = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some(escaped) = chars.next() {
+                            value.push(escaped);
+                        }
+                    }
+                    '"' => break,
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.peek() {
+                if *c == ',' {
+                    break;
+                }
+                value.push(*c);
+                chars.next();
+            }
+        }
+
+        params.insert(key.trim().to_ascii_lowercase(), value.trim().to_string());
+    }
+
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bearer_challenge() {
+        let challenge = AuthChallenge::parse(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/alpine:pull,push""#,
+        )
+        .unwrap();
+        assert_eq!(
+            challenge,
+            AuthChallenge::Bearer {
+                realm: "https://auth.docker.io/token".to_string(),
+                service: Some("registry.docker.io".to_string()),
+                scope: Some("repository:library/alpine:pull,push".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_basic_challenge() {
+        let challenge = AuthChallenge::parse(r#"Basic realm="Harbor Registry""#).unwrap();
+        assert_eq!(
+            challenge,
+            AuthChallenge::Basic {
+                realm: Some("Harbor Registry".to_string())
+            }
+        );
+
+        assert!(AuthChallenge::parse("Negotiate").is_err());
+        assert!(AuthChallenge::parse("Bearer service=x").is_err());
+    }
+}
@@ -1,19 +1,33 @@
 //! Dockerfile parser and image builder
 
-use crate::image::layer::LayerManager;
-use crate::image::oci::ImageReference;
-use crate::image::registry::RegistryClient;
-use crate::storage::images::ImageStore;
+use crate::darwin::chroot::can_chroot;
+use crate::darwin::spawn::{resolve_user, ProcessSpawner};
+use crate::filesystem::changes::Snapshot;
+use crate::filesystem::rootfs::RootFs;
+use crate::image::archive::decompress;
+use crate::image::dockerfile::{
+    expand, line_error, parse_command_args, parse_dockerfile, Instruction, Step,
+};
+use crate::image::dockerignore::DockerIgnore;
+use crate::image::layer::{Compression, CreatedLayer, LayerManager};
+use crate::image::oci::{ImageConfigSpec, ImageReference, OciImageConfig, Platform};
+use crate::image::registry::{get_host_arch, get_host_os, RegistryClient};
+use crate::storage::blobs::BlobStore;
+use crate::storage::build_cache::{BuildCache, CacheEntry};
+use crate::storage::images::{ImageMetadata, ImageStore};
 use crate::storage::paths::DarkerPaths;
 use crate::{DarkerError, Result};
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
 use std::fs;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 
 /// Image builder for Dockerfile-based builds
 pub struct ImageBuilder {
     paths: DarkerPaths,
+    registry: RegistryClient,
+    compression: Compression,
 }
 
 impl ImageBuilder {
@@ -21,448 +35,1803 @@ impl ImageBuilder {
     pub fn new(paths: &DarkerPaths) -> Result<Self> {
         Ok(Self {
             paths: paths.clone(),
+            registry: RegistryClient::new()?,
+            compression: Compression::Gzip,
         })
     }
 
+    /// Use a configured registry client for pulling base images
+    pub fn with_registry(mut self, registry: RegistryClient) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Set the compression of the layers the build creates
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
     /// Build an image from a Dockerfile
+    ///
+    /// Only the stages that `target`, or the last stage, depends on are built.
+    #[allow(clippy::too_many_arguments)]
     pub async fn build(
         &mut self,
         context_path: &Path,
         dockerfile: &str,
         tag: Option<&str>,
         build_args: &HashMap<String, String>,
-        _no_cache: bool,
-        _target: Option<&str>,
+        no_cache: bool,
+        target: Option<&str>,
         verbose: bool,
     ) -> Result<String> {
         let dockerfile_path = context_path.join(dockerfile);
         let content = fs::read_to_string(&dockerfile_path)?;
 
         // Parse Dockerfile
-        let instructions = parse_dockerfile(&content)?;
+        let dockerfile = parse_dockerfile(&content)?;
 
-        if instructions.is_empty() {
+        if dockerfile.steps.is_empty() {
             return Err(DarkerError::Build("Empty Dockerfile".to_string()));
         }
 
-        let mut _current_image_id: Option<String> = None;
-        let mut env_vars: HashMap<String, String> = build_args.clone();
-        let mut workdir = "/".to_string();
-        let mut cmd: Option<Vec<String>> = None;
-        let mut entrypoint: Option<Vec<String>> = None;
-        let mut layers: Vec<String> = Vec::new();
+        let (global_args, stages) = split_stages(dockerfile.steps, build_args, dockerfile.escape)?;
+        let target = match target {
+            Some(name) => find_stage(&stages, name, stages.len()).ok_or_else(|| {
+                DarkerError::Build(format!("Target stage {} could not be found", name))
+            })?,
+            None => stages.len() - 1,
+        };
+        let needed = needed_stages(&stages, target);
 
-        for instruction in instructions {
-            match instruction {
-                Instruction::From { image, .. } => {
-                    if verbose {
-                        eprintln!("Step: FROM {}", image);
-                    }
+        let inputs = BuildInputs {
+            context_path,
+            ignore: DockerIgnore::load(context_path, &dockerfile_path)?,
+            escape: dockerfile.escape,
+            build_args,
+            global_args,
+            no_cache,
+            verbose,
+        };
+        let mut built: Vec<Option<StageState>> = vec![None; stages.len()];
+        for index in 0..=target {
+            if !needed.contains(&index) {
+                if verbose {
+                    eprintln!("Skipping stage {}", stages[index].display_name(index));
+                }
+                continue;
+            }
+            let state = self.build_stage(&inputs, &stages, index, &built).await?;
+            built[index] = Some(state);
+        }
+        let StageState {
+            layers,
+            blob_digests,
+            env_vars,
+            labels,
+            workdir,
+            cmd,
+            entrypoint,
+            platform,
+            user,
+            ..
+        } = built[target].take().unwrap_or_default();
 
-                    // Pull base image if needed
-                    let image_ref = ImageReference::parse(&image)?;
-                    let image_store = ImageStore::new(&self.paths)?;
+        // Create the image config, whose digest is the image ID
+        let platform = platform.map(|p| Platform::parse(&p)).transpose()?;
+        let config_platform = platform
+            .clone()
+            .unwrap_or_else(|| Platform::new(get_host_os(), get_host_arch(), None));
+        let mut env: Vec<String> = env_vars
+            .into_iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        env.sort();
+        let config = OciImageConfig {
+            architecture: config_platform.architecture,
+            os: config_platform.os,
+            variant: config_platform.variant,
+            config: Some(ImageConfigSpec {
+                cmd,
+                entrypoint,
+                env: Some(env),
+                working_dir: Some(workdir),
+                user,
+                labels: (!labels.is_empty()).then_some(labels),
+                ..Default::default()
+            }),
+            rootfs: crate::image::oci::RootFs {
+                fs_type: "layers".to_string(),
+                diff_ids: layers.iter().map(|l| format!("sha256:{}", l)).collect(),
+            },
+            history: None,
+        };
+        // Maps are serialized through a Value, which sorts their keys
+        let config_bytes = serde_json::to_vec(&serde_json::to_value(&config)?)?;
+        let config_digest = LayerManager::compute_digest_bytes(&config_bytes);
+        let image_id = config_digest.trim_start_matches("sha256:").to_string();
 
-                    _current_image_id = match image_store.find_image(&image_ref) {
-                        Some(id) => {
-                            // Load layers from base image
-                            let metadata = image_store.load_metadata(&id)?;
-                            layers = metadata.layers;
-                            Some(id)
-                        }
-                        None => {
-                            if image != "scratch" {
-                                if verbose {
-                                    eprintln!("Pulling base image {}...", image);
-                                }
-                                let registry = RegistryClient::new()?;
-                                let id = registry.pull(&image_ref, &self.paths).await?;
-                                let metadata = image_store.load_metadata(&id)?;
-                                layers = metadata.layers;
-                                Some(id)
-                            } else {
-                                // scratch image
-                                None
-                            }
-                        }
-                    };
+        // Calculate total size
+        let layer_manager = LayerManager::new(&self.paths);
+        let mut total_size = 0u64;
+        for layer in &layers {
+            let tar_path = layer_manager.layer_tar_path(layer);
+            if let Ok(metadata) = fs::metadata(&tar_path) {
+                total_size += metadata.len();
+            }
+        }
+
+        // Store image metadata
+        let image_store = ImageStore::new(&self.paths)?;
+        let (repo, tag_str) = if let Some(tag) = tag {
+            let ref_parsed = ImageReference::parse(tag)?;
+            (
+                Some(ref_parsed.repository_with_registry()),
+                Some(ref_parsed.tag),
+            )
+        } else {
+            (None, None)
+        };
+
+        BlobStore::new(&self.paths).write(&config_bytes)?;
+        image_store.store(
+            &image_id,
+            repo.as_deref(),
+            tag_str.as_deref(),
+            Some(&config_digest),
+            &layers,
+            total_size,
+        )?;
+        image_store.set_blob_digests(&image_id, &blob_digests)?;
+        if let Some(platform) = platform {
+            image_store.set_platform(&image_id, &platform)?;
+        }
+
+        Ok(image_id)
+    }
+
+    /// Build one stage, given the stages built before it
+    async fn build_stage(
+        &self,
+        inputs: &BuildInputs<'_>,
+        stages: &[BuildStage],
+        index: usize,
+        built: &[Option<StageState>],
+    ) -> Result<StageState> {
+        let BuildInputs {
+            context_path,
+            ref ignore,
+            no_cache,
+            verbose,
+            ..
+        } = *inputs;
+        let stage = &stages[index];
+        let mut state = self
+            .stage_base(stage, stages, index, built, verbose)
+            .await?;
+
+        // Each step is cached under a key chained from the step before it
+        let cache = BuildCache::new(&self.paths);
+
+        for step in &stage.steps {
+            for flag in &step.ignored_flags {
+                warn_unsupported(step.line, flag);
+            }
+            let vars = state.vars();
+            let instruction = step
+                .instruction
+                .clone()
+                .expand(&vars, inputs.escape)
+                .map_err(|e| line_error(step.line, e))?;
+
+            let text = format!("{:?}", instruction);
+            let source = match &instruction {
+                Instruction::Copy {
+                    from: Some(from), ..
+                } => Some(
+                    self.copy_source(from, stages, index, built, verbose)
+                        .await?,
+                ),
+                _ => None,
+            };
+            let content = match (&instruction, &source) {
+                (Instruction::Copy { srcs, .. }, Some((id, _))) => {
+                    Some(format!("{}:{:?}", id, srcs))
                 }
+                (Instruction::Copy { srcs, .. } | Instruction::Add { srcs, .. }, None) => Some(
+                    srcs.iter()
+                        .map(|src| hash_context(context_path, src, ignore))
+                        .collect::<Result<Vec<_>>>()?
+                        .join(","),
+                ),
+                _ => None,
+            };
+            let key = BuildCache::key(&state.key, &text, &vars, content.as_deref());
+
+            match instruction {
+                Instruction::From { .. } => {}
                 Instruction::Run { command } => {
                     if verbose {
                         eprintln!("Step: RUN {}", command);
                     }
-                    // In a full implementation, we'd execute this in a container
-                    // For now, we'll skip actual execution
+                    let entry = match lookup(&cache, &key, no_cache, verbose)? {
+                        Some(entry) => entry,
+                        None => {
+                            let layer = self
+                                .run_step(
+                                    &command,
+                                    &state.layers,
+                                    &vars,
+                                    &state.workdir,
+                                    state.user.as_deref(),
+                                    verbose,
+                                )
+                                .await?;
+                            record(&cache, &key, layer)?
+                        }
+                    };
+                    state.push_layer(entry);
                 }
-                Instruction::Copy { src, dst } => {
+                Instruction::Copy { srcs, dst, from } => {
                     if verbose {
-                        eprintln!("Step: COPY {} {}", src, dst);
-                    }
-
-                    // Create a layer from the copied files
-                    let layer_manager = LayerManager::new(&self.paths);
-                    let tmp_dir = self.paths.tmp_dir().join(uuid::Uuid::new_v4().to_string());
-                    fs::create_dir_all(&tmp_dir)?;
-
-                    // Copy files from context
-                    let src_path = context_path.join(&src);
-                    let dst_path = tmp_dir.join(dst.trim_start_matches('/'));
-
-                    if let Some(parent) = dst_path.parent() {
-                        fs::create_dir_all(parent)?;
+                        match &from {
+                            Some(from) => {
+                                eprintln!("Step: COPY --from={} {} {}", from, srcs.join(" "), dst)
+                            }
+                            None => eprintln!("Step: COPY {} {}", srcs.join(" "), dst),
+                        }
                     }
-
-                    if src_path.is_dir() {
-                        copy_dir_recursive(&src_path, &dst_path)?;
-                    } else if src_path.exists() {
-                        fs::copy(&src_path, &dst_path)?;
+                    let dst = state.resolve(&dst);
+                    if from.is_none() {
+                        warn_excluded("COPY", &srcs, ignore);
                     }
 
-                    // Create layer
-                    let (digest, _) = layer_manager.create_layer_from_dir(&tmp_dir)?;
-                    let digest_short = digest.strip_prefix("sha256:").unwrap_or(&digest);
-                    layers.push(digest_short.to_string());
-
-                    // Cleanup
-                    fs::remove_dir_all(&tmp_dir)?;
+                    let entry = match lookup(&cache, &key, no_cache, verbose)? {
+                        Some(entry) => entry,
+                        None => {
+                            let layer = match &source {
+                                Some((_, layers)) => self.copy_from_layers(layers, &srcs, &dst)?,
+                                None => self.copy_step(context_path, &srcs, &dst, ignore, false)?,
+                            };
+                            record(&cache, &key, Some(layer))?
+                        }
+                    };
+                    state.push_layer(entry);
                 }
-                Instruction::Add { src, dst } => {
+                Instruction::Add { srcs, dst } => {
                     if verbose {
-                        eprintln!("Step: ADD {} {}", src, dst);
+                        eprintln!("Step: ADD {} {}", srcs.join(" "), dst);
+                    }
+                    if let Some(src) = srcs.iter().find(|src| is_remote(src)) {
+                        return Err(DarkerError::Unsupported(format!(
+                            "ADD from a URL or git repository: {}",
+                            src
+                        )));
                     }
-                    // Similar to COPY but with URL and tar extraction support
-                    // For simplicity, treat as COPY
+                    let dst = state.resolve(&dst);
+                    warn_excluded("ADD", &srcs, ignore);
+
+                    let entry = match lookup(&cache, &key, no_cache, verbose)? {
+                        Some(entry) => entry,
+                        None => {
+                            let layer = self.copy_step(context_path, &srcs, &dst, ignore, true)?;
+                            record(&cache, &key, Some(layer))?
+                        }
+                    };
+                    state.push_layer(entry);
                 }
-                Instruction::Env { key, value } => {
-                    if verbose {
-                        eprintln!("Step: ENV {}={}", key, value);
+                Instruction::Env { vars } => {
+                    for (key, value) in vars {
+                        if verbose {
+                            eprintln!("Step: ENV {}={}", key, value);
+                        }
+                        state.env_vars.insert(key, value);
                     }
-                    env_vars.insert(key, value);
                 }
                 Instruction::Workdir { path } => {
                     if verbose {
                         eprintln!("Step: WORKDIR {}", path);
                     }
-                    workdir = path;
+                    // A relative WORKDIR is relative to the previous one
+                    state.workdir = Path::new(&state.workdir)
+                        .join(path)
+                        .to_string_lossy()
+                        .to_string();
                 }
                 Instruction::Cmd { command } => {
                     if verbose {
                         eprintln!("Step: CMD {:?}", command);
                     }
-                    cmd = Some(command);
+                    state.cmd = Some(command);
                 }
                 Instruction::Entrypoint { command } => {
                     if verbose {
                         eprintln!("Step: ENTRYPOINT {:?}", command);
                     }
-                    entrypoint = Some(command);
+                    state.entrypoint = Some(command);
                 }
-                Instruction::Expose { port } => {
+                Instruction::Expose { ports } => {
                     if verbose {
-                        eprintln!("Step: EXPOSE {}", port);
+                        eprintln!("Step: EXPOSE {}", ports.join(" "));
                     }
                 }
-                Instruction::User { user } => {
+                Instruction::User { user: name } => {
                     if verbose {
-                        eprintln!("Step: USER {}", user);
+                        eprintln!("Step: USER {}", name);
                     }
+                    state.user = Some(name);
                 }
-                Instruction::Label { key, value } => {
-                    if verbose {
-                        eprintln!("Step: LABEL {}={}", key, value);
+                Instruction::Label { labels } => {
+                    for (key, value) in labels {
+                        if verbose {
+                            eprintln!("Step: LABEL {}={}", key, value);
+                        }
+                        state.labels.insert(key, value);
                     }
                 }
-                Instruction::Arg { name, default } => {
-                    if !env_vars.contains_key(&name) {
-                        if let Some(default) = default {
-                            env_vars.insert(name, default);
+                Instruction::Arg { args } => {
+                    // A build arg overrides the default, and an ARG without
+                    // one takes the value of the ARG before the first FROM
+                    for (name, default) in args {
+                        let value = inputs
+                            .build_args
+                            .get(&name)
+                            .cloned()
+                            .or(default)
+                            .or_else(|| inputs.global_args.get(&name).cloned());
+                        if let Some(value) = value {
+                            state.args.insert(name, value);
                         }
                     }
                 }
-                Instruction::Volume { path } => {
+                Instruction::Volume { paths } => {
                     if verbose {
-                        eprintln!("Step: VOLUME {}", path);
+                        eprintln!("Step: VOLUME {}", paths.join(" "));
                     }
                 }
+                Instruction::Ignored { name } => warn_unsupported(step.line, &name),
             }
+            state.key = key;
         }
 
-        // Generate image ID
-        let image_id = format!(
-            "{:x}",
-            Sha256::digest(format!("{:?}{:?}{:?}", layers, cmd, entrypoint).as_bytes())
-        );
+        Ok(state)
+    }
 
-        // Calculate total size
-        let layer_manager = LayerManager::new(&self.paths);
-        let mut total_size = 0u64;
-        for layer in &layers {
-            let tar_path = layer_manager.layer_tar_path(layer);
-            if let Ok(metadata) = fs::metadata(&tar_path) {
-                total_size += metadata.len();
+    /// The state a stage starts from: an earlier stage, an image or scratch
+    ///
+    /// A stage's ARGs are not passed on to the stages built from it.
+    async fn stage_base(
+        &self,
+        stage: &BuildStage,
+        stages: &[BuildStage],
+        index: usize,
+        built: &[Option<StageState>],
+        verbose: bool,
+    ) -> Result<StageState> {
+        if verbose {
+            match &stage.name {
+                Some(name) => eprintln!("Step: FROM {} AS {}", stage.base, name),
+                None => eprintln!("Step: FROM {}", stage.base),
             }
         }
+        let text = format!("FROM {} {:?}", stage.base, stage.platform);
 
-        // Store image metadata
-        let image_store = ImageStore::new(&self.paths)?;
-        let (repo, tag_str) = if let Some(tag) = tag {
-            let ref_parsed = ImageReference::parse(tag)?;
-            (
-                Some(ref_parsed.repository_with_registry()),
-                Some(ref_parsed.tag),
-            )
+        if let Some(parent) = find_stage(stages, &stage.base, index) {
+            let mut state = built[parent].clone().unwrap_or_default();
+            state.args.clear();
+            state.key = BuildCache::key(&state.key, &text, &state.env_vars, None);
+            return Ok(state);
+        }
+
+        let mut state = StageState {
+            workdir: "/".to_string(),
+            ..Default::default()
+        };
+
+        // FROM --platform overrides the platform of the build
+        let wanted = match &stage.platform {
+            Some(p) => Some(Platform::parse(p)?),
+            None => self.registry.platform().cloned(),
+        };
+        state.platform = wanted.as_ref().map(|p| p.to_string());
+
+        let image_id = if stage.base == "scratch" {
+            None
         } else {
-            (None, None)
+            let id = self.find_or_pull(&stage.base, wanted, verbose).await?;
+            let image_store = ImageStore::new(&self.paths)?;
+            let metadata = image_store.load_metadata(&id)?;
+
+            // The base image's ENVs and labels are inherited
+            let config = image_store.load_config(&id)?.config;
+            state.env_vars = config
+                .env
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|var| var.split_once('='))
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            state.labels = config.labels.unwrap_or_default();
+            state.blob_digests = base_blob_digests(&metadata);
+            state.layers = metadata.layers;
+            state.platform = metadata.platform.or(state.platform);
+            Some(id)
         };
 
-        image_store.store(
-            &image_id,
-            repo.as_deref(),
-            tag_str.as_deref(),
-            None,
-            &layers,
-            total_size,
-        )?;
+        // The chain starts from the base image and the layer compression
+        state.key = BuildCache::key(
+            &format!("{:?}", self.compression),
+            &text,
+            &state.env_vars,
+            Some(image_id.as_deref().unwrap_or("scratch")),
+        );
+        Ok(state)
+    }
 
-        // Create image config
-        let config = crate::storage::images::ImageConfig {
-            config: crate::storage::images::ImageConfigDetails {
-                cmd,
-                entrypoint,
-                env: Some(env_vars.into_iter().map(|(k, v)| format!("{}={}", k, v)).collect()),
-                working_dir: Some(workdir),
-                ..Default::default()
-            },
+    /// Find an image in the store, pulling it if it isn't there
+    async fn find_or_pull(
+        &self,
+        image: &str,
+        platform: Option<Platform>,
+        verbose: bool,
+    ) -> Result<String> {
+        let image_ref = ImageReference::parse(image)?;
+        let image_store = ImageStore::new(&self.paths)?;
+        if let Some(id) = image_store.find_image_for_platform(&image_ref, platform.as_ref()) {
+            return Ok(id);
+        }
+
+        if verbose {
+            eprintln!("Pulling base image {}...", image);
+        }
+        self.registry
+            .clone()
+            .with_platform(platform)
+            .pull(&image_ref, &self.paths)
+            .await
+    }
+
+    /// Resolve the stage or image a COPY --from copies from
+    ///
+    /// Returns an ID for the cache key, the stage's key or the image ID, and
+    /// the layers to copy from.
+    async fn copy_source(
+        &self,
+        from: &str,
+        stages: &[BuildStage],
+        index: usize,
+        built: &[Option<StageState>],
+        verbose: bool,
+    ) -> Result<(String, Vec<String>)> {
+        if let Some(source) = find_stage(stages, from, index) {
+            let state = built[source]
+                .as_ref()
+                .ok_or_else(|| DarkerError::Build(format!("Stage {} has not been built", from)))?;
+            return Ok((state.key.clone(), state.layers.clone()));
+        }
+
+        let platform = self.registry.platform().cloned();
+        let id = self.find_or_pull(from, platform, verbose).await?;
+        let metadata = ImageStore::new(&self.paths)?.load_metadata(&id)?;
+        Ok((id, metadata.layers))
+    }
+
+    /// Create the layer of a COPY --from instruction, copying from the
+    /// filesystem of a stack of layers
+    fn copy_from_layers(
+        &self,
+        layers: &[String],
+        srcs: &[String],
+        dst: &str,
+    ) -> Result<CreatedLayer> {
+        let container_id = uuid::Uuid::new_v4().simple().to_string();
+        let rootfs = RootFs::new(&self.paths, &container_id)?;
+        let result = rootfs.extract_layers(layers).and_then(|_| {
+            let ignore = DockerIgnore::default();
+            self.copy_step(rootfs.path(), srcs, dst, &ignore, false)
+        });
+        self.remove_build_container(&rootfs, &container_id);
+        result
+    }
+
+    /// Create the layer of a COPY or ADD instruction
+    ///
+    /// `srcs` are relative to `source_root`, even if they are absolute, and
+    /// paths `ignore` excludes are left out. Files are copied into `dst` if it
+    /// ends with a /. With `extract`, as for ADD, tar archives are unpacked
+    /// into `dst` instead of copied.
+    fn copy_step(
+        &self,
+        source_root: &Path,
+        srcs: &[String],
+        dst: &str,
+        ignore: &DockerIgnore,
+        extract: bool,
+    ) -> Result<CreatedLayer> {
+        let tmp_dir = self.paths.tmp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&tmp_dir)?;
+
+        // Create a layer from the copied files
+        let copied = copy_sources(source_root, srcs, dst, ignore, extract, &tmp_dir);
+        let layer = copied.and_then(|_| {
+            LayerManager::new(&self.paths).create_layer_from_dir(&tmp_dir, self.compression)
+        });
+        fs::remove_dir_all(&tmp_dir)?;
+        layer
+    }
+
+    /// Run a RUN instruction in a build container of the current layers
+    ///
+    /// Returns the layer of the changes the command made to the filesystem,
+    /// or None if it changed nothing. RUN needs root, to chroot into the
+    /// build container.
+    async fn run_step(
+        &self,
+        command: &str,
+        layers: &[String],
+        env_vars: &HashMap<String, String>,
+        workdir: &str,
+        user: Option<&str>,
+        verbose: bool,
+    ) -> Result<Option<CreatedLayer>> {
+        // Without chroot the command would run on the host instead
+        if !can_chroot() {
+            return Err(DarkerError::PermissionDenied(format!(
+                "Step RUN {} requires root, to run in the image's filesystem",
+                command
+            )));
+        }
+        let container_id = uuid::Uuid::new_v4().simple().to_string();
+        let rootfs = RootFs::new(&self.paths, &container_id)?;
+        let result = self
+            .run_in_rootfs(&rootfs, command, layers, env_vars, workdir, user, verbose)
+            .await;
+        self.remove_build_container(&rootfs, &container_id);
+        result
+    }
+
+    /// Remove the rootfs and directory of a build container
+    fn remove_build_container(&self, rootfs: &RootFs, container_id: &str) {
+        let cleanup = rootfs
+            .cleanup()
+            .and_then(|_| Ok(fs::remove_dir_all(self.paths.container_dir(container_id))?));
+        if let Err(e) = cleanup {
+            tracing::debug!("Could not remove build container {}: {}", container_id, e);
+        }
+    }
+
+    /// Set up a build container's rootfs and run a RUN command in it
+    #[allow(clippy::too_many_arguments)]
+    async fn run_in_rootfs(
+        &self,
+        rootfs: &RootFs,
+        command: &str,
+        layers: &[String],
+        env_vars: &HashMap<String, String>,
+        workdir: &str,
+        user: Option<&str>,
+        verbose: bool,
+    ) -> Result<Option<CreatedLayer>> {
+        rootfs.setup_layers(layers)?;
+        let root = rootfs.path();
+        let snapshot = Snapshot::capture(root)?;
+
+        // Like WORKDIR, RUN creates its working directory
+        fs::create_dir_all(root.join(workdir.trim_start_matches('/')))?;
+        let user = user.map(|user| resolve_user(root, user)).transpose()?;
+
+        let mut env = vec![
+            ("PATH".to_string(), DEFAULT_PATH.to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ];
+        let mut vars: Vec<_> = env_vars.iter().collect();
+        vars.sort();
+        env.extend(vars.into_iter().map(|(k, v)| (k.clone(), v.clone())));
+
+        let (exit_code, output) = ProcessSpawner::new()
+            .spawn_captured(
+                &parse_command_args(command),
+                root,
+                workdir,
+                &env,
+                user,
+                verbose,
+            )
+            .await?;
+        if exit_code != 0 {
+            let mut message = format!(
+                "Step RUN {} returned a non-zero code: {}",
+                command, exit_code
+            );
+            // Output already echoed during the build isn't repeated
+            if !verbose && !output.trim().is_empty() {
+                message.push('\n');
+                message.push_str(output.trim_end());
+            }
+            return Err(DarkerError::Build(message));
+        }
+
+        let changes = self.paths.tmp_dir().join(uuid::Uuid::new_v4().to_string());
+        let layer = match snapshot.write_changes(&changes) {
+            Ok(true) => LayerManager::new(&self.paths)
+                .create_layer_from_dir(&changes, self.compression)
+                .map(Some),
+            Ok(false) => Ok(None),
+            Err(e) => Err(e),
         };
-        image_store.save_config(&image_id, &config)?;
+        if changes.exists() {
+            fs::remove_dir_all(&changes)?;
+        }
+        layer
+    }
+}
 
-        Ok(image_id)
+/// Look up a build step in the cache, unless the cache is bypassed
+fn lookup(
+    cache: &BuildCache,
+    key: &str,
+    no_cache: bool,
+    verbose: bool,
+) -> Result<Option<CacheEntry>> {
+    if no_cache {
+        return Ok(None);
+    }
+    let entry = cache.get(key)?;
+    if entry.is_some() && verbose {
+        eprintln!(" ---> CACHED");
     }
+    Ok(entry)
 }
 
-/// Parsed Dockerfile instruction
-#[derive(Debug, Clone)]
-#[allow(dead_code)] // Some fields are parsed but not yet used
-enum Instruction {
-    From { image: String, alias: Option<String> },
-    Run { command: String },
-    Copy { src: String, dst: String },
-    Add { src: String, dst: String },
-    Env { key: String, value: String },
-    Workdir { path: String },
-    Cmd { command: Vec<String> },
-    Entrypoint { command: Vec<String> },
-    Expose { port: String },
-    User { user: String },
-    Label { key: String, value: String },
-    Arg { name: String, default: Option<String> },
-    Volume { path: String },
+/// Record the layer a build step created in the cache
+fn record(cache: &BuildCache, key: &str, layer: Option<CreatedLayer>) -> Result<CacheEntry> {
+    let entry = CacheEntry {
+        layer: layer
+            .as_ref()
+            .map(|layer| layer.diff_id.trim_start_matches("sha256:").to_string()),
+        blob_digest: layer.map(|layer| layer.digest),
+        created: chrono::Utc::now(),
+    };
+    cache.put(key, &entry)?;
+    Ok(entry)
 }
 
-/// Parse a Dockerfile into instructions
-fn parse_dockerfile(content: &str) -> Result<Vec<Instruction>> {
-    let mut instructions = Vec::new();
-    let mut current_line = String::new();
+/// Hash a COPY or ADD source in the build context, for its cache key
+///
+/// Names, file types, permissions and contents are hashed, but not
+/// timestamps or owners. A missing path hashes to a fixed value, and paths
+/// `ignore` excludes aren't read. The source is resolved as it is copied.
+fn hash_context(context_path: &Path, src: &str, ignore: &DockerIgnore) -> Result<String> {
+    let relative = resolve_source(context_path, src)?;
+    let mut hasher = Sha256::new();
+    hash_context_path(
+        &context_path.join(&relative),
+        &relative,
+        Path::new(""),
+        ignore,
+        &mut hasher,
+    )?;
+    Ok(format!("sha256:{:x}", hasher.finalize()))
+}
 
-    for line in content.lines() {
-        let trimmed = line.trim();
+/// Hash a path in the build context under its name relative to the COPY source
+///
+/// `relative` is the path relative to the context, which `ignore` matches.
+fn hash_context_path(
+    path: &Path,
+    relative: &Path,
+    name: &Path,
+    ignore: &DockerIgnore,
+    hasher: &mut Sha256,
+) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
 
-        // Skip comments and empty lines
-        if trimmed.starts_with('#') || trimmed.is_empty() {
-            continue;
-        }
+    if ignore.excludes_all(relative, path.is_dir()) {
+        hasher.update(format!("excluded {}\0", name.display()).as_bytes());
+        return Ok(());
+    }
 
-        // Handle line continuation
-        if trimmed.ends_with('\\') {
-            current_line.push_str(&trimmed[..trimmed.len() - 1]);
-            current_line.push(' ');
-            continue;
+    // Symlinks are copied as links
+    if path.is_symlink() {
+        let target = fs::read_link(path)?;
+        hasher.update(format!("link {} {}\0", name.display(), target.display()).as_bytes());
+        return Ok(());
+    }
+    let Ok(metadata) = fs::metadata(path) else {
+        hasher.update(format!("missing {}\0", name.display()).as_bytes());
+        return Ok(());
+    };
+    let mode = metadata.permissions().mode() & 0o7777;
+    if metadata.is_dir() {
+        hasher.update(format!("dir {} {:o}\0", name.display(), mode).as_bytes());
+        let mut entries = fs::read_dir(path)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            hash_context_path(
+                &entry.path(),
+                &relative.join(entry.file_name()),
+                &name.join(entry.file_name()),
+                ignore,
+                hasher,
+            )?;
         }
+    } else {
+        let header = format!("file {} {:o} {}\0", name.display(), mode, metadata.len());
+        hasher.update(header.as_bytes());
+        std::io::copy(&mut fs::File::open(path)?, hasher)?;
+    }
+    Ok(())
+}
 
-        current_line.push_str(trimmed);
-        let full_line = std::mem::take(&mut current_line);
+/// PATH of RUN commands, unless the Dockerfile sets one
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
 
-        // Parse instruction
-        let parts: Vec<&str> = full_line.splitn(2, char::is_whitespace).collect();
-        if parts.is_empty() {
-            continue;
+/// Blob digests of the layers of a base image
+///
+/// Layers with no compressed blob recorded are their own, uncompressed, blob.
+fn base_blob_digests(metadata: &ImageMetadata) -> Vec<String> {
+    if metadata.blob_digests.len() == metadata.layers.len() {
+        return metadata.blob_digests.clone();
+    }
+    metadata
+        .layers
+        .iter()
+        .map(|layer| format!("sha256:{}", layer))
+        .collect()
+}
+
+/// A stage of a Dockerfile: a FROM instruction and those that follow it
+#[derive(Debug)]
+struct BuildStage {
+    name: Option<String>,
+    base: String,
+    platform: Option<String>,
+    steps: Vec<Step>,
+}
+
+impl BuildStage {
+    /// Name of the stage for messages, its alias or index
+    fn display_name(&self, index: usize) -> String {
+        self.name.clone().unwrap_or_else(|| index.to_string())
+    }
+}
+
+/// Inputs of a build, shared by its stages
+struct BuildInputs<'a> {
+    context_path: &'a Path,
+    ignore: DockerIgnore,
+    /// Escape character of the Dockerfile
+    escape: char,
+    build_args: &'a HashMap<String, String>,
+    /// Values of the ARGs declared before the first FROM
+    global_args: HashMap<String, String>,
+    no_cache: bool,
+    verbose: bool,
+}
+
+/// Layer stack and config of a stage as it is built
+#[derive(Debug, Clone, Default)]
+struct StageState {
+    layers: Vec<String>,
+    blob_digests: Vec<String>,
+    /// ARGs in scope, which unlike ENVs are not saved in the image
+    args: HashMap<String, String>,
+    env_vars: HashMap<String, String>,
+    labels: HashMap<String, String>,
+    workdir: String,
+    cmd: Option<Vec<String>>,
+    entrypoint: Option<Vec<String>>,
+    platform: Option<String>,
+    user: Option<String>,
+    /// Cache key of the last step
+    key: String,
+}
+
+impl StageState {
+    /// Add the layer of a build step, if it created one
+    fn push_layer(&mut self, entry: CacheEntry) {
+        if let Some(layer) = entry.layer {
+            self.blob_digests.push(
+                entry
+                    .blob_digest
+                    .unwrap_or_else(|| format!("sha256:{}", layer)),
+            );
+            self.layers.push(layer);
         }
+    }
 
-        let instruction = parts[0].to_uppercase();
-        let args = parts.get(1).map(|s| s.trim()).unwrap_or("");
+    /// Resolve a destination path, where relative ones are relative to the
+    /// WORKDIR
+    fn resolve(&self, path: &str) -> String {
+        match path.starts_with('/') {
+            true => path.to_string(),
+            false => format!("{}/{}", self.workdir.trim_end_matches('/'), path),
+        }
+    }
 
-        match instruction.as_str() {
-            "FROM" => {
-                let parts: Vec<&str> = args.split_whitespace().collect();
-                let image = parts.first().unwrap_or(&"").to_string();
-                let alias = if parts.len() >= 3 && parts[1].to_uppercase() == "AS" {
-                    Some(parts[2].to_string())
-                } else {
-                    None
-                };
-                instructions.push(Instruction::From { image, alias });
-            }
-            "RUN" => {
-                instructions.push(Instruction::Run {
-                    command: args.to_string(),
-                });
-            }
-            "COPY" => {
-                let parts: Vec<&str> = args.split_whitespace().collect();
-                if parts.len() >= 2 {
-                    instructions.push(Instruction::Copy {
-                        src: parts[0].to_string(),
-                        dst: parts[1].to_string(),
-                    });
-                }
-            }
-            "ADD" => {
-                let parts: Vec<&str> = args.split_whitespace().collect();
-                if parts.len() >= 2 {
-                    instructions.push(Instruction::Add {
-                        src: parts[0].to_string(),
-                        dst: parts[1].to_string(),
-                    });
-                }
-            }
-            "ENV" => {
-                if let Some((key, value)) = args.split_once('=') {
-                    instructions.push(Instruction::Env {
-                        key: key.trim().to_string(),
-                        value: value.trim().to_string(),
-                    });
-                } else {
-                    let parts: Vec<&str> = args.splitn(2, char::is_whitespace).collect();
-                    if parts.len() >= 2 {
-                        instructions.push(Instruction::Env {
-                            key: parts[0].to_string(),
-                            value: parts[1].trim().to_string(),
-                        });
+    /// Variables for substitution and RUN, where ENVs override ARGs
+    fn vars(&self) -> HashMap<String, String> {
+        let mut vars = self.args.clone();
+        vars.extend(self.env_vars.clone());
+        vars
+    }
+}
+
+/// Split a Dockerfile's steps into stages
+///
+/// Also returns the values of the ARGs declared before the first FROM, which
+/// are substituted into FROM lines and the stages of COPY --from.
+fn split_stages(
+    steps: Vec<Step>,
+    build_args: &HashMap<String, String>,
+    escape: char,
+) -> Result<(HashMap<String, String>, Vec<BuildStage>)> {
+    let mut global_args = HashMap::new();
+    let mut stages: Vec<BuildStage> = Vec::new();
+
+    for step in steps {
+        let Step {
+            line,
+            instruction,
+            ignored_flags,
+        } = step;
+        let expand = |word: &str, args: &HashMap<String, String>| {
+            expand(word, args, escape).map_err(|e| line_error(line, e))
+        };
+        match (instruction, stages.last_mut()) {
+            (
+                Instruction::From {
+                    image,
+                    alias,
+                    platform,
+                },
+                _,
+            ) => stages.push(BuildStage {
+                name: alias,
+                base: expand(&image, &global_args)?,
+                platform: platform
+                    .map(|platform| expand(&platform, &global_args))
+                    .transpose()?,
+                steps: Vec::new(),
+            }),
+            // Stages are copied from by names the global ARGs can make up
+            (
+                Instruction::Copy {
+                    srcs,
+                    dst,
+                    from: Some(from),
+                },
+                Some(stage),
+            ) => stage.steps.push(Step {
+                line,
+                instruction: Instruction::Copy {
+                    srcs,
+                    dst,
+                    from: Some(expand(&from, &global_args)?),
+                },
+                ignored_flags,
+            }),
+            (instruction, Some(stage)) => stage.steps.push(Step {
+                line,
+                instruction,
+                ignored_flags,
+            }),
+            (Instruction::Arg { args }, None) => {
+                for (name, default) in args {
+                    let value = match build_args.get(&name) {
+                        Some(value) => Some(value.clone()),
+                        None => default
+                            .map(|default| expand(&default, &global_args))
+                            .transpose()?,
+                    };
+                    if let Some(value) = value {
+                        global_args.insert(name, value);
                     }
                 }
             }
-            "WORKDIR" => {
-                instructions.push(Instruction::Workdir {
-                    path: args.to_string(),
-                });
-            }
-            "CMD" => {
-                let command = parse_command_args(args);
-                instructions.push(Instruction::Cmd { command });
-            }
-            "ENTRYPOINT" => {
-                let command = parse_command_args(args);
-                instructions.push(Instruction::Entrypoint { command });
+            (_, None) => {
+                return Err(line_error(
+                    line,
+                    "no build stage in current context, only ARG may come before FROM",
+                ))
             }
-            "EXPOSE" => {
-                instructions.push(Instruction::Expose {
-                    port: args.to_string(),
-                });
-            }
-            "USER" => {
-                instructions.push(Instruction::User {
-                    user: args.to_string(),
-                });
-            }
-            "LABEL" => {
-                if let Some((key, value)) = args.split_once('=') {
-                    instructions.push(Instruction::Label {
-                        key: key.trim().to_string(),
-                        value: value.trim().trim_matches('"').to_string(),
-                    });
-                }
+        }
+    }
+
+    if stages.is_empty() {
+        return Err(DarkerError::Build(
+            "Dockerfile has no FROM instruction".to_string(),
+        ));
+    }
+    Ok((global_args, stages))
+}
+
+/// Find the stage a name or index refers to among the stages before `before`
+///
+/// Stage names are case-insensitive.
+fn find_stage(stages: &[BuildStage], name: &str, before: usize) -> Option<usize> {
+    match name.parse::<usize>() {
+        Ok(index) => (index < before).then_some(index),
+        Err(_) => stages[..before].iter().position(|stage| {
+            stage
+                .name
+                .as_deref()
+                .is_some_and(|alias| alias.eq_ignore_ascii_case(name))
+        }),
+    }
+}
+
+/// Indexes of the stages a stage depends on, including itself
+///
+/// A stage depends on the stage it is built FROM and those it copies from.
+fn needed_stages(stages: &[BuildStage], target: usize) -> HashSet<usize> {
+    let mut needed = HashSet::new();
+    let mut pending = vec![target];
+    while let Some(index) = pending.pop() {
+        if !needed.insert(index) {
+            continue;
+        }
+        let stage = &stages[index];
+        pending.extend(find_stage(stages, &stage.base, index));
+        for step in &stage.steps {
+            if let Instruction::Copy {
+                from: Some(from), ..
+            } = &step.instruction
+            {
+                pending.extend(find_stage(stages, from, index));
             }
-            "ARG" => {
-                if let Some((name, default)) = args.split_once('=') {
-                    instructions.push(Instruction::Arg {
-                        name: name.trim().to_string(),
-                        default: Some(default.trim().to_string()),
-                    });
-                } else {
-                    instructions.push(Instruction::Arg {
-                        name: args.to_string(),
-                        default: None,
-                    });
-                }
+        }
+    }
+    needed
+}
+
+/// Apply a Dockerfile instruction given to `--change` to an image config
+///
+/// Only instructions that change the config are accepted: CMD, ENTRYPOINT,
+/// ENV, EXPOSE, LABEL, USER, VOLUME and WORKDIR. Variables are substituted
+/// from the config's ENVs.
+pub fn apply_change(config: &mut ImageConfigSpec, change: &str) -> Result<()> {
+    let dockerfile = parse_dockerfile(change)?;
+    let [step] = dockerfile.steps.as_slice() else {
+        return Err(DarkerError::Build(format!("Invalid change: {}", change)));
+    };
+    let vars: HashMap<String, String> = config
+        .env
+        .iter()
+        .flatten()
+        .filter_map(|var| var.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    let instruction = step
+        .instruction
+        .clone()
+        .expand(&vars, dockerfile.escape)
+        .map_err(|e| line_error(step.line, e))?;
+
+    match instruction {
+        Instruction::Cmd { command } => config.cmd = Some(command),
+        Instruction::Entrypoint { command } => config.entrypoint = Some(command),
+        Instruction::Env { vars } => {
+            let env = config.env.get_or_insert_with(Vec::new);
+            for (key, value) in vars {
+                let prefix = format!("{}=", key);
+                env.retain(|e| !e.starts_with(&prefix));
+                env.push(format!("{}={}", key, value));
             }
-            "VOLUME" => {
-                instructions.push(Instruction::Volume {
-                    path: args.to_string(),
-                });
+        }
+        Instruction::Expose { ports: exposed } => {
+            let ports = config.exposed_ports.get_or_insert_with(HashMap::new);
+            for port in exposed {
+                let port = match port.contains('/') {
+                    true => port,
+                    false => format!("{}/tcp", port),
+                };
+                ports.insert(port, serde_json::json!({}));
             }
-            _ => {
-                // Ignore unknown instructions
+        }
+        Instruction::Label { labels } => {
+            config
+                .labels
+                .get_or_insert_with(HashMap::new)
+                .extend(labels);
+        }
+        Instruction::User { user } => config.user = Some(user),
+        Instruction::Volume { paths } => {
+            let volumes = config.volumes.get_or_insert_with(HashMap::new);
+            for path in paths {
+                volumes.insert(path, serde_json::json!({}));
             }
         }
+        Instruction::Workdir { path } => config.working_dir = Some(path),
+        _ => {
+            return Err(DarkerError::Build(format!(
+                "Unsupported instruction in change: {}",
+                change
+            )))
+        }
     }
 
-    Ok(instructions)
+    Ok(())
 }
 
-/// Parse CMD/ENTRYPOINT arguments
-fn parse_command_args(args: &str) -> Vec<String> {
-    let trimmed = args.trim();
-
-    // Check if it's JSON array format
-    if trimmed.starts_with('[') && trimmed.ends_with(']') {
-        // Simple JSON array parsing
-        let inner = &trimmed[1..trimmed.len() - 1];
-        inner
-            .split(',')
-            .map(|s| s.trim().trim_matches('"').to_string())
-            .filter(|s| !s.is_empty())
-            .collect()
-    } else {
-        // Shell format
-        vec!["/bin/sh".to_string(), "-c".to_string(), trimmed.to_string()]
+/// Copy the sources of a COPY or ADD instruction into the directory of a layer
+///
+/// Sources that are symlinks are copied as links. A missing source is an
+/// error.
+fn copy_sources(
+    source_root: &Path,
+    srcs: &[String],
+    dst: &str,
+    ignore: &DockerIgnore,
+    extract: bool,
+    layer_dir: &Path,
+) -> Result<()> {
+    // The destination is a path in the image, where .. stops at the root
+    let (dst_relative, _) = clean_path(dst);
+    let dst_dir = layer_dir.join(dst_relative);
+
+    for src in srcs {
+        let relative = resolve_source(source_root, src)?;
+        let src_path = source_root.join(&relative);
+        let metadata = fs::symlink_metadata(&src_path)
+            .map_err(|_| DarkerError::Build(format!("COPY failed: source {} not found", src)))?;
+        let mut dst_path = dst_dir.clone();
+        if dst.ends_with('/') && !metadata.is_dir() {
+            dst_path.extend(relative.file_name());
+        }
+
+        if let Some(parent) = dst_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        if ignore.excludes_all(&relative, metadata.is_dir()) {
+            // Nothing to copy
+        } else if metadata.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(&src_path)?, &dst_path)?;
+            copy_link_mtime(&src_path, &dst_path)?;
+        } else if extract && metadata.is_file() && unpack_archive(&src_path, &dst_dir)? {
+            // Unpacked into the destination directory
+        } else if metadata.is_dir() {
+            copy_dir_recursive(&src_path, &dst_path, &relative, ignore)?;
+        } else {
+            fs::copy(&src_path, &dst_path)?;
+            copy_mtime(&src_path, &dst_path)?;
+        }
     }
+    Ok(())
 }
 
-/// Recursively copy a directory
-fn copy_dir_recursive(src: &Path, dst: &Path) -> Result<()> {
+/// Clean a path into one relative to the root, resolving `.` and `..`
+///
+/// Also returns whether a `..` went above the root, where it stopped.
+fn clean_path(path: &str) -> (PathBuf, bool) {
+    let mut cleaned = PathBuf::new();
+    let mut escaped = false;
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(name) => cleaned.push(name),
+            Component::ParentDir => escaped |= !cleaned.pop(),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    (cleaned, escaped)
+}
+
+/// Resolve a COPY or ADD source to a path relative to `root`
+///
+/// The source can't use `..` to leave the root. Symlinks in its path are
+/// resolved within the root, with absolute targets relative to it as in a
+/// chroot, so they never lead to host files. The source itself is not
+/// followed if it is a symlink.
+fn resolve_source(root: &Path, src: &str) -> Result<PathBuf> {
+    let (relative, escaped) = clean_path(src);
+    if escaped {
+        return Err(DarkerError::Build(format!(
+            "COPY failed: forbidden path outside the source root: {}",
+            src
+        )));
+    }
+
+    // Names left to resolve, last first, where link targets can add / and ..
+    let names = |path: &Path| -> Vec<OsString> {
+        let names = path.components().filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some("..".into()),
+            Component::RootDir => Some("/".into()),
+            Component::CurDir | Component::Prefix(_) => None,
+        });
+        names.rev().collect()
+    };
+    let mut pending = names(&relative);
+    let mut resolved = PathBuf::new();
+    let mut links = 0;
+    while let Some(name) = pending.pop() {
+        if name == "/" {
+            resolved = PathBuf::new();
+            continue;
+        }
+        if name == ".." {
+            resolved.pop();
+            continue;
+        }
+        let path = resolved.join(&name);
+        let is_link = !pending.is_empty()
+            && fs::symlink_metadata(root.join(&path)).is_ok_and(|m| m.is_symlink());
+        if !is_link {
+            resolved = path;
+            continue;
+        }
+
+        links += 1;
+        if links > MAX_SYMLINKS {
+            return Err(DarkerError::Build(format!(
+                "COPY failed: too many levels of symbolic links: {}",
+                src
+            )));
+        }
+        pending.extend(names(&fs::read_link(root.join(&path))?));
+    }
+    Ok(resolved)
+}
+
+/// Symlinks followed when resolving a path before giving up, as on Linux
+const MAX_SYMLINKS: usize = 40;
+
+/// Recursively copy a directory, leaving out the paths `ignore` excludes
+///
+/// `relative` is the directory's path relative to the build context. Symlinks
+/// within it are copied as links, which keeps links in an image's filesystem
+/// from resolving to host paths.
+fn copy_dir_recursive(
+    src: &Path,
+    dst: &Path,
+    relative: &Path,
+    ignore: &DockerIgnore,
+) -> Result<()> {
     fs::create_dir_all(dst)?;
 
     for entry in fs::read_dir(src)? {
         let entry = entry?;
         let src_path = entry.path();
         let dst_path = dst.join(entry.file_name());
+        let relative = relative.join(entry.file_name());
+        let file_type = entry.file_type()?;
 
-        if src_path.is_dir() {
-            copy_dir_recursive(&src_path, &dst_path)?;
+        if ignore.excludes_all(&relative, file_type.is_dir()) {
+            continue;
+        }
+        if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(&src_path)?, &dst_path)?;
+            copy_link_mtime(&src_path, &dst_path)?;
+        } else if file_type.is_dir() {
+            copy_dir_recursive(&src_path, &dst_path, &relative, ignore)?;
         } else {
             fs::copy(&src_path, &dst_path)?;
+            copy_mtime(&src_path, &dst_path)?;
         }
     }
 
+    copy_mtime(src, dst)
+}
+
+/// Give a copy the modification time of its source, so layers don't depend
+/// on when they were built
+fn copy_mtime(src: &Path, dst: &Path) -> Result<()> {
+    let modified = fs::metadata(src)?.modified()?;
+    fs::File::open(dst)?.set_modified(modified)?;
+    Ok(())
+}
+
+/// Give a copied symlink the modification time of its source link
+fn copy_link_mtime(src: &Path, dst: &Path) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = fs::symlink_metadata(src)?;
+    let time = libc::timespec {
+        tv_sec: metadata.mtime() as libc::time_t,
+        tv_nsec: metadata.mtime_nsec() as libc::c_long,
+    };
+    let path = std::ffi::CString::new(dst.to_string_lossy().as_bytes()).map_err(|_| {
+        DarkerError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Invalid path for symlink",
+        ))
+    })?;
+
+    let times = [time, time];
+    let result = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if result != 0 {
+        return Err(DarkerError::Io(std::io::Error::last_os_error()));
+    }
     Ok(())
 }
 
+/// Warn about an instruction or flag of a Dockerfile line that was ignored
+fn warn_unsupported(line: usize, what: &str) {
+    eprintln!(
+        "WARNING: Dockerfile line {}: {} is not supported and was ignored",
+        line, what
+    );
+}
+
+/// Warn about COPY or ADD sources that .dockerignore excludes, since nothing
+/// of them is copied
+fn warn_excluded(instruction: &str, srcs: &[String], ignore: &DockerIgnore) {
+    for src in srcs {
+        if ignore.is_excluded(&clean_path(src).0) {
+            eprintln!(
+                "WARNING: {} source {} is excluded by .dockerignore",
+                instruction, src
+            );
+        }
+    }
+}
+
+/// Whether an ADD source is a URL or git repository rather than a path
+fn is_remote(src: &str) -> bool {
+    src.contains("://") || src.starts_with("git@")
+}
+
+/// Unpack a file into a directory if it is a tar archive, which may be gzip
+/// or zstd compressed
+///
+/// Returns whether the file was an archive. Other files are left for the
+/// caller to copy.
+fn unpack_archive(path: &Path, dst_dir: &Path) -> Result<bool> {
+    use std::io::Read;
+
+    // A tar header has the ustar magic at offset 257
+    let mut header = [0u8; 512];
+    let is_tar = decompress(fs::File::open(path)?)?
+        .read_exact(&mut header)
+        .is_ok()
+        && header[257..262] == *b"ustar";
+    if !is_tar {
+        return Ok(false);
+    }
+
+    fs::create_dir_all(dst_dir)?;
+    let mut archive = tar::Archive::new(decompress(fs::File::open(path)?)?);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.unpack(dst_dir)?;
+    Ok(true)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
-    fn test_parse_dockerfile() {
+    fn test_apply_change() {
+        let mut config = ImageConfigSpec::default();
+        apply_change(&mut config, r#"CMD ["/app", "serve"]"#).unwrap();
+        apply_change(&mut config, "ENV PATH=/usr/bin").unwrap();
+        apply_change(&mut config, "ENV PATH=/app/bin").unwrap();
+        apply_change(&mut config, "EXPOSE 8080 53/udp").unwrap();
+        apply_change(&mut config, "WORKDIR /app").unwrap();
+
+        assert_eq!(
+            config.cmd,
+            Some(vec!["/app".to_string(), "serve".to_string()])
+        );
+        assert_eq!(config.env, Some(vec!["PATH=/app/bin".to_string()]));
+        let ports = config.exposed_ports.unwrap();
+        assert!(ports.contains_key("8080/tcp") && ports.contains_key("53/udp"));
+        assert_eq!(config.working_dir.as_deref(), Some("/app"));
+
+        let mut config = ImageConfigSpec::default();
+        assert!(apply_change(&mut config, "RUN rm -rf /").is_err());
+        assert!(apply_change(&mut config, "").is_err());
+    }
+
+    #[test]
+    fn test_split_stages() {
         let content = r#"
-FROM alpine:3.18
-RUN apk add --no-cache curl
-COPY . /app
-WORKDIR /app
-CMD ["./start.sh"]
+ARG VERSION=3.18 TAG=${VERSION}-slim
+FROM alpine:${TAG} AS build
+COPY --from=golang:1.21 /usr/local/go /usr/local/go
+FROM scratch AS docs
+FROM build AS test
+FROM scratch
+COPY --chown=app --from=Build /out/app /app
 "#;
+        let split = |content: &str, args: &[(&str, &str)]| {
+            let args = args
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            split_stages(parse_dockerfile(content).unwrap().steps, &args, '\\')
+        };
+        let (global_args, stages) = split(content, &[]).unwrap();
+        assert_eq!(
+            global_args.get("TAG").map(String::as_str),
+            Some("3.18-slim")
+        );
+        assert_eq!(stages.len(), 4);
+        assert_eq!(stages[0].base, "alpine:3.18-slim");
+        match &stages[3].steps[0].instruction {
+            Instruction::Copy { srcs, dst, from } => {
+                assert_eq!(
+                    (srcs.as_slice(), dst.as_str()),
+                    (&["/out/app".to_string()][..], "/app")
+                );
+                assert_eq!(from.as_deref(), Some("Build"));
+            }
+            other => panic!("unexpected instruction {:?}", other),
+        }
+
+        // Build args override the defaults of global ARGs
+        let (_, stages) = split(content, &[("VERSION", "3.19")]).unwrap();
+        assert_eq!(stages[0].base, "alpine:3.19-slim");
+
+        // Stages are found by name or index, among the earlier stages only
+        assert_eq!(find_stage(&stages, "build", 3), Some(0));
+        assert_eq!(find_stage(&stages, "1", 3), Some(1));
+        assert_eq!(find_stage(&stages, "test", 2), None);
+        assert_eq!(find_stage(&stages, "golang:1.21", 1), None);
 
-        let instructions = parse_dockerfile(content).unwrap();
-        assert!(!instructions.is_empty());
+        assert_eq!(needed_stages(&stages, 3), HashSet::from([0, 3]));
+        assert_eq!(needed_stages(&stages, 2), HashSet::from([0, 2]));
+
+        // Stages named by global ARGs are needed too
+        let (_, stages) = split(
+            "ARG BASE=build STAGE=deps\nFROM scratch AS build\nFROM scratch AS deps\n\
+             FROM scratch AS unused\nFROM ${BASE}\nCOPY --from=${STAGE} /out /out\n",
+            &[],
+        )
+        .unwrap();
+        assert_eq!(needed_stages(&stages, 3), HashSet::from([0, 1, 3]));
+
+        let err = split("ARG A\nRUN make\n", &[]).unwrap_err();
+        assert!(err.to_string().contains("line 2"));
     }
 
-    #[test]
-    fn test_parse_command_args() {
-        let json_args = r#"["./app", "--config", "prod"]"#;
-        let parsed = parse_command_args(json_args);
-        assert_eq!(parsed, vec!["./app", "--config", "prod"]);
-
-        let shell_args = "echo hello world";
-        let parsed = parse_command_args(shell_args);
-        assert_eq!(parsed, vec!["/bin/sh", "-c", "echo hello world"]);
+    #[tokio::test]
+    async fn test_multi_stage_build() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path().join("darker"));
+        paths.ensure_directories().unwrap();
+        let context = tmp.path().join("context");
+        fs::create_dir_all(context.join("src")).unwrap();
+        fs::write(context.join("src/app"), "app").unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", context.join("src/link")).unwrap();
+        fs::write(context.join("docs.txt"), "docs").unwrap();
+        fs::write(
+            context.join("Dockerfile"),
+            r#"ARG APP_STAGE=build
+FROM scratch AS build
+COPY src /out
+ENV STAGE=build
+FROM scratch AS docs
+COPY docs.txt /docs.txt
+FROM scratch
+COPY --from=${APP_STAGE} /out/app /usr/bin/app
+COPY --from=0 /out /copy
+CMD ["/usr/bin/app"]
+"#,
+        )
+        .unwrap();
+
+        let build = |target: Option<&'static str>| {
+            let mut builder = ImageBuilder::new(&paths).unwrap();
+            let context = context.clone();
+            async move {
+                builder
+                    .build(
+                        &context,
+                        "Dockerfile",
+                        None,
+                        &HashMap::new(),
+                        false,
+                        target,
+                        false,
+                    )
+                    .await
+            }
+        };
+        let image_store = ImageStore::new(&paths).unwrap();
+        let rootfs_of = |image_id: &str| {
+            let rootfs = RootFs::new(&paths, &uuid::Uuid::new_v4().simple().to_string()).unwrap();
+            rootfs
+                .extract_layers(&image_store.load_metadata(image_id).unwrap().layers)
+                .unwrap();
+            rootfs.path().to_path_buf()
+        };
+
+        // The docs stage isn't needed by the last stage
+        let image_id = build(None).await.unwrap();
+        assert_eq!(BuildCache::new(&paths).list().unwrap().len(), 3);
+        let root = rootfs_of(&image_id);
+        assert_eq!(fs::read_to_string(root.join("usr/bin/app")).unwrap(), "app");
+        assert_eq!(
+            fs::read_link(root.join("copy/link")).unwrap(),
+            Path::new("/etc/passwd")
+        );
+        assert!(!root.join("out").exists() && !root.join("docs.txt").exists());
+        let config = image_store.load_config(&image_id).unwrap();
+        assert!(config.config.env.unwrap().is_empty());
+
+        // Building stops at the target, with that stage's layers and config
+        let build_id = build(Some("BUILD")).await.unwrap();
+        let root = rootfs_of(&build_id);
+        assert_eq!(fs::read_to_string(root.join("out/app")).unwrap(), "app");
+        assert!(!root.join("usr").exists());
+        let config = image_store.load_config(&build_id).unwrap();
+        assert_eq!(config.config.env, Some(vec!["STAGE=build".to_string()]));
+
+        assert!(build(Some("missing")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_copy_sources_stay_in_root() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = tempfile::TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path().join("darker"));
+        paths.ensure_directories().unwrap();
+        let context = tmp.path().join("context");
+        fs::create_dir_all(context.join("real")).unwrap();
+        fs::write(context.join("real/app"), "context app").unwrap();
+        fs::write(tmp.path().join("secret"), "host secret").unwrap();
+        fs::create_dir_all(tmp.path().join("real")).unwrap();
+        fs::write(tmp.path().join("real/app"), "host app").unwrap();
+        // Links to host paths, which must resolve within the context
+        symlink(tmp.path().join("secret"), context.join("secret")).unwrap();
+        symlink("/real", context.join("dir")).unwrap();
+        symlink("../../..", context.join("up")).unwrap();
+
+        let build = |dockerfile: String| {
+            fs::write(context.join("Dockerfile"), dockerfile).unwrap();
+            let mut builder = ImageBuilder::new(&paths).unwrap();
+            let context = context.clone();
+            async move {
+                builder
+                    .build(
+                        &context,
+                        "Dockerfile",
+                        None,
+                        &HashMap::new(),
+                        false,
+                        None,
+                        false,
+                    )
+                    .await
+            }
+        };
+        let image_store = ImageStore::new(&paths).unwrap();
+        let rootfs_of = |image_id: &str| {
+            let rootfs = RootFs::new(&paths, &uuid::Uuid::new_v4().simple().to_string()).unwrap();
+            rootfs
+                .extract_layers(&image_store.load_metadata(image_id).unwrap().layers)
+                .unwrap();
+            rootfs.path().to_path_buf()
+        };
+
+        let image_id = build(
+            "FROM scratch AS build\nCOPY secret dir/app up/real/app /out/\n\
+             FROM scratch\nCOPY --from=build /out/secret /out/app /copy/\n\
+             COPY --from=build /out/../out/app ../../app\n"
+                .to_string(),
+        )
+        .await
+        .unwrap();
+        let root = rootfs_of(&image_id);
+        // A source that is a symlink is copied as one, and not followed
+        assert_eq!(
+            fs::read_link(root.join("copy/secret")).unwrap(),
+            tmp.path().join("secret")
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("copy/app")).unwrap(),
+            "context app"
+        );
+        assert_eq!(fs::read_to_string(root.join("app")).unwrap(), "context app");
+
+        // Sources can't leave the root, and must exist
+        let outside = format!("../{}", tmp.path().join("secret").display());
+        for src in ["../secret", "real/../../secret", outside.as_str()] {
+            let err = build(format!("FROM scratch\nCOPY {} /x\n", src))
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("outside"), "{}", err);
+        }
+        let err = build("FROM scratch\nCOPY missing /x\n".to_string())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("missing not found"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_build_variables() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path().join("darker"));
+        paths.ensure_directories().unwrap();
+        let context = tmp.path().join("context");
+        fs::create_dir_all(&context).unwrap();
+        fs::write(context.join("app-2.txt"), "v2").unwrap();
+        fs::write(
+            context.join("Dockerfile"),
+            r#"ARG BASE=scratch VERSION=1
+FROM ${BASE} AS base
+ARG VERSION
+ARG UNUSED
+ENV APP_VERSION=${VERSION:-0} MODE="prod mode" \
+    FLAGS=${UNUSED:+on}
+LABEL version=$VERSION "description"="an app"
+WORKDIR /app
+WORKDIR src
+COPY app-${VERSION}.txt ./
+FROM base
+ENV NEXT=${VERSION:-unset}
+"#,
+        )
+        .unwrap();
+
+        let args = HashMap::from([("VERSION".to_string(), "2".to_string())]);
+        let image_id = ImageBuilder::new(&paths)
+            .unwrap()
+            .build(&context, "Dockerfile", None, &args, false, None, false)
+            .await
+            .unwrap();
+
+        let image_store = ImageStore::new(&paths).unwrap();
+        let config = image_store.load_config(&image_id).unwrap().config;
+        let mut env = config.env.unwrap();
+        env.sort();
+        // ARGs aren't saved in the image, or passed on to later stages
+        assert_eq!(
+            env,
+            vec!["APP_VERSION=2", "FLAGS=", "MODE=prod mode", "NEXT=unset"]
+        );
+        let labels = config.labels.unwrap();
+        assert_eq!(labels["version"], "2");
+        assert_eq!(labels["description"], "an app");
+        assert_eq!(config.working_dir.as_deref(), Some("/app/src"));
+
+        let rootfs = RootFs::new(&paths, "variables").unwrap();
+        rootfs
+            .extract_layers(&image_store.load_metadata(&image_id).unwrap().layers)
+            .unwrap();
+        assert!(rootfs.path().join("app/src/app-2.txt").is_file());
+    }
+
+    #[tokio::test]
+    async fn test_build_dockerignore() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path().join("darker"));
+        paths.ensure_directories().unwrap();
+        let context = tmp.path().join("context");
+        fs::create_dir_all(context.join(".git")).unwrap();
+        fs::create_dir_all(context.join("logs")).unwrap();
+        fs::write(context.join(".git/HEAD"), "ref").unwrap();
+        fs::write(context.join("logs/build.log"), "build").unwrap();
+        fs::write(context.join("logs/keep.log"), "keep").unwrap();
+        fs::write(context.join("app"), "app").unwrap();
+        fs::write(
+            context.join(".dockerignore"),
+            ".git\n**/*.log\n!logs/keep.log\n",
+        )
+        .unwrap();
+        fs::write(
+            context.join("Dockerfile"),
+            "FROM scratch\nCOPY . /app\nCOPY .git /git\nADD logs /added\n",
+        )
+        .unwrap();
+
+        let build = || {
+            let mut builder = ImageBuilder::new(&paths).unwrap();
+            let context = context.clone();
+            async move {
+                builder
+                    .build(
+                        &context,
+                        "Dockerfile",
+                        None,
+                        &HashMap::new(),
+                        false,
+                        None,
+                        false,
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let image_id = build().await;
+        let rootfs = RootFs::new(&paths, "dockerignore").unwrap();
+        let image_store = ImageStore::new(&paths).unwrap();
+        rootfs
+            .extract_layers(&image_store.load_metadata(&image_id).unwrap().layers)
+            .unwrap();
+        let root = rootfs.path();
+        assert_eq!(fs::read_to_string(root.join("app/app")).unwrap(), "app");
+        assert!(root.join("app/logs/keep.log").is_file());
+        assert!(!root.join("app/logs/build.log").exists());
+        assert!(!root.join("app/.git").exists() && !root.join("git/HEAD").exists());
+        assert!(root.join("added/keep.log").is_file());
+        assert!(!root.join("added/build.log").exists());
+
+        // Excluded files aren't part of the cache key
+        fs::write(context.join("logs/build.log"), "rebuilt").unwrap();
+        fs::write(context.join(".git/HEAD"), "other").unwrap();
+        assert_eq!(build().await, image_id);
+        assert_eq!(BuildCache::new(&paths).list().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_build_add() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path().join("darker"));
+        paths.ensure_directories().unwrap();
+        let context = tmp.path().join("context");
+        fs::create_dir_all(&context).unwrap();
+        fs::write(context.join("app.txt"), "app").unwrap();
+
+        // A gzipped tarball of a single file
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o755);
+        header.set_cksum();
+        tar.append_data(&mut header, "bin/tool", &b"tool"[..])
+            .unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        std::io::Write::write_all(&mut encoder, &tar.into_inner().unwrap()).unwrap();
+        fs::write(context.join("tools.tar.gz"), encoder.finish().unwrap()).unwrap();
+
+        let build = |dockerfile: &'static str| {
+            fs::write(context.join("Dockerfile"), dockerfile).unwrap();
+            let mut builder = ImageBuilder::new(&paths).unwrap();
+            let context = context.clone();
+            async move {
+                builder
+                    .build(
+                        &context,
+                        "Dockerfile",
+                        None,
+                        &HashMap::new(),
+                        false,
+                        None,
+                        false,
+                    )
+                    .await
+            }
+        };
+
+        // Files are copied as with COPY, and archives are unpacked
+        let image_id = build("FROM scratch\nWORKDIR /opt\nADD app.txt tools.tar.gz ./\n")
+            .await
+            .unwrap();
+        let rootfs = RootFs::new(&paths, "add").unwrap();
+        let image_store = ImageStore::new(&paths).unwrap();
+        rootfs
+            .extract_layers(&image_store.load_metadata(&image_id).unwrap().layers)
+            .unwrap();
+        let root = rootfs.path();
+        assert_eq!(fs::read_to_string(root.join("opt/app.txt")).unwrap(), "app");
+        assert_eq!(
+            fs::read_to_string(root.join("opt/bin/tool")).unwrap(),
+            "tool"
+        );
+        assert!(!root.join("opt/tools.tar.gz").exists());
+
+        let err = build("FROM scratch\nADD https://example.com/app.tar.gz /\n")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DarkerError::Unsupported(_)));
+    }
+
+    #[tokio::test]
+    async fn test_image_id_from_config() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path().join("darker"));
+        paths.ensure_directories().unwrap();
+        let context = tmp.path().join("context");
+        fs::create_dir_all(&context).unwrap();
+
+        let build = |dockerfile: &'static str| {
+            fs::write(context.join("Dockerfile"), dockerfile).unwrap();
+            let mut builder = ImageBuilder::new(&paths).unwrap();
+            let context = context.clone();
+            async move {
+                builder
+                    .build(
+                        &context,
+                        "Dockerfile",
+                        None,
+                        &HashMap::new(),
+                        false,
+                        None,
+                        false,
+                    )
+                    .await
+                    .unwrap()
+            }
+        };
+
+        // Images that only differ in their config have different IDs
+        let dev = build("FROM scratch\nENV MODE=dev\nCMD [\"/app\"]\n").await;
+        let prod = build("FROM scratch\nENV MODE=prod\nCMD [\"/app\"]\n").await;
+        assert_ne!(dev, prod);
+        let image_store = ImageStore::new(&paths).unwrap();
+        let env = |id: &str| image_store.load_config(id).unwrap().config.env.unwrap();
+        assert_eq!(env(&dev), vec!["MODE=dev"]);
+        assert_eq!(env(&prod), vec!["MODE=prod"]);
+
+        // The ID is the digest of the config, and the same for the same build
+        let bytes = image_store.config_bytes(&prod).unwrap().unwrap();
+        let digest = LayerManager::compute_digest_bytes(&bytes);
+        assert_eq!(digest, format!("sha256:{}", prod));
+        assert_eq!(
+            build("FROM scratch\nENV MODE=prod\nCMD [\"/app\"]\n").await,
+            prod
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_cache() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path().join("darker"));
+        paths.ensure_directories().unwrap();
+        let context = tmp.path().join("context");
+        fs::create_dir_all(&context).unwrap();
+        fs::write(
+            context.join("Dockerfile"),
+            "FROM scratch\nARG MODE=dev\nCOPY app.txt /app.txt\nCMD [\"/app\"]\n",
+        )
+        .unwrap();
+        fs::write(context.join("app.txt"), "v1").unwrap();
+
+        let build = |args: &[(&str, &str)], no_cache: bool| {
+            let args: HashMap<String, String> = args
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            let mut builder = ImageBuilder::new(&paths).unwrap();
+            let context = context.clone();
+            async move {
+                builder
+                    .build(&context, "Dockerfile", None, &args, no_cache, None, false)
+                    .await
+                    .unwrap()
+            }
+        };
+        let cache = BuildCache::new(&paths);
+
+        let first = build(&[], false).await;
+        let steps = cache.list().unwrap().len();
+        assert_eq!(steps, 1);
+        assert_eq!(build(&[], false).await, first);
+        assert_eq!(cache.list().unwrap().len(), steps);
+
+        // Build args and context content are part of the key
+        build(&[("MODE", "prod")], false).await;
+        assert_eq!(cache.list().unwrap().len(), steps + 1);
+        fs::write(context.join("app.txt"), "v2").unwrap();
+        let changed = build(&[], false).await;
+        assert_ne!(changed, first);
+        assert_eq!(cache.list().unwrap().len(), steps + 2);
+
+        // Without the cache the step is run again, to the same layer
+        assert_eq!(build(&[], true).await, changed);
+
+        // Cached layers outlive the images built from them until pruned
+        let image_store = ImageStore::new(&paths).unwrap();
+        for image in image_store.list().unwrap() {
+            image_store.remove(&image.id, true).unwrap();
+        }
+        assert_eq!(LayerManager::new(&paths).list_layers().unwrap().len(), 2);
+        cache.clear().unwrap();
+        LayerManager::new(&paths).prune().unwrap();
+        assert!(LayerManager::new(&paths).list_layers().unwrap().is_empty());
     }
 }
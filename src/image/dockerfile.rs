@@ -0,0 +1,933 @@
+//! Dockerfile parser
+//!
+//! Handles parser directives, line continuations, heredocs, and the exec and
+//! shell forms of instructions. Variable substitution depends on the ARGs
+//! and ENVs in scope, so values are kept as written and expanded while
+//! building.
+
+use crate::{DarkerError, Result};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+
+/// Escape character, unless the `escape` parser directive changes it
+pub(crate) const DEFAULT_ESCAPE: char = '\\';
+
+/// Parsed Dockerfile instruction
+#[derive(Debug, Clone)]
+#[allow(dead_code)] // Some fields are parsed but not yet used
+pub(crate) enum Instruction {
+    From {
+        image: String,
+        alias: Option<String>,
+        platform: Option<String>,
+    },
+    Run {
+        command: String,
+    },
+    Copy {
+        srcs: Vec<String>,
+        dst: String,
+        from: Option<String>,
+    },
+    Add {
+        srcs: Vec<String>,
+        dst: String,
+    },
+    Env {
+        vars: Vec<(String, String)>,
+    },
+    Workdir {
+        path: String,
+    },
+    Cmd {
+        command: Vec<String>,
+    },
+    Entrypoint {
+        command: Vec<String>,
+    },
+    Expose {
+        ports: Vec<String>,
+    },
+    User {
+        user: String,
+    },
+    Label {
+        labels: Vec<(String, String)>,
+    },
+    Arg {
+        args: Vec<(String, Option<String>)>,
+    },
+    Volume {
+        paths: Vec<String>,
+    },
+    /// A valid instruction that builds don't support, such as HEALTHCHECK
+    Ignored {
+        name: String,
+    },
+}
+
+impl Instruction {
+    /// Substitute variables into the values that allow it
+    ///
+    /// RUN, CMD and ENTRYPOINT are left to the shell, and ARG names and the
+    /// FROM line are not expanded here.
+    pub(crate) fn expand(
+        self,
+        vars: &HashMap<String, String>,
+        escape: char,
+    ) -> std::result::Result<Self, String> {
+        let word = |word: &str| expand(word, vars, escape);
+        let words = |words: &[String]| {
+            words
+                .iter()
+                .map(|w| word(w))
+                .collect::<std::result::Result<Vec<_>, String>>()
+        };
+        let pairs = |pairs: &[(String, String)]| {
+            pairs
+                .iter()
+                .map(|(key, value)| Ok((word(key)?, word(value)?)))
+                .collect::<std::result::Result<Vec<_>, String>>()
+        };
+
+        Ok(match self {
+            Instruction::Copy { srcs, dst, from } => Instruction::Copy {
+                srcs: words(&srcs)?,
+                dst: word(&dst)?,
+                from: from.as_deref().map(word).transpose()?,
+            },
+            Instruction::Add { srcs, dst } => Instruction::Add {
+                srcs: words(&srcs)?,
+                dst: word(&dst)?,
+            },
+            Instruction::Env { vars } => Instruction::Env {
+                vars: pairs(&vars)?,
+            },
+            Instruction::Workdir { path } => Instruction::Workdir { path: word(&path)? },
+            Instruction::Expose { ports } => Instruction::Expose {
+                ports: words(&ports)?,
+            },
+            Instruction::User { user } => Instruction::User { user: word(&user)? },
+            Instruction::Label { labels } => Instruction::Label {
+                labels: pairs(&labels)?,
+            },
+            Instruction::Arg { args } => Instruction::Arg {
+                args: args
+                    .into_iter()
+                    .map(|(name, default)| Ok((name, default.as_deref().map(word).transpose()?)))
+                    .collect::<std::result::Result<_, String>>()?,
+            },
+            Instruction::Volume { paths } => Instruction::Volume {
+                paths: words(&paths)?,
+            },
+            instruction => instruction,
+        })
+    }
+}
+
+/// An instruction with the line it starts on
+#[derive(Debug, Clone)]
+pub(crate) struct Step {
+    pub(crate) line: usize,
+    pub(crate) instruction: Instruction,
+    /// Valid flags that builds don't support, such as `COPY --chown`
+    pub(crate) ignored_flags: Vec<String>,
+}
+
+/// A parsed Dockerfile
+#[derive(Debug, Clone)]
+pub(crate) struct Dockerfile {
+    /// Escape character of the file, for expanding its values
+    pub(crate) escape: char,
+    pub(crate) steps: Vec<Step>,
+}
+
+/// Error about a line of a Dockerfile
+pub(crate) fn line_error(line: usize, message: impl Display) -> DarkerError {
+    DarkerError::Build(format!("Dockerfile line {}: {}", line, message))
+}
+
+/// A heredoc of an instruction, such as `RUN <<EOF`
+struct Heredoc {
+    /// The word that ends it
+    delimiter: String,
+    /// The `<<-` form, which strips leading tabs
+    strip_tabs: bool,
+    body: String,
+}
+
+/// Parse a Dockerfile
+pub(crate) fn parse_dockerfile(content: &str) -> Result<Dockerfile> {
+    let lines: Vec<&str> = content.trim_start_matches('\u{feff}').lines().collect();
+    let mut escape = DEFAULT_ESCAPE;
+    let mut i = 0;
+
+    // Parser directives must come first, before any comment or blank line
+    let mut directives = HashSet::new();
+    while let Some((name, value)) = lines.get(i).and_then(|line| parse_directive(line)) {
+        if !matches!(name.as_str(), "syntax" | "escape" | "check") {
+            break;
+        }
+        if !directives.insert(name.clone()) {
+            return Err(line_error(
+                i + 1,
+                format!("only one {} parser directive can be used", name),
+            ));
+        }
+        if name == "escape" {
+            escape = match value.as_str() {
+                "\\" => '\\',
+                "`" => '`',
+                _ => {
+                    return Err(line_error(
+                        i + 1,
+                        format!("invalid escape token '{}' does not match ` or \\", value),
+                    ))
+                }
+            };
+        }
+        i += 1;
+    }
+
+    let mut steps = Vec::new();
+    while i < lines.len() {
+        let line = i + 1;
+        let mut current = lines[i];
+        i += 1;
+        if is_blank_or_comment(current) {
+            continue;
+        }
+
+        // Comments and blank lines within a continued instruction are skipped
+        let mut text = String::new();
+        while let Some(stripped) = strip_continuation(current, escape) {
+            text.push_str(stripped);
+            while lines.get(i).is_some_and(|line| is_blank_or_comment(line)) {
+                i += 1;
+            }
+            current = lines.get(i).copied().unwrap_or("");
+            i += 1;
+        }
+        text.push_str(current);
+
+        let text = text.trim();
+        let (name, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let name = name.to_uppercase();
+        let args = args.trim();
+
+        // Heredoc bodies follow the line that starts them
+        let mut heredocs = Vec::new();
+        if matches!(name.as_str(), "RUN" | "COPY" | "ADD") {
+            for (delimiter, strip_tabs) in heredoc_markers(args) {
+                let mut body = String::new();
+                loop {
+                    let Some(next) = lines.get(i) else {
+                        return Err(line_error(
+                            line,
+                            format!("unterminated heredoc {}", delimiter),
+                        ));
+                    };
+                    i += 1;
+                    let next = match strip_tabs {
+                        true => next.trim_start_matches('\t'),
+                        false => next,
+                    };
+                    if next == delimiter {
+                        break;
+                    }
+                    body.push_str(next);
+                    body.push('\n');
+                }
+                heredocs.push(Heredoc {
+                    delimiter,
+                    strip_tabs,
+                    body,
+                });
+            }
+        }
+
+        let mut ignored_flags = Vec::new();
+        let instruction = parse_instruction(&name, args, heredocs, escape, &mut ignored_flags)
+            .map_err(|e| line_error(line, e))?;
+        steps.push(Step {
+            line,
+            instruction,
+            ignored_flags,
+        });
+    }
+
+    Ok(Dockerfile { escape, steps })
+}
+
+/// Parse a parser directive, `# name=value`, with its name lowercased
+fn parse_directive(line: &str) -> Option<(String, String)> {
+    let (name, value) = line.strip_prefix('#')?.split_once('=')?;
+    let name = name.trim();
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric());
+    valid.then(|| (name.to_lowercase(), value.trim().to_string()))
+}
+
+fn is_blank_or_comment(line: &str) -> bool {
+    let line = line.trim_start();
+    line.is_empty() || line.starts_with('#')
+}
+
+/// The line without its escape character, if it continues on the next line
+fn strip_continuation(line: &str, escape: char) -> Option<&str> {
+    line.trim_end().strip_suffix(escape)
+}
+
+/// Delimiters of the heredocs an instruction starts, and whether they strip
+/// leading tabs
+fn heredoc_markers(args: &str) -> Vec<(String, bool)> {
+    args.split_whitespace()
+        .filter_map(|word| {
+            let marker = word
+                .trim_start_matches(|c: char| c.is_ascii_digit())
+                .strip_prefix("<<")?;
+            let (marker, strip_tabs) = match marker.strip_prefix('-') {
+                Some(marker) => (marker, true),
+                None => (marker, false),
+            };
+            let delimiter = marker.trim_matches(|c| c == '"' || c == '\'');
+            let valid = !delimiter.is_empty() && !delimiter.contains('<');
+            valid.then(|| (delimiter.to_string(), strip_tabs))
+        })
+        .collect()
+}
+
+/// Parse an instruction, given its uppercased name and arguments
+///
+/// Flags it has that builds don't support are added to `ignored_flags`.
+fn parse_instruction(
+    name: &str,
+    args: &str,
+    heredocs: Vec<Heredoc>,
+    escape: char,
+    ignored_flags: &mut Vec<String>,
+) -> std::result::Result<Instruction, String> {
+    if !heredocs.is_empty() && name != "RUN" {
+        return Err(format!("heredocs are not supported in {}", name));
+    }
+
+    match name {
+        "FROM" => {
+            let (mut flags, args) = take_flags(name, args, &["platform"], &[], ignored_flags)?;
+            let words: Vec<&str> = args.split_whitespace().collect();
+            let alias = match words.as_slice() {
+                [_] => None,
+                [_, keyword, alias] if keyword.eq_ignore_ascii_case("AS") => {
+                    Some(alias.to_string())
+                }
+                _ => return Err("FROM requires either one or three arguments".to_string()),
+            };
+            Ok(Instruction::From {
+                image: words[0].to_string(),
+                alias,
+                platform: flags.remove("platform"),
+            })
+        }
+        "RUN" => {
+            // RUN's mounts, network and security modes aren't supported
+            let unsupported = ["mount", "network", "security"];
+            let (_, args) = take_flags(name, args, &[], &unsupported, ignored_flags)?;
+            if args.is_empty() {
+                return Err("RUN requires at least one argument".to_string());
+            }
+
+            // A heredoc alone is a script, otherwise the shell reads them
+            let command = match heredocs.as_slice() {
+                [heredoc] if heredoc_markers(args).len() == 1 && !args.contains(' ') => {
+                    heredoc.body.clone()
+                }
+                _ => {
+                    let mut command = args.to_string();
+                    for heredoc in &heredocs {
+                        command.push('\n');
+                        command.push_str(&heredoc.body);
+                        if heredoc.strip_tabs {
+                            command.push('\t');
+                        }
+                        command.push_str(&heredoc.delimiter);
+                    }
+                    command
+                }
+            };
+            Ok(Instruction::Run { command })
+        }
+        "COPY" | "ADD" => {
+            // Only COPY --from is supported, and no ADD flags
+            let (supported, unsupported): (&[&str], &[&str]) = match name {
+                "COPY" => (&["from"], &["chown", "chmod", "link", "parents", "exclude"]),
+                _ => (
+                    &[],
+                    &[
+                        "chown",
+                        "chmod",
+                        "link",
+                        "checksum",
+                        "keep-git-dir",
+                        "exclude",
+                    ],
+                ),
+            };
+            let (mut flags, args) = take_flags(name, args, supported, unsupported, ignored_flags)?;
+            let mut paths = parse_json_array(args)
+                .unwrap_or_else(|| args.split_whitespace().map(String::from).collect());
+            let Some(dst) = paths.pop().filter(|_| !paths.is_empty()) else {
+                return Err(format!(
+                    "{} requires at least two arguments, but only one was provided. \
+                     Destination could not be determined",
+                    name
+                ));
+            };
+            if paths.len() > 1 && !dst.ends_with('/') {
+                return Err(format!(
+                    "When using {} with more than one source file, the destination must be \
+                     a directory and end with a /",
+                    name
+                ));
+            }
+            Ok(match name {
+                "COPY" => Instruction::Copy {
+                    srcs: paths,
+                    dst,
+                    from: flags.remove("from"),
+                },
+                _ => Instruction::Add { srcs: paths, dst },
+            })
+        }
+        "ENV" => Ok(Instruction::Env {
+            vars: parse_name_values(name, args, escape)?,
+        }),
+        "LABEL" => Ok(Instruction::Label {
+            labels: parse_name_values(name, args, escape)?,
+        }),
+        "ARG" => {
+            let words = split_words(args, escape)?;
+            if words.is_empty() {
+                return Err("ARG requires at least one argument".to_string());
+            }
+            let args = words
+                .into_iter()
+                .map(|word| match word.split_once('=') {
+                    Some((name, default)) => (name.to_string(), Some(default.to_string())),
+                    None => (word, None),
+                })
+                .collect();
+            Ok(Instruction::Arg { args })
+        }
+        "WORKDIR" | "USER" => {
+            if args.is_empty() {
+                return Err(format!("{} requires exactly one argument", name));
+            }
+            Ok(match name {
+                "WORKDIR" => Instruction::Workdir {
+                    path: args.to_string(),
+                },
+                _ => Instruction::User {
+                    user: args.to_string(),
+                },
+            })
+        }
+        "CMD" | "ENTRYPOINT" => {
+            if args.is_empty() {
+                return Err(format!("{} requires at least one argument", name));
+            }
+            let command = parse_command_args(args);
+            Ok(match name {
+                "CMD" => Instruction::Cmd { command },
+                _ => Instruction::Entrypoint { command },
+            })
+        }
+        "EXPOSE" => {
+            let ports: Vec<String> = args.split_whitespace().map(String::from).collect();
+            if ports.is_empty() {
+                return Err("EXPOSE requires at least one argument".to_string());
+            }
+            Ok(Instruction::Expose { ports })
+        }
+        "VOLUME" => {
+            let paths = parse_json_array(args)
+                .unwrap_or_else(|| args.split_whitespace().map(String::from).collect());
+            if paths.is_empty() {
+                return Err("VOLUME requires at least one argument".to_string());
+            }
+            Ok(Instruction::Volume { paths })
+        }
+        "HEALTHCHECK" | "MAINTAINER" | "ONBUILD" | "SHELL" | "STOPSIGNAL" => {
+            Ok(Instruction::Ignored {
+                name: name.to_string(),
+            })
+        }
+        _ => Err(format!("unknown instruction: {}", name)),
+    }
+}
+
+/// Take the leading `--name=value` flags of an instruction
+///
+/// Flags without a value, such as `--link`, are given an empty one. Valid
+/// flags that aren't supported are left out and added to `ignored_flags`,
+/// and any other flag is an error.
+fn take_flags<'a>(
+    instruction: &str,
+    mut args: &'a str,
+    supported: &[&str],
+    unsupported: &[&str],
+    ignored_flags: &mut Vec<String>,
+) -> std::result::Result<(HashMap<String, String>, &'a str), String> {
+    let mut flags = HashMap::new();
+    while let Some(flag) = args.strip_prefix("--") {
+        let (flag, rest) = flag.split_once(char::is_whitespace).unwrap_or((flag, ""));
+        let (name, value) = flag.split_once('=').unwrap_or((flag, ""));
+        if supported.contains(&name) {
+            flags.insert(name.to_string(), value.to_string());
+        } else if unsupported.contains(&name) {
+            ignored_flags.push(format!("{} --{}", instruction, name));
+        } else {
+            return Err(format!("unknown flag: {}", name));
+        }
+        args = rest.trim_start();
+    }
+    Ok((flags, args))
+}
+
+/// Parse the exec form of an instruction, a JSON array of strings
+fn parse_json_array(args: &str) -> Option<Vec<String>> {
+    args.starts_with('[')
+        .then(|| serde_json::from_str(args).ok())
+        .flatten()
+}
+
+/// Parse the arguments of CMD, ENTRYPOINT or RUN
+///
+/// The exec form is a JSON array of strings, and anything else is run by the
+/// shell.
+pub(crate) fn parse_command_args(args: &str) -> Vec<String> {
+    let trimmed = args.trim();
+    match parse_json_array(trimmed) {
+        Some(command) => command,
+        None => vec!["/bin/sh".to_string(), "-c".to_string(), trimmed.to_string()],
+    }
+}
+
+/// Parse the `name=value` pairs of ENV or LABEL
+///
+/// The older `ENV name value` form sets one variable to the rest of the line.
+fn parse_name_values(
+    instruction: &str,
+    args: &str,
+    escape: char,
+) -> std::result::Result<Vec<(String, String)>, String> {
+    let words = split_words(args, escape)?;
+    let Some(first) = words.first() else {
+        return Err(format!("{} requires at least one argument", instruction));
+    };
+
+    if !first.contains('=') {
+        return match args.split_once(char::is_whitespace) {
+            Some((name, value)) => Ok(vec![(name.to_string(), value.trim().to_string())]),
+            None => Err(format!("{} must have two arguments", instruction)),
+        };
+    }
+
+    words
+        .iter()
+        .map(|word| match word.split_once('=') {
+            Some(("", _)) => Err(format!("{} names can not be blank", instruction)),
+            Some((name, value)) => Ok((name.to_string(), value.to_string())),
+            None => Err(format!(
+                "Syntax error - can't find = in \"{}\". Must be of the form: name=value",
+                word
+            )),
+        })
+        .collect()
+}
+
+/// Split arguments into words at whitespace outside quotes
+///
+/// Quotes and escapes are kept, to be processed when the word is expanded.
+fn split_words(args: &str, escape: char) -> std::result::Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote = None;
+    let mut chars = args.chars();
+
+    while let Some(c) = chars.next() {
+        match quote {
+            None if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+                continue;
+            }
+            None if c == '"' || c == '\'' => quote = Some(c),
+            Some(q) if c == q => quote = None,
+            _ => {}
+        }
+        word.push(c);
+        if c == escape && quote != Some('\'') {
+            word.extend(chars.next());
+        }
+    }
+
+    if let Some(quote) = quote {
+        return Err(unterminated(quote));
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+fn unterminated(quote: char) -> String {
+    let name = match quote {
+        '\'' => "single-quote",
+        _ => "double-quote",
+    };
+    format!(
+        "unexpected end of statement while looking for matching {}",
+        name
+    )
+}
+
+/// Expand a word as the shell would, removing its quotes and escapes and
+/// substituting variables
+///
+/// Supports `$VAR`, `${VAR}`, and `${VAR:-default}`, `${VAR:+alternative}`
+/// and `${VAR:?error}`, along with their forms without the colon, which only
+/// check whether the variable is set rather than also whether it is empty.
+/// Nothing is substituted within single quotes.
+pub(crate) fn expand(
+    word: &str,
+    vars: &HashMap<String, String>,
+    escape: char,
+) -> std::result::Result<String, String> {
+    let mut lexer = Lexer {
+        chars: word.chars().collect(),
+        pos: 0,
+        escape,
+        vars,
+    };
+    let expanded = lexer.process(false)?;
+    Ok(expanded)
+}
+
+/// State of the expansion of a word
+struct Lexer<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    escape: char,
+    vars: &'a HashMap<String, String>,
+}
+
+impl Lexer<'_> {
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.get(self.pos).copied();
+        self.pos += 1;
+        c
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    /// Process the word, up to the `}` closing a substitution if `in_braces`
+    fn process(&mut self, in_braces: bool) -> std::result::Result<String, String> {
+        let mut out = String::new();
+        while let Some(c) = self.peek() {
+            if in_braces && c == '}' {
+                break;
+            }
+            self.pos += 1;
+            match c {
+                '\'' => loop {
+                    match self.next() {
+                        Some('\'') => break,
+                        Some(c) => out.push(c),
+                        None => return Err(unterminated('\'')),
+                    }
+                },
+                '"' => loop {
+                    match self.next() {
+                        Some('"') => break,
+                        Some('$') => out.push_str(&self.substitute()?),
+                        Some(c) if c == self.escape => match self.peek() {
+                            Some(next) if next == '"' || next == '$' || next == self.escape => {
+                                self.pos += 1;
+                                out.push(next);
+                            }
+                            _ => out.push(c),
+                        },
+                        Some(c) => out.push(c),
+                        None => return Err(unterminated('"')),
+                    }
+                },
+                '$' => out.push_str(&self.substitute()?),
+                c if c == self.escape => out.push(self.next().unwrap_or(c)),
+                c => out.push(c),
+            }
+        }
+        Ok(out)
+    }
+
+    /// Substitute the variable after a `$`
+    fn substitute(&mut self) -> std::result::Result<String, String> {
+        let braced = self.peek() == Some('{');
+        if braced {
+            self.pos += 1;
+        }
+        let mut name = String::new();
+        while let Some(c) = self
+            .peek()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+        {
+            name.push(c);
+            self.pos += 1;
+        }
+
+        if !braced {
+            return Ok(match name.is_empty() {
+                true => "$".to_string(),
+                false => self.vars.get(&name).cloned().unwrap_or_default(),
+            });
+        }
+        let value = self.vars.get(&name);
+        let bad = || format!("bad substitution in ${{{}}}", name);
+        if name.is_empty() {
+            return Err(bad());
+        }
+
+        let colon = self.peek() == Some(':');
+        if colon {
+            self.pos += 1;
+        }
+        let op = match self.next() {
+            Some('}') if !colon => return Ok(value.cloned().unwrap_or_default()),
+            Some(op @ ('-' | '+' | '?')) => op,
+            _ => return Err(bad()),
+        };
+        let word = self.process(true)?;
+        if self.next() != Some('}') {
+            return Err(bad());
+        }
+
+        let set = value.filter(|value| !(colon && value.is_empty()));
+        match (op, set) {
+            ('-', Some(value)) | ('?', Some(value)) => Ok(value.clone()),
+            ('-', None) | ('+', Some(_)) => Ok(word),
+            ('+', None) => Ok(String::new()),
+            _ => Err(match word.is_empty() {
+                true => format!("{}: parameter not set", name),
+                false => format!("{}: {}", name, word),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instructions(content: &str) -> Vec<Instruction> {
+        parse_dockerfile(content)
+            .unwrap()
+            .steps
+            .into_iter()
+            .map(|step| step.instruction)
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_dockerfile() {
+        let content = r#"
+FROM alpine:3.18
+RUN apk add --no-cache curl
+COPY . /app
+WORKDIR /app
+CMD ["./start.sh"]
+"#;
+
+        let dockerfile = parse_dockerfile(content).unwrap();
+        assert_eq!(dockerfile.escape, '\\');
+        let lines: Vec<usize> = dockerfile.steps.iter().map(|step| step.line).collect();
+        assert_eq!(lines, vec![2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn test_parse_command_args() {
+        let json_args = r#"["./app", "--config", "prod"]"#;
+        let parsed = parse_command_args(json_args);
+        assert_eq!(parsed, vec!["./app", "--config", "prod"]);
+
+        let shell_args = "echo hello world";
+        let parsed = parse_command_args(shell_args);
+        assert_eq!(parsed, vec!["/bin/sh", "-c", "echo hello world"]);
+
+        // Commas and escapes within strings are kept
+        let parsed = parse_command_args(r#"["echo", "a, b", "say \"hi\""]"#);
+        assert_eq!(parsed, vec!["echo", "a, b", "say \"hi\""]);
+
+        // Arrays that aren't valid JSON are run by the shell
+        let parsed = parse_command_args("[ -f /app ]");
+        assert_eq!(parsed, vec!["/bin/sh", "-c", "[ -f /app ]"]);
+    }
+
+    #[test]
+    fn test_parse_from_platform() {
+        let instructions = instructions("FROM --platform=linux/arm64 alpine:3.18 AS base\n");
+        match &instructions[0] {
+            Instruction::From {
+                image,
+                alias,
+                platform,
+            } => {
+                assert_eq!(image, "alpine:3.18");
+                assert_eq!(alias.as_deref(), Some("base"));
+                assert_eq!(platform.as_deref(), Some("linux/arm64"));
+            }
+            other => panic!("unexpected instruction {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_directives_and_continuations() {
+        let content = "# syntax=docker/dockerfile:1\n# escape=`\n\nFROM scratch\n\
+                       RUN echo one `\n# a comment\n\n    two\nWORKDIR C:\\app\n";
+        let dockerfile = parse_dockerfile(content).unwrap();
+        assert_eq!(dockerfile.escape, '`');
+        match &dockerfile.steps[1].instruction {
+            Instruction::Run { command } => assert_eq!(command, "echo one     two"),
+            other => panic!("unexpected instruction {:?}", other),
+        }
+        assert_eq!(dockerfile.steps[2].line, 9);
+
+        // Directives after the first comment are plain comments
+        let dockerfile = parse_dockerfile("# hello\n# escape=`\nFROM scratch\n").unwrap();
+        assert_eq!(dockerfile.escape, '\\');
+
+        assert!(parse_dockerfile("# escape=x\nFROM scratch\n").is_err());
+        assert!(parse_dockerfile("# escape=`\n# escape=\\\nFROM scratch\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_heredocs() {
+        let content = "FROM scratch\nRUN <<EOF\nset -e\necho hi\nEOF\n\
+                       RUN cat <<-\"A\" > /a.txt\n\tindented\n\tA\nCMD [\"/app\"]\n";
+        let instructions = instructions(content);
+        match (&instructions[1], &instructions[2]) {
+            (Instruction::Run { command: script }, Instruction::Run { command }) => {
+                assert_eq!(script, "set -e\necho hi\n");
+                assert_eq!(command, "cat <<-\"A\" > /a.txt\nindented\n\tA");
+            }
+            other => panic!("unexpected instructions {:?}", other),
+        }
+        assert!(matches!(instructions[3], Instruction::Cmd { .. }));
+
+        let err = parse_dockerfile("FROM scratch\nRUN <<EOF\necho hi\n").unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+    }
+
+    #[test]
+    fn test_parse_name_values() {
+        let instructions = instructions(
+            "ENV A=1 B=\"two words\" C='$A' D=a\\ b\nENV OLD legacy value\n\
+             LABEL version=\"1.0\" \"com.example.vendor\"=ACME\nARG X Y=2\n\
+             COPY [\"a b.txt\", \"c.txt\", \"/dst/\"]\nVOLUME [\"/data\", \"/logs\"]\n",
+        );
+        let vars = HashMap::new();
+        let expanded: Vec<String> = instructions
+            .into_iter()
+            .map(|instruction| format!("{:?}", instruction.expand(&vars, '\\').unwrap()))
+            .collect();
+
+        assert_eq!(
+            expanded,
+            vec![
+                r#"Env { vars: [("A", "1"), ("B", "two words"), ("C", "$A"), ("D", "a b")] }"#,
+                r#"Env { vars: [("OLD", "legacy value")] }"#,
+                r#"Label { labels: [("version", "1.0"), ("com.example.vendor", "ACME")] }"#,
+                r#"Arg { args: [("X", None), ("Y", Some("2"))] }"#,
+                r#"Copy { srcs: ["a b.txt", "c.txt"], dst: "/dst/", from: None }"#,
+                r#"Volume { paths: ["/data", "/logs"] }"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand() {
+        let vars = HashMap::from([
+            ("NAME".to_string(), "app".to_string()),
+            ("EMPTY".to_string(), String::new()),
+        ]);
+        let expand = |word: &str| expand(word, &vars, '\\');
+
+        assert_eq!(
+            expand("/srv/$NAME/${NAME}.conf").unwrap(),
+            "/srv/app/app.conf"
+        );
+        assert_eq!(expand("${MISSING:-default}").unwrap(), "default");
+        assert_eq!(expand("${EMPTY:-default}").unwrap(), "default");
+        assert_eq!(expand("${EMPTY-default}").unwrap(), "");
+        assert_eq!(expand("${NAME:+set}").unwrap(), "set");
+        assert_eq!(expand("${EMPTY:+set}").unwrap(), "");
+        assert_eq!(expand("${EMPTY+set}").unwrap(), "set");
+        assert_eq!(expand("${MISSING:-${NAME}-x}").unwrap(), "app-x");
+        assert_eq!(
+            expand("'$NAME' \"$NAME\" \\$NAME").unwrap(),
+            "$NAME app $NAME"
+        );
+        assert_eq!(expand("cost: $5 $").unwrap(), "cost:  $");
+        assert_eq!(
+            expand("${MISSING:?must be set}").unwrap_err(),
+            "MISSING: must be set"
+        );
+        assert!(expand("${NAME").is_err());
+        assert!(expand("${NAME%%.*}").is_err());
+        assert!(expand("\"open").is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |content: &str| parse_dockerfile(content).unwrap_err().to_string();
+
+        assert!(
+            error("FROM scratch\n\nFORM alpine\n").contains("line 3: unknown instruction: FORM")
+        );
+        assert!(error("FROM scratch\nCOPY app\n").contains("line 2"));
+        assert!(error("FROM scratch\nCOPY a b /dst\n").contains("must be a directory"));
+        assert!(error("FROM scratch\nCOPY --own=1 a /a\n").contains("unknown flag: own"));
+        assert!(error("FROM alpine AS\n").contains("one or three arguments"));
+        assert!(error("FROM scratch\nENV A=1 B\n").contains("can't find ="));
+        assert!(error("FROM scratch\nENV NAME\n").contains("two arguments"));
+        assert!(error("FROM scratch\nLABEL a=\"b\n").contains("double-quote"));
+        assert!(error("FROM scratch\nCOPY <<EOF /a\nhi\nEOF\n").contains("heredocs"));
+
+        let instructions = instructions("FROM scratch\nHEALTHCHECK NONE\n");
+        assert!(matches!(&instructions[1], Instruction::Ignored { name } if name == "HEALTHCHECK"));
+
+        // Valid flags that aren't supported are kept for a warning
+        let dockerfile = parse_dockerfile(
+            "FROM scratch\nRUN --network=none --mount=type=cache,target=/c make\n\
+             COPY --chown=app:app --from=build /out /app\nADD --checksum=sha256:0 a /a\n",
+        )
+        .unwrap();
+        let ignored: Vec<&[String]> = dockerfile
+            .steps
+            .iter()
+            .map(|step| step.ignored_flags.as_slice())
+            .collect();
+        assert_eq!(
+            ignored,
+            [
+                &[][..],
+                &["RUN --network".to_string(), "RUN --mount".to_string()],
+                &["COPY --chown".to_string()],
+                &["ADD --checksum".to_string()],
+            ]
+        );
+        assert!(error("FROM scratch\nADD --from=build a /a\n").contains("unknown flag: from"));
+        assert!(error("FROM --chown=a scratch\n").contains("unknown flag: chown"));
+    }
+}
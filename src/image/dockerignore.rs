@@ -0,0 +1,264 @@
+//! `.dockerignore` files, excluding paths from the build context
+
+use crate::Result;
+use std::fs;
+use std::path::{Component, Path};
+
+/// A pattern of a `.dockerignore` file
+#[derive(Debug, Clone)]
+struct Pattern {
+    /// Path components of the pattern, where `**` matches any number of them
+    components: Vec<String>,
+    /// Whether the pattern re-includes paths, written with a leading `!`
+    exception: bool,
+}
+
+/// Patterns of a `.dockerignore` file
+///
+/// Patterns are matched against paths relative to the context, with the
+/// last matching pattern deciding whether a path is excluded. A pattern also
+/// excludes everything below the directories it matches.
+#[derive(Debug, Clone, Default)]
+pub struct DockerIgnore {
+    patterns: Vec<Pattern>,
+}
+
+impl DockerIgnore {
+    /// Load the ignore file of a build
+    ///
+    /// `<Dockerfile>.dockerignore` next to the Dockerfile takes precedence
+    /// over `.dockerignore` in the context. With neither, nothing is excluded.
+    pub fn load(context_path: &Path, dockerfile: &Path) -> Result<Self> {
+        let mut name = dockerfile.as_os_str().to_owned();
+        name.push(".dockerignore");
+        for path in [Path::new(&name), &context_path.join(".dockerignore")] {
+            if path.is_file() {
+                return Ok(Self::parse(&fs::read_to_string(path)?));
+            }
+        }
+        Ok(Self::default())
+    }
+
+    /// Parse the contents of an ignore file
+    pub fn parse(content: &str) -> Self {
+        let mut patterns = Vec::new();
+        for line in content.trim_start_matches('\u{feff}').lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+            let mut line = line.trim();
+            let exception = line.starts_with('!');
+            if exception {
+                line = line[1..].trim();
+            }
+
+            // Patterns are cleaned paths, and a leading / is the context root
+            let mut components: Vec<String> = Vec::new();
+            for component in line.split('/') {
+                match component {
+                    "" | "." => {}
+                    ".." => {
+                        components.pop();
+                    }
+                    _ => components.push(component.to_string()),
+                }
+            }
+            if !components.is_empty() {
+                patterns.push(Pattern {
+                    components,
+                    exception,
+                });
+            }
+        }
+        Self { patterns }
+    }
+
+    /// Whether a path relative to the context is excluded
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        let names = names(path);
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        if names.is_empty() {
+            return false;
+        }
+
+        let mut excluded = false;
+        for pattern in &self.patterns {
+            // Only patterns that can change the outcome are matched
+            if pattern.exception != excluded {
+                continue;
+            }
+            let matched = (1..=names.len())
+                .rev()
+                .any(|len| match_components(&pattern.components, &names[..len]));
+            if matched {
+                excluded = !pattern.exception;
+            }
+        }
+        excluded
+    }
+
+    /// Whether a path and everything below it are excluded
+    ///
+    /// Excluded directories still have to be walked if an exception could
+    /// re-include something in them.
+    pub fn excludes_all(&self, path: &Path, is_dir: bool) -> bool {
+        if !self.is_excluded(path) {
+            return false;
+        }
+        let names = names(path);
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        !(is_dir
+            && self
+                .patterns
+                .iter()
+                .any(|pattern| pattern.exception && match_below(&pattern.components, &names)))
+    }
+}
+
+/// Names of the components of a relative path
+fn names(path: &Path) -> Vec<String> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether pattern components could match paths below a directory
+fn match_below(pattern: &[String], names: &[&str]) -> bool {
+    match (pattern.split_first(), names.split_first()) {
+        (_, None) => true,
+        (None, Some(_)) => false,
+        (Some((first, _)), Some(_)) if first == "**" => true,
+        (Some((first, rest)), Some((name, names))) => {
+            let pattern: Vec<char> = first.chars().collect();
+            let name: Vec<char> = name.chars().collect();
+            match_name(&pattern, &name) && match_below(rest, names)
+        }
+    }
+}
+
+/// Match path components against pattern components
+fn match_components(pattern: &[String], names: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => names.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=names.len()).any(|skip| match_components(rest, &names[skip..]))
+        }
+        Some((first, rest)) => names.split_first().is_some_and(|(name, names)| {
+            let pattern: Vec<char> = first.chars().collect();
+            let name: Vec<char> = name.chars().collect();
+            match_name(&pattern, &name) && match_components(rest, names)
+        }),
+    }
+}
+
+/// Match a file name against a pattern with `*`, `?`, `[...]` and `\` escapes
+fn match_name(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| match_name(&pattern[1..], &name[skip..])),
+        Some('?') => !name.is_empty() && match_name(&pattern[1..], &name[1..]),
+        Some('[') => match (name.first(), match_class(&pattern[1..])) {
+            (Some(c), Some((class, rest))) => class(*c) && match_name(rest, &name[1..]),
+            // An unterminated class is matched literally
+            (Some('['), None) => match_name(&pattern[1..], &name[1..]),
+            _ => false,
+        },
+        Some('\\') if pattern.len() > 1 => {
+            name.first() == Some(&pattern[1]) && match_name(&pattern[2..], &name[1..])
+        }
+        Some(c) => name.first() == Some(c) && match_name(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Parse a character class after its `[`, returning a matcher for it and the
+/// rest of the pattern
+fn match_class(pattern: &[char]) -> Option<(impl Fn(char) -> bool, &[char])> {
+    let (negated, start) = match pattern.first() {
+        Some('^' | '!') => (true, 1),
+        _ => (false, 0),
+    };
+
+    let mut ranges = Vec::new();
+    let mut i = start;
+    loop {
+        let mut low = *pattern.get(i)?;
+        if low == ']' && i > start {
+            break;
+        }
+        if low == '\\' {
+            i += 1;
+            low = *pattern.get(i)?;
+        }
+        let mut high = low;
+        if pattern.get(i + 1) == Some(&'-') && pattern.get(i + 2).is_some_and(|c| *c != ']') {
+            i += 2;
+            high = pattern[i];
+            if high == '\\' {
+                i += 1;
+                high = *pattern.get(i)?;
+            }
+        }
+        ranges.push((low, high));
+        i += 1;
+    }
+
+    let class =
+        move |c: char| ranges.iter().any(|(low, high)| (*low..=*high).contains(&c)) != negated;
+    Some((class, &pattern[i + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_dockerignore() {
+        let ignore = DockerIgnore::parse(
+            "# build output\n/target\n.git\n**/node_modules\n*.log\n!keep.log\ndocs/*.md\n\
+             !docs/README.md\ntmp?/\n./cache/../secrets\nfile[0-9].txt\n",
+        );
+        let excluded = |path: &str| ignore.is_excluded(Path::new(path));
+
+        assert!(excluded("target") && excluded("target/debug/darker"));
+        assert!(excluded(".git/HEAD"));
+        assert!(excluded("node_modules") && excluded("web/app/node_modules/x/index.js"));
+        assert!(excluded("build.log") && !excluded("keep.log"));
+        assert!(!excluded("logs/build.log"));
+        assert!(excluded("docs/guide.md") && !excluded("docs/README.md"));
+        assert!(!excluded("docs/api/guide.md"));
+        assert!(excluded("tmp1/file") && !excluded("tmp12/file"));
+        assert!(excluded("secrets/key") && !excluded("cache/secrets"));
+        assert!(excluded("file3.txt") && !excluded("fileA.txt"));
+        assert!(!excluded("src/main.rs") && !excluded(""));
+
+        // The leading / only anchors to the context, as every pattern is
+        assert!(!excluded("src/target"));
+
+        assert!(ignore.excludes_all(Path::new("target"), true));
+        assert!(!ignore.excludes_all(Path::new("docs"), true));
+        assert!(!DockerIgnore::parse("docs\n").excludes_all(Path::new("src"), true));
+    }
+
+    #[test]
+    fn test_load_dockerignore() {
+        let tmp = TempDir::new().unwrap();
+        let dockerfile = tmp.path().join("Dockerfile.prod");
+        assert!(!DockerIgnore::load(tmp.path(), &dockerfile)
+            .unwrap()
+            .is_excluded(Path::new("a.txt")));
+
+        fs::write(tmp.path().join(".dockerignore"), "*.txt\n").unwrap();
+        let ignore = DockerIgnore::load(tmp.path(), &dockerfile).unwrap();
+        assert!(ignore.is_excluded(Path::new("a.txt")));
+
+        // An ignore file for the Dockerfile replaces the context's
+        fs::write(tmp.path().join("Dockerfile.prod.dockerignore"), "*.md\n").unwrap();
+        let ignore = DockerIgnore::load(tmp.path(), &dockerfile).unwrap();
+        assert!(!ignore.is_excluded(Path::new("a.txt")));
+        assert!(ignore.is_excluded(Path::new("README.md")));
+    }
+}
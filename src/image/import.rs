@@ -0,0 +1,144 @@
+//! Images created from a tarball of a root filesystem
+
+use crate::image::archive::{decompress, hash_stream};
+use crate::image::build::apply_change;
+use crate::image::layer::LayerManager;
+use crate::image::oci::{ImageConfigSpec, ImageReference, OciImageConfig, Platform, RootFs};
+use crate::image::registry::{get_host_arch, get_host_os};
+use crate::storage::blobs::BlobStore;
+use crate::storage::images::ImageStore;
+use crate::storage::paths::DarkerPaths;
+use crate::{DarkerError, Result};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::Read;
+
+/// Create a single-layer image from a root filesystem tarball
+///
+/// The tarball may be gzip or zstd compressed. `changes` are Dockerfile
+/// instructions applied to the image config, as with `docker import
+/// --change`. Returns the ID of the new image.
+pub fn import_rootfs<R: Read>(
+    paths: &DarkerPaths,
+    reader: R,
+    reference: Option<&ImageReference>,
+    changes: &[String],
+    platform: Option<&Platform>,
+) -> Result<String> {
+    let mut spec = ImageConfigSpec::default();
+    for change in changes {
+        apply_change(&mut spec, change)?;
+    }
+
+    // Store the uncompressed tar, checking that it is one
+    let tmp_path = paths
+        .tmp_dir()
+        .join(format!("{}.tar", uuid::Uuid::new_v4()));
+    std::io::copy(&mut decompress(reader)?, &mut File::create(&tmp_path)?)?;
+    let valid = tar::Archive::new(File::open(&tmp_path)?)
+        .entries()
+        .and_then(|mut entries| entries.try_for_each(|entry| entry.map(|_| ())));
+    if let Err(e) = valid {
+        fs::remove_file(&tmp_path)?;
+        return Err(DarkerError::Archive(format!("Not a tar archive: {}", e)));
+    }
+
+    let (diff_id, size) = hash_stream(File::open(&tmp_path)?)?;
+    let layer = diff_id.trim_start_matches("sha256:").to_string();
+    let layer_manager = LayerManager::new(paths);
+    if layer_manager.exists(&layer) {
+        fs::remove_file(&tmp_path)?;
+    } else {
+        fs::create_dir_all(paths.layer_dir(&layer))?;
+        fs::rename(&tmp_path, layer_manager.layer_tar_path(&layer))?;
+    }
+
+    let platform = platform
+        .cloned()
+        .unwrap_or_else(|| Platform::new(get_host_os(), get_host_arch(), None));
+    let config = OciImageConfig {
+        architecture: platform.architecture.clone(),
+        os: platform.os.clone(),
+        variant: platform.variant.clone(),
+        config: Some(spec),
+        rootfs: RootFs {
+            fs_type: "layers".to_string(),
+            diff_ids: vec![diff_id],
+        },
+        history: None,
+    };
+    let config_bytes = serde_json::to_vec(&config)?;
+    let config_digest = format!("sha256:{:x}", Sha256::digest(&config_bytes));
+    let image_id = config_digest.trim_start_matches("sha256:").to_string();
+
+    let image_store = ImageStore::new(paths)?;
+    image_store.store(
+        &image_id,
+        reference.map(|r| r.repository_with_registry()).as_deref(),
+        reference.map(|r| r.tag()),
+        Some(&config_digest),
+        &[layer],
+        size,
+    )?;
+    image_store.set_platform(&image_id, &platform)?;
+    BlobStore::new(paths).write(&config_bytes)?;
+
+    Ok(image_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_import_rootfs() {
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "bin/app", b"hello".as_slice())
+            .unwrap();
+        let tar = builder.into_inner().unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        std::io::Write::write_all(&mut encoder, &tar).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let reference = ImageReference::parse("example/rootfs:v1").unwrap();
+        let changes = vec![
+            r#"CMD ["/bin/app"]"#.to_string(),
+            "ENV MODE=prod".to_string(),
+        ];
+        let platform = Platform::new("linux", "arm64", None);
+        let image_id = import_rootfs(
+            &paths,
+            gzipped.as_slice(),
+            Some(&reference),
+            &changes,
+            Some(&platform),
+        )
+        .unwrap();
+
+        let image_store = ImageStore::new(&paths).unwrap();
+        assert_eq!(image_store.find_image(&reference), Some(image_id.clone()));
+        let metadata = image_store.load_metadata(&image_id).unwrap();
+        assert_eq!(metadata.platform.as_deref(), Some("linux/arm64"));
+        assert_eq!(metadata.layers.len(), 1);
+        assert_eq!(fs::read(paths.layer_tar(&metadata.layers[0])).unwrap(), tar);
+
+        let config = image_store.load_config(&image_id).unwrap();
+        assert_eq!(config.cmd(), Some(vec!["/bin/app".to_string()]));
+        assert_eq!(config.env(), Some(vec!["MODE=prod".to_string()]));
+
+        // Invalid input leaves nothing behind
+        let err = import_rootfs(&paths, b"not a tar".as_slice(), None, &[], None);
+        assert!(err.is_err());
+        assert_eq!(fs::read_dir(paths.tmp_dir()).unwrap().count(), 0);
+    }
+}
@@ -1,12 +1,108 @@
 //! Layer management for OCI images
 
+use crate::image::oci::media_types;
+use crate::storage::blobs::BlobStore;
+use crate::storage::build_cache::BuildCache;
+use crate::storage::containers::ContainerStore;
+use crate::storage::images::ImageStore;
 use crate::storage::paths::DarkerPaths;
-use crate::Result;
+use crate::{DarkerError, Result, GZIP_MAGIC, ZSTD_MAGIC};
 use sha2::{Digest, Sha256};
+use std::collections::HashSet;
 use std::fs::{self, File};
-use std::io::{Read, Write};
+use std::io::{BufWriter, Read, Write};
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 
+/// Size of the chunks created layers are compressed in
+///
+/// Layers bigger than one chunk have their chunks compressed in parallel.
+const COMPRESSION_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Gzip header of created layers, without a timestamp or file name
+const GZIP_HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+
+/// Compression of a layer blob, as given by its media type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Get the compression of a layer from its media type
+    pub fn from_media_type(media_type: &str) -> Result<Self> {
+        match media_type {
+            media_types::OCI_LAYER_TAR
+            | media_types::OCI_NONDISTRIBUTABLE_LAYER_TAR
+            | media_types::DOCKER_LAYER_TAR => Ok(Self::None),
+            media_types::OCI_LAYER_TAR_GZIP
+            | media_types::OCI_NONDISTRIBUTABLE_LAYER_TAR_GZIP
+            | media_types::DOCKER_LAYER_TAR_GZIP
+            | media_types::DOCKER_FOREIGN_LAYER_TAR_GZIP => Ok(Self::Gzip),
+            media_types::OCI_LAYER_TAR_ZSTD | media_types::OCI_NONDISTRIBUTABLE_LAYER_TAR_ZSTD => {
+                Ok(Self::Zstd)
+            }
+            other => Err(DarkerError::Layer(format!(
+                "Unsupported layer media type: {}",
+                other
+            ))),
+        }
+    }
+
+    /// Wrap a reader of a compressed layer in a decoder for its tar stream
+    pub fn decoder<'a, R: Read + 'a>(self, reader: R) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Self::None => Box::new(reader),
+            Self::Gzip => Box::new(flate2::read::GzDecoder::new(reader)),
+            Self::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+        })
+    }
+
+    /// Get the OCI media type of a layer with this compression
+    pub fn media_type(self) -> &'static str {
+        match self {
+            Self::None => media_types::OCI_LAYER_TAR,
+            Self::Gzip => media_types::OCI_LAYER_TAR_GZIP,
+            Self::Zstd => media_types::OCI_LAYER_TAR_ZSTD,
+        }
+    }
+
+    /// Detect the compression of a layer file from its magic bytes
+    pub fn detect(path: &Path) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        let mut file = File::open(path)?;
+        let mut read = 0;
+        while read < magic.len() {
+            match file.read(&mut magic[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        Ok(if magic[..read].starts_with(&GZIP_MAGIC) {
+            Self::Gzip
+        } else if magic[..read].starts_with(&ZSTD_MAGIC) {
+            Self::Zstd
+        } else {
+            Self::None
+        })
+    }
+}
+
+/// A layer created from a directory
+#[derive(Debug, Clone)]
+pub struct CreatedLayer {
+    /// Digest of the uncompressed tar, which the layer is stored under
+    pub diff_id: String,
+    /// Digest of the compressed blob
+    pub digest: String,
+    /// Size of the compressed blob
+    pub size: u64,
+    /// Compression of the blob
+    pub compression: Compression,
+}
+
 /// Layer manager for handling OCI image layers
 pub struct LayerManager {
     paths: DarkerPaths,
@@ -36,7 +132,7 @@ impl LayerManager {
         self.paths.layer_extracted(digest)
     }
 
-    /// Store a layer from a reader
+    /// Store an uncompressed layer tar from a reader
     pub async fn store_layer<R: Read>(&self, digest: &str, mut reader: R) -> Result<()> {
         let layer_dir = self.paths.layer_dir(digest);
         fs::create_dir_all(&layer_dir)?;
@@ -56,7 +152,7 @@ impl LayerManager {
         Ok(())
     }
 
-    /// Store a layer from bytes
+    /// Store an uncompressed layer tar from bytes
     pub fn store_layer_bytes(&self, digest: &str, data: &[u8]) -> Result<()> {
         let layer_dir = self.paths.layer_dir(digest);
         fs::create_dir_all(&layer_dir)?;
@@ -67,7 +163,10 @@ impl LayerManager {
         Ok(())
     }
 
-    /// Extract a layer
+    /// Extract a stored layer
+    ///
+    /// Layers are decompressed according to their media type when they are
+    /// pulled, so the stored tar is always uncompressed.
     pub fn extract_layer(&self, digest: &str) -> Result<PathBuf> {
         let tar_path = self.paths.layer_tar(digest);
         let extracted_path = self.paths.layer_extracted(digest);
@@ -78,27 +177,9 @@ impl LayerManager {
 
         fs::create_dir_all(&extracted_path)?;
 
-        // Open the tar file
         let file = File::open(&tar_path)?;
-
-        // Check if it's gzipped
-        let mut reader = std::io::BufReader::new(file);
-        let mut magic = [0u8; 2];
-        reader.read_exact(&mut magic)?;
-
-        // Reopen the file
-        let file = File::open(&tar_path)?;
-
-        if magic == [0x1f, 0x8b] {
-            // Gzipped
-            let decoder = flate2::read::GzDecoder::new(file);
-            let mut archive = tar::Archive::new(decoder);
-            archive.unpack(&extracted_path)?;
-        } else {
-            // Plain tar
-            let mut archive = tar::Archive::new(file);
-            archive.unpack(&extracted_path)?;
-        }
+        let mut archive = tar::Archive::new(file);
+        archive.unpack(&extracted_path)?;
 
         Ok(extracted_path)
     }
@@ -112,6 +193,58 @@ impl LayerManager {
         Ok(())
     }
 
+    /// Layers referenced by any image, container or cached build step
+    ///
+    /// Fails if an image's metadata can't be read, rather than treating its
+    /// layers as unused.
+    pub fn referenced(&self) -> Result<HashSet<String>> {
+        let mut referenced = HashSet::new();
+
+        let images_dir = self.paths.images_dir();
+        if images_dir.exists() {
+            let image_store = ImageStore::new(&self.paths)?;
+            for entry in fs::read_dir(images_dir)? {
+                let entry = entry?;
+                let image_id = entry.file_name().to_string_lossy().to_string();
+                if !entry.file_type()?.is_dir() || !self.paths.image_metadata(&image_id).exists()
+                {
+                    continue;
+                }
+                referenced.extend(image_store.load_metadata(&image_id)?.layers);
+            }
+        }
+
+        for container in ContainerStore::new(&self.paths)?.list()? {
+            referenced.extend(container.layers);
+        }
+        referenced.extend(BuildCache::new(&self.paths).layers()?);
+
+        Ok(referenced)
+    }
+
+    /// Remove the given layers that nothing references
+    ///
+    /// Returns the number of bytes reclaimed.
+    pub fn remove_unreferenced(&self, layers: &[String]) -> Result<u64> {
+        let referenced = self.referenced()?;
+        let mut reclaimed = 0;
+        for layer in layers {
+            if !referenced.contains(layer) && self.exists(layer) {
+                reclaimed += dir_size(&self.paths.layer_dir(layer));
+                self.remove_layer(layer)?;
+            }
+        }
+        Ok(reclaimed)
+    }
+
+    /// Remove every layer that nothing references
+    ///
+    /// Returns the number of bytes reclaimed.
+    pub fn prune(&self) -> Result<u64> {
+        let layers = self.list_layers()?;
+        self.remove_unreferenced(&layers)
+    }
+
     /// Compute the SHA256 digest of a file
     pub fn compute_digest(path: &Path) -> Result<String> {
         let mut file = File::open(path)?;
@@ -137,30 +270,71 @@ impl LayerManager {
     }
 
     /// Create a layer from a directory
-    pub fn create_layer_from_dir(&self, dir: &Path) -> Result<(String, PathBuf)> {
-        // Create a temporary tar file
+    ///
+    /// The tar is reproducible: entries are sorted, owned by root and have
+    /// their mtimes clamped to `SOURCE_DATE_EPOCH` if it is set. It is
+    /// stored under its diff ID, and compressed into the blob store unless
+    /// `compression` is none, in which case the blob is the tar itself.
+    pub fn create_layer_from_dir(
+        &self,
+        dir: &Path,
+        compression: Compression,
+    ) -> Result<CreatedLayer> {
+        let epoch = source_date_epoch()?;
         let tmp_dir = self.paths.tmp_dir();
         fs::create_dir_all(&tmp_dir)?;
-        let tmp_tar = tmp_dir.join(format!("layer_{}.tar", uuid::Uuid::new_v4()));
-
-        // Create tar archive
-        let file = File::create(&tmp_tar)?;
-        let mut builder = tar::Builder::new(file);
-        builder.append_dir_all(".", dir)?;
-        builder.finish()?;
 
-        // Compute digest
-        let digest = Self::compute_digest(&tmp_tar)?;
-        let digest_short = digest.strip_prefix("sha256:").unwrap_or(&digest);
+        let tmp_tar = tmp_dir.join(format!("layer_{}.tar", uuid::Uuid::new_v4()));
+        let diff_id = File::create(&tmp_tar)
+            .map_err(Into::into)
+            .and_then(|file| write_layer_tar(dir, BufWriter::new(file), epoch))
+            .and_then(|_| Self::compute_digest(&tmp_tar));
+        let diff_id = match diff_id {
+            Ok(diff_id) => diff_id,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_tar);
+                return Err(e);
+            }
+        };
 
-        // Move to layer storage
-        let layer_dir = self.paths.layer_dir(digest_short);
-        fs::create_dir_all(&layer_dir)?;
+        // Move to layer storage, unless the same layer already exists
+        let digest_short = diff_id.trim_start_matches("sha256:");
+        let tar_path = self.paths.layer_tar(digest_short);
+        if self.exists(digest_short) {
+            fs::remove_file(&tmp_tar)?;
+        } else {
+            fs::create_dir_all(self.paths.layer_dir(digest_short))?;
+            fs::rename(&tmp_tar, &tar_path)?;
+        }
 
-        let final_path = self.paths.layer_tar(digest_short);
-        fs::rename(&tmp_tar, &final_path)?;
+        if compression == Compression::None {
+            return Ok(CreatedLayer {
+                digest: diff_id.clone(),
+                diff_id,
+                size: fs::metadata(&tar_path)?.len(),
+                compression,
+            });
+        }
 
-        Ok((digest, final_path))
+        let tmp_blob = tmp_dir.join(format!("layer_{}.blob", uuid::Uuid::new_v4()));
+        let digest = compress_file(&tar_path, &tmp_blob, compression)
+            .and_then(|_| Self::compute_digest(&tmp_blob));
+        let digest = match digest {
+            Ok(digest) => digest,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_blob);
+                return Err(e);
+            }
+        };
+        let size = fs::metadata(&tmp_blob)?.len();
+        BlobStore::new(&self.paths).insert_file(&tmp_blob, &digest)?;
+
+        Ok(CreatedLayer {
+            diff_id,
+            digest,
+            size,
+            compression,
+        })
     }
 
     /// List all layers
@@ -197,6 +371,190 @@ impl LayerManager {
     }
 }
 
+/// Get the time given by `SOURCE_DATE_EPOCH`, if it is set
+fn source_date_epoch() -> Result<Option<u64>> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) if !value.is_empty() => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| DarkerError::Layer(format!("Invalid SOURCE_DATE_EPOCH: {}", value))),
+        _ => Ok(None),
+    }
+}
+
+/// Write a directory as a reproducible layer tar
+///
+/// Entries are sorted by name and owned by root, and mtimes later than
+/// `epoch` are clamped to it. Sockets, fifos and devices are left out.
+pub fn write_layer_tar<W: Write>(dir: &Path, writer: W, epoch: Option<u64>) -> Result<()> {
+    let mut builder = tar::Builder::new(writer);
+    append_sorted(&mut builder, dir, Path::new(""), epoch)?;
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+/// Append the entries of a directory to a tar, sorted by name
+fn append_sorted<W: Write>(
+    builder: &mut tar::Builder<W>,
+    dir: &Path,
+    prefix: &Path,
+    epoch: Option<u64>,
+) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = prefix.join(entry.file_name());
+        let metadata = fs::symlink_metadata(&path)?;
+        let mtime = metadata.mtime().max(0) as u64;
+
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(metadata.mode() & 0o7777);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(epoch.map_or(mtime, |epoch| mtime.min(epoch)));
+        header.set_size(0);
+
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            header.set_entry_type(tar::EntryType::Directory);
+            builder.append_data(&mut header, &name, std::io::empty())?;
+            append_sorted(builder, &path, &name, epoch)?;
+        } else if file_type.is_symlink() {
+            header.set_entry_type(tar::EntryType::Symlink);
+            builder.append_link(&mut header, &name, fs::read_link(&path)?)?;
+        } else if file_type.is_file() {
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(metadata.len());
+            builder.append_data(&mut header, &name, File::open(&path)?)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Compress a file in fixed-size chunks, compressing chunks in parallel
+///
+/// Each chunk is compressed on its own, so the output does not depend on
+/// the number of threads. Gzip chunks are deflate streams ended with a sync
+/// flush and joined in a single gzip member; zstd chunks are frames.
+fn compress_file(src: &Path, dst: &Path, compression: Compression) -> Result<()> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut reader = File::open(src)?;
+    let mut remaining = reader.metadata()?.len();
+    let mut writer = BufWriter::new(File::create(dst)?);
+    let mut crc = flate2::Crc::new();
+
+    if compression == Compression::Gzip {
+        writer.write_all(&GZIP_HEADER)?;
+    }
+    loop {
+        let mut chunks = Vec::new();
+        while chunks.len() < threads {
+            let mut chunk = Vec::with_capacity(COMPRESSION_CHUNK_SIZE.min(remaining as usize));
+            (&mut reader)
+                .take(COMPRESSION_CHUNK_SIZE as u64)
+                .read_to_end(&mut chunk)?;
+            remaining = remaining.saturating_sub(chunk.len() as u64);
+            let end = chunk.len() < COMPRESSION_CHUNK_SIZE || remaining == 0;
+            chunks.push(chunk);
+            if end {
+                remaining = 0;
+                break;
+            }
+        }
+
+        let last = remaining == 0;
+        let count = chunks.len();
+        let compressed = if count == 1 {
+            vec![compress_chunk(&chunks[0], compression, last)?]
+        } else {
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = chunks
+                    .iter()
+                    .enumerate()
+                    .map(|(i, chunk)| {
+                        let last = last && i + 1 == count;
+                        scope.spawn(move || compress_chunk(chunk, compression, last))
+                    })
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|handle| {
+                        handle.join().unwrap_or_else(|_| {
+                            Err(DarkerError::Layer("Layer compression failed".to_string()))
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })?
+        };
+
+        for (chunk, output) in chunks.iter().zip(compressed) {
+            crc.update(chunk);
+            writer.write_all(&output)?;
+        }
+        if last {
+            break;
+        }
+    }
+    if compression == Compression::Gzip {
+        writer.write_all(&crc.sum().to_le_bytes())?;
+        writer.write_all(&crc.amount().to_le_bytes())?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Compress one chunk of a layer, finishing the stream if it is the last
+fn compress_chunk(data: &[u8], compression: Compression, last: bool) -> Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Zstd => Ok(zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL)?),
+        Compression::Gzip => {
+            let mut compress = flate2::Compress::new(flate2::Compression::default(), false);
+            let flush = if last {
+                flate2::FlushCompress::Finish
+            } else {
+                flate2::FlushCompress::Sync
+            };
+            let mut output = Vec::with_capacity(data.len() / 2 + 1024);
+            loop {
+                if output.capacity() - output.len() < 1024 {
+                    output.reserve(64 * 1024);
+                }
+                let consumed = compress.total_in() as usize;
+                let status = compress
+                    .compress_vec(&data[consumed..], &mut output, flush)
+                    .map_err(|e| DarkerError::Layer(format!("Layer compression failed: {}", e)))?;
+                let flushed =
+                    compress.total_in() as usize == data.len() && output.len() < output.capacity();
+                if status == flate2::Status::StreamEnd || (!last && flushed) {
+                    break;
+                }
+            }
+            Ok(output)
+        }
+    }
+}
+
+/// Total size of the files below a directory, without following symlinks
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .map(|entry| match entry.file_type() {
+            Ok(ft) if ft.is_dir() => dir_size(&entry.path()),
+            Ok(_) => entry.metadata().map(|m| m.len()).unwrap_or(0),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -221,6 +579,219 @@ mod tests {
         assert!(!manager.exists(digest));
     }
 
+    #[test]
+    fn test_layers_are_removed_once_unreferenced() {
+        use crate::storage::containers::ContainerConfig;
+
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let manager = LayerManager::new(&paths);
+        for layer in ["base", "app", "tool"] {
+            manager.store_layer_bytes(layer, &[0u8; 1024]).unwrap();
+        }
+        let layers = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let image_store = ImageStore::new(&paths).unwrap();
+        image_store
+            .store("app-image", None, None, None, &layers(&["base", "app"]), 0)
+            .unwrap();
+        image_store
+            .store(
+                "tool-image",
+                None,
+                None,
+                None,
+                &layers(&["base", "tool"]),
+                0,
+            )
+            .unwrap();
+
+        // A container keeps its image's layers after the image is removed
+        let container_store = ContainerStore::new(&paths).unwrap();
+        container_store
+            .create(&ContainerConfig {
+                id: "container123".to_string(),
+                name: "tool".to_string(),
+                image_id: "tool-image".to_string(),
+                layers: layers(&["base", "tool"]),
+                ..Default::default()
+            })
+            .unwrap();
+
+        // Only the layer no other image uses is deleted
+        assert_eq!(image_store.remove("app-image", true).unwrap(), 1024);
+        assert!(!manager.exists("app"));
+        assert!(manager.exists("base"));
+
+        assert_eq!(image_store.remove("tool-image", true).unwrap(), 0);
+        assert!(manager.exists("base"));
+        assert!(manager.exists("tool"));
+
+        container_store.remove("container123").unwrap();
+        manager.store_layer_bytes("orphan", b"orphan").unwrap();
+        assert_eq!(manager.prune().unwrap(), 2 * 1024 + 6);
+        assert!(manager.list_layers().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_layer_tars_are_reproducible() {
+        use std::time::{Duration, SystemTime};
+
+        let tmp = TempDir::new().unwrap();
+        let epoch = 1_700_000_000;
+        let tree = |name: &str, mtime: u64| {
+            let dir = tmp.path().join(name);
+            fs::create_dir_all(dir.join("etc/app")).unwrap();
+            // Created in a different order in each tree
+            let files: &[&str] = if name == "a" {
+                &["etc/app/b.conf", "etc/app/a.conf", "run.sh"]
+            } else {
+                &["run.sh", "etc/app/a.conf", "etc/app/b.conf"]
+            };
+            for file in files {
+                fs::write(dir.join(file), file.as_bytes()).unwrap();
+            }
+            std::os::unix::fs::symlink("etc/app/a.conf", dir.join("link")).unwrap();
+            let time = SystemTime::UNIX_EPOCH + Duration::from_secs(mtime);
+            for file in files {
+                File::options()
+                    .write(true)
+                    .open(dir.join(file))
+                    .unwrap()
+                    .set_modified(time)
+                    .unwrap();
+            }
+            dir
+        };
+        let a = tree("a", epoch + 100);
+        let b = tree("b", epoch + 200);
+
+        let mut tar_a = Vec::new();
+        write_layer_tar(&a, &mut tar_a, Some(epoch)).unwrap();
+        let mut tar_b = Vec::new();
+        write_layer_tar(&b, &mut tar_b, Some(epoch)).unwrap();
+        assert_eq!(tar_a, tar_b);
+
+        let mut archive = tar::Archive::new(tar_a.as_slice());
+        let mut names = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let entry = entry.unwrap();
+            let header = entry.header();
+            assert_eq!((header.uid().unwrap(), header.gid().unwrap()), (0, 0));
+            if header.entry_type().is_file() {
+                assert_eq!(header.mtime().unwrap(), epoch);
+            }
+            names.push(entry.path().unwrap().to_string_lossy().to_string());
+        }
+        assert_eq!(
+            names,
+            [
+                "etc",
+                "etc/app",
+                "etc/app/a.conf",
+                "etc/app/b.conf",
+                "link",
+                "run.sh"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_create_compressed_layer() {
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path().join("darker"));
+        paths.ensure_directories().unwrap();
+        let manager = LayerManager::new(&paths);
+        let blobs = BlobStore::new(&paths);
+
+        // Big enough to be compressed in several chunks
+        let dir = tmp.path().join("context");
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..COMPRESSION_CHUNK_SIZE as u32 * 3)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        fs::write(dir.join("data.bin"), &data).unwrap();
+        fs::write(dir.join("small.txt"), "small").unwrap();
+
+        let gzip = manager
+            .create_layer_from_dir(&dir, Compression::Gzip)
+            .unwrap();
+        let zstd = manager
+            .create_layer_from_dir(&dir, Compression::Zstd)
+            .unwrap();
+        let none = manager
+            .create_layer_from_dir(&dir, Compression::None)
+            .unwrap();
+        assert_eq!(gzip.diff_id, zstd.diff_id);
+        assert_eq!(none.digest, none.diff_id);
+        assert!(!blobs.exists(&none.digest));
+
+        let diff_hex = gzip.diff_id.trim_start_matches("sha256:");
+        assert!(manager.exists(diff_hex));
+        let tar = fs::read(manager.layer_tar_path(diff_hex)).unwrap();
+        for layer in [&gzip, &zstd] {
+            let blob_path = blobs.path(&layer.digest).unwrap();
+            assert_eq!(fs::metadata(&blob_path).unwrap().len(), layer.size);
+            assert_eq!(Compression::detect(&blob_path).unwrap(), layer.compression);
+
+            let mut decoded = Vec::new();
+            layer
+                .compression
+                .decoder(File::open(&blob_path).unwrap())
+                .unwrap()
+                .read_to_end(&mut decoded)
+                .unwrap();
+            assert!(decoded == tar);
+        }
+
+        // The same directory gives the same blob
+        let again = manager
+            .create_layer_from_dir(&dir, Compression::Gzip)
+            .unwrap();
+        assert_eq!(again.digest, gzip.digest);
+        assert_eq!(fs::read_dir(paths.tmp_dir()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_compression_from_media_type() {
+        use std::io::Write;
+
+        assert_eq!(
+            Compression::from_media_type(media_types::OCI_LAYER_TAR).unwrap(),
+            Compression::None
+        );
+        assert_eq!(
+            Compression::from_media_type(media_types::DOCKER_LAYER_TAR_GZIP).unwrap(),
+            Compression::Gzip
+        );
+        assert_eq!(
+            Compression::from_media_type(media_types::OCI_LAYER_TAR_ZSTD).unwrap(),
+            Compression::Zstd
+        );
+        assert!(Compression::from_media_type("application/vnd.example.layer.v1.tar+lz4").is_err());
+
+        let data = b"layer contents".repeat(100);
+        let mut gzipped = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzipped.write_all(&data).unwrap();
+        let gzipped = gzipped.finish().unwrap();
+        let zstded = zstd::stream::encode_all(&data[..], 0).unwrap();
+
+        for (compression, blob) in [
+            (Compression::None, data.clone()),
+            (Compression::Gzip, gzipped),
+            (Compression::Zstd, zstded),
+        ] {
+            let mut decoded = Vec::new();
+            compression
+                .decoder(&blob[..])
+                .unwrap()
+                .read_to_end(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, data);
+        }
+    }
+
     #[test]
     fn test_compute_digest() {
         let data = b"hello world";
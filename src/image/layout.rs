@@ -0,0 +1,743 @@
+//! OCI image layouts, as read and written by skopeo, crane and umoci
+//!
+//! A layout is a directory holding an `oci-layout` marker, an `index.json`
+//! naming its manifests, and all content under `blobs/sha256/`. An
+//! oci-archive is a tarball of such a directory. Manifests in the index are
+//! named by their `org.opencontainers.image.ref.name` annotation.
+
+use crate::image::archive::{self, archive_header, hash_stream, repo_tag, LoadedImage};
+use crate::image::layer::{Compression, LayerManager};
+use crate::image::oci::{
+    annotations, media_types, ImageIndex, ImageManifest, ImageReference, ManifestDescriptor,
+    OciImageConfig, Platform,
+};
+use crate::image::registry::{select_manifest, LocalManifest};
+use crate::storage::blobs::BlobStore;
+use crate::storage::images::ImageStore;
+use crate::storage::paths::DarkerPaths;
+use crate::{DarkerError, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Contents of the `oci-layout` marker file
+const LAYOUT_VERSION: &str = r#"{"imageLayoutVersion":"1.0.0"}"#;
+
+/// A location images are saved to or loaded from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    /// A docker-archive tarball (`docker-archive:<path>`)
+    DockerArchive(PathBuf),
+    /// An OCI image layout directory (`oci:<path>[:<ref>]`)
+    Oci {
+        path: PathBuf,
+        reference: Option<String>,
+    },
+    /// A tarball of an OCI image layout (`oci-archive:<path>[:<ref>]`)
+    OciArchive {
+        path: PathBuf,
+        reference: Option<String>,
+    },
+}
+
+impl Transport {
+    /// Parse a location with a transport prefix, such as `oci:./layout:v1`
+    ///
+    /// Returns None if the value doesn't start with a known transport.
+    pub fn parse(value: &str) -> Option<Self> {
+        let (transport, rest) = value.split_once(':')?;
+        let (path, reference) = match rest.split_once(':') {
+            Some((path, reference)) if !reference.is_empty() => {
+                (PathBuf::from(path), Some(reference.to_string()))
+            }
+            _ => (PathBuf::from(rest.trim_end_matches(':')), None),
+        };
+
+        match transport {
+            "docker-archive" => Some(Self::DockerArchive(PathBuf::from(rest))),
+            "oci" => Some(Self::Oci { path, reference }),
+            "oci-archive" => Some(Self::OciArchive { path, reference }),
+            _ => None,
+        }
+    }
+}
+
+/// Check whether a directory holds an OCI image layout
+pub fn is_layout(dir: &Path) -> bool {
+    dir.join("oci-layout").is_file()
+}
+
+/// Content to write into a layout
+enum Blob<'a> {
+    File(&'a Path),
+    Bytes(&'a [u8]),
+}
+
+/// Somewhere the files of a layout are written to
+trait LayoutSink {
+    /// Write a file into the layout, skipping blobs that are already there
+    fn put(&mut self, name: &str, blob: Blob) -> Result<()>;
+}
+
+/// Writes a layout into a directory
+struct DirSink<'a>(&'a Path);
+
+impl LayoutSink for DirSink<'_> {
+    fn put(&mut self, name: &str, blob: Blob) -> Result<()> {
+        let path = self.0.join(name);
+        if name.starts_with("blobs/") && path.exists() {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match blob {
+            Blob::File(source) => {
+                fs::copy(source, &path)?;
+            }
+            Blob::Bytes(data) => fs::write(&path, data)?,
+        }
+        Ok(())
+    }
+}
+
+/// Writes a layout into a tarball
+struct TarSink<W: Write> {
+    builder: tar::Builder<W>,
+    written: HashSet<String>,
+}
+
+impl<W: Write> LayoutSink for TarSink<W> {
+    fn put(&mut self, name: &str, blob: Blob) -> Result<()> {
+        if !self.written.insert(name.to_string()) {
+            return Ok(());
+        }
+        match blob {
+            Blob::File(source) => {
+                let mut header = archive_header(fs::metadata(source)?.len());
+                self.builder
+                    .append_data(&mut header, name, File::open(source)?)?;
+            }
+            Blob::Bytes(data) => {
+                let mut header = archive_header(data.len() as u64);
+                self.builder.append_data(&mut header, name, data)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write images into an OCI image layout directory
+///
+/// Manifests are added to the index of an existing layout, replacing any
+/// with the same name. With a reference, the single image is named by it
+/// instead of by its tags.
+pub fn save_layout(
+    paths: &DarkerPaths,
+    images: &[(String, Vec<ImageReference>)],
+    dir: &Path,
+    reference: Option<&str>,
+) -> Result<()> {
+    let index_path = dir.join("index.json");
+    let mut index = match index_path.exists() {
+        true => serde_json::from_slice(&fs::read(&index_path)?)?,
+        false => ImageIndex {
+            schema_version: 2,
+            media_type: Some(media_types::OCI_IMAGE_INDEX.to_string()),
+            manifests: Vec::new(),
+            annotations: None,
+        },
+    };
+
+    let mut sink = DirSink(dir);
+    let descriptors = write_images(paths, images, reference, &mut sink)?;
+    index.manifests.retain(|existing| {
+        !descriptors
+            .iter()
+            .any(|added| match (name_of(existing), name_of(added)) {
+                (Some(a), Some(b)) => a == b,
+                (None, None) => existing.digest == added.digest,
+                _ => false,
+            })
+    });
+    index.manifests.extend(descriptors);
+
+    sink.put("oci-layout", Blob::Bytes(LAYOUT_VERSION.as_bytes()))?;
+    sink.put("index.json", Blob::Bytes(&serde_json::to_vec(&index)?))?;
+    Ok(())
+}
+
+/// Write images to an oci-archive tarball
+pub fn save_layout_archive<W: Write>(
+    paths: &DarkerPaths,
+    images: &[(String, Vec<ImageReference>)],
+    reference: Option<&str>,
+    writer: W,
+) -> Result<()> {
+    let mut sink = TarSink {
+        builder: tar::Builder::new(writer),
+        written: HashSet::new(),
+    };
+    sink.put("oci-layout", Blob::Bytes(LAYOUT_VERSION.as_bytes()))?;
+    let index = ImageIndex {
+        schema_version: 2,
+        media_type: Some(media_types::OCI_IMAGE_INDEX.to_string()),
+        manifests: write_images(paths, images, reference, &mut sink)?,
+        annotations: None,
+    };
+    sink.put("index.json", Blob::Bytes(&serde_json::to_vec(&index)?))?;
+
+    sink.builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+/// Write the blobs of images into a layout, returning their index entries
+fn write_images(
+    paths: &DarkerPaths,
+    images: &[(String, Vec<ImageReference>)],
+    reference: Option<&str>,
+    sink: &mut dyn LayoutSink,
+) -> Result<Vec<ManifestDescriptor>> {
+    if reference.is_some() && images.len() > 1 {
+        return Err(DarkerError::Archive(
+            "A layout reference can only name a single image".to_string(),
+        ));
+    }
+
+    let mut descriptors = Vec::new();
+    for (image_id, references) in images {
+        let local = LocalManifest::load(paths, image_id)?;
+        for (path, layer) in &local.layers {
+            sink.put(&blob_name(&layer.digest), Blob::File(path))?;
+        }
+        let config_digest = LayerManager::compute_digest_bytes(&local.config);
+        sink.put(&blob_name(&config_digest), Blob::Bytes(&local.config))?;
+        sink.put(&blob_name(&local.digest()), Blob::Bytes(&local.manifest))?;
+
+        let descriptor = ManifestDescriptor {
+            media_type: local.media_type.clone(),
+            digest: local.digest(),
+            size: local.manifest.len() as i64,
+            platform: Some(local.platform.clone()),
+            annotations: None,
+        };
+
+        // Name the manifest by the tag, keeping the full name for loading
+        let names: Vec<HashMap<String, String>> = match reference {
+            Some(reference) => vec![HashMap::from([(
+                annotations::REF_NAME.to_string(),
+                reference.to_string(),
+            )])],
+            None => references
+                .iter()
+                .map(|r| {
+                    HashMap::from([
+                        (annotations::REF_NAME.to_string(), r.tag().to_string()),
+                        (
+                            annotations::IMAGE_NAME.to_string(),
+                            format!("{}/{}:{}", r.registry, r.repository, r.tag()),
+                        ),
+                    ])
+                })
+                .collect(),
+        };
+        if names.is_empty() {
+            descriptors.push(descriptor.clone());
+        }
+        for name in names {
+            descriptors.push(ManifestDescriptor {
+                annotations: Some(name),
+                ..descriptor.clone()
+            });
+        }
+    }
+
+    Ok(descriptors)
+}
+
+/// Import images from a transport into the store
+///
+/// Images in a layout without a full name are named after the layout, as
+/// `localhost/<name>:<ref>`. Image indexes in a layout are resolved to the
+/// manifest for the platform.
+pub fn load_transport(
+    paths: &DarkerPaths,
+    transport: &Transport,
+    platform: Option<&Platform>,
+) -> Result<Vec<LoadedImage>> {
+    match transport {
+        Transport::DockerArchive(path) => archive::load(paths, File::open(path)?),
+        Transport::Oci { path, reference } => load_layout(
+            paths,
+            path,
+            reference.as_deref(),
+            platform,
+            default_name(path).as_deref(),
+        ),
+        Transport::OciArchive { path, reference } => {
+            let dir = paths.tmp_dir().join(uuid::Uuid::new_v4().to_string());
+            fs::create_dir_all(&dir)?;
+            let result = File::open(path)
+                .map_err(DarkerError::from)
+                .and_then(|file| archive::unpack(file, &dir))
+                .and_then(|_| {
+                    load_layout(
+                        paths,
+                        &dir,
+                        reference.as_deref(),
+                        platform,
+                        default_name(path).as_deref(),
+                    )
+                });
+            fs::remove_dir_all(&dir)?;
+            result
+        }
+    }
+}
+
+/// Import the images of an OCI image layout directory into the store
+///
+/// With a reference, only the manifests named by it are imported. `name` is
+/// the repository given to manifests that are only named by a tag.
+pub fn load_layout(
+    paths: &DarkerPaths,
+    dir: &Path,
+    reference: Option<&str>,
+    platform: Option<&Platform>,
+    name: Option<&str>,
+) -> Result<Vec<LoadedImage>> {
+    if !is_layout(dir) {
+        return Err(DarkerError::Archive(format!(
+            "{} is not an OCI image layout",
+            dir.display()
+        )));
+    }
+    let index: ImageIndex = serde_json::from_slice(&fs::read(dir.join("index.json"))?)?;
+
+    let entries: Vec<&ManifestDescriptor> = index
+        .manifests
+        .iter()
+        .filter(|entry| reference.is_none_or(|r| is_named(entry, r)))
+        .collect();
+    if entries.is_empty() {
+        return Err(DarkerError::Archive(match reference {
+            Some(reference) => format!("No image named {} in {}", reference, dir.display()),
+            None => format!("No images in {}", dir.display()),
+        }));
+    }
+
+    let image_store = ImageStore::new(paths)?;
+    let mut loaded: Vec<LoadedImage> = Vec::new();
+    for entry in entries {
+        let descriptor = resolve_manifest(dir, entry, platform)?;
+        let image_id = import_manifest(paths, dir, &descriptor)?;
+
+        let names = entry_names(entry, name);
+        for reference in &names {
+            image_store.tag(&image_id, reference)?;
+        }
+        let tags = names.iter().map(repo_tag);
+        match loaded.iter_mut().find(|l| l.image_id == image_id) {
+            Some(image) => image.tags.extend(tags),
+            None => loaded.push(LoadedImage {
+                image_id,
+                tags: tags.collect(),
+            }),
+        }
+    }
+
+    Ok(loaded)
+}
+
+/// Pick the image manifest for an index entry, which may be a nested index
+fn resolve_manifest(
+    dir: &Path,
+    entry: &ManifestDescriptor,
+    platform: Option<&Platform>,
+) -> Result<ManifestDescriptor> {
+    match entry.media_type.as_str() {
+        media_types::OCI_IMAGE_INDEX | media_types::DOCKER_MANIFEST_LIST => {
+            let index: ImageIndex = serde_json::from_slice(&read_blob(dir, &entry.digest)?)?;
+            Ok(select_manifest(&index, platform)?.clone())
+        }
+        _ => Ok(entry.clone()),
+    }
+}
+
+/// Import an image manifest and its blobs, returning the image ID
+fn import_manifest(
+    paths: &DarkerPaths,
+    dir: &Path,
+    descriptor: &ManifestDescriptor,
+) -> Result<String> {
+    let manifest_bytes = read_blob(dir, &descriptor.digest)?;
+    let manifest: ImageManifest = serde_json::from_slice(&manifest_bytes)?;
+    let config_bytes = read_blob(dir, &manifest.config.digest)?;
+    let config: OciImageConfig = serde_json::from_slice(&config_bytes)?;
+    if config.rootfs.diff_ids.len() != manifest.layers.len() {
+        return Err(DarkerError::Archive(format!(
+            "Manifest {} has {} layers, but its config lists {}",
+            descriptor.digest,
+            manifest.layers.len(),
+            config.rootfs.diff_ids.len()
+        )));
+    }
+
+    // Layers are stored uncompressed under their diff ID, as when pulled, and
+    // their blobs are kept as they are
+    let layer_manager = LayerManager::new(paths);
+    let blobs = BlobStore::new(paths);
+    let mut layers = Vec::new();
+    let mut total_size = 0;
+    for (layer, diff_id) in manifest.layers.iter().zip(&config.rootfs.diff_ids) {
+        let compression = Compression::from_media_type(&layer.media_type)?;
+        let digest = diff_id.trim_start_matches("sha256:").to_string();
+
+        if !blobs.exists(&layer.digest) {
+            let path = blob_path(dir, &layer.digest)?;
+            if hash_stream(File::open(&path)?)?.0 != layer.digest {
+                return Err(corrupt_blob(&layer.digest));
+            }
+            blobs.copy_file(&path)?;
+        }
+        if !layer_manager.exists(&digest) {
+            let decoder = compression.decoder(File::open(blobs.path(&layer.digest)?)?)?;
+            archive::store_layer(paths, &layer.digest, decoder, diff_id)?;
+        }
+        layers.push(digest);
+        total_size += layer.size as u64;
+    }
+
+    // The image ID is the digest of its config, as for pulled images
+    let image_id = manifest
+        .config
+        .digest
+        .trim_start_matches("sha256:")
+        .to_string();
+    let image_store = ImageStore::new(paths)?;
+    image_store.store(
+        &image_id,
+        None,
+        None,
+        Some(&manifest.config.digest),
+        &layers,
+        total_size,
+    )?;
+    let platform = Platform::new(&config.os, &config.architecture, config.variant.as_deref());
+    image_store.set_platform(&image_id, &platform)?;
+    let blob_digests: Vec<String> = manifest.layers.iter().map(|l| l.digest.clone()).collect();
+    image_store.set_blob_digests(&image_id, &blob_digests)?;
+    blobs.write(&config_bytes)?;
+    let manifest_digest = blobs.write(&manifest_bytes)?;
+    image_store.set_manifest(&image_id, &manifest_digest, None)?;
+
+    Ok(image_id)
+}
+
+/// Name of a manifest in an index, preferring the full image name
+fn name_of(entry: &ManifestDescriptor) -> Option<&str> {
+    let annotations = entry.annotations.as_ref()?;
+    annotations
+        .get(annotations::IMAGE_NAME)
+        .or_else(|| annotations.get(annotations::REF_NAME))
+        .map(String::as_str)
+}
+
+/// Check whether an index entry is named by a reference
+fn is_named(entry: &ManifestDescriptor, reference: &str) -> bool {
+    let ref_name = entry
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get(annotations::REF_NAME));
+    if ref_name.map(String::as_str) == Some(reference) {
+        return true;
+    }
+
+    let wanted = ImageReference::parse(reference).ok().map(|r| r.full_name());
+    entry_names(entry, None)
+        .iter()
+        .any(|name| Some(name.full_name()) == wanted)
+}
+
+/// Image names of an index entry
+///
+/// Ref names that are only a tag are combined with `repository`.
+fn entry_names(entry: &ManifestDescriptor, repository: Option<&str>) -> Vec<ImageReference> {
+    let Some(annotations) = entry.annotations.as_ref() else {
+        return Vec::new();
+    };
+    if let Some(full_name) = annotations.get(annotations::IMAGE_NAME) {
+        if let Ok(reference) = ImageReference::parse(full_name) {
+            return vec![reference];
+        }
+    }
+
+    let name = match annotations.get(annotations::REF_NAME) {
+        Some(ref_name) if ref_name.contains('/') || ref_name.contains(':') => ref_name.clone(),
+        Some(tag) => match repository {
+            Some(repository) => format!("{}:{}", repository, tag),
+            None => return Vec::new(),
+        },
+        None => return Vec::new(),
+    };
+    ImageReference::parse(&name).into_iter().collect()
+}
+
+/// Repository for images in a layout that are only named by a tag
+fn default_name(path: &Path) -> Option<String> {
+    let stem = path.file_stem()?.to_str()?.to_ascii_lowercase();
+    let valid = !stem.is_empty()
+        && stem
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        && stem
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphanumeric());
+    valid.then(|| format!("localhost/{}", stem))
+}
+
+/// Path of a blob within a layout
+fn blob_name(digest: &str) -> String {
+    format!("blobs/sha256/{}", digest.trim_start_matches("sha256:"))
+}
+
+/// Find a blob in a layout, refusing malformed digests
+fn blob_path(dir: &Path, digest: &str) -> Result<PathBuf> {
+    let hex = digest.strip_prefix("sha256:").unwrap_or("");
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(DarkerError::Archive(format!(
+            "Unsupported digest: {}",
+            digest
+        )));
+    }
+    let path = dir.join(blob_name(digest));
+    if !path.is_file() {
+        return Err(DarkerError::Archive(format!(
+            "Blob {} not found in {}",
+            digest,
+            dir.display()
+        )));
+    }
+    Ok(path)
+}
+
+/// Read a blob from a layout, checking it against its digest
+fn read_blob(dir: &Path, digest: &str) -> Result<Vec<u8>> {
+    let data = fs::read(blob_path(dir, digest)?)?;
+    if LayerManager::compute_digest_bytes(&data) != digest {
+        return Err(corrupt_blob(digest));
+    }
+    Ok(data)
+}
+
+fn corrupt_blob(digest: &str) -> DarkerError {
+    DarkerError::Archive(format!("Blob {} does not match its digest", digest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::images::{ImageConfig, ImageConfigDetails};
+    use tempfile::TempDir;
+
+    fn store_image(paths: &DarkerPaths, image_id: &str, name: &str) -> (String, ImageReference) {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = archive_header(image_id.len() as u64);
+        builder
+            .append_data(&mut header, "id.txt", image_id.as_bytes())
+            .unwrap();
+        let tar = builder.into_inner().unwrap();
+        let digest = LayerManager::compute_digest_bytes(&tar)
+            .trim_start_matches("sha256:")
+            .to_string();
+        LayerManager::new(paths)
+            .store_layer_bytes(&digest, &tar)
+            .unwrap();
+
+        let reference = ImageReference::parse(name).unwrap();
+        let image_store = ImageStore::new(paths).unwrap();
+        image_store
+            .store(
+                image_id,
+                Some(&reference.repository_with_registry()),
+                Some(reference.tag()),
+                None,
+                &[digest],
+                0,
+            )
+            .unwrap();
+        image_store
+            .save_config(
+                image_id,
+                &ImageConfig {
+                    config: ImageConfigDetails {
+                        cmd: Some(vec![format!("/{}", image_id)]),
+                        ..Default::default()
+                    },
+                },
+            )
+            .unwrap();
+        (image_id.to_string(), reference)
+    }
+
+    #[test]
+    fn test_parse_transport() {
+        assert_eq!(
+            Transport::parse("oci:/tmp/layout:v1"),
+            Some(Transport::Oci {
+                path: PathBuf::from("/tmp/layout"),
+                reference: Some("v1".to_string()),
+            })
+        );
+        assert_eq!(
+            Transport::parse("oci-archive:app.tar"),
+            Some(Transport::OciArchive {
+                path: PathBuf::from("app.tar"),
+                reference: None,
+            })
+        );
+        assert_eq!(
+            Transport::parse("docker-archive:app.tar"),
+            Some(Transport::DockerArchive(PathBuf::from("app.tar")))
+        );
+        assert_eq!(Transport::parse("alpine:3.19"), None);
+        assert_eq!(Transport::parse("app.tar"), None);
+    }
+
+    #[test]
+    fn test_layout_round_trip() {
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path().join("src"));
+        paths.ensure_directories().unwrap();
+        let app = store_image(&paths, "aaaa", "alpine:3.19");
+        let tool = store_image(&paths, "bbbb", "ghcr.io/org/tool:v1");
+
+        let layout = tmp.path().join("layout");
+        let images = vec![(app.0.clone(), vec![app.1.clone()])];
+        save_layout(&paths, &images, &layout, None).unwrap();
+        save_layout(&paths, &[(tool.0.clone(), vec![])], &layout, Some("tool")).unwrap();
+        save_layout(&paths, &images, &layout, None).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(layout.join("oci-layout")).unwrap(),
+            LAYOUT_VERSION
+        );
+        let index: ImageIndex =
+            serde_json::from_slice(&fs::read(layout.join("index.json")).unwrap()).unwrap();
+        assert_eq!(index.manifests.len(), 2);
+        let names: Vec<&str> = index
+            .manifests
+            .iter()
+            .map(|m| m.annotations.as_ref().unwrap()[annotations::REF_NAME].as_str())
+            .collect();
+        assert_eq!(names, vec!["tool", "3.19"]);
+        for manifest in &index.manifests {
+            let bytes = read_blob(&layout, &manifest.digest).unwrap();
+            let manifest: ImageManifest = serde_json::from_slice(&bytes).unwrap();
+            read_blob(&layout, &manifest.config.digest).unwrap();
+        }
+
+        // Load one image by its ref name; tags alone are named after the layout
+        let dest = DarkerPaths::with_root(tmp.path().join("dest"));
+        dest.ensure_directories().unwrap();
+        let transport = Transport::parse(&format!("oci:{}:tool", layout.display())).unwrap();
+        let loaded = load_transport(&dest, &transport, None).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].tags, vec!["localhost/layout:tool"]);
+        let config = ImageStore::new(&dest)
+            .unwrap()
+            .load_config(&loaded[0].image_id)
+            .unwrap();
+        assert_eq!(config.cmd(), Some(vec!["/bbbb".to_string()]));
+
+        // Full names are kept when the whole layout is loaded
+        let loaded = load_layout(&dest, &layout, None, None, None).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[1].tags, vec!["alpine:3.19"]);
+        let image_store = ImageStore::new(&dest).unwrap();
+        assert_eq!(
+            image_store.find_image(&app.1),
+            Some(loaded[1].image_id.clone())
+        );
+
+        let err = load_layout(&dest, &layout, Some("missing"), None, None).unwrap_err();
+        assert!(err.to_string().contains("No image named missing"));
+    }
+
+    #[test]
+    fn test_oci_archive_round_trip() {
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path().join("src"));
+        paths.ensure_directories().unwrap();
+        let app = store_image(&paths, "aaaa", "ghcr.io/org/app:v2");
+
+        let path = tmp.path().join("app.tar");
+        let file = File::create(&path).unwrap();
+        save_layout_archive(&paths, &[(app.0, vec![app.1.clone()])], None, file).unwrap();
+
+        // oci-archive is loaded through the transport, or detected by `load`
+        let dest = DarkerPaths::with_root(tmp.path().join("dest"));
+        dest.ensure_directories().unwrap();
+        let transport = Transport::parse(&format!("oci-archive:{}:v2", path.display())).unwrap();
+        let loaded = load_transport(&dest, &transport, None).unwrap();
+        assert_eq!(loaded[0].tags, vec!["ghcr.io/org/app:v2"]);
+
+        let detected = archive::load(&dest, File::open(&path).unwrap()).unwrap();
+        assert_eq!(detected[0].image_id, loaded[0].image_id);
+        assert_eq!(fs::read_dir(dest.tmp_dir()).unwrap().count(), 0);
+
+        // A corrupted blob is refused
+        let layout = tmp.path().join("layout");
+        let mut archive = tar::Archive::new(File::open(&path).unwrap());
+        archive.unpack(&layout).unwrap();
+        let index: ImageIndex =
+            serde_json::from_slice(&fs::read(layout.join("index.json")).unwrap()).unwrap();
+        fs::write(layout.join(blob_name(&index.manifests[0].digest)), b"{}").unwrap();
+        let err = load_layout(&dest, &layout, None, None, None).unwrap_err();
+        assert!(err.to_string().contains("does not match its digest"));
+    }
+
+    #[test]
+    fn test_load_layout_checks_diff_ids() {
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path().join("src"));
+        paths.ensure_directories().unwrap();
+        let app = store_image(&paths, "aaaa", "alpine:3.19");
+        let layout = tmp.path().join("layout");
+        save_layout(&paths, &[(app.0, vec![app.1])], &layout, None).unwrap();
+
+        // Point the config at a diff ID the layer doesn't have
+        let read_json = |digest: &serde_json::Value| -> serde_json::Value {
+            serde_json::from_slice(&read_blob(&layout, digest.as_str().unwrap()).unwrap()).unwrap()
+        };
+        let write_json = |descriptor: &mut serde_json::Value, value: &serde_json::Value| {
+            let bytes = serde_json::to_vec(value).unwrap();
+            let digest = LayerManager::compute_digest_bytes(&bytes);
+            fs::write(layout.join(blob_name(&digest)), &bytes).unwrap();
+            descriptor["digest"] = digest.into();
+            descriptor["size"] = bytes.len().into();
+        };
+        let mut index: serde_json::Value =
+            serde_json::from_slice(&fs::read(layout.join("index.json")).unwrap()).unwrap();
+        let mut manifest = read_json(&index["manifests"][0]["digest"]);
+        let mut config = read_json(&manifest["config"]["digest"]);
+        config["rootfs"]["diff_ids"][0] = format!("sha256:{}", "0".repeat(64)).into();
+        write_json(&mut manifest["config"], &config);
+        write_json(&mut index["manifests"][0], &manifest);
+        fs::write(
+            layout.join("index.json"),
+            serde_json::to_vec(&index).unwrap(),
+        )
+        .unwrap();
+
+        // The layer is refused without leaving anything behind
+        let dest = DarkerPaths::with_root(tmp.path().join("dest"));
+        dest.ensure_directories().unwrap();
+        let err = load_layout(&dest, &layout, None, None, None).unwrap_err();
+        assert!(err.to_string().contains("but the image config expects"));
+        assert_eq!(fs::read_dir(dest.layers_dir()).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(dest.tmp_dir()).unwrap().count(), 0);
+    }
+}
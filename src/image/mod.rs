@@ -1,6 +1,17 @@
 //! Image handling module
 
+pub mod archive;
+pub mod auth;
 pub mod build;
+pub mod dockerfile;
+pub mod dockerignore;
+pub mod import;
 pub mod layer;
+pub mod layout;
 pub mod oci;
+pub mod progress;
 pub mod registry;
+pub mod retry;
+
+#[cfg(test)]
+pub(crate) mod test_registry;
@@ -27,7 +27,9 @@ impl ImageReference {
         // Handle digest
         let (ref_without_digest, digest) = if reference.contains('@') {
             let parts: Vec<&str> = reference.splitn(2, '@').collect();
-            (parts[0], Some(parts.get(1).unwrap_or(&"").to_string()))
+            let digest = parts.get(1).unwrap_or(&"").to_string();
+            validate_digest(&digest)?;
+            (parts[0], Some(digest))
         } else {
             (reference, None)
         };
@@ -81,9 +83,17 @@ impl ImageReference {
         }
     }
 
-    /// Get the full image name with tag
+    /// Get the full image name with tag, or with digest when pinned
     pub fn full_name(&self) -> String {
-        format!("{}:{}", self.repository_with_registry(), self.tag)
+        match &self.digest {
+            Some(digest) => format!("{}@{}", self.repository_with_registry(), digest),
+            None => format!("{}:{}", self.repository_with_registry(), self.tag),
+        }
+    }
+
+    /// Get the tag or digest to request from the manifests endpoint
+    pub fn manifest_reference(&self) -> &str {
+        self.digest.as_deref().unwrap_or(&self.tag)
     }
 
     /// Get the tag
@@ -93,13 +103,32 @@ impl ImageReference {
 
     /// Get the API URL for the registry
     pub fn registry_url(&self) -> String {
-        if self.registry == "docker.io" {
-            "https://registry-1.docker.io".to_string()
-        } else if self.registry.starts_with("localhost") {
-            format!("http://{}", self.registry)
-        } else {
-            format!("https://{}", self.registry)
-        }
+        registry_api_url(&self.registry)
+    }
+}
+
+/// Check that a digest is a well-formed sha256 digest
+fn validate_digest(digest: &str) -> Result<()> {
+    let hex = digest.strip_prefix("sha256:").ok_or_else(|| {
+        DarkerError::InvalidImageRef(format!("Unsupported digest: {}", digest))
+    })?;
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)) {
+        return Err(DarkerError::InvalidImageRef(format!(
+            "Invalid digest: {}",
+            digest
+        )));
+    }
+    Ok(())
+}
+
+/// Get the API URL for a registry host
+pub fn registry_api_url(registry: &str) -> String {
+    if registry == "docker.io" {
+        "https://registry-1.docker.io".to_string()
+    } else if registry.starts_with("localhost") {
+        format!("http://{}", registry)
+    } else {
+        format!("https://{}", registry)
     }
 }
 
@@ -108,9 +137,11 @@ impl ImageReference {
 #[serde(rename_all = "camelCase")]
 pub struct ImageManifest {
     pub schema_version: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub media_type: Option<String>,
     pub config: Descriptor,
     pub layers: Vec<Descriptor>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub annotations: Option<std::collections::HashMap<String, String>>,
 }
 
@@ -121,7 +152,9 @@ pub struct Descriptor {
     pub media_type: String,
     pub digest: String,
     pub size: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub urls: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub annotations: Option<std::collections::HashMap<String, String>>,
 }
 
@@ -130,8 +163,10 @@ pub struct Descriptor {
 #[serde(rename_all = "camelCase")]
 pub struct ImageIndex {
     pub schema_version: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub media_type: Option<String>,
     pub manifests: Vec<ManifestDescriptor>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub annotations: Option<std::collections::HashMap<String, String>>,
 }
 
@@ -142,7 +177,9 @@ pub struct ManifestDescriptor {
     pub media_type: String,
     pub digest: String,
     pub size: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub platform: Option<Platform>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub annotations: Option<std::collections::HashMap<String, String>>,
 }
 
@@ -162,13 +199,140 @@ pub struct Platform {
     pub features: Option<Vec<String>>,
 }
 
+impl Platform {
+    /// Environment variable holding the platform used when none is requested
+    pub const DEFAULT_ENV: &'static str = "DARKER_DEFAULT_PLATFORM";
+
+    /// Create a normalized platform from an OS, architecture and variant
+    pub fn new(os: &str, architecture: &str, variant: Option<&str>) -> Self {
+        let os = os.to_ascii_lowercase();
+        let architecture = architecture.to_ascii_lowercase();
+        let variant = variant
+            .filter(|v| !v.is_empty())
+            .map(|v| v.to_ascii_lowercase());
+
+        // Normalize the aliases used by uname, Debian and others
+        let (architecture, variant) = match (architecture.as_str(), variant.as_deref()) {
+            ("x86_64" | "x86-64", v) => ("amd64".to_string(), v.map(String::from)),
+            ("i386" | "i686", v) => ("386".to_string(), v.map(String::from)),
+            ("aarch64" | "arm64", Some("8" | "v8") | None) => ("arm64".to_string(), None),
+            ("aarch64", v) => ("arm64".to_string(), v.map(String::from)),
+            ("armhf", _) => ("arm".to_string(), Some("v7".to_string())),
+            ("armel", _) => ("arm".to_string(), Some("v6".to_string())),
+            ("arm", None) => ("arm".to_string(), Some("v7".to_string())),
+            ("arm", Some(v)) if v.chars().all(|c| c.is_ascii_digit()) => {
+                ("arm".to_string(), Some(format!("v{}", v)))
+            }
+            (arch, v) => (arch.to_string(), v.map(String::from)),
+        };
+
+        Self {
+            architecture,
+            os,
+            os_version: None,
+            os_features: None,
+            variant,
+            features: None,
+        }
+    }
+
+    /// Parse an `os/arch[/variant]` platform string
+    pub fn parse(platform: &str) -> Result<Self> {
+        let parts: Vec<&str> = platform.trim().split('/').collect();
+        match parts.as_slice() {
+            [os, arch] if !os.is_empty() && !arch.is_empty() => Ok(Self::new(os, arch, None)),
+            [os, arch, variant] if !os.is_empty() && !arch.is_empty() && !variant.is_empty() => {
+                Ok(Self::new(os, arch, Some(variant)))
+            }
+            _ => Err(DarkerError::InvalidPlatform(format!(
+                "{} (expected os/arch[/variant])",
+                platform
+            ))),
+        }
+    }
+
+    /// Resolve the platform requested on the command line
+    ///
+    /// Falls back to `DARKER_DEFAULT_PLATFORM` when no platform was given.
+    pub fn requested(platform: Option<&str>) -> Result<Option<Self>> {
+        match platform {
+            Some(platform) => Self::parse(platform).map(Some),
+            None => match std::env::var(Self::DEFAULT_ENV) {
+                Ok(platform) if !platform.trim().is_empty() => Self::parse(&platform).map(Some),
+                _ => Ok(None),
+            },
+        }
+    }
+
+    /// Normalize a platform as found in a manifest list or image config
+    pub fn normalized(&self) -> Self {
+        Self::new(&self.os, &self.architecture, self.variant.as_deref())
+    }
+
+    /// Check whether an image built for `candidate` can run on this platform
+    ///
+    /// The OS and architecture must be equal. A numbered variant also accepts
+    /// older variants of the same architecture, so `linux/arm/v7` runs
+    /// `linux/arm/v6` images.
+    pub fn matches(&self, candidate: &Platform) -> bool {
+        let wanted = self.normalized();
+        let candidate = candidate.normalized();
+        if wanted.os != candidate.os || wanted.architecture != candidate.architecture {
+            return false;
+        }
+
+        match (&wanted.variant, &candidate.variant) {
+            (None, _) => true,
+            (Some(_), None) => true,
+            (Some(w), Some(c)) => match (variant_level(w), variant_level(c)) {
+                (Some(w), Some(c)) => c <= w,
+                _ => w == c,
+            },
+        }
+    }
+
+    /// Rank a matching candidate, higher is a closer match
+    pub fn match_rank(&self, candidate: &Platform) -> u32 {
+        let wanted = self.normalized();
+        let candidate = candidate.normalized();
+        if wanted.variant == candidate.variant {
+            return u32::MAX;
+        }
+        candidate
+            .variant
+            .as_deref()
+            .and_then(variant_level)
+            .map(|level| level + 1)
+            .unwrap_or(0)
+    }
+}
+
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+/// Numeric level of a `vN` architecture variant
+fn variant_level(variant: &str) -> Option<u32> {
+    variant.strip_prefix('v')?.parse().ok()
+}
+
 /// OCI Image Configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OciImageConfig {
     pub architecture: String,
     pub os: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub config: Option<ImageConfigSpec>,
     pub rootfs: RootFs,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub history: Option<Vec<History>>,
 }
 
@@ -213,19 +377,38 @@ pub struct History {
     pub empty_layer: Option<bool>,
 }
 
+/// Annotation keys
+pub mod annotations {
+    /// Name of a manifest in an OCI image layout, usually its tag
+    pub const REF_NAME: &str = "org.opencontainers.image.ref.name";
+    /// Full image name, as written by containerd and Docker
+    pub const IMAGE_NAME: &str = "io.containerd.image.name";
+}
+
 /// Media types
 pub mod media_types {
     pub const OCI_IMAGE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
     pub const OCI_IMAGE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
     pub const OCI_IMAGE_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+    pub const OCI_LAYER_TAR: &str = "application/vnd.oci.image.layer.v1.tar";
     pub const OCI_LAYER_TAR_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+    pub const OCI_LAYER_TAR_ZSTD: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+    pub const OCI_NONDISTRIBUTABLE_LAYER_TAR: &str =
+        "application/vnd.oci.image.layer.nondistributable.v1.tar";
+    pub const OCI_NONDISTRIBUTABLE_LAYER_TAR_GZIP: &str =
+        "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip";
+    pub const OCI_NONDISTRIBUTABLE_LAYER_TAR_ZSTD: &str =
+        "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd";
 
     pub const DOCKER_MANIFEST_V2: &str = "application/vnd.docker.distribution.manifest.v2+json";
     pub const DOCKER_MANIFEST_LIST: &str =
         "application/vnd.docker.distribution.manifest.list.v2+json";
     pub const DOCKER_CONTAINER_IMAGE: &str = "application/vnd.docker.container.image.v1+json";
+    pub const DOCKER_LAYER_TAR: &str = "application/vnd.docker.image.rootfs.diff.tar";
     pub const DOCKER_LAYER_TAR_GZIP: &str =
         "application/vnd.docker.image.rootfs.diff.tar.gzip";
+    pub const DOCKER_FOREIGN_LAYER_TAR_GZIP: &str =
+        "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip";
 }
 
 #[cfg(test)]
@@ -272,4 +455,73 @@ mod tests {
         let reference = ImageReference::parse("ghcr.io/owner/repo:tag").unwrap();
         assert_eq!(reference.full_name(), "ghcr.io/owner/repo:tag");
     }
+
+    #[test]
+    fn test_parse_image_with_digest() {
+        let digest = format!("sha256:{}", "ab".repeat(32));
+        let reference = ImageReference::parse(&format!("alpine@{}", digest)).unwrap();
+        assert_eq!(reference.repository, "library/alpine");
+        assert_eq!(reference.digest.as_deref(), Some(digest.as_str()));
+        assert_eq!(reference.manifest_reference(), digest);
+        assert_eq!(reference.full_name(), format!("library/alpine@{}", digest));
+
+        let reference =
+            ImageReference::parse(&format!("localhost:5000/app:v1@{}", digest)).unwrap();
+        assert_eq!(reference.registry, "localhost:5000");
+        assert_eq!(reference.repository, "app");
+        assert_eq!(reference.tag, "v1");
+        assert_eq!(reference.manifest_reference(), digest);
+
+        assert!(ImageReference::parse("alpine@sha256:abc").is_err());
+        assert!(ImageReference::parse("alpine@md5:abc").is_err());
+    }
+
+    #[test]
+    fn test_parse_platform() {
+        let platform = Platform::parse("linux/arm64/v8").unwrap();
+        assert_eq!(platform.to_string(), "linux/arm64");
+        assert_eq!(
+            Platform::parse("linux/aarch64").unwrap().to_string(),
+            "linux/arm64"
+        );
+        assert_eq!(
+            Platform::parse("linux/x86_64").unwrap().to_string(),
+            "linux/amd64"
+        );
+        assert_eq!(
+            Platform::parse("linux/arm").unwrap().to_string(),
+            "linux/arm/v7"
+        );
+        assert_eq!(
+            Platform::parse("linux/armhf").unwrap().to_string(),
+            "linux/arm/v7"
+        );
+        assert_eq!(
+            Platform::parse("Linux/ARM/6").unwrap().to_string(),
+            "linux/arm/v6"
+        );
+
+        assert!(Platform::parse("linux").is_err());
+        assert!(Platform::parse("linux/").is_err());
+        assert!(Platform::parse("linux/arm/v7/extra").is_err());
+    }
+
+    #[test]
+    fn test_platform_matching() {
+        let arm_v7 = Platform::parse("linux/arm/v7").unwrap();
+        let arm_v6 = Platform::parse("linux/arm/v6").unwrap();
+        let arm_v8 = Platform::new("linux", "arm", Some("v8"));
+
+        assert!(arm_v7.matches(&arm_v7));
+        assert!(arm_v7.matches(&arm_v6));
+        assert!(!arm_v6.matches(&arm_v7));
+        assert!(!arm_v7.matches(&arm_v8));
+        assert!(arm_v7.match_rank(&arm_v7) > arm_v7.match_rank(&arm_v6));
+
+        let arm64 = Platform::parse("linux/arm64").unwrap();
+        assert!(arm64.matches(&Platform::new("linux", "arm64", Some("v8"))));
+        assert!(arm64.matches(&Platform::new("linux", "aarch64", None)));
+        assert!(!arm64.matches(&arm_v7));
+        assert!(!arm64.matches(&Platform::new("darwin", "arm64", None)));
+    }
 }
@@ -0,0 +1,498 @@
+//! Layer transfer progress reporting
+//!
+//! The registry client reports the state of every layer it pulls to a
+//! [`ProgressSink`]. The sinks here render those updates as plain log lines,
+//! as a live per-layer display on a terminal, or as JSON lines.
+
+use std::collections::HashMap;
+use std::io::{IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Minimum interval between download updates in line-based output
+const LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Minimum interval between redraws of the terminal display
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Width of the progress bar in the terminal display
+const BAR_WIDTH: usize = 30;
+
+/// State of a single layer transfer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayerStatus {
+    /// Queued behind other downloads
+    Waiting,
+    /// Already present in the layer store
+    AlreadyExists,
+    /// Transferring, with bytes received so far and the layer size
+    Downloading { current: u64, total: u64 },
+    /// Checking the downloaded content against its digest
+    Verifying,
+    /// Unpacking into the layer store
+    Extracting,
+    /// Stored and ready to use
+    Complete,
+}
+
+impl LayerStatus {
+    /// Docker-style description of the status
+    pub fn label(&self) -> &'static str {
+        match self {
+            LayerStatus::Waiting => "Waiting",
+            LayerStatus::AlreadyExists => "Already exists",
+            LayerStatus::Downloading { .. } => "Downloading",
+            LayerStatus::Verifying => "Verifying Checksum",
+            LayerStatus::Extracting => "Extracting",
+            LayerStatus::Complete => "Pull complete",
+        }
+    }
+}
+
+/// Receiver of layer progress updates
+///
+/// Layers are identified by the short form of their digest. Updates for
+/// different layers may arrive interleaved when layers are pulled concurrently.
+pub trait ProgressSink: Send + Sync {
+    /// Record a new status for a layer
+    fn update(&self, id: &str, status: &LayerStatus);
+
+    /// Called once all layers have been handled
+    fn finish(&self) {}
+}
+
+/// Progress output style, as selected with `--progress`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressMode {
+    /// Live display on a terminal, plain lines otherwise
+    #[default]
+    Auto,
+    /// One line per status change, suitable for CI logs
+    Plain,
+    /// Live per-layer display
+    Tty,
+    /// One JSON object per line
+    Json,
+}
+
+impl ProgressMode {
+    /// Create a sink that writes progress in this mode to stderr
+    pub fn sink(self) -> Arc<dyn ProgressSink> {
+        match self {
+            ProgressMode::Auto if std::io::stderr().is_terminal() => Arc::new(TtyProgress::new()),
+            ProgressMode::Auto | ProgressMode::Plain => Arc::new(PlainProgress::new()),
+            ProgressMode::Tty => Arc::new(TtyProgress::new()),
+            ProgressMode::Json => Arc::new(JsonProgress::new()),
+        }
+    }
+}
+
+/// Sink that discards all updates
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn update(&self, _id: &str, _status: &LayerStatus) {}
+}
+
+/// Progress of one layer as tracked by the renderers
+#[derive(Debug, Clone)]
+struct LayerState {
+    status: LayerStatus,
+    started: Option<Instant>,
+    downloaded: u64,
+    last_logged: Option<Instant>,
+}
+
+impl LayerState {
+    /// Average transfer rate in bytes per second
+    fn rate(&self) -> f64 {
+        match self.started {
+            Some(started) => per_second(self.downloaded, started.elapsed()),
+            None => 0.0,
+        }
+    }
+}
+
+/// Per-layer bookkeeping shared by all renderers
+#[derive(Debug)]
+struct Tracker {
+    started: Instant,
+    order: Vec<String>,
+    layers: HashMap<String, LayerState>,
+}
+
+impl Tracker {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            order: Vec::new(),
+            layers: HashMap::new(),
+        }
+    }
+
+    /// Apply an update, returning whether the layer changed status
+    fn apply(&mut self, id: &str, status: &LayerStatus) -> bool {
+        if !self.layers.contains_key(id) {
+            self.order.push(id.to_string());
+        }
+        let layer = self.layers.entry(id.to_string()).or_insert(LayerState {
+            status: status.clone(),
+            started: None,
+            downloaded: 0,
+            last_logged: None,
+        });
+
+        let changed = std::mem::discriminant(&layer.status) != std::mem::discriminant(status);
+        if let LayerStatus::Downloading { current, .. } = status {
+            layer.started.get_or_insert_with(Instant::now);
+            layer.downloaded = *current;
+        }
+        layer.status = status.clone();
+        changed
+    }
+
+    /// Whether a line-based renderer should log this update
+    fn should_log(&mut self, id: &str, changed: bool) -> bool {
+        let Some(layer) = self.layers.get_mut(id) else {
+            return false;
+        };
+        let due = layer
+            .last_logged
+            .map(|at| at.elapsed() >= LOG_INTERVAL)
+            .unwrap_or(true);
+        if changed || due {
+            layer.last_logged = Some(Instant::now());
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Total bytes downloaded across all layers
+    fn downloaded(&self) -> u64 {
+        self.layers.values().map(|l| l.downloaded).sum()
+    }
+
+    /// One-line summary of the whole transfer
+    fn summary(&self) -> String {
+        let elapsed = self.started.elapsed();
+        format!(
+            "Downloaded {} in {:.1}s ({}/s)",
+            format_bytes(self.downloaded()),
+            elapsed.as_secs_f64(),
+            format_bytes(per_second(self.downloaded(), elapsed) as u64)
+        )
+    }
+}
+
+/// Renders updates as one line per status change
+pub struct PlainProgress {
+    tracker: Mutex<Tracker>,
+}
+
+impl PlainProgress {
+    /// Create a plain renderer writing to stderr
+    pub fn new() -> Self {
+        Self {
+            tracker: Mutex::new(Tracker::new()),
+        }
+    }
+}
+
+impl Default for PlainProgress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressSink for PlainProgress {
+    fn update(&self, id: &str, status: &LayerStatus) {
+        let mut tracker = self.tracker.lock().unwrap();
+        let changed = tracker.apply(id, status);
+        if tracker.should_log(id, changed) {
+            eprintln!("{}", plain_line(id, &tracker.layers[id]));
+        }
+    }
+
+    fn finish(&self) {
+        let tracker = self.tracker.lock().unwrap();
+        if tracker.downloaded() > 0 {
+            eprintln!("{}", tracker.summary());
+        }
+    }
+}
+
+/// Renders a live display with one redrawn line per layer
+pub struct TtyProgress {
+    state: Mutex<(Tracker, TtyState)>,
+}
+
+/// Redraw bookkeeping for [`TtyProgress`]
+struct TtyState {
+    lines_drawn: usize,
+    last_draw: Option<Instant>,
+}
+
+impl TtyProgress {
+    /// Create a terminal renderer writing to stderr
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new((
+                Tracker::new(),
+                TtyState {
+                    lines_drawn: 0,
+                    last_draw: None,
+                },
+            )),
+        }
+    }
+
+    fn redraw(tracker: &Tracker, tty: &mut TtyState) {
+        let mut out = String::new();
+        if tty.lines_drawn > 0 {
+            // Move back to the first layer line
+            out.push_str(&format!("\x1b[{}A", tty.lines_drawn));
+        }
+        for id in &tracker.order {
+            out.push_str("\x1b[2K");
+            out.push_str(&tty_line(id, &tracker.layers[id]));
+            out.push('\n');
+        }
+
+        let mut stderr = std::io::stderr().lock();
+        let _ = stderr.write_all(out.as_bytes());
+        let _ = stderr.flush();
+        tty.lines_drawn = tracker.order.len();
+        tty.last_draw = Some(Instant::now());
+    }
+}
+
+impl Default for TtyProgress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressSink for TtyProgress {
+    fn update(&self, id: &str, status: &LayerStatus) {
+        let mut guard = self.state.lock().unwrap();
+        let (tracker, tty) = &mut *guard;
+        let changed = tracker.apply(id, status);
+        let due = tty
+            .last_draw
+            .map(|at| at.elapsed() >= REDRAW_INTERVAL)
+            .unwrap_or(true);
+        if changed || due {
+            Self::redraw(tracker, tty);
+        }
+    }
+
+    fn finish(&self) {
+        let mut guard = self.state.lock().unwrap();
+        let (tracker, tty) = &mut *guard;
+        Self::redraw(tracker, tty);
+        if tracker.downloaded() > 0 {
+            eprintln!("{}", tracker.summary());
+        }
+    }
+}
+
+/// Renders updates as one JSON object per line
+pub struct JsonProgress {
+    tracker: Mutex<Tracker>,
+}
+
+impl JsonProgress {
+    /// Create a JSON renderer writing to stderr
+    pub fn new() -> Self {
+        Self {
+            tracker: Mutex::new(Tracker::new()),
+        }
+    }
+}
+
+impl Default for JsonProgress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressSink for JsonProgress {
+    fn update(&self, id: &str, status: &LayerStatus) {
+        let mut tracker = self.tracker.lock().unwrap();
+        let changed = tracker.apply(id, status);
+        if tracker.should_log(id, changed) {
+            eprintln!("{}", json_line(id, &tracker.layers[id]));
+        }
+    }
+
+    fn finish(&self) {
+        let tracker = self.tracker.lock().unwrap();
+        let elapsed = tracker.started.elapsed();
+        let summary = serde_json::json!({
+            "status": "Done",
+            "downloaded": tracker.downloaded(),
+            "seconds": elapsed.as_secs_f64(),
+            "rate": per_second(tracker.downloaded(), elapsed) as u64,
+        });
+        eprintln!("{}", summary);
+    }
+}
+
+/// Format a layer's state as a plain log line
+fn plain_line(id: &str, layer: &LayerState) -> String {
+    match layer.status {
+        LayerStatus::Downloading { current, total } => format!(
+            "{}: Downloading {}/{} ({}/s)",
+            id,
+            format_bytes(current),
+            format_bytes(total),
+            format_bytes(layer.rate() as u64)
+        ),
+        ref status => format!("{}: {}", id, status.label()),
+    }
+}
+
+/// Format a layer's state as a terminal line with a progress bar
+fn tty_line(id: &str, layer: &LayerState) -> String {
+    match layer.status {
+        LayerStatus::Downloading { current, total } => format!(
+            "{}: Downloading {} {}/{} {}/s",
+            id,
+            progress_bar(current, total),
+            format_bytes(current),
+            format_bytes(total),
+            format_bytes(layer.rate() as u64)
+        ),
+        ref status => format!("{}: {}", id, status.label()),
+    }
+}
+
+/// Format a layer's state as a JSON object
+fn json_line(id: &str, layer: &LayerState) -> serde_json::Value {
+    let mut value = serde_json::json!({
+        "id": id,
+        "status": layer.status.label(),
+    });
+    if let LayerStatus::Downloading { current, total } = layer.status {
+        value["current"] = current.into();
+        value["total"] = total.into();
+        value["rate"] = (layer.rate() as u64).into();
+    }
+    value
+}
+
+/// Render a fixed-width progress bar
+fn progress_bar(current: u64, total: u64) -> String {
+    let filled = if total == 0 {
+        0
+    } else {
+        ((current.min(total) as f64 / total as f64) * BAR_WIDTH as f64) as usize
+    };
+    let head = if filled < BAR_WIDTH { ">" } else { "" };
+    format!(
+        "[{}{}{}]",
+        "=".repeat(filled),
+        head,
+        " ".repeat(BAR_WIDTH - filled - head.len())
+    )
+}
+
+/// Bytes per second over an elapsed duration
+fn per_second(bytes: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        bytes as f64 / secs
+    } else {
+        0.0
+    }
+}
+
+/// Format a byte count as a human-readable string
+fn format_bytes(bytes: u64) -> String {
+    const KB: u64 = 1024;
+    const MB: u64 = KB * 1024;
+    const GB: u64 = MB * 1024;
+
+    if bytes >= GB {
+        format!("{:.2}GB", bytes as f64 / GB as f64)
+    } else if bytes >= MB {
+        format!("{:.2}MB", bytes as f64 / MB as f64)
+    } else if bytes >= KB {
+        format!("{:.2}KB", bytes as f64 / KB as f64)
+    } else {
+        format!("{}B", bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_bar() {
+        assert_eq!(progress_bar(0, 100), format!("[>{}]", " ".repeat(29)));
+        assert_eq!(
+            progress_bar(50, 100),
+            format!("[{}>{}]", "=".repeat(15), " ".repeat(14))
+        );
+        assert_eq!(progress_bar(100, 100), format!("[{}]", "=".repeat(30)));
+        assert_eq!(progress_bar(10, 0), format!("[>{}]", " ".repeat(29)));
+    }
+
+    #[test]
+    fn test_tracker_logs_status_changes_and_throttles_downloads() {
+        let mut tracker = Tracker::new();
+        let downloading = |current| LayerStatus::Downloading {
+            current,
+            total: 2048,
+        };
+
+        let changed = tracker.apply("abc", &LayerStatus::Waiting);
+        assert!(tracker.should_log("abc", changed));
+
+        let changed = tracker.apply("abc", &downloading(512));
+        assert!(tracker.should_log("abc", changed));
+        let changed = tracker.apply("abc", &downloading(1024));
+        assert!(!tracker.should_log("abc", changed));
+
+        let changed = tracker.apply("abc", &LayerStatus::Complete);
+        assert!(tracker.should_log("abc", changed));
+
+        tracker.apply("def", &LayerStatus::AlreadyExists);
+        assert_eq!(tracker.order, vec!["abc", "def"]);
+        assert_eq!(tracker.downloaded(), 1024);
+    }
+
+    #[test]
+    fn test_line_formats() {
+        let layer = LayerState {
+            status: LayerStatus::Downloading {
+                current: 1536,
+                total: 3 * 1024 * 1024,
+            },
+            started: None,
+            downloaded: 1536,
+            last_logged: None,
+        };
+        assert_eq!(
+            plain_line("abc", &layer),
+            "abc: Downloading 1.50KB/3.00MB (0B/s)"
+        );
+
+        let json = json_line("abc", &layer);
+        assert_eq!(json["status"], "Downloading");
+        assert_eq!(json["current"], 1536);
+        assert_eq!(json["total"], 3 * 1024 * 1024);
+
+        let done = LayerState {
+            status: LayerStatus::Complete,
+            ..layer
+        };
+        assert_eq!(plain_line("abc", &done), "abc: Pull complete");
+        assert_eq!(
+            json_line("abc", &done),
+            serde_json::json!({"id": "abc", "status": "Pull complete"})
+        );
+    }
+}
@@ -1,17 +1,58 @@
 //! Docker Registry HTTP API V2 client
 
-use crate::image::layer::LayerManager;
-use crate::image::oci::{ImageIndex, ImageManifest, ImageReference, OciImageConfig};
-use crate::storage::images::ImageStore;
+use crate::image::auth::AuthChallenge;
+use crate::image::layer::{Compression, LayerManager};
+use crate::image::oci::{
+    media_types, Descriptor, ImageConfigSpec, ImageIndex, ImageManifest,
+    ImageReference, ManifestDescriptor, OciImageConfig, Platform, RootFs,
+};
+use crate::image::progress::{LayerStatus, ProgressMode, ProgressSink};
+use crate::image::retry::{is_retryable_status, parse_retry_after, RetryPolicy};
+use crate::storage::blobs::BlobStore;
+use crate::storage::credentials::{CredentialStore, Credentials};
+use crate::storage::images::{ImageMetadata, ImageStore};
+use crate::storage::manifest_lists::ManifestList;
 use crate::storage::paths::DarkerPaths;
-use crate::{DarkerError, Result};
-use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION};
+use crate::storage::registries::{RegistriesConfig, RegistryLocation};
+use crate::{DarkerError, RegistryError, Result};
+use reqwest::header::{
+    HeaderMap, HeaderValue, ACCEPT, ACCEPT_RANGES, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE,
+    LINK, LOCATION, RANGE, RETRY_AFTER, WWW_AUTHENTICATE,
+};
+use reqwest::StatusCode;
 use serde::Deserialize;
-use std::fs;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Number of layers downloaded at the same time by default
+pub const DEFAULT_MAX_CONCURRENT_DOWNLOADS: usize = 3;
+
+/// Accept header for single image manifests
+const IMAGE_MANIFEST_ACCEPT: &str = "application/vnd.docker.distribution.manifest.v2+json, \
+                                     application/vnd.oci.image.manifest.v1+json";
+
+/// Accept header for image manifests and manifest lists
+const MANIFEST_ACCEPT: &str = "application/vnd.docker.distribution.manifest.v2+json, \
+                               application/vnd.oci.image.manifest.v1+json, \
+                               application/vnd.docker.distribution.manifest.list.v2+json, \
+                               application/vnd.oci.image.index.v1+json";
 
 /// Registry client for pulling and pushing images
+#[derive(Clone)]
 pub struct RegistryClient {
     client: reqwest::Client,
+    registries: RegistriesConfig,
+    tls_clients: HashMap<String, reqwest::Client>,
+    quiet: bool,
+    max_concurrent_downloads: usize,
+    progress: Arc<dyn ProgressSink>,
+    platform: Option<Platform>,
+    retry: RetryPolicy,
 }
 
 impl RegistryClient {
@@ -20,46 +61,218 @@ impl RegistryClient {
         let client = reqwest::Client::builder()
             .user_agent("darker/0.1.0")
             .build()
-            .map_err(|e| DarkerError::Http(e))?;
+            .map_err(DarkerError::Http)?;
+
+        Ok(Self {
+            client,
+            registries: RegistriesConfig::default(),
+            tls_clients: HashMap::new(),
+            quiet: false,
+            max_concurrent_downloads: DEFAULT_MAX_CONCURRENT_DOWNLOADS,
+            progress: ProgressMode::default().sink(),
+            platform: None,
+            retry: RetryPolicy::default(),
+        })
+    }
+
+    /// Create a registry client using the registry settings under the darker root
+    pub fn configured(paths: &DarkerPaths) -> Result<Self> {
+        Self::new()?.with_registries(RegistriesConfig::load(paths)?)
+    }
+
+    /// Use mirror and connection settings for registries
+    ///
+    /// Registries with TLS settings get their own HTTP client.
+    pub fn with_registries(mut self, registries: RegistriesConfig) -> Result<Self> {
+        self.tls_clients.clear();
+        for host in registries.registries.keys() {
+            let settings = registries.settings(host);
+            if !settings.skip_verify && settings.ca_file.is_none() {
+                continue;
+            }
+
+            let mut builder = reqwest::Client::builder()
+                .user_agent("darker/0.1.0")
+                .danger_accept_invalid_certs(settings.skip_verify);
+            if let Some(ca_file) = &settings.ca_file {
+                let pem = fs::read(ca_file).map_err(|e| {
+                    RegistryError::Other(format!(
+                        "Failed to read CA file {} for {}: {}",
+                        ca_file.display(),
+                        host,
+                        e
+                    ))
+                })?;
+                for certificate in reqwest::Certificate::from_pem_bundle(&pem)? {
+                    builder = builder.add_root_certificate(certificate);
+                }
+            }
+            let client = builder.build().map_err(DarkerError::Http)?;
+            self.tls_clients
+                .insert(registries.location(host).host, client);
+        }
+
+        self.registries = registries;
+        Ok(self)
+    }
+
+    /// Suppress per-layer progress output
+    pub fn with_quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
 
-        Ok(Self { client })
+    /// Set how many layers are downloaded at the same time
+    pub fn with_max_concurrent_downloads(mut self, limit: usize) -> Self {
+        self.max_concurrent_downloads = limit.max(1);
+        self
+    }
+
+    /// Set the platform to pull from multi-platform images
+    ///
+    /// Without one, linux and then darwin images for the host architecture
+    /// are accepted.
+    pub fn with_platform(mut self, platform: Option<Platform>) -> Self {
+        self.platform = platform;
+        self
+    }
+
+    /// Platform requested for pulls, if any
+    pub fn platform(&self) -> Option<&Platform> {
+        self.platform.as_ref()
+    }
+
+    /// Set where per-layer progress is reported
+    pub fn with_progress(mut self, progress: Arc<dyn ProgressSink>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Set how transient registry failures are retried
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
     }
 
     /// Pull an image from a registry
+    ///
+    /// References pinned by digest are fetched by that digest. The digest of
+    /// the pulled manifest is recorded as a repo digest of the image.
+    ///
+    /// Configured mirrors of the registry are tried in order before the
+    /// registry itself.
     pub async fn pull(&self, reference: &ImageReference, paths: &DarkerPaths) -> Result<String> {
-        // Get authentication token
-        let token = self.get_auth_token(reference).await?;
+        for location in self.registries.mirror_locations(&reference.registry) {
+            let endpoint = self.endpoint(location);
+            match self.pull_from(&endpoint, reference, paths).await {
+                Ok(image_id) => return Ok(image_id),
+                Err(e) => {
+                    if !self.quiet {
+                        eprintln!(
+                            "Failed to pull from mirror {}: {}",
+                            endpoint.location.url, e
+                        );
+                    }
+                }
+            }
+        }
+
+        let endpoint = self.endpoint(self.registries.location(&reference.registry));
+        self.pull_from(&endpoint, reference, paths).await
+    }
+
+    /// Pull an image from a single registry or mirror
+    async fn pull_from(
+        &self,
+        endpoint: &Endpoint,
+        reference: &ImageReference,
+        paths: &DarkerPaths,
+    ) -> Result<String> {
+        // Authenticate for pulling from the repository
+        let auth = self.authenticate(endpoint, reference, "pull", paths).await?;
 
         // Fetch manifest
-        let manifest = self.fetch_manifest(reference, &token).await?;
+        let resolved = self.fetch_manifest(endpoint, reference, &auth).await?;
+        let manifest = &resolved.manifest;
+
+        // Fetch config, unless a local image already has it
+        let blobs = BlobStore::new(paths);
+        let config_bytes = match blobs.read(&manifest.config.digest) {
+            Ok(bytes) => bytes,
+            Err(_) => {
+                self.fetch_config(endpoint, reference, &manifest.config.digest, &auth)
+                    .await?
+            }
+        };
+        let config: OciImageConfig = serde_json::from_slice(&config_bytes)
+            .map_err(|e| RegistryError::Other(format!("Failed to parse image config: {}", e)))?;
+
+        // Single-platform images must still match an explicitly requested platform
+        let platform = Platform::new(&config.os, &config.architecture, config.variant.as_deref());
+        if let Some(wanted) = &self.platform {
+            if !wanted.matches(&platform) {
+                return Err(RegistryError::Other(format!(
+                    "image {} does not match the specified platform: wanted {}, actual {}",
+                    reference.full_name(),
+                    wanted,
+                    platform
+                ))
+                .into());
+            }
+        }
 
-        // Fetch config
-        let config = self.fetch_config(reference, &manifest.config.digest, &token).await?;
+        // Layers are stored by diff ID, so the same content is shared whatever
+        // compression or registry it comes from
+        if config.rootfs.diff_ids.len() != manifest.layers.len() {
+            return Err(DarkerError::Layer(format!(
+                "Config {} lists {} layers, but the manifest has {}",
+                manifest.config.digest,
+                config.rootfs.diff_ids.len(),
+                manifest.layers.len()
+            )));
+        }
 
-        // Pull layers
+        // Work out which layers are missing locally
         let layer_manager = LayerManager::new(paths);
         let mut layer_digests = Vec::new();
+        let mut blob_digests = Vec::new();
         let mut total_size: u64 = 0;
+        let mut missing: Vec<(&Descriptor, &str)> = Vec::new();
 
-        for (idx, layer) in manifest.layers.iter().enumerate() {
-            let digest = layer.digest.strip_prefix("sha256:").unwrap_or(&layer.digest);
+        for (layer, diff_id) in manifest.layers.iter().zip(&config.rootfs.diff_ids) {
+            let digest = diff_id.strip_prefix("sha256:").unwrap_or(diff_id);
 
-            eprintln!(
-                "Pulling layer {}/{}: {}",
-                idx + 1,
-                manifest.layers.len(),
-                &digest[..12]
-            );
-
-            if !layer_manager.exists(digest) {
-                self.fetch_layer(reference, &layer.digest, &token, paths)
-                    .await?;
+            if layer_manager.exists(digest) {
+                self.report(&layer.digest, &LayerStatus::AlreadyExists);
+            } else if !missing.iter().any(|(_, d)| *d == diff_id) {
+                self.report(&layer.digest, &LayerStatus::Waiting);
+                missing.push((layer, diff_id));
             }
 
             layer_digests.push(digest.to_string());
+            blob_digests.push(layer.digest.clone());
             total_size += layer.size as u64;
         }
 
+        // Pull missing layers concurrently
+        {
+            use futures_util::{StreamExt, TryStreamExt};
+
+            // Interrupted downloads resume from what was already received
+            futures_util::stream::iter(missing)
+                .map(|(layer, diff_id)| {
+                    self.retrying(|| {
+                        self.fetch_layer(endpoint, reference, layer, diff_id, &auth, paths)
+                    })
+                })
+                .buffer_unordered(self.max_concurrent_downloads)
+                .try_collect::<Vec<()>>()
+                .await?;
+        }
+        if !self.quiet {
+            self.progress.finish();
+        }
+
         // Calculate image ID from config digest
         let image_id = manifest
             .config
@@ -68,304 +281,2941 @@ impl RegistryClient {
             .unwrap_or(&manifest.config.digest)
             .to_string();
 
-        // Store image metadata
+        // Store image metadata; images pulled by digest aren't tagged
         let image_store = ImageStore::new(paths)?;
+        let repository = reference.repository_with_registry();
+        let tag = match reference.digest {
+            Some(_) => None,
+            None => Some(reference.tag.as_str()),
+        };
         image_store.store(
             &image_id,
-            Some(&reference.repository_with_registry()),
-            Some(&reference.tag),
+            Some(&repository),
+            tag,
             Some(&manifest.config.digest),
             &layer_digests,
             total_size,
         )?;
+        image_store.add_repo_digest(&image_id, &repository, &resolved.digest())?;
+        image_store.set_platform(&image_id, &platform)?;
+        image_store.set_blob_digests(&image_id, &blob_digests)?;
 
-        // Store config
-        let config_path = paths.image_config(&image_id);
-        let config_json = serde_json::to_string_pretty(&config)?;
-        fs::write(&config_path, config_json)?;
-
-        // Store manifest
-        let manifest_path = paths.image_manifest(&image_id);
-        let manifest_json = serde_json::to_string_pretty(&manifest)?;
-        fs::write(&manifest_path, manifest_json)?;
+        // Keep the config, manifest and index exactly as the registry served them
+        blobs.write(&config_bytes)?;
+        let manifest_digest = blobs.write(&resolved.bytes)?;
+        let index_digest = resolved.index.as_deref().map(|i| blobs.write(i)).transpose()?;
+        image_store.set_manifest(&image_id, &manifest_digest, index_digest.as_deref())?;
 
         Ok(image_id)
     }
 
     /// Push an image to a registry
+    ///
+    /// Uploads every layer and the config blob that the registry doesn't
+    /// already have, then puts the manifest under the reference's tag.
+    /// Returns the manifest digest and its size in bytes.
     pub async fn push(
         &self,
-        _reference: &ImageReference,
-        _image_id: &str,
-        _paths: &DarkerPaths,
-    ) -> Result<()> {
-        // Push is more complex and requires proper authentication
-        // This is a placeholder for the full implementation
-        Err(DarkerError::Unsupported(
-            "Push is not yet implemented".to_string(),
-        ))
-    }
-
-    /// Get authentication token for a registry
-    async fn get_auth_token(&self, reference: &ImageReference) -> Result<Option<String>> {
-        // For Docker Hub, we need to get a token
-        if reference.registry == "docker.io" {
-            let url = format!(
-                "https://auth.docker.io/token?service=registry.docker.io&scope=repository:{}:pull",
-                reference.repository
-            );
+        reference: &ImageReference,
+        image_id: &str,
+        paths: &DarkerPaths,
+    ) -> Result<(String, usize)> {
+        let endpoint = self.endpoint(self.registries.location(&reference.registry));
+        let auth = self
+            .authenticate(&endpoint, reference, "pull,push", paths)
+            .await?;
 
-            let response = self.client.get(&url).send().await?;
+        let local = LocalManifest::load(paths, image_id)?;
+        self.upload_image(&endpoint, reference, &local, &auth).await?;
 
-            if response.status().is_success() {
-                let body: TokenResponse = response.json().await?;
-                return Ok(Some(body.token));
+        // Upload manifest
+        let manifest_size = local.manifest.len();
+        self.put_manifest(
+            &endpoint,
+            reference,
+            &reference.tag,
+            &local.media_type,
+            local.manifest.clone(),
+            &auth,
+        )
+        .await?;
+
+        Ok((local.digest(), manifest_size))
+    }
+
+    /// Upload the layers and config of a local image that the registry doesn't already have
+    async fn upload_image(
+        &self,
+        endpoint: &Endpoint,
+        reference: &ImageReference,
+        local: &LocalManifest,
+        auth: &Option<String>,
+    ) -> Result<()> {
+        for (tar_path, layer) in &local.layers {
+            let short = &layer.digest.trim_start_matches("sha256:")[..12];
+            if self.blob_exists(endpoint, reference, &layer.digest, auth).await? {
+                if !self.quiet {
+                    eprintln!("{}: Layer already exists", short);
+                }
+                continue;
+            }
+
+            // The streamed upload can't be replayed, so retries start a new one
+            let size = layer.size as u64;
+            self.retrying(|| async {
+                let file = tokio::fs::File::open(tar_path).await?;
+                let body = reqwest::Body::wrap_stream(tokio_util::io::ReaderStream::new(file));
+                self.upload_blob(endpoint, reference, &layer.digest, body, size, auth)
+                    .await
+            })
+            .await?;
+            if !self.quiet {
+                eprintln!("{}: Pushed", short);
             }
         }
 
-        // Try anonymous access
-        Ok(None)
+        // Upload config
+        let config_digest = LayerManager::compute_digest_bytes(&local.config);
+        if !self
+            .blob_exists(endpoint, reference, &config_digest, auth)
+            .await?
+        {
+            let size = local.config.len() as u64;
+            self.retrying(|| {
+                let body = local.config.clone().into();
+                self.upload_blob(endpoint, reference, &config_digest, body, size, auth)
+            })
+            .await?;
+        }
+
+        Ok(())
     }
 
-    /// Fetch image manifest (handles manifest lists/indexes for multi-platform images)
-    async fn fetch_manifest(
+    /// Put a manifest or index into a repository under a tag or digest
+    async fn put_manifest(
         &self,
+        endpoint: &Endpoint,
         reference: &ImageReference,
-        token: &Option<String>,
-    ) -> Result<ImageManifest> {
-        let url = format!(
-            "{}/v2/{}/manifests/{}",
-            reference.registry_url(),
-            reference.repository,
-            reference.tag
+        manifest_ref: &str,
+        media_type: &str,
+        manifest: Vec<u8>,
+        auth: &Option<String>,
+    ) -> Result<()> {
+        let url = endpoint.url(reference, &format!("manifests/{}", manifest_ref));
+        let mut headers = auth_headers(auth)?;
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_str(media_type).map_err(|e| RegistryError::Other(e.to_string()))?,
         );
 
-        let mut headers = HeaderMap::new();
-        // Accept both single manifests and manifest lists
+        let response = self
+            .send(endpoint.client.put(&url).headers(headers).body(manifest))
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(status_error(endpoint, "push manifest", &response).into());
+        }
+        Ok(())
+    }
+
+    /// Push a local manifest list to the registry as an image index
+    ///
+    /// Entries made from local images are pushed to the list's repository
+    /// first. Manifests in other repositories are copied there along with
+    /// their blobs. Returns the index digest and its size in bytes.
+    pub async fn push_manifest_list(
+        &self,
+        list: &ManifestList,
+        paths: &DarkerPaths,
+    ) -> Result<(String, usize)> {
+        let reference = ImageReference::parse(&list.name)?;
+        let endpoint = self.endpoint(self.registries.location(&reference.registry));
+        let auth = self
+            .authenticate(&endpoint, &reference, "pull,push", paths)
+            .await?;
+
+        let mut index = list.index();
+        for (entry, descriptor) in list.entries.iter().zip(index.manifests.iter_mut()) {
+            if let Some(image_id) = &entry.image_id {
+                let local = LocalManifest::load(paths, image_id)?;
+                self.upload_image(&endpoint, &reference, &local, &auth)
+                    .await?;
+                descriptor.digest = local.digest();
+                descriptor.size = local.manifest.len() as i64;
+                self.put_manifest(
+                    &endpoint,
+                    &reference,
+                    &descriptor.digest,
+                    &local.media_type,
+                    local.manifest,
+                    &auth,
+                )
+                .await?;
+                continue;
+            }
+
+            let source = ImageReference::parse(&entry.source)?;
+            if source.repository_with_registry() != reference.repository_with_registry() {
+                self.copy_manifest(&source, &descriptor.digest, &endpoint, &reference, &auth, paths)
+                    .await?;
+            }
+        }
+
+        let index_bytes = serde_json::to_vec(&index)?;
+        let index_digest = LayerManager::compute_digest_bytes(&index_bytes);
+        let index_size = index_bytes.len();
+        self.put_manifest(
+            &endpoint,
+            &reference,
+            reference.manifest_reference(),
+            media_types::OCI_IMAGE_INDEX,
+            index_bytes,
+            &auth,
+        )
+        .await?;
+
+        Ok((index_digest, index_size))
+    }
+
+    /// Copy an image manifest and its blobs from another repository
+    async fn copy_manifest(
+        &self,
+        source: &ImageReference,
+        digest: &str,
+        endpoint: &Endpoint,
+        reference: &ImageReference,
+        auth: &Option<String>,
+        paths: &DarkerPaths,
+    ) -> Result<()> {
+        let source_endpoint = self.endpoint(self.registries.location(&source.registry));
+        let source_auth = self
+            .authenticate(&source_endpoint, source, "pull", paths)
+            .await?;
+
+        let (content_type, body) = self
+            .fetch_manifest_bytes(
+                &source_endpoint,
+                source,
+                digest,
+                IMAGE_MANIFEST_ACCEPT,
+                &source_auth,
+            )
+            .await?;
+        verify_manifest(
+            &source_endpoint,
+            digest,
+            &format!("sha256:{:x}", Sha256::digest(&body)),
+        )?;
+        let manifest: ImageManifest = serde_json::from_slice(&body)
+            .map_err(|e| RegistryError::Other(format!("Failed to parse manifest: {}", e)))?;
+
+        for blob in std::iter::once(&manifest.config).chain(&manifest.layers) {
+            if self
+                .blob_exists(endpoint, reference, &blob.digest, auth)
+                .await?
+            {
+                continue;
+            }
+
+            // Stream the blob from the source repository into an upload
+            let url = source_endpoint.url(source, &format!("blobs/{}", blob.digest));
+            self.retrying(|| async {
+                let response = self
+                    .get_blob(&source_endpoint.client, &url, 0, &source_auth)
+                    .await?;
+                if !response.status().is_success() {
+                    return Err(status_error(&source_endpoint, "fetch blob", &response).into());
+                }
+                let body = reqwest::Body::wrap_stream(response.bytes_stream());
+                self.upload_blob(endpoint, reference, &blob.digest, body, blob.size as u64, auth)
+                    .await
+            })
+            .await?;
+        }
+
+        let media_type = match manifest.media_type {
+            Some(media_type) if content_type.is_empty() => media_type,
+            _ if content_type.is_empty() => media_types::OCI_IMAGE_MANIFEST.to_string(),
+            _ => content_type,
+        };
+        self.put_manifest(endpoint, reference, digest, &media_type, body, auth)
+            .await
+    }
+
+    /// Check whether a blob already exists in the repository
+    async fn blob_exists(
+        &self,
+        endpoint: &Endpoint,
+        reference: &ImageReference,
+        digest: &str,
+        auth: &Option<String>,
+    ) -> Result<bool> {
+        let url = endpoint.url(reference, &format!("blobs/{}", digest));
+
+        let response = self
+            .send(endpoint.client.head(&url).headers(auth_headers(auth)?))
+            .await?;
+
+        match response.status() {
+            status if status.is_success() => Ok(true),
+            reqwest::StatusCode::NOT_FOUND => Ok(false),
+            _ => Err(status_error(endpoint, &format!("check blob {}", digest), &response).into()),
+        }
+    }
+
+    /// Upload a blob using a POST/PATCH/PUT upload session
+    ///
+    /// The requests aren't retried individually, since the upload session
+    /// can't continue after a failed PATCH.
+    async fn upload_blob(
+        &self,
+        endpoint: &Endpoint,
+        reference: &ImageReference,
+        digest: &str,
+        body: reqwest::Body,
+        size: u64,
+        auth: &Option<String>,
+    ) -> Result<()> {
+        // Start the upload session
+        let url = endpoint.url(reference, "blobs/uploads/");
+        let mut headers = auth_headers(auth)?;
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(0));
+
+        let response = endpoint.client.post(&url).headers(headers).send().await?;
+        if !response.status().is_success() {
+            return Err(status_error(endpoint, "start blob upload", &response).into());
+        }
+        let location = upload_location(endpoint, &response)?;
+
+        // Send the blob contents
+        let mut headers = auth_headers(auth)?;
         headers.insert(
-            ACCEPT,
-            HeaderValue::from_static(
-                "application/vnd.docker.distribution.manifest.v2+json, \
-                 application/vnd.oci.image.manifest.v1+json, \
-                 application/vnd.docker.distribution.manifest.list.v2+json, \
-                 application/vnd.oci.image.index.v1+json",
-            ),
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
         );
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(size));
 
-        if let Some(token) = token {
-            headers.insert(
-                AUTHORIZATION,
-                HeaderValue::from_str(&format!("Bearer {}", token))
-                    .map_err(|_| DarkerError::Registry("Invalid token".to_string()))?,
-            );
+        let response = endpoint
+            .client
+            .patch(&location)
+            .headers(headers)
+            .body(body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let action = format!("upload blob {}", digest);
+            return Err(status_error(endpoint, &action, &response).into());
         }
+        let location = upload_location(endpoint, &response)?;
 
-        let response = self.client.get(&url).headers(headers.clone()).send().await?;
+        // Commit the upload
+        let mut headers = auth_headers(auth)?;
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(0));
 
+        let response = endpoint
+            .client
+            .put(&location)
+            .query(&[("digest", digest)])
+            .headers(headers)
+            .send()
+            .await?;
         if !response.status().is_success() {
-            return Err(DarkerError::Registry(format!(
-                "Failed to fetch manifest: {}",
-                response.status()
-            )));
+            let action = format!("commit blob {}", digest);
+            return Err(status_error(endpoint, &action, &response).into());
         }
 
-        // Get the content type to determine what we received
-        let content_type = response
-            .headers()
-            .get("content-type")
-            .and_then(|v| v.to_str().ok())
-            .unwrap_or("")
-            .to_string();
+        Ok(())
+    }
 
-        let body = response.text().await?;
+    /// List the tags of a repository
+    pub async fn list_tags(
+        &self,
+        reference: &ImageReference,
+        paths: &DarkerPaths,
+    ) -> Result<Vec<String>> {
+        let endpoint = self.endpoint(self.registries.location(&reference.registry));
+        let auth = self.authenticate(&endpoint, reference, "pull", paths).await?;
+        let url = endpoint.url(reference, "tags/list");
 
-        // Check if this is a manifest list/index
-        if content_type.contains("manifest.list") || content_type.contains("image.index") {
-            let index: ImageIndex = serde_json::from_str(&body)
-                .map_err(|e| DarkerError::Registry(format!("Failed to parse manifest list: {}", e)))?;
+        let pages: Vec<TagList> = self.fetch_pages(&endpoint, url, &auth).await?;
+        Ok(pages
+            .into_iter()
+            .flat_map(|page| page.tags.unwrap_or_default())
+            .collect())
+    }
 
-            // Find the manifest for our platform (macOS/darwin)
-            let arch = get_host_arch();
-            let platform_manifest = index
-                .manifests
-                .iter()
-                .find(|m| {
-                    if let Some(ref platform) = m.platform {
-                        // Look for linux (most containers are linux-based) or darwin
-                        // Prefer linux since macOS containers aren't common
-                        (platform.os == "linux" || platform.os == "darwin")
-                            && platform.architecture == arch
-                    } else {
-                        false
-                    }
-                })
-                .or_else(|| {
-                    // Fallback: find any manifest for our arch
-                    index.manifests.iter().find(|m| {
-                        m.platform
-                            .as_ref()
-                            .map(|p| p.architecture == arch)
-                            .unwrap_or(false)
-                    })
-                })
-                .or_else(|| {
-                    // Final fallback: just use the first one
-                    index.manifests.first()
-                })
-                .ok_or_else(|| DarkerError::Registry("No suitable manifest found in index".to_string()))?;
-
-            // Fetch the actual manifest by digest
-            let manifest_url = format!(
-                "{}/v2/{}/manifests/{}",
-                reference.registry_url(),
-                reference.repository,
-                platform_manifest.digest
-            );
+    /// List the repositories in a registry
+    pub async fn catalog(&self, registry: &str, paths: &DarkerPaths) -> Result<Vec<String>> {
+        let endpoint = self.endpoint(self.registries.location(registry));
+        let credentials = CredentialStore::new(paths)?.get(&endpoint.location.host)?;
+        let auth = self
+            .authorize(&endpoint, credentials.as_ref(), Some("registry:catalog:*"))
+            .await?;
+        let url = format!("{}/v2/_catalog", endpoint.location.url);
 
-            // Update headers to only accept single manifests
-            headers.insert(
-                ACCEPT,
-                HeaderValue::from_static(
-                    "application/vnd.docker.distribution.manifest.v2+json, \
-                     application/vnd.oci.image.manifest.v1+json",
-                ),
-            );
+        let pages: Vec<Catalog> = self.fetch_pages(&endpoint, url, &auth).await?;
+        Ok(pages
+            .into_iter()
+            .flat_map(|page| page.repositories.unwrap_or_default())
+            .collect())
+    }
 
-            let manifest_response = self.client.get(&manifest_url).headers(headers).send().await?;
+    /// Fetch every page of a paginated listing, following `Link` headers
+    async fn fetch_pages<T: serde::de::DeserializeOwned>(
+        &self,
+        endpoint: &Endpoint,
+        url: String,
+        auth: &Option<String>,
+    ) -> Result<Vec<T>> {
+        let mut pages = Vec::new();
+        let mut next = Some(url);
 
-            if !manifest_response.status().is_success() {
-                return Err(DarkerError::Registry(format!(
-                    "Failed to fetch platform manifest: {}",
-                    manifest_response.status()
-                )));
+        while let Some(url) = next.take() {
+            let response = self
+                .send(endpoint.client.get(&url).headers(auth_headers(auth)?))
+                .await?;
+
+            if !response.status().is_success() {
+                let action = format!("list {}", url);
+                return Err(status_error(endpoint, &action, &response).into());
             }
 
-            let manifest: ImageManifest = manifest_response.json().await?;
-            Ok(manifest)
-        } else {
-            // It's already a single manifest
-            let manifest: ImageManifest = serde_json::from_str(&body)
-                .map_err(|e| DarkerError::Registry(format!("Failed to parse manifest: {}", e)))?;
-            Ok(manifest)
+            next = response
+                .headers()
+                .get(LINK)
+                .and_then(|v| v.to_str().ok())
+                .and_then(next_link)
+                .map(|link| resolve_url(endpoint, &link));
+            pages.push(response.json().await?);
         }
+
+        Ok(pages)
     }
 
-    /// Fetch image config
-    async fn fetch_config(
+    /// Log in to a registry, verifying the credentials
+    pub async fn login(&self, registry: &str, credentials: &Credentials) -> Result<()> {
+        let endpoint = self.endpoint(self.registries.location(registry));
+        let auth = self.authorize(&endpoint, Some(credentials), None).await?;
+
+        let response = self
+            .send(
+                endpoint
+                    .client
+                    .get(format!("{}/v2/", endpoint.location.url))
+                    .headers(auth_headers(&auth)?),
+            )
+            .await?;
+
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return Err(status_error(&endpoint, "log in", &response).into());
+        }
+
+        Ok(())
+    }
+
+    /// Authenticate for the given actions ("pull" or "pull,push") on a repository
+    ///
+    /// Uses the credentials saved by `darker login` for the registry, if any.
+    /// Returns the `Authorization` header value to send, or None for anonymous access.
+    async fn authenticate(
         &self,
+        endpoint: &Endpoint,
         reference: &ImageReference,
-        digest: &str,
-        token: &Option<String>,
-    ) -> Result<OciImageConfig> {
-        let url = format!(
-            "{}/v2/{}/blobs/{}",
-            reference.registry_url(),
-            reference.repository,
-            digest
+        actions: &str,
+        paths: &DarkerPaths,
+    ) -> Result<Option<String>> {
+        let credentials = CredentialStore::new(paths)?.get(&endpoint.location.host)?;
+        let scope = format!(
+            "repository:{}:{}",
+            endpoint.location.repository(&reference.repository),
+            actions
         );
+        self.authorize(endpoint, credentials.as_ref(), Some(&scope))
+            .await
+    }
 
-        let mut headers = HeaderMap::new();
-        if let Some(token) = token {
-            headers.insert(
-                AUTHORIZATION,
-                HeaderValue::from_str(&format!("Bearer {}", token))
-                    .map_err(|_| DarkerError::Registry("Invalid token".to_string()))?,
-            );
+    /// Answer the registry's `WWW-Authenticate` challenge
+    async fn authorize(
+        &self,
+        endpoint: &Endpoint,
+        credentials: Option<&Credentials>,
+        scope: Option<&str>,
+    ) -> Result<Option<String>> {
+        let registry_url = &endpoint.location.url;
+        let response = self
+            .send(endpoint.client.get(format!("{}/v2/", registry_url)))
+            .await?;
+
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(None);
         }
 
-        let response = self.client.get(&url).headers(headers).send().await?;
+        let header = response
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| {
+                RegistryError::Other(format!(
+                    "{} requires authentication but sent no challenge",
+                    registry_url
+                ))
+            })?;
 
-        if !response.status().is_success() {
-            return Err(DarkerError::Registry(format!(
-                "Failed to fetch config: {}",
-                response.status()
-            )));
+        match AuthChallenge::parse(header)? {
+            AuthChallenge::Basic { .. } => match credentials {
+                Some(credentials) => Ok(Some(credentials.basic_auth())),
+                None => Err(status_error(endpoint, "authenticate", &response).into()),
+            },
+            AuthChallenge::Bearer { realm, service, .. } => {
+                let mut query = Vec::new();
+                if let Some(service) = service {
+                    query.push(("service", service));
+                }
+                if let Some(scope) = scope {
+                    query.push(("scope", scope.to_string()));
+                }
+
+                let mut request = endpoint.client.get(&realm).query(&query);
+                if let Some(credentials) = credentials {
+                    request =
+                        request.basic_auth(&credentials.username, Some(&credentials.password));
+                }
+
+                let response = self.send(request).await?;
+                if !response.status().is_success() {
+                    let action = format!("get token from {}", realm);
+                    return Err(status_error(endpoint, &action, &response).into());
+                }
+
+                let body: TokenResponse = response.json().await?;
+                let token = body.token.or(body.access_token).ok_or_else(|| {
+                    RegistryError::Other(format!("No token in response from {}", realm))
+                })?;
+                Ok(Some(format!("Bearer {}", token)))
+            }
+        }
+    }
+
+    /// Fetch image manifest (handles manifest lists/indexes for multi-platform images)
+    ///
+    /// Returns the image manifest along with the digest of the manifest the
+    /// reference resolved to, which is the index digest for multi-platform
+    /// images. Manifests requested by digest are verified against it.
+    async fn fetch_manifest(
+        &self,
+        endpoint: &Endpoint,
+        reference: &ImageReference,
+        auth: &Option<String>,
+    ) -> Result<ResolvedManifest> {
+        // Accept both single manifests and manifest lists
+        let (content_type, body) = self
+            .fetch_manifest_bytes(
+                endpoint,
+                reference,
+                reference.manifest_reference(),
+                MANIFEST_ACCEPT,
+                auth,
+            )
+            .await?;
+
+        let digest = format!("sha256:{:x}", Sha256::digest(&body));
+        if let Some(expected) = &reference.digest {
+            verify_manifest(endpoint, expected, &digest)?;
         }
 
-        let config: OciImageConfig = response.json().await?;
-        Ok(config)
+        // Check if this is a manifest list/index
+        if content_type.contains("manifest.list") || content_type.contains("image.index") {
+            let index: ImageIndex = serde_json::from_slice(&body)
+                .map_err(|e| RegistryError::Other(format!("Failed to parse manifest list: {}", e)))?;
+
+            // Find the manifest for the requested platform
+            let platform_manifest = select_manifest(&index, self.platform.as_ref())?;
+
+            // Fetch the actual manifest by digest, only accepting single manifests
+            let (_, manifest_body) = self
+                .fetch_manifest_bytes(
+                    endpoint,
+                    reference,
+                    &platform_manifest.digest,
+                    IMAGE_MANIFEST_ACCEPT,
+                    auth,
+                )
+                .await?;
+            verify_manifest(
+                endpoint,
+                &platform_manifest.digest,
+                &format!("sha256:{:x}", Sha256::digest(&manifest_body)),
+            )?;
+
+            let manifest: ImageManifest = serde_json::from_slice(&manifest_body)
+                .map_err(|e| RegistryError::Other(format!("Failed to parse manifest: {}", e)))?;
+            Ok(ResolvedManifest {
+                manifest,
+                bytes: manifest_body,
+                index: Some(body),
+            })
+        } else {
+            // It's already a single manifest
+            let manifest: ImageManifest = serde_json::from_slice(&body)
+                .map_err(|e| RegistryError::Other(format!("Failed to parse manifest: {}", e)))?;
+            Ok(ResolvedManifest {
+                manifest,
+                bytes: body,
+                index: None,
+            })
+        }
     }
 
-    /// Fetch a layer
-    async fn fetch_layer(
+    /// Fetch the manifest or index a reference points to, as the registry stores it
+    ///
+    /// Returns the content type and the raw bytes. Manifests requested by
+    /// digest are verified against it.
+    pub async fn fetch_raw_manifest(
         &self,
         reference: &ImageReference,
-        digest: &str,
-        token: &Option<String>,
         paths: &DarkerPaths,
-    ) -> Result<()> {
-        let url = format!(
-            "{}/v2/{}/blobs/{}",
-            reference.registry_url(),
-            reference.repository,
-            digest
-        );
+    ) -> Result<(String, Vec<u8>)> {
+        let endpoint = self.endpoint(self.registries.location(&reference.registry));
+        let auth = self.authenticate(&endpoint, reference, "pull", paths).await?;
+        let (content_type, body) = self
+            .fetch_manifest_bytes(
+                &endpoint,
+                reference,
+                reference.manifest_reference(),
+                MANIFEST_ACCEPT,
+                &auth,
+            )
+            .await?;
 
-        let mut headers = HeaderMap::new();
-        if let Some(token) = token {
-            headers.insert(
-                AUTHORIZATION,
-                HeaderValue::from_str(&format!("Bearer {}", token))
-                    .map_err(|_| DarkerError::Registry("Invalid token".to_string()))?,
-            );
+        if let Some(expected) = &reference.digest {
+            verify_manifest(
+                &endpoint,
+                expected,
+                &format!("sha256:{:x}", Sha256::digest(&body)),
+            )?;
+        }
+        Ok((content_type, body))
+    }
+
+    /// Describe the image manifest a reference points to, for a manifest list
+    ///
+    /// The platform is taken from the image config. References to manifest
+    /// lists are rejected, since lists can't be nested.
+    pub async fn manifest_descriptor(
+        &self,
+        reference: &ImageReference,
+        paths: &DarkerPaths,
+    ) -> Result<ManifestDescriptor> {
+        let (content_type, body) = self.fetch_raw_manifest(reference, paths).await?;
+        if content_type.contains("manifest.list") || content_type.contains("image.index") {
+            return Err(RegistryError::Other(format!(
+                "{} is a manifest list",
+                reference.full_name()
+            ))
+            .into());
         }
+        let manifest: ImageManifest = serde_json::from_slice(&body)
+            .map_err(|e| RegistryError::Other(format!("Failed to parse manifest: {}", e)))?;
+
+        let endpoint = self.endpoint(self.registries.location(&reference.registry));
+        let auth = self.authenticate(&endpoint, reference, "pull", paths).await?;
+        let config_bytes = self
+            .fetch_config(&endpoint, reference, &manifest.config.digest, &auth)
+            .await?;
+        let config: OciImageConfig = serde_json::from_slice(&config_bytes)
+            .map_err(|e| RegistryError::Other(format!("Failed to parse image config: {}", e)))?;
+
+        let media_type = match manifest.media_type {
+            Some(media_type) if content_type.is_empty() => media_type,
+            _ if content_type.is_empty() => media_types::OCI_IMAGE_MANIFEST.to_string(),
+            _ => content_type,
+        };
+        Ok(ManifestDescriptor {
+            media_type,
+            digest: format!("sha256:{:x}", Sha256::digest(&body)),
+            size: body.len() as i64,
+            platform: Some(Platform::new(
+                &config.os,
+                &config.architecture,
+                config.variant.as_deref(),
+            )),
+            annotations: None,
+        })
+    }
 
-        let response = self.client.get(&url).headers(headers).send().await?;
+    /// Fetch the raw bytes and content type of a manifest by tag or digest
+    async fn fetch_manifest_bytes(
+        &self,
+        endpoint: &Endpoint,
+        reference: &ImageReference,
+        manifest_ref: &str,
+        accept: &'static str,
+        auth: &Option<String>,
+    ) -> Result<(String, Vec<u8>)> {
+        let url = endpoint.url(reference, &format!("manifests/{}", manifest_ref));
+
+        let mut headers = auth_headers(auth)?;
+        headers.insert(ACCEPT, HeaderValue::from_static(accept));
+
+        let response = self.send(endpoint.client.get(&url).headers(headers)).await?;
 
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(RegistryError::ManifestUnknown {
+                registry: endpoint.location.host.clone(),
+                reference: format!(
+                    "{}:{}",
+                    endpoint.location.repository(&reference.repository),
+                    manifest_ref
+                ),
+            }
+            .into());
+        }
         if !response.status().is_success() {
-            return Err(DarkerError::Registry(format!(
-                "Failed to fetch layer: {}",
-                response.status()
-            )));
+            return Err(status_error(endpoint, "fetch manifest", &response).into());
         }
 
-        let digest_short = digest.strip_prefix("sha256:").unwrap_or(digest);
-        let layer_dir = paths.layer_dir(digest_short);
-        fs::create_dir_all(&layer_dir)?;
+        // Get the content type to determine what we received
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+
+        let body = response.bytes().await?;
+        Ok((content_type, body.to_vec()))
+    }
 
-        let tar_path = paths.layer_tar(digest_short);
-        let bytes = response.bytes().await?;
+    /// Fetch the raw bytes of an image config, checking them against its digest
+    async fn fetch_config(
+        &self,
+        endpoint: &Endpoint,
+        reference: &ImageReference,
+        digest: &str,
+        auth: &Option<String>,
+    ) -> Result<Vec<u8>> {
+        let url = endpoint.url(reference, &format!("blobs/{}", digest));
 
-        // Decompress if gzipped
-        if bytes.len() >= 2 && bytes[0] == crate::GZIP_MAGIC[0] && bytes[1] == crate::GZIP_MAGIC[1] {
-            use flate2::read::GzDecoder;
-            use std::io::Read;
+        let response = self
+            .send(endpoint.client.get(&url).headers(auth_headers(auth)?))
+            .await?;
 
-            let mut decoder = GzDecoder::new(&bytes[..]);
-            let mut decompressed = Vec::new();
-            decoder.read_to_end(&mut decompressed)?;
-            fs::write(&tar_path, &decompressed)?;
-        } else {
-            fs::write(&tar_path, &bytes)?;
+        if !response.status().is_success() {
+            return Err(status_error(endpoint, "fetch config", &response).into());
         }
 
-        Ok(())
+        let body = response.bytes().await?.to_vec();
+        verify_manifest(
+            endpoint,
+            digest,
+            &format!("sha256:{:x}", Sha256::digest(&body)),
+        )?;
+        Ok(body)
     }
-}
 
-/// Get the host architecture in OCI format
-fn get_host_arch() -> &'static str {
-    match std::env::consts::ARCH {
-        "x86_64" => "amd64",
-        "aarch64" => "arm64",
-        "arm" => "arm",
-        "x86" => "386",
-        arch => arch,
+    /// Get the endpoint for a registry location, with its HTTP client
+    fn endpoint(&self, location: RegistryLocation) -> Endpoint {
+        let client = self
+            .tls_clients
+            .get(&location.host)
+            .unwrap_or(&self.client)
+            .clone();
+        Endpoint { location, client }
     }
-}
 
-#[derive(Debug, Deserialize)]
-struct TokenResponse {
-    token: String,
-}
+    /// Send a request, retrying transient failures according to the retry policy
+    ///
+    /// Connection failures and retryable statuses are retried after a backoff,
+    /// or the wait asked for by `Retry-After`. The last response is returned
+    /// whatever its status. Requests with streaming bodies can't be repeated
+    /// and get a single attempt.
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let mut attempt = 1;
+        loop {
+            let current = match request.try_clone() {
+                Some(current) if self.retry.should_retry(attempt) => current,
+                _ => return Ok(request.send().await?),
+            };
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+            let retry_after = match current.send().await {
+                Ok(response) if is_retryable_status(response.status()) => retry_after(&response),
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    let e = DarkerError::from(e);
+                    if !e.is_transient() {
+                        return Err(e);
+                    }
+                    None
+                }
+            };
+
+            tokio::time::sleep(self.retry.delay(attempt, retry_after)).await;
+            attempt += 1;
+        }
+    }
+
+    /// Run a whole registry operation, retrying it while it fails transiently
+    async fn retrying<T, F, Fut>(&self, mut operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match operation().await {
+                Err(e) if e.is_transient() && self.retry.should_retry(attempt) => {
+                    tokio::time::sleep(self.retry.delay(attempt, e.retry_after())).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Report the status of a layer to the progress sink
+    fn report(&self, digest: &str, status: &LayerStatus) {
+        if !self.quiet {
+            let hex = digest.strip_prefix("sha256:").unwrap_or(digest);
+            self.progress.update(&hex[..hex.len().min(12)], status);
+        }
+    }
+
+    /// Check whether a registry or other server serves byte ranges of a blob
+    async fn accepts_ranges(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        auth: &Option<String>,
+    ) -> Result<bool> {
+        let response = self
+            .send(client.head(url).headers(auth_headers(auth)?))
+            .await?;
+
+        Ok(response.status().is_success()
+            && response
+                .headers()
+                .get(ACCEPT_RANGES)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.contains("bytes"))
+                .unwrap_or(false))
+    }
+
+    /// Request a layer blob, from `offset` onwards if the source serves ranges
+    ///
+    /// Layers the registry doesn't serve, such as foreign layers, are requested
+    /// from the URLs in their descriptor in order.
+    async fn open_layer(
+        &self,
+        endpoint: &Endpoint,
+        url: &str,
+        layer: &Descriptor,
+        offset: u64,
+        auth: &Option<String>,
+    ) -> Result<reqwest::Response> {
+        let response = self.get_blob(&endpoint.client, url, offset, auth).await?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+
+        let mut result = Err(status_error(endpoint, "fetch layer", &response).into());
+        if response.status() != StatusCode::NOT_FOUND {
+            return result;
+        }
+        let urls = layer.urls.iter().flatten();
+        for url in urls.filter(|url| url.starts_with("http://") || url.starts_with("https://")) {
+            // Foreign URLs are on other servers, so the registry's credentials aren't sent
+            match self.get_blob(&self.client, url, offset, &None).await {
+                Ok(response) if response.status().is_success() => return Ok(response),
+                Ok(response) => {
+                    let host = reqwest::Url::parse(url)
+                        .ok()
+                        .and_then(|u| u.host_str().map(String::from))
+                        .unwrap_or_else(|| url.clone());
+                    let status = response.status();
+                    result = Err(RegistryError::from_status(
+                        &host,
+                        "fetch foreign layer",
+                        status,
+                        retry_after(&response),
+                    )
+                    .into());
+                }
+                Err(e) => result = Err(e),
+            }
+        }
+        result
+    }
+
+    /// Send a GET for a blob, from `offset` onwards if the source serves ranges
+    ///
+    /// The request isn't retried by itself, since retrying the whole layer
+    /// fetch resumes what was already downloaded.
+    async fn get_blob(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        offset: u64,
+        auth: &Option<String>,
+    ) -> Result<reqwest::Response> {
+        let mut headers = auth_headers(auth)?;
+        if offset > 0 && self.accepts_ranges(client, url, auth).await? {
+            headers.insert(
+                RANGE,
+                HeaderValue::from_str(&format!("bytes={}-", offset))
+                    .map_err(|e| RegistryError::Other(e.to_string()))?,
+            );
+        }
+        Ok(client.get(url).headers(headers).send().await?)
+    }
+
+    /// Fetch a layer
+    ///
+    /// The blob is streamed to a partial file in the tmp directory, keyed by
+    /// digest, while its digest is computed. An interrupted download is resumed
+    /// from that file on the next pull when the registry supports range requests.
+    /// Once the blob matches the descriptor it is kept in the blob store, and
+    /// blobs already there aren't downloaded again. The layer is decompressed as
+    /// given by its media type, and only moved into the layer store, under its
+    /// diff ID, once the uncompressed tar matches `diff_id`.
+    async fn fetch_layer(
+        &self,
+        endpoint: &Endpoint,
+        reference: &ImageReference,
+        layer: &Descriptor,
+        diff_id: &str,
+        auth: &Option<String>,
+        paths: &DarkerPaths,
+    ) -> Result<()> {
+        let url = endpoint.url(reference, &format!("blobs/{}", layer.digest));
+        let compression = Compression::from_media_type(&layer.media_type)?;
+        let blobs = BlobStore::new(paths);
+        let blob_path = blobs.path(&layer.digest)?;
+
+        let tmp_dir = paths.tmp_dir();
+        fs::create_dir_all(&tmp_dir)?;
+        let digest_short = layer.digest.strip_prefix("sha256:").unwrap_or(&layer.digest);
+        let download_path = tmp_dir.join(format!("{}.partial", digest_short));
+
+        // A complete leftover download only needs verifying
+        let stored = blob_path.exists();
+        let mut offset = fs::metadata(&download_path).map(|m| m.len()).unwrap_or(0);
+        if !stored && offset > 0 && offset >= layer.size as u64 {
+            self.report(&layer.digest, &LayerStatus::Verifying);
+            if verify_file(&download_path, layer).await.is_err() {
+                let _ = fs::remove_file(&download_path);
+                offset = 0;
+            }
+        }
+
+        if !stored && (offset == 0 || offset < layer.size as u64) {
+            let response = self.open_layer(endpoint, &url, layer, offset, auth).await?;
+
+            // Registries may ignore the range and send the whole blob
+            let resume = offset > 0 && response.status() == StatusCode::PARTIAL_CONTENT;
+            let total = layer.size.max(0) as u64;
+            download_blob(response, &download_path, layer, resume, |current| {
+                self.report(&layer.digest, &LayerStatus::Downloading { current, total })
+            })
+            .await?;
+        }
+
+        if !stored {
+            blobs.insert_file(&download_path, &layer.digest)?;
+        }
+        self.report(&layer.digest, &LayerStatus::Extracting);
+
+        // Decompress into a staging directory, then move it into place
+        let staging_id = uuid::Uuid::new_v4().to_string();
+        let staging_dir = tmp_dir.join(&staging_id);
+        fs::create_dir_all(&staging_dir)?;
+        let staged_tar = staging_dir.join("layer.tar");
+        let source = blob_path.clone();
+        let unpacked = tokio::task::spawn_blocking(move || {
+            decompress_layer(&source, &staged_tar, compression)
+        })
+        .await
+        .map_err(|e| DarkerError::Layer(e.to_string()))
+        .and_then(|result| result)
+        .and_then(|actual| {
+            if actual != diff_id {
+                return Err(DarkerError::Layer(format!(
+                    "Diff ID mismatch for {}: uncompressed content has digest {}, expected {}",
+                    layer.digest, actual, diff_id
+                )));
+            }
+            Ok(())
+        });
+        if let Err(e) = unpacked {
+            let _ = fs::remove_dir_all(&staging_dir);
+            return Err(e);
+        }
+
+        let layer_dir = paths.layer_dir(diff_id.strip_prefix("sha256:").unwrap_or(diff_id));
+        fs::create_dir_all(paths.layers_dir())?;
+        if let Err(e) = fs::rename(&staging_dir, &layer_dir) {
+            let _ = fs::remove_dir_all(&staging_dir);
+            // Another pull may have stored the same layer concurrently
+            if !layer_dir.exists() {
+                return Err(e.into());
+            }
+        }
+
+        self.report(&layer.digest, &LayerStatus::Complete);
+        Ok(())
+    }
+}
+
+/// An image manifest resolved for pulling, with the bytes it was served as
+struct ResolvedManifest {
+    manifest: ImageManifest,
+    /// Raw bytes of the image manifest
+    bytes: Vec<u8>,
+    /// Raw bytes of the index the manifest was picked from, if any
+    index: Option<Vec<u8>>,
+}
+
+impl ResolvedManifest {
+    /// Digest the reference resolved to, that of the index for multi-platform images
+    fn digest(&self) -> String {
+        let bytes = self.index.as_ref().unwrap_or(&self.bytes);
+        format!("sha256:{:x}", Sha256::digest(bytes))
+    }
+}
+
+/// A registry or mirror location with the HTTP client configured for it
+struct Endpoint {
+    location: RegistryLocation,
+    client: reqwest::Client,
+}
+
+impl Endpoint {
+    /// URL of an API path under a repository at this endpoint
+    fn url(&self, reference: &ImageReference, path: &str) -> String {
+        format!(
+            "{}/v2/{}/{}",
+            self.location.url,
+            self.location.repository(&reference.repository),
+            path
+        )
+    }
+}
+
+/// Build authorization headers for a registry request
+fn auth_headers(auth: &Option<String>) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    if let Some(auth) = auth {
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(auth)
+                .map_err(|_| RegistryError::Other("Invalid token".to_string()))?,
+        );
+    }
+    Ok(headers)
+}
+
+/// Resolve the Location header of an upload response to an absolute URL
+fn upload_location(endpoint: &Endpoint, response: &reqwest::Response) -> Result<String> {
+    let location = response
+        .headers()
+        .get(LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| {
+            RegistryError::Other("Upload response has no Location header".to_string())
+        })?;
+
+    Ok(resolve_url(endpoint, location))
+}
+
+/// Build the error for an unsuccessful response from an endpoint
+fn status_error(endpoint: &Endpoint, action: &str, response: &reqwest::Response) -> RegistryError {
+    RegistryError::from_status(
+        &endpoint.location.host,
+        action,
+        response.status(),
+        retry_after(response),
+    )
+}
+
+/// Wait asked for by a response's `Retry-After` header
+fn retry_after(response: &reqwest::Response) -> Option<std::time::Duration> {
+    response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_retry_after)
+}
+
+/// Stream a blob response to a file, verifying it against its descriptor
+///
+/// When `resume` is set the response continues the bytes already in `dest`,
+/// which are hashed first so that verification covers the whole blob. The
+/// file is kept if the transfer fails, and removed if verification fails.
+/// `on_progress` is called with the number of bytes of the blob on disk.
+async fn download_blob(
+    response: reqwest::Response,
+    dest: &Path,
+    descriptor: &Descriptor,
+    resume: bool,
+    on_progress: impl Fn(u64),
+) -> Result<()> {
+    use futures_util::StreamExt;
+    use tokio::io::AsyncWriteExt;
+
+    if !descriptor.digest.starts_with("sha256:") {
+        return Err(DarkerError::Layer(format!(
+            "Unsupported digest algorithm: {}",
+            descriptor.digest
+        )));
+    }
+
+    let (mut file, mut hasher, mut size) = if resume {
+        let (hasher, size) = hash_file(dest).await?;
+        let file = tokio::fs::OpenOptions::new().append(true).open(dest).await?;
+        (file, hasher, size)
+    } else {
+        (tokio::fs::File::create(dest).await?, Sha256::new(), 0)
+    };
+
+    on_progress(size);
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        hasher.update(&chunk);
+        size += chunk.len() as u64;
+        file.write_all(&chunk).await?;
+        on_progress(size);
+    }
+    file.flush().await?;
+
+    let result = verify_blob(descriptor, &format!("sha256:{:x}", hasher.finalize()), size);
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(dest).await;
+    }
+    result
+}
+
+/// Hash the contents of a file, returning the hasher and the byte count
+async fn hash_file(path: &Path) -> Result<(Sha256, u64)> {
+    use tokio::io::AsyncReadExt;
+
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut size: u64 = 0;
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+    Ok((hasher, size))
+}
+
+/// Verify an already downloaded blob file against its descriptor
+async fn verify_file(path: &Path, descriptor: &Descriptor) -> Result<()> {
+    let (hasher, size) = hash_file(path).await?;
+    verify_blob(descriptor, &format!("sha256:{:x}", hasher.finalize()), size)
+}
+
+/// Check that a manifest's content matches the digest it was requested by
+fn verify_manifest(endpoint: &Endpoint, expected: &str, digest: &str) -> Result<()> {
+    if digest != expected {
+        return Err(RegistryError::DigestMismatch {
+            registry: endpoint.location.host.clone(),
+            expected: expected.to_string(),
+            actual: digest.to_string(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Check a downloaded blob's digest and size against its descriptor
+fn verify_blob(descriptor: &Descriptor, digest: &str, size: u64) -> Result<()> {
+    if descriptor.size >= 0 && size != descriptor.size as u64 {
+        return Err(DarkerError::Layer(format!(
+            "Size mismatch for {}: expected {} bytes, got {}",
+            descriptor.digest, descriptor.size, size
+        )));
+    }
+
+    if digest != descriptor.digest {
+        return Err(DarkerError::Layer(format!(
+            "Digest mismatch for {}: downloaded content has digest {}",
+            descriptor.digest, digest
+        )));
+    }
+
+    Ok(())
+}
+
+/// Decompress a downloaded layer blob into a plain tar file
+///
+/// Returns the digest of the uncompressed tar, its diff ID.
+fn decompress_layer(source: &Path, dest: &Path, compression: Compression) -> Result<String> {
+    let mut output = File::create(dest)?;
+    let mut decoder = compression.decoder(File::open(source)?)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = decoder.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        output.write_all(&buffer[..read])?;
+    }
+    Ok(format!("sha256:{:x}", hasher.finalize()))
+}
+
+/// Check whether a file starts with the gzip magic bytes
+fn is_gzip_file(path: &Path) -> Result<bool> {
+    let mut magic = [0u8; 2];
+    let mut file = File::open(path)?;
+    Ok(file.read_exact(&mut magic).is_ok() && magic == crate::GZIP_MAGIC)
+}
+
+/// Compute the digest of a gzipped layer's uncompressed contents
+fn compute_diff_id(path: &Path) -> Result<String> {
+    let mut decoder = flate2::read::GzDecoder::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut decoder, &mut hasher)?;
+    Ok(format!("sha256:{:x}", hasher.finalize()))
+}
+
+/// The manifest and blobs a locally stored image is pushed as
+pub(crate) struct LocalManifest {
+    /// Layer tar files with their descriptors
+    pub(crate) layers: Vec<(PathBuf, Descriptor)>,
+    /// Serialized image config
+    pub(crate) config: Vec<u8>,
+    /// Serialized image manifest
+    pub(crate) manifest: Vec<u8>,
+    /// Media type of the manifest
+    pub(crate) media_type: String,
+    /// Platform of the image
+    pub(crate) platform: Platform,
+}
+
+impl LocalManifest {
+    /// Build the manifest of a locally stored image
+    ///
+    /// Images pulled or loaded with all their blobs kept are pushed as the
+    /// exact manifest they came with. Other images get a new manifest for
+    /// their stored compressed blobs, or their uncompressed layers.
+    pub(crate) fn load(paths: &DarkerPaths, image_id: &str) -> Result<Self> {
+        let image_store = ImageStore::new(paths)?;
+        let metadata = image_store.load_metadata(image_id)?;
+        if let Some(stored) = Self::load_stored(paths, &metadata)? {
+            return Ok(stored);
+        }
+        let layer_manager = LayerManager::new(paths);
+        let blobs = BlobStore::new(paths);
+
+        let mut layers = Vec::new();
+        let mut diff_ids = Vec::new();
+        for (i, layer) in metadata.layers.iter().enumerate() {
+            // Layers created by builds keep their compressed blob
+            let blob = metadata
+                .blob_digests
+                .get(i)
+                .filter(|_| metadata.blob_digests.len() == metadata.layers.len())
+                .filter(|digest| blobs.exists(digest));
+            if let Some(digest) = blob {
+                let blob_path = blobs.path(digest)?;
+                diff_ids.push(format!("sha256:{}", layer));
+                let descriptor = Descriptor {
+                    media_type: Compression::detect(&blob_path)?.media_type().to_string(),
+                    digest: digest.clone(),
+                    size: fs::metadata(&blob_path)?.len() as i64,
+                    urls: None,
+                    annotations: None,
+                };
+                layers.push((blob_path, descriptor));
+                continue;
+            }
+
+            let tar_path = layer_manager.layer_tar_path(layer);
+            if !tar_path.exists() {
+                return Err(DarkerError::Layer(format!(
+                    "Layer {} of image {} is missing",
+                    layer, image_id
+                )));
+            }
+
+            let digest = LayerManager::compute_digest(&tar_path)?;
+            let size = fs::metadata(&tar_path)?.len();
+            let gzipped = is_gzip_file(&tar_path)?;
+            diff_ids.push(if gzipped {
+                compute_diff_id(&tar_path)?
+            } else {
+                digest.clone()
+            });
+
+            let descriptor = Descriptor {
+                media_type: if gzipped {
+                    media_types::OCI_LAYER_TAR_GZIP.to_string()
+                } else {
+                    media_types::OCI_LAYER_TAR.to_string()
+                },
+                digest,
+                size: size as i64,
+                urls: None,
+                annotations: None,
+            };
+            layers.push((tar_path, descriptor));
+        }
+
+        let config = push_config(&image_store, image_id, diff_ids)?;
+        let platform = Platform::new(&config.os, &config.architecture, config.variant.as_deref());
+        let config_bytes = serde_json::to_vec(&config)?;
+
+        let manifest = ImageManifest {
+            schema_version: 2,
+            media_type: Some(media_types::OCI_IMAGE_MANIFEST.to_string()),
+            config: Descriptor {
+                media_type: media_types::OCI_IMAGE_CONFIG.to_string(),
+                digest: LayerManager::compute_digest_bytes(&config_bytes),
+                size: config_bytes.len() as i64,
+                urls: None,
+                annotations: None,
+            },
+            layers: layers.iter().map(|(_, layer)| layer.clone()).collect(),
+            annotations: None,
+        };
+
+        Ok(Self {
+            layers,
+            config: config_bytes,
+            manifest: serde_json::to_vec(&manifest)?,
+            media_type: media_types::OCI_IMAGE_MANIFEST.to_string(),
+            platform,
+        })
+    }
+
+    /// The manifest an image was pulled or loaded as, if all its blobs are stored
+    fn load_stored(paths: &DarkerPaths, metadata: &ImageMetadata) -> Result<Option<Self>> {
+        let blobs = BlobStore::new(paths);
+        let Some(manifest_digest) = metadata.manifest_digest.as_deref() else {
+            return Ok(None);
+        };
+        if !blobs.exists(manifest_digest) {
+            return Ok(None);
+        }
+
+        let manifest_bytes = blobs.read(manifest_digest)?;
+        let manifest: ImageManifest = serde_json::from_slice(&manifest_bytes)?;
+        let complete = std::iter::once(&manifest.config)
+            .chain(&manifest.layers)
+            .all(|blob| blobs.exists(&blob.digest));
+        if !complete {
+            return Ok(None);
+        }
+
+        let config_bytes = blobs.read(&manifest.config.digest)?;
+        let config: OciImageConfig = serde_json::from_slice(&config_bytes)?;
+        let layers = manifest
+            .layers
+            .iter()
+            .map(|layer| Ok((blobs.path(&layer.digest)?, layer.clone())))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Some(Self {
+            layers,
+            config: config_bytes,
+            media_type: manifest
+                .media_type
+                .clone()
+                .unwrap_or_else(|| media_types::OCI_IMAGE_MANIFEST.to_string()),
+            manifest: manifest_bytes,
+            platform: Platform::new(&config.os, &config.architecture, config.variant.as_deref()),
+        }))
+    }
+
+    /// Digest of the manifest
+    pub(crate) fn digest(&self) -> String {
+        LayerManager::compute_digest_bytes(&self.manifest)
+    }
+}
+
+/// Describe the manifest a locally stored image is pushed with
+pub fn local_manifest_descriptor(
+    paths: &DarkerPaths,
+    image_id: &str,
+) -> Result<ManifestDescriptor> {
+    let local = LocalManifest::load(paths, image_id)?;
+    Ok(ManifestDescriptor {
+        media_type: local.media_type.clone(),
+        digest: local.digest(),
+        size: local.manifest.len() as i64,
+        platform: Some(local.platform),
+        annotations: None,
+    })
+}
+
+/// Build the OCI config to push for a locally stored image
+///
+/// Pulled images keep their original OCI config. Built images only store the
+/// simplified runtime config, so a full one is synthesized for the platform
+/// recorded for the image, or the host platform.
+pub(crate) fn push_config(
+    image_store: &ImageStore,
+    image_id: &str,
+    diff_ids: Vec<String>,
+) -> Result<OciImageConfig> {
+    let stored = image_store
+        .config_bytes(image_id)?
+        .and_then(|bytes| serde_json::from_slice::<OciImageConfig>(&bytes).ok());
+
+    let mut config = match stored {
+        Some(config) => config,
+        None => {
+            let details = image_store.load_config(image_id)?.config;
+            let platform = image_store
+                .load_metadata(image_id)?
+                .platform
+                .and_then(|p| Platform::parse(&p).ok())
+                .unwrap_or_else(|| Platform::new(get_host_os(), get_host_arch(), None));
+            OciImageConfig {
+                architecture: platform.architecture,
+                os: platform.os,
+                variant: platform.variant,
+                config: Some(ImageConfigSpec {
+                    user: details.user,
+                    exposed_ports: details.exposed_ports,
+                    env: details.env,
+                    entrypoint: details.entrypoint,
+                    cmd: details.cmd,
+                    volumes: details.volumes,
+                    working_dir: details.working_dir,
+                    labels: details.labels,
+                    stop_signal: None,
+                }),
+                rootfs: RootFs {
+                    fs_type: "layers".to_string(),
+                    diff_ids: Vec::new(),
+                },
+                history: None,
+            }
+        }
+    };
+
+    config.rootfs.diff_ids = diff_ids;
+    Ok(config)
+}
+
+/// Pick the manifest for a platform from a manifest list
+///
+/// Without an explicit platform, linux and then darwin images for the host
+/// architecture are accepted, since most images are built for linux.
+pub(crate) fn select_manifest<'a>(
+    index: &'a ImageIndex,
+    platform: Option<&Platform>,
+) -> Result<&'a ManifestDescriptor> {
+    let wanted = match platform {
+        Some(platform) => vec![platform.clone()],
+        None => vec![
+            Platform::new("linux", get_host_arch(), None),
+            Platform::new("darwin", get_host_arch(), None),
+        ],
+    };
+
+    for wanted in &wanted {
+        // Prefer the closest variant, then the first entry in the list
+        let best = index
+            .manifests
+            .iter()
+            .rev()
+            .filter_map(|m| {
+                let candidate = m.platform.as_ref()?;
+                wanted
+                    .matches(candidate)
+                    .then(|| (wanted.match_rank(candidate), m))
+            })
+            .max_by_key(|(rank, _)| *rank);
+        if let Some((_, manifest)) = best {
+            return Ok(manifest);
+        }
+    }
+
+    let available: Vec<String> = index
+        .manifests
+        .iter()
+        .filter_map(|m| m.platform.as_ref())
+        .map(|p| p.normalized().to_string())
+        .collect();
+    Err(RegistryError::Other(format!(
+        "no matching manifest for {} in the manifest list entries (available: {})",
+        wanted[0],
+        available.join(", ")
+    ))
+    .into())
+}
+
+/// Get the host operating system in OCI format
+pub(crate) fn get_host_os() -> &'static str {
+    match std::env::consts::OS {
+        "macos" => "darwin",
+        os => os,
+    }
+}
+
+/// Get the host architecture in OCI format
+pub(crate) fn get_host_arch() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "arm" => "arm",
+        "x86" => "386",
+        arch => arch,
+    }
+}
+
+/// Parse the target of the `rel="next"` entry of a `Link` header
+fn next_link(header: &str) -> Option<String> {
+    header.split(',').find_map(|link| {
+        let (target, params) = link.split_once(';')?;
+        let is_next = params.split(';').any(|param| {
+            let param = param.trim().replace(' ', "");
+            param == "rel=\"next\"" || param == "rel=next"
+        });
+        let target = target.trim().strip_prefix('<')?.strip_suffix('>')?;
+        is_next.then(|| target.to_string())
+    })
+}
+
+/// Resolve a URL returned by the registry against the endpoint
+fn resolve_url(endpoint: &Endpoint, url: &str) -> String {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        url.to_string()
+    } else {
+        format!("{}{}", endpoint.location.url, url)
+    }
+}
+
+/// A page of the tag list of a repository
+#[derive(Debug, Deserialize)]
+struct TagList {
+    tags: Option<Vec<String>>,
+}
+
+/// A page of the repository catalog of a registry
+#[derive(Debug, Deserialize)]
+struct Catalog {
+    repositories: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::test_registry::{Fault, TestAuth, TestRegistry};
+    use crate::storage::images::{ImageConfig, ImageConfigDetails};
+    use crate::storage::manifest_lists::ManifestListEntry;
+    use tempfile::TempDir;
+
+    fn layer_tar(name: &str, contents: &[u8]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, contents).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn store_local_image(paths: &DarkerPaths, repository: &str, tag: &str) -> String {
+        let layer_manager = LayerManager::new(paths);
+        let tar = layer_tar("hello.txt", b"hello from darker");
+        let layer = LayerManager::compute_digest_bytes(&tar)
+            .trim_start_matches("sha256:")
+            .to_string();
+        layer_manager.store_layer_bytes(&layer, &tar).unwrap();
+
+        let image_store = ImageStore::new(paths).unwrap();
+        let image_id = "0123456789abcdef0123456789abcdef";
+        image_store
+            .store(
+                image_id,
+                Some(repository),
+                Some(tag),
+                None,
+                &[layer],
+                tar.len() as u64,
+            )
+            .unwrap();
+        image_store
+            .save_config(
+                image_id,
+                &ImageConfig {
+                    config: ImageConfigDetails {
+                        cmd: Some(vec!["/hello".to_string()]),
+                        ..Default::default()
+                    },
+                },
+            )
+            .unwrap();
+        image_id.to_string()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Hex digest a layer tar is stored under, its diff ID
+    fn stored_as(tar: &[u8]) -> String {
+        LayerManager::compute_digest_bytes(tar)
+            .trim_start_matches("sha256:")
+            .to_string()
+    }
+
+    /// Digest of a gzipped blob's uncompressed contents
+    fn gunzip_digest(blob: &[u8]) -> String {
+        let mut contents = Vec::new();
+        flate2::read::GzDecoder::new(blob)
+            .read_to_end(&mut contents)
+            .unwrap();
+        LayerManager::compute_digest_bytes(&contents)
+    }
+
+    /// Publish an image with the given gzipped layer blobs, returning the manifest
+    fn publish_image(
+        registry: &TestRegistry,
+        repository: &str,
+        tag: &str,
+        blobs: &[Vec<u8>],
+    ) -> ImageManifest {
+        let diff_ids = blobs.iter().map(|blob| gunzip_digest(blob)).collect();
+        let layers = blobs
+            .iter()
+            .map(|blob| Descriptor {
+                media_type: media_types::OCI_LAYER_TAR_GZIP.to_string(),
+                digest: registry.add_blob(blob),
+                size: blob.len() as i64,
+                urls: None,
+                annotations: None,
+            })
+            .collect();
+        publish_layers(registry, repository, tag, layers, diff_ids)
+    }
+
+    /// Publish an image with the given layer descriptors and diff IDs, whose
+    /// blobs are added separately
+    fn publish_layers(
+        registry: &TestRegistry,
+        repository: &str,
+        tag: &str,
+        layers: Vec<Descriptor>,
+        diff_ids: Vec<String>,
+    ) -> ImageManifest {
+        let config = serde_json::json!({
+            "architecture": get_host_arch(),
+            "os": "linux",
+            "config": { "Cmd": ["/bin/sh"] },
+            "rootfs": { "type": "layers", "diff_ids": diff_ids },
+        });
+        let config_bytes = serde_json::to_vec(&config).unwrap();
+
+        let manifest = ImageManifest {
+            schema_version: 2,
+            media_type: Some(media_types::OCI_IMAGE_MANIFEST.to_string()),
+            config: Descriptor {
+                media_type: media_types::OCI_IMAGE_CONFIG.to_string(),
+                digest: registry.add_blob(&config_bytes),
+                size: config_bytes.len() as i64,
+                urls: None,
+                annotations: None,
+            },
+            layers,
+            annotations: None,
+        };
+        registry.add_manifest(
+            repository,
+            tag,
+            media_types::OCI_IMAGE_MANIFEST,
+            &serde_json::to_vec(&manifest).unwrap(),
+        );
+        manifest
+    }
 
     #[test]
     fn test_registry_client_creation() {
         let client = RegistryClient::new();
         assert!(client.is_ok());
     }
+
+    #[tokio::test]
+    async fn test_push_and_pull_round_trip() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path().join("src"));
+        paths.ensure_directories().unwrap();
+
+        let reference = ImageReference::parse(&format!("{}/test/app:v1", registry.host())).unwrap();
+        let image_id = store_local_image(&paths, &reference.repository_with_registry(), "v1");
+
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        let (digest, size) = client.push(&reference, &image_id, &paths).await.unwrap();
+
+        {
+            let state = registry.state();
+            let (media_type, bytes) = state.manifests.get("test/app@v1").unwrap();
+            assert_eq!(media_type, media_types::OCI_IMAGE_MANIFEST);
+            assert_eq!(bytes.len(), size);
+            assert_eq!(LayerManager::compute_digest_bytes(bytes), digest);
+
+            let manifest: ImageManifest = serde_json::from_slice(bytes).unwrap();
+            assert!(state.blobs.contains_key(&manifest.config.digest));
+            assert_eq!(manifest.layers.len(), 1);
+            assert!(state.blobs.contains_key(&manifest.layers[0].digest));
+        }
+
+        // Pull it back into an empty store
+        let dest = DarkerPaths::with_root(tmp.path().join("dest"));
+        dest.ensure_directories().unwrap();
+        let pulled_id = client.pull(&reference, &dest).await.unwrap();
+
+        let image_store = ImageStore::new(&dest).unwrap();
+        let metadata = image_store.load_metadata(&pulled_id).unwrap();
+        assert_eq!(metadata.layers.len(), 1);
+        assert!(dest.layer_tar(&metadata.layers[0]).exists());
+        let config = image_store.load_config(&pulled_id).unwrap();
+        assert_eq!(config.cmd(), Some(vec!["/hello".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn test_push_skips_existing_blobs() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let reference = ImageReference::parse(&format!("{}/test/app:v1", registry.host())).unwrap();
+        let image_id = store_local_image(&paths, &reference.repository_with_registry(), "v1");
+
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        client.push(&reference, &image_id, &paths).await.unwrap();
+        assert_eq!(
+            registry.count_requests(&hyper::Method::POST, "/blobs/uploads/"),
+            2
+        );
+
+        let mut retagged = reference.clone();
+        retagged.tag = "v2".to_string();
+        client.push(&retagged, &image_id, &paths).await.unwrap();
+        assert_eq!(
+            registry.count_requests(&hyper::Method::POST, "/blobs/uploads/"),
+            2
+        );
+        assert!(registry.state().manifests.contains_key("test/app@v2"));
+    }
+
+    #[tokio::test]
+    async fn test_push_retries_config_upload() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let reference = ImageReference::parse(&format!("{}/test/app:v1", registry.host())).unwrap();
+        let image_id = store_local_image(&paths, &reference.repository_with_registry(), "v1");
+        let client = RegistryClient::new()
+            .unwrap()
+            .with_quiet(true)
+            .with_retry_policy(fast_retries());
+        client.push(&reference, &image_id, &paths).await.unwrap();
+
+        // Only the config is uploaded again, and its first upload fails
+        {
+            let mut state = registry.state();
+            let (_, bytes) = state.manifests.get("test/app@v1").unwrap();
+            let manifest: ImageManifest = serde_json::from_slice(bytes).unwrap();
+            state.blobs.remove(&manifest.config.digest);
+            state.faults = vec![(
+                "/blobs/uploads/".to_string(),
+                Fault::Status(hyper::StatusCode::SERVICE_UNAVAILABLE, None),
+            )];
+        }
+        client.push(&reference, &image_id, &paths).await.unwrap();
+
+        assert_eq!(
+            registry.count_requests(&hyper::Method::POST, "/blobs/uploads/"),
+            4
+        );
+        assert!(registry.state().faults.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_push_manifest_list() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+
+        // One entry from a local image, one from a manifest in another repository
+        let target = ImageReference::parse(&format!("{}/test/app:multi", registry.host())).unwrap();
+        let image_id = store_local_image(&paths, &target.repository_with_registry(), "amd64");
+        let mut local = ManifestListEntry {
+            source: format!("{}/test/app:amd64", registry.host()),
+            image_id: Some(image_id.clone()),
+            descriptor: local_manifest_descriptor(&paths, &image_id).unwrap(),
+        };
+        local.descriptor.platform = Some(Platform::new("linux", "amd64", None));
+
+        publish_image(&registry, "other/base", "arm", &[gzip(b"arm layer")]);
+        let remote_ref =
+            ImageReference::parse(&format!("{}/other/base:arm", registry.host())).unwrap();
+        let mut remote = ManifestListEntry {
+            source: remote_ref.full_name(),
+            image_id: None,
+            descriptor: client
+                .manifest_descriptor(&remote_ref, &paths)
+                .await
+                .unwrap(),
+        };
+        assert_eq!(
+            remote.descriptor.platform.as_ref().unwrap().architecture,
+            get_host_arch()
+        );
+        remote.descriptor.platform = Some(Platform::new("linux", "arm", Some("v7")));
+
+        let mut list = ManifestList::new(&target);
+        list.add(local.clone());
+        list.add(remote.clone());
+        let (digest, size) = client.push_manifest_list(&list, &paths).await.unwrap();
+
+        let state = registry.state();
+        let (media_type, bytes) = state.manifests.get("test/app@multi").unwrap();
+        assert_eq!(media_type, media_types::OCI_IMAGE_INDEX);
+        assert_eq!(bytes.len(), size);
+        assert_eq!(LayerManager::compute_digest_bytes(bytes), digest);
+
+        let index: ImageIndex = serde_json::from_slice(bytes).unwrap();
+        assert_eq!(index.manifests.len(), 2);
+        assert_eq!(index.manifests[0].digest, local.descriptor.digest);
+        assert_eq!(index.manifests[1].digest, remote.descriptor.digest);
+        let platform = index.manifests[1].platform.as_ref().unwrap();
+        assert_eq!(platform.architecture, "arm");
+        assert_eq!(platform.variant.as_deref(), Some("v7"));
+
+        // Both manifests are now in the list's repository
+        for entry in &index.manifests {
+            assert!(state
+                .manifests
+                .contains_key(&format!("test/app@{}", entry.digest)));
+        }
+    }
+
+    fn test_credentials() -> Credentials {
+        Credentials {
+            username: "ci".to_string(),
+            password: "hunter2".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bearer_auth_scopes() {
+        let registry = TestRegistry::start_with_auth(TestAuth::Bearer {
+            username: "ci".to_string(),
+            password: "hunter2".to_string(),
+        })
+        .await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let reference = ImageReference::parse(&format!("{}/team/app:v1", registry.host())).unwrap();
+        let image_id = store_local_image(&paths, &reference.repository_with_registry(), "v1");
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+
+        // Without credentials the token service refuses
+        assert!(client.push(&reference, &image_id, &paths).await.is_err());
+
+        CredentialStore::new(&paths)
+            .unwrap()
+            .store(&registry.host(), &test_credentials())
+            .unwrap();
+        client.push(&reference, &image_id, &paths).await.unwrap();
+        client.pull(&reference, &paths).await.unwrap();
+
+        assert_eq!(
+            registry.state().token_scopes,
+            vec![
+                "repository:team/app:pull,push".to_string(),
+                "repository:team/app:pull".to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth_and_login() {
+        let registry = TestRegistry::start_with_auth(TestAuth::Basic {
+            username: "ci".to_string(),
+            password: "hunter2".to_string(),
+        })
+        .await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        let wrong = Credentials {
+            username: "ci".to_string(),
+            password: "wrong".to_string(),
+        };
+        assert!(client.login(&registry.host(), &wrong).await.is_err());
+        client
+            .login(&registry.host(), &test_credentials())
+            .await
+            .unwrap();
+
+        let reference = ImageReference::parse(&format!("{}/team/app:v1", registry.host())).unwrap();
+        let image_id = store_local_image(&paths, &reference.repository_with_registry(), "v1");
+        assert!(client.push(&reference, &image_id, &paths).await.is_err());
+
+        CredentialStore::new(&paths)
+            .unwrap()
+            .store(&registry.host(), &test_credentials())
+            .unwrap();
+        client.push(&reference, &image_id, &paths).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pull_streams_gzipped_layers() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let tar = layer_tar("etc/motd", b"welcome");
+        publish_image(&registry, "lib/base", "1.0", &[gzip(&tar)]);
+
+        let reference =
+            ImageReference::parse(&format!("{}/lib/base:1.0", registry.host())).unwrap();
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        client.pull(&reference, &paths).await.unwrap();
+
+        assert_eq!(fs::read(paths.layer_tar(&stored_as(&tar))).unwrap(), tar);
+        assert_eq!(fs::read_dir(paths.tmp_dir()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_pull_rejects_corrupt_layer() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let tar = layer_tar("etc/motd", b"welcome");
+        let blob = gzip(&tar);
+        let manifest = publish_image(&registry, "lib/base", "1.0", std::slice::from_ref(&blob));
+
+        // Serve different bytes of the same length under the layer digest
+        let mut tampered = blob;
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0xff;
+        registry
+            .state()
+            .blobs
+            .insert(manifest.layers[0].digest.clone(), tampered);
+
+        let reference =
+            ImageReference::parse(&format!("{}/lib/base:1.0", registry.host())).unwrap();
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        let err = client.pull(&reference, &paths).await.unwrap_err();
+
+        assert!(matches!(err, DarkerError::Layer(ref msg) if msg.contains("Digest mismatch")));
+        assert!(!paths.layer_dir(&stored_as(&tar)).exists());
+        assert_eq!(fs::read_dir(paths.tmp_dir()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_pull_resumes_partial_download() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let tar = layer_tar("data.bin", &[7u8; 64 * 1024]);
+        let blob = gzip(&tar);
+        let manifest = publish_image(&registry, "lib/big", "1.0", std::slice::from_ref(&blob));
+        let digest = manifest.layers[0].digest.trim_start_matches("sha256:");
+
+        // Leave half of the blob behind as an interrupted download
+        let partial = paths.tmp_dir().join(format!("{}.partial", digest));
+        let half = blob.len() / 2;
+        fs::write(&partial, &blob[..half]).unwrap();
+
+        let reference = ImageReference::parse(&format!("{}/lib/big:1.0", registry.host())).unwrap();
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        client.pull(&reference, &paths).await.unwrap();
+
+        assert_eq!(registry.state().ranges, vec![format!("bytes={}-", half)]);
+        assert_eq!(fs::read(paths.layer_tar(&stored_as(&tar))).unwrap(), tar);
+        assert!(!partial.exists());
+    }
+
+    #[tokio::test]
+    async fn test_pull_restarts_without_range_support() {
+        let registry = TestRegistry::start().await;
+        registry.state().ignore_ranges = true;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let tar = layer_tar("data.bin", &[7u8; 64 * 1024]);
+        let blob = gzip(&tar);
+        let manifest = publish_image(&registry, "lib/big", "1.0", std::slice::from_ref(&blob));
+        let digest = manifest.layers[0].digest.trim_start_matches("sha256:");
+
+        // Garbage that would fail verification if it were resumed from
+        let partial = paths.tmp_dir().join(format!("{}.partial", digest));
+        fs::write(&partial, vec![0u8; blob.len() / 2]).unwrap();
+
+        let reference = ImageReference::parse(&format!("{}/lib/big:1.0", registry.host())).unwrap();
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        client.pull(&reference, &paths).await.unwrap();
+
+        assert!(registry.state().ranges.is_empty());
+        assert_eq!(fs::read(paths.layer_tar(&stored_as(&tar))).unwrap(), tar);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_partial_download_is_discarded() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let blob = gzip(&layer_tar("data.bin", &[7u8; 64 * 1024]));
+        let manifest = publish_image(&registry, "lib/big", "1.0", std::slice::from_ref(&blob));
+        let digest = manifest.layers[0].digest.trim_start_matches("sha256:");
+
+        let partial = paths.tmp_dir().join(format!("{}.partial", digest));
+        fs::write(&partial, vec![0u8; blob.len() / 2]).unwrap();
+
+        let reference = ImageReference::parse(&format!("{}/lib/big:1.0", registry.host())).unwrap();
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+
+        // The resumed blob fails verification, and the next pull starts over
+        let err = client.pull(&reference, &paths).await.unwrap_err();
+        assert!(matches!(err, DarkerError::Layer(_)));
+        assert!(!partial.exists());
+        client.pull(&reference, &paths).await.unwrap();
+    }
+
+    /// Progress sink that records every update
+    #[derive(Default)]
+    struct RecordingProgress {
+        updates: std::sync::Mutex<Vec<(String, LayerStatus)>>,
+    }
+
+    impl ProgressSink for RecordingProgress {
+        fn update(&self, id: &str, status: &LayerStatus) {
+            self.updates
+                .lock()
+                .unwrap()
+                .push((id.to_string(), status.clone()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pull_layers_concurrently_with_progress() {
+        let registry = TestRegistry::start().await;
+        registry.state().blob_delay = Some(std::time::Duration::from_millis(200));
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let blobs: Vec<Vec<u8>> = (0..4)
+            .map(|i| gzip(&layer_tar(&format!("file{}", i), &[i as u8; 4096])))
+            .collect();
+        let manifest = publish_image(&registry, "lib/multi", "1.0", &blobs);
+
+        let reference =
+            ImageReference::parse(&format!("{}/lib/multi:1.0", registry.host())).unwrap();
+        let progress = Arc::new(RecordingProgress::default());
+        let client = RegistryClient::new()
+            .unwrap()
+            .with_max_concurrent_downloads(2)
+            .with_progress(progress.clone());
+        let image_id = client.pull(&reference, &paths).await.unwrap();
+
+        assert_eq!(registry.state().max_active_blob_gets, 2);
+
+        // Layers stay in manifest order regardless of completion order
+        let metadata = ImageStore::new(&paths)
+            .unwrap()
+            .load_metadata(&image_id)
+            .unwrap();
+        let diff_ids: Vec<String> = blobs
+            .iter()
+            .map(|blob| {
+                gunzip_digest(blob)
+                    .trim_start_matches("sha256:")
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(metadata.layers, diff_ids);
+        let blob_digests: Vec<String> = manifest.layers.iter().map(|l| l.digest.clone()).collect();
+        assert_eq!(metadata.blob_digests, blob_digests);
+
+        // Progress is reported by blob digest
+        let expected: Vec<&str> = blob_digests
+            .iter()
+            .map(|digest| digest.trim_start_matches("sha256:"))
+            .collect();
+
+        let updates = progress.updates.lock().unwrap().clone();
+        for layer in &expected {
+            let statuses: Vec<&LayerStatus> = updates
+                .iter()
+                .filter(|(id, _)| *id == layer[..12])
+                .map(|(_, status)| status)
+                .collect();
+            assert_eq!(statuses.first(), Some(&&LayerStatus::Waiting));
+            assert_eq!(statuses.last(), Some(&&LayerStatus::Complete));
+            assert!(statuses.iter().any(|s| matches!(
+                s,
+                LayerStatus::Downloading { current, total } if current == total
+            )));
+        }
+
+        // A second pull finds every layer in the store
+        progress.updates.lock().unwrap().clear();
+        client.pull(&reference, &paths).await.unwrap();
+        let updates = progress.updates.lock().unwrap().clone();
+        assert_eq!(updates.len(), 4);
+        assert!(updates
+            .iter()
+            .all(|(_, status)| *status == LayerStatus::AlreadyExists));
+    }
+
+    fn manifest_digest(manifest: &ImageManifest) -> String {
+        format!(
+            "sha256:{:x}",
+            Sha256::digest(serde_json::to_vec(manifest).unwrap())
+        )
+    }
+
+    #[tokio::test]
+    async fn test_pull_by_digest() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let blob = gzip(&layer_tar("hello", b"hi"));
+        let manifest = publish_image(&registry, "lib/app", "1.0", &[blob]);
+        let digest = manifest_digest(&manifest);
+
+        let name = format!("{}/lib/app@{}", registry.host(), digest);
+        let reference = ImageReference::parse(&name).unwrap();
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        let image_id = client.pull(&reference, &paths).await.unwrap();
+
+        assert_eq!(
+            registry.count_requests(&hyper::Method::GET, &format!("/manifests/{}", digest)),
+            1
+        );
+        assert_eq!(
+            registry.count_requests(&hyper::Method::GET, "/manifests/1.0"),
+            0
+        );
+
+        // The digest resolves locally, and no tag is created
+        let store = ImageStore::new(&paths).unwrap();
+        assert_eq!(store.find_image(&reference), Some(image_id.clone()));
+        assert_eq!(store.find(&name), Some(image_id.clone()));
+        let tagged = ImageReference::parse(&format!("{}/lib/app:1.0", registry.host())).unwrap();
+        assert_eq!(store.find_image(&tagged), None);
+
+        let metadata = store.load_metadata(&image_id).unwrap();
+        assert_eq!(metadata.repo_digests, vec![name]);
+        assert_eq!(metadata.tag, None);
+    }
+
+    #[tokio::test]
+    async fn test_pull_by_tag_records_repo_digest() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let blob = gzip(&layer_tar("hello", b"hi"));
+        let manifest = publish_image(&registry, "lib/app", "1.0", &[blob]);
+
+        let reference = ImageReference::parse(&format!("{}/lib/app:1.0", registry.host())).unwrap();
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        let image_id = client.pull(&reference, &paths).await.unwrap();
+
+        let pinned = format!("{}/lib/app@{}", registry.host(), manifest_digest(&manifest));
+        let store = ImageStore::new(&paths).unwrap();
+        let metadata = store.load_metadata(&image_id).unwrap();
+        assert_eq!(metadata.repo_digests, vec![pinned.clone()]);
+        assert_eq!(metadata.tag.as_deref(), Some("1.0"));
+        assert_eq!(
+            store.find_image(&ImageReference::parse(&pinned).unwrap()),
+            Some(image_id)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pull_by_index_digest_verifies_platform_manifest() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let blob = gzip(&layer_tar("hello", b"hi"));
+        let manifest = publish_image(&registry, "lib/app", "1.0", &[blob]);
+        let manifest_bytes = serde_json::to_vec(&manifest).unwrap();
+        let index = serde_json::to_vec(&serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": media_types::OCI_IMAGE_INDEX,
+            "manifests": [{
+                "mediaType": media_types::OCI_IMAGE_MANIFEST,
+                "digest": manifest_digest(&manifest),
+                "size": manifest_bytes.len(),
+                "platform": { "architecture": get_host_arch(), "os": "linux" },
+            }],
+        }))
+        .unwrap();
+        let index_digest =
+            registry.add_manifest("lib/app", "multi", media_types::OCI_IMAGE_INDEX, &index);
+
+        let name = format!("{}/lib/app@{}", registry.host(), index_digest);
+        let reference = ImageReference::parse(&name).unwrap();
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        let image_id = client.pull(&reference, &paths).await.unwrap();
+
+        let metadata = ImageStore::new(&paths)
+            .unwrap()
+            .load_metadata(&image_id)
+            .unwrap();
+        assert_eq!(metadata.repo_digests, vec![name]);
+
+        // A platform manifest that doesn't match its descriptor is rejected
+        registry
+            .state()
+            .manifests
+            .get_mut(&format!("lib/app@{}", manifest_digest(&manifest)))
+            .unwrap()
+            .1
+            .push(b' ');
+        let err = client.pull(&reference, &paths).await.unwrap_err();
+        assert!(err.to_string().contains("Manifest digest mismatch"));
+    }
+
+    #[tokio::test]
+    async fn test_pull_rejects_manifest_not_matching_digest() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let blob = gzip(&layer_tar("hello", b"hi"));
+        let manifest = publish_image(&registry, "lib/app", "1.0", &[blob]);
+
+        // Serve the manifest under a digest it doesn't hash to
+        let forged = format!("sha256:{}", "0".repeat(64));
+        registry.state().manifests.insert(
+            format!("lib/app@{}", forged),
+            (
+                media_types::OCI_IMAGE_MANIFEST.to_string(),
+                serde_json::to_vec(&manifest).unwrap(),
+            ),
+        );
+
+        let reference =
+            ImageReference::parse(&format!("{}/lib/app@{}", registry.host(), forged)).unwrap();
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        let err = client.pull(&reference, &paths).await.unwrap_err();
+        assert!(err.to_string().contains("Manifest digest mismatch"));
+        assert!(ImageStore::new(&paths).unwrap().list().unwrap().is_empty());
+    }
+
+    fn platform_index(platforms: &[&str]) -> ImageIndex {
+        serde_json::from_value(serde_json::json!({
+            "schemaVersion": 2,
+            "manifests": platforms
+                .iter()
+                .enumerate()
+                .map(|(i, platform)| {
+                    let parts: Vec<&str> = platform.split('/').collect();
+                    serde_json::json!({
+                        "mediaType": media_types::OCI_IMAGE_MANIFEST,
+                        "digest": format!("sha256:{:064}", i),
+                        "size": 1,
+                        "platform": {
+                            "os": parts[0],
+                            "architecture": parts[1],
+                            "variant": parts.get(2),
+                        },
+                    })
+                })
+                .collect::<Vec<_>>(),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_select_manifest() {
+        let index = platform_index(&[
+            "linux/amd64",
+            "linux/arm/v6",
+            "linux/arm/v7",
+            "linux/arm64/v8",
+        ]);
+        let select = |platform: &str| {
+            let platform = Platform::parse(platform).unwrap();
+            select_manifest(&index, Some(&platform)).map(|m| m.digest.clone())
+        };
+
+        assert_eq!(select("linux/amd64").unwrap(), format!("sha256:{:064}", 0));
+        assert_eq!(select("linux/arm/v6").unwrap(), format!("sha256:{:064}", 1));
+        assert_eq!(select("linux/arm").unwrap(), format!("sha256:{:064}", 2));
+        assert_eq!(
+            select("linux/aarch64").unwrap(),
+            format!("sha256:{:064}", 3)
+        );
+
+        let err = select("linux/s390x").unwrap_err().to_string();
+        assert!(err.contains("no matching manifest for linux/s390x"));
+        assert!(err.contains("linux/arm/v7"));
+
+        // Older variants are used when the exact one is missing
+        let index = platform_index(&["linux/arm/v5", "linux/arm/v6"]);
+        let wanted = Platform::parse("linux/arm/v7").unwrap();
+        let selected = select_manifest(&index, Some(&wanted)).unwrap();
+        assert_eq!(selected.digest, format!("sha256:{:064}", 1));
+    }
+
+    #[tokio::test]
+    async fn test_pull_records_platform() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let blob = gzip(&layer_tar("hello", b"hi"));
+        publish_image(&registry, "lib/app", "1.0", &[blob]);
+
+        let reference = ImageReference::parse(&format!("{}/lib/app:1.0", registry.host())).unwrap();
+        let host = Platform::new("linux", get_host_arch(), None);
+        let client = RegistryClient::new()
+            .unwrap()
+            .with_quiet(true)
+            .with_platform(Some(host.clone()));
+        let image_id = client.pull(&reference, &paths).await.unwrap();
+
+        let store = ImageStore::new(&paths).unwrap();
+        let metadata = store.load_metadata(&image_id).unwrap();
+        assert_eq!(metadata.platform, Some(host.to_string()));
+        assert_eq!(
+            store.find_image_for_platform(&reference, Some(&host)),
+            Some(image_id)
+        );
+
+        // A single-platform image for another platform is rejected
+        let other = Platform::parse("linux/s390x").unwrap();
+        assert_eq!(
+            store.find_image_for_platform(&reference, Some(&other)),
+            None
+        );
+        let err = client
+            .with_platform(Some(other))
+            .pull(&reference, &paths)
+            .await
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("does not match the specified platform"));
+    }
+
+    fn mirror_config(registry: &str, mirrors: &[String]) -> RegistriesConfig {
+        let mut config = RegistriesConfig::default();
+        config.registries.insert(
+            registry.to_string(),
+            crate::storage::registries::RegistrySettings {
+                mirrors: mirrors.to_vec(),
+                ..Default::default()
+            },
+        );
+        config
+    }
+
+    #[tokio::test]
+    async fn test_pull_from_mirror() {
+        let upstream = TestRegistry::start().await;
+        let mirror = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let blob = gzip(&layer_tar("hello", b"hi"));
+        publish_image(&upstream, "lib/app", "1.0", std::slice::from_ref(&blob));
+        publish_image(&mirror, "proxy/lib/app", "1.0", &[blob]);
+
+        // The mirror keeps the repository under a namespace
+        let config = mirror_config(
+            &upstream.host(),
+            &[format!("http://{}/proxy", mirror.host())],
+        );
+        let reference = ImageReference::parse(&format!("{}/lib/app:1.0", upstream.host())).unwrap();
+        let client = RegistryClient::new()
+            .unwrap()
+            .with_quiet(true)
+            .with_registries(config)
+            .unwrap();
+        client.pull(&reference, &paths).await.unwrap();
+
+        assert_eq!(
+            mirror.count_requests(&hyper::Method::GET, "/v2/proxy/lib/app/manifests/1.0"),
+            1
+        );
+        assert!(upstream.state().requests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pull_falls_back_to_upstream() {
+        let upstream = TestRegistry::start().await;
+        let mirror = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let blob = gzip(&layer_tar("hello", b"hi"));
+        publish_image(&upstream, "lib/app", "1.0", &[blob]);
+
+        // An unreachable mirror and one without the image are skipped
+        let config = mirror_config(
+            &upstream.host(),
+            &["localhost:1".to_string(), mirror.host()],
+        );
+        let reference = ImageReference::parse(&format!("{}/lib/app:1.0", upstream.host())).unwrap();
+        let client = RegistryClient::new()
+            .unwrap()
+            .with_quiet(true)
+            .with_registries(config)
+            .unwrap();
+        client.pull(&reference, &paths).await.unwrap();
+
+        assert_eq!(
+            mirror.count_requests(&hyper::Method::GET, "/manifests/1.0"),
+            1
+        );
+        assert_eq!(
+            upstream.count_requests(&hyper::Method::GET, "/manifests/1.0"),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_plain_http_registry() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let blob = gzip(&layer_tar("hello", b"hi"));
+        publish_image(&registry, "lib/app", "1.0", &[blob]);
+
+        // Hosts other than localhost default to HTTPS
+        let host = registry.host().replace("localhost", "127.0.0.1");
+        let reference = ImageReference::parse(&format!("{}/lib/app:1.0", host)).unwrap();
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        assert!(client.pull(&reference, &paths).await.is_err());
+
+        let mut config = RegistriesConfig::default();
+        config.registries.insert(
+            host.clone(),
+            crate::storage::registries::RegistrySettings {
+                plain_http: true,
+                ..Default::default()
+            },
+        );
+        let client = client.with_registries(config).unwrap();
+        client.pull(&reference, &paths).await.unwrap();
+    }
+
+    #[test]
+    fn test_missing_ca_file() {
+        let mut config = RegistriesConfig::default();
+        config.registries.insert(
+            "corp.example".to_string(),
+            crate::storage::registries::RegistrySettings {
+                ca_file: Some("/nonexistent/ca.pem".into()),
+                ..Default::default()
+            },
+        );
+        let err = RegistryClient::new()
+            .unwrap()
+            .with_registries(config)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("/nonexistent/ca.pem"));
+    }
+
+    #[tokio::test]
+    async fn test_list_tags_follows_pagination() {
+        let registry = TestRegistry::start().await;
+        registry.state().page_size = Some(2);
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+
+        let blobs = vec![gzip(&layer_tar("hello", b"hi"))];
+        for tag in ["1.0", "1.1", "2.0", "latest", "edge"] {
+            publish_image(&registry, "lib/app", tag, &blobs);
+        }
+
+        let reference = ImageReference::parse(&format!("{}/lib/app", registry.host())).unwrap();
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        let tags = client.list_tags(&reference, &paths).await.unwrap();
+
+        assert_eq!(tags, vec!["1.0", "1.1", "2.0", "edge", "latest"]);
+        assert_eq!(
+            registry.count_requests(&hyper::Method::GET, "/tags/list"),
+            3
+        );
+
+        let missing = ImageReference::parse(&format!("{}/lib/none", registry.host())).unwrap();
+        assert!(client.list_tags(&missing, &paths).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_catalog_requests_catalog_scope() {
+        let registry = TestRegistry::start_with_auth(TestAuth::Bearer {
+            username: "ci".to_string(),
+            password: "hunter2".to_string(),
+        })
+        .await;
+        registry.state().page_size = Some(1);
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        CredentialStore::new(&paths)
+            .unwrap()
+            .store(&registry.host(), &test_credentials())
+            .unwrap();
+
+        let blobs = vec![gzip(&layer_tar("hello", b"hi"))];
+        publish_image(&registry, "team/web", "v1", &blobs);
+        publish_image(&registry, "team/web", "v2", &blobs);
+        publish_image(&registry, "team/api", "v1", &blobs);
+
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        let repositories = client.catalog(&registry.host(), &paths).await.unwrap();
+
+        assert_eq!(repositories, vec!["team/api", "team/web"]);
+        assert_eq!(
+            registry.state().token_scopes,
+            vec!["registry:catalog:*".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_next_link() {
+        assert_eq!(
+            next_link(r#"</v2/_catalog?n=2&last=b>; rel="next""#),
+            Some("/v2/_catalog?n=2&last=b".to_string())
+        );
+        assert_eq!(
+            next_link(r#"<https://r.example/a>; rel="prev", <https://r.example/b>; rel=next"#),
+            Some("https://r.example/b".to_string())
+        );
+        assert_eq!(next_link(r#"</v2/_catalog?n=2>; rel="prev""#), None);
+    }
+
+    fn fast_retries() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: std::time::Duration::from_millis(1),
+            max_delay: std::time::Duration::from_millis(10),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pull_retries_transient_failures() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let blobs = vec![gzip(&layer_tar("hello", b"hi"))];
+        let manifest = publish_image(&registry, "lib/app", "1.0", &blobs);
+        let layer = manifest.layers[0].digest.clone();
+        registry.state().faults = vec![
+            (
+                "/manifests/".to_string(),
+                Fault::Status(hyper::StatusCode::SERVICE_UNAVAILABLE, Some("0")),
+            ),
+            (
+                "/manifests/".to_string(),
+                Fault::Status(hyper::StatusCode::TOO_MANY_REQUESTS, None),
+            ),
+            (
+                layer.clone(),
+                Fault::Status(hyper::StatusCode::BAD_GATEWAY, None),
+            ),
+        ];
+
+        let reference = ImageReference::parse(&format!("{}/lib/app:1.0", registry.host())).unwrap();
+        let client = RegistryClient::new()
+            .unwrap()
+            .with_quiet(true)
+            .with_retry_policy(fast_retries());
+        client.pull(&reference, &paths).await.unwrap();
+
+        assert_eq!(
+            registry.count_requests(&hyper::Method::GET, "/manifests/"),
+            3
+        );
+        assert_eq!(registry.count_requests(&hyper::Method::GET, &layer), 2);
+    }
+
+    #[tokio::test]
+    async fn test_pull_resumes_interrupted_transfer() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let tar = layer_tar("data.bin", &[7u8; 64 * 1024]);
+        let blobs = vec![gzip(&tar)];
+        let manifest = publish_image(&registry, "lib/big", "1.0", &blobs);
+        let layer = manifest.layers[0].digest.clone();
+        registry.state().faults = vec![(layer.clone(), Fault::Truncate(100))];
+
+        let reference = ImageReference::parse(&format!("{}/lib/big:1.0", registry.host())).unwrap();
+        let client = RegistryClient::new()
+            .unwrap()
+            .with_quiet(true)
+            .with_retry_policy(fast_retries());
+        client.pull(&reference, &paths).await.unwrap();
+
+        assert_eq!(registry.state().ranges, vec!["bytes=100-".to_string()]);
+        assert_eq!(fs::read(paths.layer_tar(&stored_as(&tar))).unwrap(), tar);
+    }
+
+    #[tokio::test]
+    async fn test_fatal_registry_errors() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+        let client = RegistryClient::new()
+            .unwrap()
+            .with_quiet(true)
+            .with_retry_policy(fast_retries());
+
+        // Unknown manifests fail on the first attempt
+        let missing = ImageReference::parse(&format!("{}/lib/none:1.0", registry.host())).unwrap();
+        let err = client.pull(&missing, &paths).await.unwrap_err();
+        assert!(matches!(
+            err,
+            DarkerError::Registry(RegistryError::ManifestUnknown { ref registry, ref reference })
+                if *registry == missing.registry && reference == "lib/none:1.0"
+        ));
+        assert!(!err.is_transient());
+        assert_eq!(registry.count_requests(&hyper::Method::GET, "lib/none"), 1);
+
+        // Transient failures give up after the last attempt
+        let blobs = vec![gzip(&layer_tar("hello", b"hi"))];
+        publish_image(&registry, "lib/app", "1.0", &blobs);
+        let fault = Fault::Status(hyper::StatusCode::SERVICE_UNAVAILABLE, None);
+        registry.state().faults = vec![("lib/app/manifests/".to_string(), fault); 3];
+        let reference = ImageReference::parse(&format!("{}/lib/app:1.0", registry.host())).unwrap();
+        let err = client.pull(&reference, &paths).await.unwrap_err();
+        assert!(matches!(
+            err,
+            DarkerError::Registry(RegistryError::Status { status, .. })
+                if status == StatusCode::SERVICE_UNAVAILABLE
+        ));
+        assert_eq!(
+            registry.count_requests(&hyper::Method::GET, "lib/app/manifests/"),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized_error() {
+        let registry = TestRegistry::start_with_auth(TestAuth::Basic {
+            username: "ci".to_string(),
+            password: "hunter2".to_string(),
+        })
+        .await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let reference = ImageReference::parse(&format!("{}/team/app:v1", registry.host())).unwrap();
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        let err = client.pull(&reference, &paths).await.unwrap_err();
+        assert!(matches!(
+            err,
+            DarkerError::Registry(RegistryError::Unauthorized { status, .. })
+                if status == StatusCode::UNAUTHORIZED
+        ));
+        assert!(err.to_string().contains("darker login"));
+    }
+
+    fn layer_descriptor(media_type: &str, blob: &[u8]) -> Descriptor {
+        Descriptor {
+            media_type: media_type.to_string(),
+            digest: LayerManager::compute_digest_bytes(blob),
+            size: blob.len() as i64,
+            urls: None,
+            annotations: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pull_zstd_and_uncompressed_layers() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let zstd_tar = layer_tar("a", b"zstd layer");
+        let plain_tar = layer_tar("b", b"plain layer");
+        let zstd_blob = zstd::stream::encode_all(&zstd_tar[..], 0).unwrap();
+        let layers = vec![
+            layer_descriptor(media_types::OCI_LAYER_TAR_ZSTD, &zstd_blob),
+            layer_descriptor(media_types::DOCKER_LAYER_TAR, &plain_tar),
+        ];
+        registry.add_blob(&zstd_blob);
+        registry.add_blob(&plain_tar);
+        let diff_ids = vec![
+            LayerManager::compute_digest_bytes(&zstd_tar),
+            LayerManager::compute_digest_bytes(&plain_tar),
+        ];
+        publish_layers(&registry, "lib/app", "1.0", layers, diff_ids);
+
+        let reference = ImageReference::parse(&format!("{}/lib/app:1.0", registry.host())).unwrap();
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        client.pull(&reference, &paths).await.unwrap();
+
+        for tar in [zstd_tar, plain_tar] {
+            assert_eq!(fs::read(paths.layer_tar(&stored_as(&tar))).unwrap(), tar);
+        }
+
+        // Layers are decompressed by media type, not by their contents
+        let gzip_blob = gzip(&layer_tar("c", b"mislabeled"));
+        registry.add_blob(&gzip_blob);
+        let layers = vec![layer_descriptor(
+            media_types::OCI_LAYER_TAR_ZSTD,
+            &gzip_blob,
+        )];
+        let diff_ids = vec![gunzip_digest(&gzip_blob)];
+        publish_layers(&registry, "lib/app", "2.0", layers, diff_ids);
+        let reference = ImageReference::parse(&format!("{}/lib/app:2.0", registry.host())).unwrap();
+        assert!(client.pull(&reference, &paths).await.is_err());
+
+        let other_tar = layer_tar("d", b"unknown compression");
+        registry.add_blob(&other_tar);
+        let unknown = layer_descriptor("application/vnd.example.layer.v1.tar+lz4", &other_tar);
+        let diff_ids = vec![LayerManager::compute_digest_bytes(&other_tar)];
+        publish_layers(&registry, "lib/app", "3.0", vec![unknown], diff_ids);
+        let reference = ImageReference::parse(&format!("{}/lib/app:3.0", registry.host())).unwrap();
+        let err = client.pull(&reference, &paths).await.unwrap_err();
+        assert!(err.to_string().contains("Unsupported layer media type"));
+    }
+
+    #[tokio::test]
+    async fn test_pull_foreign_layer_from_urls() {
+        let registry = TestRegistry::start_with_auth(TestAuth::Basic {
+            username: "ci".to_string(),
+            password: "hunter2".to_string(),
+        })
+        .await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+        CredentialStore::new(&paths)
+            .unwrap()
+            .store(&registry.host(), &test_credentials())
+            .unwrap();
+
+        // The registry doesn't have the blob; only the second URL serves it
+        let tar = layer_tar("base", b"foreign");
+        let blob = gzip(&tar);
+        registry
+            .state()
+            .files
+            .insert("base.tar.gz".to_string(), blob.clone());
+        let mut layer = layer_descriptor(media_types::DOCKER_FOREIGN_LAYER_TAR_GZIP, &blob);
+        layer.urls = Some(vec![
+            format!("http://{}/files/missing.tar.gz", registry.host()),
+            format!("http://{}/files/base.tar.gz", registry.host()),
+        ]);
+        let diff_ids = vec![LayerManager::compute_digest_bytes(&tar)];
+        publish_layers(&registry, "lib/win", "1.0", vec![layer], diff_ids);
+
+        let reference = ImageReference::parse(&format!("{}/lib/win:1.0", registry.host())).unwrap();
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        client.pull(&reference, &paths).await.unwrap();
+
+        assert_eq!(fs::read(paths.layer_tar(&stored_as(&tar))).unwrap(), tar);
+        assert_eq!(
+            registry.count_requests(&hyper::Method::GET, "/files/base.tar.gz"),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pull_shares_layers_across_compressions() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        // The same tar published gzipped and zstd compressed
+        let tar = layer_tar("etc/os-release", b"ID=darker");
+        let gzip_blob = gzip(&tar);
+        let zstd_blob = zstd::stream::encode_all(&tar[..], 0).unwrap();
+        let diff_id = LayerManager::compute_digest_bytes(&tar);
+        for (tag, media_type, blob) in [
+            ("gzip", media_types::OCI_LAYER_TAR_GZIP, &gzip_blob),
+            ("zstd", media_types::OCI_LAYER_TAR_ZSTD, &zstd_blob),
+        ] {
+            registry.add_blob(blob);
+            let layers = vec![layer_descriptor(media_type, blob)];
+            publish_layers(&registry, "lib/os", tag, layers, vec![diff_id.clone()]);
+        }
+
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        let image_store = ImageStore::new(&paths).unwrap();
+        for (tag, blob) in [("gzip", &gzip_blob), ("zstd", &zstd_blob)] {
+            let reference =
+                ImageReference::parse(&format!("{}/lib/os:{}", registry.host(), tag)).unwrap();
+            let image_id = client.pull(&reference, &paths).await.unwrap();
+
+            let metadata = image_store.load_metadata(&image_id).unwrap();
+            assert_eq!(metadata.layers, vec![stored_as(&tar)]);
+            assert_eq!(
+                metadata.blob_digests,
+                vec![LayerManager::compute_digest_bytes(blob)]
+            );
+        }
+        assert_eq!(fs::read_dir(paths.layers_dir()).unwrap().count(), 1);
+
+        // Only the first pull downloaded the layer
+        let zstd_digest = LayerManager::compute_digest_bytes(&zstd_blob);
+        let path = format!("/blobs/{}", zstd_digest);
+        assert_eq!(registry.count_requests(&hyper::Method::GET, &path), 0);
+    }
+
+    #[tokio::test]
+    async fn test_pull_rejects_wrong_diff_id() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        // The blob matches its descriptor, but not the config's diff ID
+        let blob = gzip(&layer_tar("etc/motd", b"welcome"));
+        registry.add_blob(&blob);
+        let layers = vec![layer_descriptor(media_types::OCI_LAYER_TAR_GZIP, &blob)];
+        let wrong = LayerManager::compute_digest_bytes(&layer_tar("etc/motd", b"goodbye"));
+        publish_layers(&registry, "lib/base", "1.0", layers, vec![wrong]);
+
+        let reference =
+            ImageReference::parse(&format!("{}/lib/base:1.0", registry.host())).unwrap();
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        let err = client.pull(&reference, &paths).await.unwrap_err();
+
+        assert!(matches!(err, DarkerError::Layer(ref msg) if msg.contains("Diff ID mismatch")));
+        assert_eq!(fs::read_dir(paths.layers_dir()).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(paths.tmp_dir()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_pull_keeps_blobs_byte_for_byte() {
+        let registry = TestRegistry::start().await;
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        // Pretty-printed Docker manifests and configs, which re-serializing would change
+        let blob = gzip(&layer_tar("hello", b"hi"));
+        let layer_digest = registry.add_blob(&blob);
+        let config = serde_json::json!({
+            "architecture": get_host_arch(),
+            "os": "linux",
+            "config": { "Cmd": ["/hello"] },
+            "rootfs": { "type": "layers", "diff_ids": [gunzip_digest(&blob)] },
+        });
+        let mut config_bytes = serde_json::to_vec_pretty(&config).unwrap();
+        config_bytes.push(b'\n');
+        let config_digest = registry.add_blob(&config_bytes);
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": media_types::DOCKER_MANIFEST_V2,
+            "config": {
+                "mediaType": media_types::DOCKER_CONTAINER_IMAGE,
+                "size": config_bytes.len(),
+                "digest": config_digest,
+            },
+            "layers": [{
+                "mediaType": media_types::DOCKER_LAYER_TAR_GZIP,
+                "size": blob.len(),
+                "digest": layer_digest,
+            }],
+        });
+        let manifest_bytes = serde_json::to_vec_pretty(&manifest).unwrap();
+        for tag in ["1.0", "1.1"] {
+            registry.add_manifest(
+                "lib/app",
+                tag,
+                media_types::DOCKER_MANIFEST_V2,
+                &manifest_bytes,
+            );
+        }
+
+        let client = RegistryClient::new().unwrap().with_quiet(true);
+        let reference = ImageReference::parse(&format!("{}/lib/app:1.0", registry.host())).unwrap();
+        let image_id = client.pull(&reference, &paths).await.unwrap();
+
+        let metadata = ImageStore::new(&paths)
+            .unwrap()
+            .load_metadata(&image_id)
+            .unwrap();
+        let manifest_digest = LayerManager::compute_digest_bytes(&manifest_bytes);
+        assert_eq!(metadata.manifest_digest.as_ref(), Some(&manifest_digest));
+        assert_eq!(metadata.digest.as_ref(), Some(&config_digest));
+        let blobs = BlobStore::new(&paths);
+        assert_eq!(blobs.read(&manifest_digest).unwrap(), manifest_bytes);
+        assert_eq!(blobs.read(&config_digest).unwrap(), config_bytes);
+        assert_eq!(blobs.read(&layer_digest).unwrap(), blob);
+        assert!(!paths.image_config(&image_id).exists());
+
+        // Another tag of the same image downloads nothing but its manifest
+        let reference = ImageReference::parse(&format!("{}/lib/app:1.1", registry.host())).unwrap();
+        client.pull(&reference, &paths).await.unwrap();
+        for digest in [&config_digest, &layer_digest] {
+            let path = format!("/blobs/{}", digest);
+            assert_eq!(registry.count_requests(&hyper::Method::GET, &path), 1);
+        }
+
+        // The image is pushed as the exact manifest it was pulled as
+        let target = ImageReference::parse(&format!("{}/copy/app:v1", registry.host())).unwrap();
+        let (digest, _) = client.push(&target, &image_id, &paths).await.unwrap();
+        assert_eq!(digest, manifest_digest);
+        let state = registry.state();
+        let (media_type, bytes) = state.manifests.get("copy/app@v1").unwrap();
+        assert_eq!(media_type, media_types::DOCKER_MANIFEST_V2);
+        assert_eq!(bytes, &manifest_bytes);
+    }
 }
@@ -0,0 +1,568 @@
+//! In-process registry stand-in used by the registry client tests
+//!
+//! Implements just enough of the Distribution API (blobs, chunked uploads,
+//! manifests, tag lists and the catalog) for pull and push round trips
+//! against `localhost:<port>`, with optional Basic or Bearer token
+//! authentication.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Token handed out by the Bearer token endpoint
+const TEST_TOKEN: &str = "test-token";
+
+/// Authentication required by the test registry
+#[derive(Debug, Clone)]
+pub(crate) enum TestAuth {
+    /// HTTP Basic authentication with the given credentials
+    Basic { username: String, password: String },
+    /// Bearer tokens from `/token`, which requires the given credentials
+    Bearer { username: String, password: String },
+}
+
+/// A failure injected into a request to the test registry
+#[derive(Debug, Clone)]
+pub(crate) enum Fault {
+    /// Answer with a status and an optional `Retry-After` header
+    Status(StatusCode, Option<&'static str>),
+    /// Send only the first bytes of a blob, then drop the connection
+    Truncate(usize),
+}
+
+/// Shared state of the test registry
+#[derive(Debug, Default)]
+pub(crate) struct RegistryState {
+    /// Required authentication, if any
+    pub auth: Option<TestAuth>,
+    /// Base URL of the registry, used for the token realm
+    pub base_url: String,
+    /// Scopes requested from the token endpoint
+    pub token_scopes: Vec<String>,
+    /// Blobs by digest
+    pub blobs: HashMap<String, Vec<u8>>,
+    /// Files served without authentication under `/files/`, standing in for
+    /// the URLs of foreign layers
+    pub files: HashMap<String, Vec<u8>>,
+    /// Manifests keyed by "repository@reference" (tag or digest)
+    pub manifests: HashMap<String, (String, Vec<u8>)>,
+    /// In-flight uploads by session ID
+    pub uploads: HashMap<String, Vec<u8>>,
+    /// Log of received requests as (method, path)
+    pub requests: Vec<(Method, String)>,
+    /// Range headers received on blob requests
+    pub ranges: Vec<String>,
+    /// Serve whole blobs and don't advertise `Accept-Ranges`
+    pub ignore_ranges: bool,
+    /// Delay before answering blob downloads
+    pub blob_delay: Option<Duration>,
+    /// Blob downloads currently being answered
+    pub active_blob_gets: usize,
+    /// Highest number of blob downloads answered at the same time
+    pub max_active_blob_gets: usize,
+    /// Page size for tag and catalog listings when the client doesn't pass `n`
+    pub page_size: Option<usize>,
+    /// Faults to inject, each into the next request whose path contains the
+    /// given string
+    pub faults: Vec<(String, Fault)>,
+}
+
+/// A registry listening on an ephemeral localhost port
+pub(crate) struct TestRegistry {
+    addr: SocketAddr,
+    state: Arc<Mutex<RegistryState>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl TestRegistry {
+    /// Start a new registry on 127.0.0.1
+    pub async fn start() -> Self {
+        Self::start_with(None).await
+    }
+
+    /// Start a new registry that requires authentication
+    pub async fn start_with_auth(auth: TestAuth) -> Self {
+        Self::start_with(Some(auth)).await
+    }
+
+    async fn start_with(auth: Option<TestAuth>) -> Self {
+        let state = Arc::new(Mutex::new(RegistryState {
+            auth,
+            ..Default::default()
+        }));
+        let service_state = state.clone();
+
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let state = state.clone();
+                    async move { Ok::<_, Infallible>(handle(state, req).await) }
+                }))
+            }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let addr = server.local_addr();
+        state.lock().unwrap().base_url = format!("http://localhost:{}", addr.port());
+        let (tx, rx) = oneshot::channel::<()>();
+        tokio::spawn(server.with_graceful_shutdown(async {
+            let _ = rx.await;
+        }));
+
+        Self {
+            addr,
+            state,
+            shutdown: Some(tx),
+        }
+    }
+
+    /// Registry host as used in image references
+    pub fn host(&self) -> String {
+        format!("localhost:{}", self.addr.port())
+    }
+
+    /// Lock and access the registry state
+    pub fn state(&self) -> std::sync::MutexGuard<'_, RegistryState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Add a blob and return its digest
+    pub fn add_blob(&self, data: &[u8]) -> String {
+        let digest = sha256_digest(data);
+        self.state().blobs.insert(digest.clone(), data.to_vec());
+        digest
+    }
+
+    /// Add a manifest under a tag and under its digest, returning the digest
+    pub fn add_manifest(
+        &self,
+        repository: &str,
+        tag: &str,
+        media_type: &str,
+        data: &[u8],
+    ) -> String {
+        let digest = sha256_digest(data);
+        let mut state = self.state();
+        let entry = (media_type.to_string(), data.to_vec());
+        state
+            .manifests
+            .insert(format!("{}@{}", repository, tag), entry.clone());
+        state
+            .manifests
+            .insert(format!("{}@{}", repository, digest), entry);
+        digest
+    }
+
+    /// Count received requests matching a method and path substring
+    pub fn count_requests(&self, method: &Method, path_contains: &str) -> usize {
+        self.state()
+            .requests
+            .iter()
+            .filter(|(m, p)| m == method && p.contains(path_contains))
+            .count()
+    }
+}
+
+impl Drop for TestRegistry {
+    fn drop(&mut self) {
+        if let Some(tx) = self.shutdown.take() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+fn sha256_digest(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
+
+/// Serve a page of a sorted listing, honoring the `n` and `last` parameters
+fn listing(
+    path: &str,
+    query: &str,
+    page_size: Option<usize>,
+    field: &str,
+    items: Vec<String>,
+    name: Option<&str>,
+) -> Response<Body> {
+    let param = |key: &str| {
+        query
+            .split('&')
+            .find_map(|kv| kv.strip_prefix(&format!("{}=", key)))
+            .map(String::from)
+    };
+    let last = param("last");
+    let limit = param("n")
+        .and_then(|n| n.parse::<usize>().ok())
+        .or(page_size);
+
+    let remaining: Vec<String> = items
+        .into_iter()
+        .filter(|item| last.as_ref().map(|last| item > last).unwrap_or(true))
+        .collect();
+    let page: Vec<String> = remaining
+        .iter()
+        .take(limit.unwrap_or(usize::MAX))
+        .cloned()
+        .collect();
+
+    let mut body = serde_json::json!({ field: page });
+    if let Some(name) = name {
+        body["name"] = name.into();
+    }
+    let mut builder = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json");
+    if let (Some(limit), Some(last)) = (limit, page.last()) {
+        if remaining.len() > page.len() {
+            builder = builder.header(
+                "Link",
+                format!("<{}?n={}&last={}>; rel=\"next\"", path, limit, last),
+            );
+        }
+    }
+    builder.body(Body::from(body.to_string())).unwrap()
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .body(Body::empty())
+        .unwrap()
+}
+
+async fn handle(state: Arc<Mutex<RegistryState>>, req: Request<Body>) -> Response<Body> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let query = req.uri().query().unwrap_or("").to_string();
+    state
+        .lock()
+        .unwrap()
+        .requests
+        .push((method.clone(), path.clone()));
+
+    let fault = {
+        let mut state = state.lock().unwrap();
+        let index = state.faults.iter().position(|(p, _)| path.contains(p));
+        index.map(|index| state.faults.remove(index).1)
+    };
+    let truncate = match fault {
+        Some(Fault::Status(code, retry_after)) => {
+            let mut builder = Response::builder().status(code);
+            if let Some(retry_after) = retry_after {
+                builder = builder.header("Retry-After", retry_after);
+            }
+            return builder.body(Body::empty()).unwrap();
+        }
+        Some(Fault::Truncate(len)) => Some(len),
+        None => None,
+    };
+
+    if path == "/token" {
+        return token_response(&state, &req, &query);
+    }
+    if let Some(name) = path.strip_prefix("/files/") {
+        return match state.lock().unwrap().files.get(name) {
+            Some(data) if method == Method::GET => Response::new(Body::from(data.clone())),
+            Some(_) => status(StatusCode::OK),
+            None => status(StatusCode::NOT_FOUND),
+        };
+    }
+    if let Some(response) = check_auth(&state, &req) {
+        return response;
+    }
+
+    let rest = match path.strip_prefix("/v2/") {
+        Some("") => return status(StatusCode::OK),
+        Some(rest) => rest.to_string(),
+        None => return status(StatusCode::NOT_FOUND),
+    };
+
+    if rest == "_catalog" {
+        let state = state.lock().unwrap();
+        let mut names: Vec<String> = state
+            .manifests
+            .keys()
+            .filter_map(|key| key.split_once('@').map(|(name, _)| name.to_string()))
+            .collect();
+        names.sort();
+        names.dedup();
+        return listing(&path, &query, state.page_size, "repositories", names, None);
+    }
+
+    if let Some(name) = rest.strip_suffix("/tags/list") {
+        let state = state.lock().unwrap();
+        let prefix = format!("{}@", name);
+        let mut tags: Vec<String> = state
+            .manifests
+            .keys()
+            .filter_map(|key| key.strip_prefix(&prefix))
+            .filter(|tag| !tag.starts_with("sha256:"))
+            .map(String::from)
+            .collect();
+        if tags.is_empty() {
+            return status(StatusCode::NOT_FOUND);
+        }
+        tags.sort();
+        return listing(&path, &query, state.page_size, "tags", tags, Some(name));
+    }
+
+    if let Some((name, upload_id)) = rest.split_once("/blobs/uploads/") {
+        let body = hyper::body::to_bytes(req.into_body())
+            .await
+            .unwrap_or_default();
+        let mut state = state.lock().unwrap();
+        return match method {
+            Method::POST => {
+                let id = uuid::Uuid::new_v4().to_string();
+                state.uploads.insert(id.clone(), Vec::new());
+                Response::builder()
+                    .status(StatusCode::ACCEPTED)
+                    .header("Location", format!("/v2/{}/blobs/uploads/{}", name, id))
+                    .body(Body::empty())
+                    .unwrap()
+            }
+            Method::PATCH => match state.uploads.get_mut(upload_id) {
+                Some(buffer) => {
+                    buffer.extend_from_slice(&body);
+                    Response::builder()
+                        .status(StatusCode::ACCEPTED)
+                        .header(
+                            "Location",
+                            format!("/v2/{}/blobs/uploads/{}", name, upload_id),
+                        )
+                        .header("Range", format!("0-{}", buffer.len().saturating_sub(1)))
+                        .body(Body::empty())
+                        .unwrap()
+                }
+                None => status(StatusCode::NOT_FOUND),
+            },
+            Method::PUT => {
+                let Some(mut data) = state.uploads.remove(upload_id) else {
+                    return status(StatusCode::NOT_FOUND);
+                };
+                data.extend_from_slice(&body);
+                let expected = query
+                    .split('&')
+                    .find_map(|kv| kv.strip_prefix("digest="))
+                    .unwrap_or("")
+                    .replace("%3A", ":");
+                if sha256_digest(&data) != expected {
+                    return status(StatusCode::BAD_REQUEST);
+                }
+                state.blobs.insert(expected.clone(), data);
+                Response::builder()
+                    .status(StatusCode::CREATED)
+                    .header("Docker-Content-Digest", expected)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+            _ => status(StatusCode::METHOD_NOT_ALLOWED),
+        };
+    }
+
+    if let Some((_name, digest)) = rest.rsplit_once("/blobs/") {
+        if method == Method::GET {
+            let delay = {
+                let mut state = state.lock().unwrap();
+                state.active_blob_gets += 1;
+                state.max_active_blob_gets = state.max_active_blob_gets.max(state.active_blob_gets);
+                state.blob_delay
+            };
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+            state.lock().unwrap().active_blob_gets -= 1;
+        }
+
+        let mut state = state.lock().unwrap();
+        let range = req
+            .headers()
+            .get("range")
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        if let Some(range) = &range {
+            state.ranges.push(range.clone());
+        }
+        let Some(blob) = state.blobs.get(digest) else {
+            return status(StatusCode::NOT_FOUND);
+        };
+
+        let mut builder = Response::builder().header("Docker-Content-Digest", digest);
+        if !state.ignore_ranges {
+            builder = builder.header("Accept-Ranges", "bytes");
+        }
+
+        // Serve "bytes=<start>-" ranges with 206 Partial Content
+        let start = range.filter(|_| !state.ignore_ranges).and_then(|r| {
+            r.strip_prefix("bytes=")?
+                .strip_suffix('-')?
+                .parse::<usize>()
+                .ok()
+        });
+        let body = match start {
+            Some(start) if start < blob.len() => {
+                builder = builder.status(StatusCode::PARTIAL_CONTENT).header(
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", start, blob.len() - 1, blob.len()),
+                );
+                blob[start..].to_vec()
+            }
+            Some(_) => return status(StatusCode::RANGE_NOT_SATISFIABLE),
+            None => {
+                builder = builder.status(StatusCode::OK);
+                blob.clone()
+            }
+        };
+        builder = builder.header("Content-Length", body.len());
+
+        return match method {
+            Method::HEAD => builder.body(Body::empty()).unwrap(),
+            Method::GET => match truncate {
+                Some(len) => {
+                    let (mut sender, stream) = Body::channel();
+                    let partial = body[..len.min(body.len())].to_vec();
+                    tokio::spawn(async move {
+                        let _ = sender.send_data(partial.into()).await;
+                        // Let the data go out before the connection is dropped
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        sender.abort();
+                    });
+                    builder.body(stream).unwrap()
+                }
+                None => builder.body(Body::from(body)).unwrap(),
+            },
+            _ => status(StatusCode::METHOD_NOT_ALLOWED),
+        };
+    }
+
+    if let Some((name, reference)) = rest.rsplit_once("/manifests/") {
+        let key = format!("{}@{}", name, reference);
+        return match method {
+            Method::PUT => {
+                let content_type = req
+                    .headers()
+                    .get("content-type")
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("")
+                    .to_string();
+                let body = hyper::body::to_bytes(req.into_body())
+                    .await
+                    .unwrap_or_default();
+                let digest = sha256_digest(&body);
+                let mut state = state.lock().unwrap();
+                let entry = (content_type, body.to_vec());
+                state.manifests.insert(key, entry.clone());
+                state
+                    .manifests
+                    .insert(format!("{}@{}", name, digest), entry);
+                Response::builder()
+                    .status(StatusCode::CREATED)
+                    .header("Docker-Content-Digest", digest)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+            Method::GET | Method::HEAD => {
+                let state = state.lock().unwrap();
+                let Some((content_type, data)) = state.manifests.get(&key) else {
+                    return status(StatusCode::NOT_FOUND);
+                };
+                let builder = Response::builder()
+                    .status(StatusCode::OK)
+                    .header("Content-Type", content_type.as_str())
+                    .header("Content-Length", data.len())
+                    .header("Docker-Content-Digest", sha256_digest(data));
+                if method == Method::HEAD {
+                    builder.body(Body::empty()).unwrap()
+                } else {
+                    builder.body(Body::from(data.clone())).unwrap()
+                }
+            }
+            _ => status(StatusCode::METHOD_NOT_ALLOWED),
+        };
+    }
+
+    status(StatusCode::NOT_FOUND)
+}
+
+fn basic_header(username: &str, password: &str) -> String {
+    format!(
+        "Basic {}",
+        STANDARD.encode(format!("{}:{}", username, password))
+    )
+}
+
+fn authorization(req: &Request<Body>) -> Option<&str> {
+    req.headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+}
+
+fn unauthorized(challenge: String) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header("WWW-Authenticate", challenge)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Reject requests that lack the configured authorization
+fn check_auth(state: &Arc<Mutex<RegistryState>>, req: &Request<Body>) -> Option<Response<Body>> {
+    let state = state.lock().unwrap();
+    let (expected, challenge) = match state.auth.as_ref()? {
+        TestAuth::Basic { username, password } => (
+            basic_header(username, password),
+            r#"Basic realm="test-registry""#.to_string(),
+        ),
+        TestAuth::Bearer { .. } => (
+            format!("Bearer {}", TEST_TOKEN),
+            format!(
+                r#"Bearer realm="{}/token",service="test-registry""#,
+                state.base_url
+            ),
+        ),
+    };
+
+    if authorization(req) == Some(expected.as_str()) {
+        None
+    } else {
+        Some(unauthorized(challenge))
+    }
+}
+
+/// Token endpoint for Bearer authentication
+fn token_response(
+    state: &Arc<Mutex<RegistryState>>,
+    req: &Request<Body>,
+    query: &str,
+) -> Response<Body> {
+    let mut state = state.lock().unwrap();
+    let Some(TestAuth::Bearer { username, password }) = state.auth.clone() else {
+        return status(StatusCode::NOT_FOUND);
+    };
+    if authorization(req) != Some(basic_header(&username, &password).as_str()) {
+        return status(StatusCode::UNAUTHORIZED);
+    }
+
+    if let Some(scope) = query.split('&').find_map(|kv| kv.strip_prefix("scope=")) {
+        let scope = scope
+            .replace("%3A", ":")
+            .replace("%2C", ",")
+            .replace("%2F", "/");
+        state.token_scopes.push(scope);
+    }
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(format!(r#"{{"token":"{}"}}"#, TEST_TOKEN)))
+        .unwrap()
+}
@@ -40,11 +40,14 @@ pub enum DarkerError {
     ContainerAlreadyRunning(String),
 
     #[error("Registry error: {0}")]
-    Registry(String),
+    Registry(#[from] RegistryError),
 
     #[error("Invalid image reference: {0}")]
     InvalidImageRef(String),
 
+    #[error("Invalid platform: {0}")]
+    InvalidPlatform(String),
+
     #[error("Sandbox error: {0}")]
     Sandbox(String),
 
@@ -57,6 +60,9 @@ pub enum DarkerError {
     #[error("Layer error: {0}")]
     Layer(String),
 
+    #[error("Archive error: {0}")]
+    Archive(String),
+
     #[error("HTTP error: {0}")]
     Http(#[from] reqwest::Error),
 
@@ -70,6 +76,128 @@ pub enum DarkerError {
     Unsupported(String),
 }
 
+/// Errors reported by or about a registry
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("Failed to {action} on {registry}: {status} (run `darker login` to authenticate)")]
+    Unauthorized {
+        registry: String,
+        action: String,
+        status: reqwest::StatusCode,
+    },
+
+    #[error("Failed to {action} on {registry}: {status} (access denied)")]
+    Denied {
+        registry: String,
+        action: String,
+        status: reqwest::StatusCode,
+    },
+
+    #[error("Failed to {action} on {registry}: {status}")]
+    NotFound {
+        registry: String,
+        action: String,
+        status: reqwest::StatusCode,
+    },
+
+    #[error("Manifest unknown on {registry}: {reference}")]
+    ManifestUnknown { registry: String, reference: String },
+
+    #[error("Manifest digest mismatch for {expected}: {registry} returned digest {actual}")]
+    DigestMismatch {
+        registry: String,
+        expected: String,
+        actual: String,
+    },
+
+    #[error("Failed to {action} on {registry}: {status}")]
+    Status {
+        registry: String,
+        action: String,
+        status: reqwest::StatusCode,
+        /// Wait asked for by the registry's `Retry-After` header
+        retry_after: Option<std::time::Duration>,
+    },
+
+    #[error("{0}")]
+    Other(String),
+}
+
+impl RegistryError {
+    /// Build the error for an unsuccessful response to a registry request
+    pub fn from_status(
+        registry: &str,
+        action: &str,
+        status: reqwest::StatusCode,
+        retry_after: Option<std::time::Duration>,
+    ) -> Self {
+        let (registry, action) = (registry.to_string(), action.to_string());
+        match status {
+            reqwest::StatusCode::UNAUTHORIZED => Self::Unauthorized {
+                registry,
+                action,
+                status,
+            },
+            reqwest::StatusCode::FORBIDDEN => Self::Denied {
+                registry,
+                action,
+                status,
+            },
+            reqwest::StatusCode::NOT_FOUND => Self::NotFound {
+                registry,
+                action,
+                status,
+            },
+            status => Self::Status {
+                registry,
+                action,
+                status,
+                retry_after,
+            },
+        }
+    }
+
+    /// HTTP status of the response the error came from, if any
+    pub fn status(&self) -> Option<reqwest::StatusCode> {
+        match self {
+            Self::Unauthorized { status, .. }
+            | Self::Denied { status, .. }
+            | Self::NotFound { status, .. }
+            | Self::Status { status, .. } => Some(*status),
+            Self::ManifestUnknown { .. } => Some(reqwest::StatusCode::NOT_FOUND),
+            Self::DigestMismatch { .. } | Self::Other(_) => None,
+        }
+    }
+}
+
+impl DarkerError {
+    /// Whether the operation may succeed if it's tried again
+    ///
+    /// Connection failures, interrupted transfers and throttling or gateway
+    /// responses are transient; authentication, missing content and digest
+    /// mismatches are not.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            DarkerError::Http(e) => {
+                e.is_connect() || e.is_timeout() || e.is_request() || e.is_body()
+            }
+            DarkerError::Registry(e) => e
+                .status()
+                .map(image::retry::is_retryable_status)
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    /// Wait the registry asked for before the operation is tried again
+    pub fn retry_after(&self) -> Option<std::time::Duration> {
+        match self {
+            DarkerError::Registry(RegistryError::Status { retry_after, .. }) => *retry_after,
+            _ => None,
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, DarkerError>;
 
 /// Application version
@@ -83,3 +211,6 @@ pub const SHORT_ID_LENGTH: usize = 12;
 
 /// GZIP magic bytes
 pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Zstandard magic bytes
+pub const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
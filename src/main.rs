@@ -21,12 +21,22 @@ async fn main() -> anyhow::Result<()> {
         Commands::Run(args) => darker::cli::run::execute(args).await,
         Commands::Exec(args) => darker::cli::exec::execute(args).await,
         Commands::Build(args) => darker::cli::build::execute(args).await,
+        Commands::Builder(args) => darker::cli::builder::execute(args).await,
         Commands::Images(args) => darker::cli::images::execute(args).await,
         Commands::Ps(args) => darker::cli::ps::execute(args).await,
         Commands::Rm(args) => darker::cli::rm::execute(args).await,
         Commands::Rmi(args) => darker::cli::rm::execute_rmi(args).await,
         Commands::Pull(args) => darker::cli::pull::execute(args).await,
         Commands::Push(args) => darker::cli::push::execute(args).await,
+        Commands::Save(args) => darker::cli::save::execute(args).await,
+        Commands::Load(args) => darker::cli::save::execute_load(args).await,
+        Commands::Export(args) => darker::cli::export::execute(args).await,
+        Commands::Import(args) => darker::cli::export::execute_import(args).await,
+        Commands::Search(args) => darker::cli::search::execute(args).await,
+        Commands::Image(args) => darker::cli::image::execute(args).await,
+        Commands::Manifest(args) => darker::cli::manifest::execute(args).await,
+        Commands::Login(args) => darker::cli::login::execute(args).await,
+        Commands::Logout(args) => darker::cli::login::execute_logout(args).await,
         Commands::Logs(args) => darker::cli::logs::execute(args).await,
         Commands::Start(args) => darker::cli::start::execute(args).await,
         Commands::Stop(args) => darker::cli::stop::execute(args).await,
@@ -133,7 +133,7 @@ impl Container {
         let mut env_args = Vec::new();
         env_args.push(format!("HOME={}", home_value));
         env_args.push(format!("TMPDIR={}", tmp_value));
-        env_args.push(format!("PATH=/usr/local/bin:/usr/bin:/bin:/usr/sbin:/sbin"));
+        env_args.push("PATH=/usr/local/bin:/usr/bin:/bin:/usr/sbin:/sbin".to_string());
         env_args.push(format!("HOSTNAME={}", self.config.hostname));
         for e in &self.config.env {
             env_args.push(e.clone());
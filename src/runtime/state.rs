@@ -56,20 +56,20 @@ pub enum ContainerEvent {
 impl ContainerEvent {
     /// Check if a state transition is valid
     pub fn is_valid_transition(from: ContainerStatus, event: &ContainerEvent) -> bool {
-        match (from, event) {
-            (ContainerStatus::Created, ContainerEvent::Start) => true,
-            (ContainerStatus::Created, ContainerEvent::Remove) => true,
-            (ContainerStatus::Running, ContainerEvent::Pause) => true,
-            (ContainerStatus::Running, ContainerEvent::Stop) => true,
-            (ContainerStatus::Running, ContainerEvent::Kill) => true,
-            (ContainerStatus::Running, ContainerEvent::Die { .. }) => true,
-            (ContainerStatus::Paused, ContainerEvent::Unpause) => true,
-            (ContainerStatus::Paused, ContainerEvent::Stop) => true,
-            (ContainerStatus::Paused, ContainerEvent::Kill) => true,
-            (ContainerStatus::Stopped, ContainerEvent::Start) => true,
-            (ContainerStatus::Stopped, ContainerEvent::Remove) => true,
-            _ => false,
-        }
+        matches!(
+            (from, event),
+            (ContainerStatus::Created, ContainerEvent::Start)
+                | (ContainerStatus::Created, ContainerEvent::Remove)
+                | (ContainerStatus::Running, ContainerEvent::Pause)
+                | (ContainerStatus::Running, ContainerEvent::Stop)
+                | (ContainerStatus::Running, ContainerEvent::Kill)
+                | (ContainerStatus::Running, ContainerEvent::Die { .. })
+                | (ContainerStatus::Paused, ContainerEvent::Unpause)
+                | (ContainerStatus::Paused, ContainerEvent::Stop)
+                | (ContainerStatus::Paused, ContainerEvent::Kill)
+                | (ContainerStatus::Stopped, ContainerEvent::Start)
+                | (ContainerStatus::Stopped, ContainerEvent::Remove)
+        )
     }
 
     /// Apply event to get new status
@@ -0,0 +1,240 @@
+//! Content-addressable storage for manifests, configs and layer blobs
+
+use crate::storage::build_cache::BuildCache;
+use crate::storage::images::ImageStore;
+use crate::storage::paths::DarkerPaths;
+use crate::{DarkerError, Result};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Stores blobs byte-for-byte under `blobs/sha256/<hex>`
+///
+/// Blobs are only ever added under the digest of their content, so anything
+/// in the store can be pushed or served again exactly as it was received.
+pub struct BlobStore {
+    paths: DarkerPaths,
+}
+
+impl BlobStore {
+    /// Create a new blob store
+    pub fn new(paths: &DarkerPaths) -> Self {
+        Self {
+            paths: paths.clone(),
+        }
+    }
+
+    /// Path of a blob, rejecting digests that aren't sha256
+    pub fn path(&self, digest: &str) -> Result<PathBuf> {
+        let valid = digest.strip_prefix("sha256:").is_some_and(|hex| {
+            hex.len() == 64
+                && hex
+                    .bytes()
+                    .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        });
+        if !valid {
+            return Err(DarkerError::Layer(format!(
+                "Invalid blob digest: {}",
+                digest
+            )));
+        }
+        Ok(self.paths.blob(digest))
+    }
+
+    /// Check if a blob is stored
+    pub fn exists(&self, digest: &str) -> bool {
+        self.path(digest).map(|p| p.exists()).unwrap_or(false)
+    }
+
+    /// Read a blob, checking that it still matches its digest
+    pub fn read(&self, digest: &str) -> Result<Vec<u8>> {
+        let bytes = fs::read(self.path(digest)?)?;
+        let actual = format!("sha256:{:x}", Sha256::digest(&bytes));
+        if actual != digest {
+            return Err(DarkerError::Layer(format!(
+                "Blob {} is corrupt: its content has digest {}",
+                digest, actual
+            )));
+        }
+        Ok(bytes)
+    }
+
+    /// Store bytes, returning their digest
+    pub fn write(&self, bytes: &[u8]) -> Result<String> {
+        let digest = format!("sha256:{:x}", Sha256::digest(bytes));
+        if !self.exists(&digest) {
+            let tmp_path = self.tmp_path()?;
+            fs::write(&tmp_path, bytes)?;
+            self.commit(&tmp_path, &digest)?;
+        }
+        Ok(digest)
+    }
+
+    /// Move a file whose content is known to match `digest` into the store
+    ///
+    /// The file is removed if the blob is already stored.
+    pub fn insert_file(&self, file: &Path, digest: &str) -> Result<()> {
+        if self.exists(digest) {
+            fs::remove_file(file)?;
+            return Ok(());
+        }
+        self.commit(file, digest)
+    }
+
+    /// Copy a file into the store, returning its digest
+    pub fn copy_file(&self, file: &Path) -> Result<String> {
+        let tmp_path = self.tmp_path()?;
+        let mut hasher = Sha256::new();
+        let mut reader = fs::File::open(file)?;
+        let mut writer = fs::File::create(&tmp_path)?;
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            std::io::Write::write_all(&mut writer, &buffer[..read])?;
+        }
+
+        let digest = format!("sha256:{:x}", hasher.finalize());
+        self.insert_file(&tmp_path, &digest)?;
+        Ok(digest)
+    }
+
+    /// Remove a blob, returning the number of bytes freed
+    pub fn remove(&self, digest: &str) -> Result<u64> {
+        let path = self.path(digest)?;
+        match fs::metadata(&path) {
+            Ok(metadata) => {
+                fs::remove_file(&path)?;
+                Ok(metadata.len())
+            }
+            Err(_) => Ok(0),
+        }
+    }
+
+    /// List the digests of all stored blobs
+    pub fn list(&self) -> Result<Vec<String>> {
+        let dir = self.paths.blobs_dir().join("sha256");
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut digests = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let digest = format!("sha256:{}", entry?.file_name().to_string_lossy());
+            if self.path(&digest).is_ok() {
+                digests.push(digest);
+            }
+        }
+        Ok(digests)
+    }
+
+    /// Blobs referenced by any image or cached build step
+    ///
+    /// Fails if an image's metadata can't be read, rather than treating its
+    /// blobs as unused.
+    pub fn referenced(&self) -> Result<HashSet<String>> {
+        let mut referenced = HashSet::new();
+
+        let images_dir = self.paths.images_dir();
+        if images_dir.exists() {
+            let image_store = ImageStore::new(&self.paths)?;
+            for entry in fs::read_dir(images_dir)? {
+                let entry = entry?;
+                let image_id = entry.file_name().to_string_lossy().to_string();
+                if !entry.file_type()?.is_dir() || !self.paths.image_metadata(&image_id).exists() {
+                    continue;
+                }
+                referenced.extend(image_store.load_metadata(&image_id)?.blobs());
+            }
+        }
+        referenced.extend(BuildCache::new(&self.paths).blobs()?);
+
+        Ok(referenced)
+    }
+
+    /// Remove the given blobs that nothing references
+    ///
+    /// Returns the number of bytes reclaimed.
+    pub fn remove_unreferenced(&self, digests: &[String]) -> Result<u64> {
+        let referenced = self.referenced()?;
+        let mut reclaimed = 0;
+        for digest in digests {
+            if !referenced.contains(digest) {
+                reclaimed += self.remove(digest)?;
+            }
+        }
+        Ok(reclaimed)
+    }
+
+    /// Remove every blob that nothing references
+    ///
+    /// Returns the number of bytes reclaimed.
+    pub fn prune(&self) -> Result<u64> {
+        let digests = self.list()?;
+        self.remove_unreferenced(&digests)
+    }
+
+    /// A new file in the tmp directory to write a blob to
+    fn tmp_path(&self) -> Result<PathBuf> {
+        let tmp_dir = self.paths.tmp_dir();
+        fs::create_dir_all(&tmp_dir)?;
+        Ok(tmp_dir.join(format!("{}.blob", uuid::Uuid::new_v4())))
+    }
+
+    /// Move a complete file into place under its digest
+    fn commit(&self, file: &Path, digest: &str) -> Result<()> {
+        let path = self.path(digest)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if let Err(e) = fs::rename(file, &path) {
+            let _ = fs::remove_file(file);
+            // Another pull may have stored the same blob concurrently
+            if !path.exists() {
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_blob_store() {
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+        let store = BlobStore::new(&paths);
+
+        // Bytes are kept exactly as written
+        let manifest = b"{ \"schemaVersion\" : 2 }\n";
+        let digest = store.write(manifest).unwrap();
+        assert_eq!(digest, format!("sha256:{:x}", Sha256::digest(manifest)));
+        assert_eq!(store.read(&digest).unwrap(), manifest);
+        assert_eq!(store.write(manifest).unwrap(), digest);
+        assert_eq!(store.list().unwrap(), vec![digest.clone()]);
+
+        // Corruption is detected on read
+        fs::write(paths.blob(&digest), b"tampered").unwrap();
+        assert!(store.read(&digest).is_err());
+
+        // Digests can't escape the store
+        assert!(store.path("sha256:../../etc/passwd").is_err());
+        assert!(store.path("md5:abc").is_err());
+        assert!(!store.exists("sha256:../../etc/passwd"));
+
+        // Unreferenced blobs are pruned
+        assert_eq!(store.prune().unwrap(), 8);
+        assert!(store.list().unwrap().is_empty());
+        assert_eq!(fs::read_dir(paths.tmp_dir()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,204 @@
+//! Cache of build steps, keyed on everything that determines their result
+
+use crate::storage::paths::DarkerPaths;
+use crate::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+
+/// Result of a cached build step
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    /// Layer the step created, by the hex digest it is stored under
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer: Option<String>,
+    /// Digest of the layer's blob
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob_digest: Option<String>,
+    /// When the step was run
+    pub created: DateTime<Utc>,
+}
+
+/// Build cache stored in the build-cache directory
+pub struct BuildCache {
+    paths: DarkerPaths,
+}
+
+impl BuildCache {
+    /// Create a new build cache
+    pub fn new(paths: &DarkerPaths) -> Self {
+        Self {
+            paths: paths.clone(),
+        }
+    }
+
+    /// Compute the cache key of a build step
+    ///
+    /// `parent` is the key of the step before it, `build_args` the variables
+    /// in effect and `content` a hash of the context files the step uses.
+    pub fn key(
+        parent: &str,
+        instruction: &str,
+        build_args: &HashMap<String, String>,
+        content: Option<&str>,
+    ) -> String {
+        let mut args: Vec<_> = build_args.iter().collect();
+        args.sort();
+
+        let mut hasher = Sha256::new();
+        for part in [parent, instruction, content.unwrap_or("")] {
+            hasher.update(part.as_bytes());
+            hasher.update([0]);
+        }
+        for (name, value) in args {
+            hasher.update(format!("{}={}", name, value).as_bytes());
+            hasher.update([0]);
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Look up a build step
+    ///
+    /// Entries whose layer or blob has since been removed are ignored.
+    pub fn get(&self, key: &str) -> Result<Option<CacheEntry>> {
+        let path = self.paths.build_cache_entry(key);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let entry: CacheEntry = serde_json::from_str(&fs::read_to_string(&path)?)?;
+
+        let layer_exists = entry
+            .layer
+            .as_deref()
+            .is_none_or(|layer| self.paths.layer_tar(layer).exists());
+        let blob_exists = match (&entry.layer, &entry.blob_digest) {
+            // Uncompressed layers are their own blob
+            (Some(layer), Some(digest)) => {
+                digest.strip_prefix("sha256:") == Some(layer.as_str())
+                    || self.paths.blob(digest).exists()
+            }
+            _ => true,
+        };
+        Ok((layer_exists && blob_exists).then_some(entry))
+    }
+
+    /// Record the result of a build step
+    pub fn put(&self, key: &str, entry: &CacheEntry) -> Result<()> {
+        fs::create_dir_all(self.paths.build_cache_dir())?;
+        fs::write(
+            self.paths.build_cache_entry(key),
+            serde_json::to_string_pretty(entry)?,
+        )?;
+        Ok(())
+    }
+
+    /// List the cached steps with their keys
+    ///
+    /// Entries that can't be read are skipped.
+    pub fn list(&self) -> Result<Vec<(String, CacheEntry)>> {
+        let dir = self.paths.build_cache_dir();
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut entries = Vec::new();
+        for file in fs::read_dir(dir)? {
+            let path = file?.path();
+            let Some(key) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".json"))
+            else {
+                continue;
+            };
+            let entry = fs::read_to_string(&path)
+                .ok()
+                .and_then(|json| serde_json::from_str::<CacheEntry>(&json).ok());
+            if let Some(entry) = entry {
+                entries.push((key.to_string(), entry));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Layers kept by cached steps
+    pub fn layers(&self) -> Result<HashSet<String>> {
+        Ok(self
+            .list()?
+            .into_iter()
+            .filter_map(|(_, entry)| entry.layer)
+            .collect())
+    }
+
+    /// Blobs kept by cached steps
+    pub fn blobs(&self) -> Result<HashSet<String>> {
+        Ok(self
+            .list()?
+            .into_iter()
+            .filter_map(|(_, entry)| entry.blob_digest)
+            .collect())
+    }
+
+    /// Remove every cached step, returning the removed entries
+    ///
+    /// Their layers and blobs are left for the caller to remove if nothing
+    /// else uses them.
+    pub fn clear(&self) -> Result<Vec<(String, CacheEntry)>> {
+        let entries = self.list()?;
+        for (key, _) in &entries {
+            fs::remove_file(self.paths.build_cache_entry(key))?;
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_build_cache() {
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+        let cache = BuildCache::new(&paths);
+
+        let args = HashMap::from([("VERSION".to_string(), "1".to_string())]);
+        let key = BuildCache::key("parent", "RUN make", &args, None);
+        assert_eq!(key, BuildCache::key("parent", "RUN make", &args, None));
+        assert_ne!(key, BuildCache::key("other", "RUN make", &args, None));
+        assert_ne!(
+            key,
+            BuildCache::key("parent", "RUN make", &HashMap::new(), None)
+        );
+        assert_ne!(
+            key,
+            BuildCache::key("parent", "RUN make", &args, Some("sha256:0"))
+        );
+
+        assert!(cache.get(&key).unwrap().is_none());
+        fs::create_dir_all(paths.layer_dir("abc")).unwrap();
+        fs::write(paths.layer_tar("abc"), "tar").unwrap();
+        let entry = CacheEntry {
+            layer: Some("abc".to_string()),
+            blob_digest: Some("sha256:abc".to_string()),
+            created: Utc::now(),
+        };
+        cache.put(&key, &entry).unwrap();
+        assert_eq!(
+            cache.get(&key).unwrap().unwrap().layer.as_deref(),
+            Some("abc")
+        );
+        assert_eq!(cache.layers().unwrap(), HashSet::from(["abc".to_string()]));
+
+        // A step whose layer is gone is a miss
+        fs::remove_dir_all(paths.layer_dir("abc")).unwrap();
+        assert!(cache.get(&key).unwrap().is_none());
+
+        assert_eq!(cache.clear().unwrap().len(), 1);
+        assert!(cache.list().unwrap().is_empty());
+    }
+}
@@ -26,6 +26,9 @@ pub struct ContainerConfig {
     pub read_only: bool,
     pub auto_remove: bool,
     pub created: DateTime<Utc>,
+    /// Layers of the image the container was created from
+    #[serde(default)]
+    pub layers: Vec<String>,
 }
 
 /// Container runtime state
@@ -58,6 +61,7 @@ impl Default for ContainerConfig {
             read_only: false,
             auto_remove: false,
             created: Utc::now(),
+            layers: Vec::new(),
         }
     }
 }
@@ -0,0 +1,221 @@
+//! Registry credential storage
+
+use crate::storage::paths::DarkerPaths;
+use crate::{RegistryError, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+/// Username and password for a registry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// Value for an HTTP Basic `Authorization` header
+    pub fn basic_auth(&self) -> String {
+        format!("Basic {}", self.encode())
+    }
+
+    fn encode(&self) -> String {
+        STANDARD.encode(format!("{}:{}", self.username, self.password))
+    }
+
+    fn decode(auth: &str) -> Result<Self> {
+        let decoded = STANDARD
+            .decode(auth)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or_else(|| RegistryError::Other("Invalid stored credentials".to_string()))?;
+        let (username, password) = decoded
+            .split_once(':')
+            .ok_or_else(|| RegistryError::Other("Invalid stored credentials".to_string()))?;
+        Ok(Self {
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+    }
+}
+
+/// On-disk credential file, compatible with the `auths` section of docker's config.json
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AuthFile {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AuthEntry {
+    auth: String,
+}
+
+/// Manages stored registry credentials
+pub struct CredentialStore {
+    paths: DarkerPaths,
+}
+
+impl CredentialStore {
+    /// Create a new credential store
+    pub fn new(paths: &DarkerPaths) -> Result<Self> {
+        Ok(Self {
+            paths: paths.clone(),
+        })
+    }
+
+    /// Get the credentials for a registry
+    pub fn get(&self, registry: &str) -> Result<Option<Credentials>> {
+        let file = self.load()?;
+        file.auths
+            .get(&normalize_registry(registry))
+            .map(|entry| Credentials::decode(&entry.auth))
+            .transpose()
+    }
+
+    /// Save the credentials for a registry
+    pub fn store(&self, registry: &str, credentials: &Credentials) -> Result<()> {
+        let mut file = self.load()?;
+        file.auths.insert(
+            normalize_registry(registry),
+            AuthEntry {
+                auth: credentials.encode(),
+            },
+        );
+        self.save(&file)
+    }
+
+    /// Remove the credentials for a registry, returning whether any existed
+    pub fn remove(&self, registry: &str) -> Result<bool> {
+        let mut file = self.load()?;
+        let removed = file.auths.remove(&normalize_registry(registry)).is_some();
+        if removed {
+            self.save(&file)?;
+        }
+        Ok(removed)
+    }
+
+    fn load(&self) -> Result<AuthFile> {
+        let path = self.paths.auth_config();
+        if !path.exists() {
+            return Ok(AuthFile::default());
+        }
+        let json = fs::read_to_string(&path)?;
+        let file: AuthFile = serde_json::from_str(&json)?;
+        Ok(file)
+    }
+
+    fn save(&self, file: &AuthFile) -> Result<()> {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let path = self.paths.auth_config();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Credentials are only readable by the owner. The file is replaced
+        // rather than rewritten, so an existing one with wider permissions
+        // never holds them.
+        let json = serde_json::to_string_pretty(file)?;
+        let tmp_path = path.with_extension("json.tmp");
+        let _ = fs::remove_file(&tmp_path);
+        let mut out = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        if let Err(e) = out.write_all(json.as_bytes()) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e.into());
+        }
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+/// Normalize a registry server address to the key used in image references
+pub fn normalize_registry(server: &str) -> String {
+    let host = server
+        .trim()
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .split('/')
+        .next()
+        .unwrap_or("");
+
+    match host {
+        "" | "index.docker.io" | "registry-1.docker.io" | "registry.hub.docker.com" => {
+            "docker.io".to_string()
+        }
+        host => host.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_credential_store() {
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        let store = CredentialStore::new(&paths).unwrap();
+
+        let credentials = Credentials {
+            username: "me".to_string(),
+            password: "s3cr:et".to_string(),
+        };
+        store
+            .store("https://index.docker.io/v1/", &credentials)
+            .unwrap();
+        store.store("ghcr.io", &credentials).unwrap();
+
+        assert_eq!(store.get("docker.io").unwrap(), Some(credentials.clone()));
+        assert_eq!(store.get("ghcr.io").unwrap(), Some(credentials));
+        assert_eq!(store.get("quay.io").unwrap(), None);
+
+        assert!(store.remove("ghcr.io").unwrap());
+        assert!(!store.remove("ghcr.io").unwrap());
+        assert_eq!(store.get("ghcr.io").unwrap(), None);
+    }
+
+    #[test]
+    fn test_credentials_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        let store = CredentialStore::new(&paths).unwrap();
+
+        // A file created with wider permissions is tightened when saved
+        let path = paths.auth_config();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "{}").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let credentials = Credentials {
+            username: "me".to_string(),
+            password: "secret".to_string(),
+        };
+        store.store("ghcr.io", &credentials).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(store.get("ghcr.io").unwrap(), Some(credentials));
+    }
+
+    #[test]
+    fn test_normalize_registry() {
+        assert_eq!(
+            normalize_registry("https://index.docker.io/v1/"),
+            "docker.io"
+        );
+        assert_eq!(normalize_registry(""), "docker.io");
+        assert_eq!(normalize_registry("localhost:5000"), "localhost:5000");
+        assert_eq!(normalize_registry("https://ghcr.io/"), "ghcr.io");
+    }
+}
@@ -1,6 +1,8 @@
 //! Image metadata storage
 
-use crate::image::oci::ImageReference;
+use crate::image::layer::LayerManager;
+use crate::image::oci::{ImageReference, Platform};
+use crate::storage::blobs::BlobStore;
 use crate::storage::paths::DarkerPaths;
 use crate::{DarkerError, Result};
 use chrono::{DateTime, Utc};
@@ -17,8 +19,37 @@ pub struct ImageMetadata {
     pub digest: Option<String>,
     pub created: DateTime<Utc>,
     pub size: u64,
+    /// Layers in the layer store, by the hex digest they are stored under
     pub layers: Vec<String>,
     pub parent: Option<String>,
+    /// Manifest digests the image was pulled by, as "repository@digest"
+    #[serde(default)]
+    pub repo_digests: Vec<String>,
+    /// Platform of the image as "os/arch[/variant]"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    /// Compressed digests of the layer blobs as pulled, in the order of `layers`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blob_digests: Vec<String>,
+    /// Digest of the image manifest in the blob store
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest_digest: Option<String>,
+    /// Digest of the index the manifest was picked from, in the blob store
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index_digest: Option<String>,
+}
+
+impl ImageMetadata {
+    /// Digests of every blob the image refers to
+    pub fn blobs(&self) -> Vec<String> {
+        self.digest
+            .iter()
+            .chain(&self.manifest_digest)
+            .chain(&self.index_digest)
+            .chain(&self.blob_digests)
+            .cloned()
+            .collect()
+    }
 }
 
 /// Image config from OCI spec (simplified)
@@ -77,6 +108,9 @@ struct ImageIndex {
     tags: HashMap<String, String>,
     /// Maps short IDs to full IDs
     short_ids: HashMap<String, String>,
+    /// Maps "repository@digest" to image IDs
+    #[serde(default)]
+    digests: HashMap<String, String>,
 }
 
 /// Manages image metadata storage
@@ -93,11 +127,38 @@ impl ImageStore {
     }
 
     /// Find an image by reference
+    ///
+    /// References pinned by digest only match images pulled by that digest.
     pub fn find_image(&self, reference: &ImageReference) -> Option<String> {
+        if let Some(digest) = &reference.digest {
+            let digest_key = format!("{}@{}", reference.repository_with_registry(), digest);
+            return self.load_index().ok()?.digests.get(&digest_key).cloned();
+        }
+
         let tag_key = format!("{}:{}", reference.repository_with_registry(), reference.tag());
         self.find(&tag_key)
     }
 
+    /// Find an image by reference that can run on a platform
+    ///
+    /// Images without a recorded platform are assumed to match.
+    pub fn find_image_for_platform(
+        &self,
+        reference: &ImageReference,
+        platform: Option<&Platform>,
+    ) -> Option<String> {
+        let image_id = self.find_image(reference)?;
+        let Some(wanted) = platform else {
+            return Some(image_id);
+        };
+
+        let recorded = self.load_metadata(&image_id).ok()?.platform;
+        match recorded.map(|p| Platform::parse(&p)) {
+            Some(Ok(actual)) if !wanted.matches(&actual) => None,
+            _ => Some(image_id),
+        }
+    }
+
     /// Find an image by name, tag, or ID
     pub fn find(&self, name_or_id: &str) -> Option<String> {
         if let Ok(index) = self.load_index() {
@@ -106,6 +167,16 @@ impl ImageStore {
                 return Some(id.clone());
             }
 
+            // Check if it's a digest reference
+            if name_or_id.contains('@') {
+                let with_library = format!("library/{}", name_or_id);
+                return index
+                    .digests
+                    .get(name_or_id)
+                    .or_else(|| index.digests.get(&with_library))
+                    .cloned();
+            }
+
             // Try with :latest
             if !name_or_id.contains(':') {
                 let with_latest = format!("{}:latest", name_or_id);
@@ -161,6 +232,11 @@ impl ImageStore {
         let image_dir = self.paths.image_dir(image_id);
         fs::create_dir_all(&image_dir)?;
 
+        // Keep the name and repo digests of an image that is stored again
+        let existing = self.load_metadata(image_id).ok();
+        let repository = repository.or_else(|| existing.as_ref()?.repository.as_deref());
+        let tag = tag.or_else(|| existing.as_ref()?.tag.as_deref());
+
         // Write metadata
         let metadata = ImageMetadata {
             id: image_id.to_string(),
@@ -171,6 +247,18 @@ impl ImageStore {
             size,
             layers: layers.to_vec(),
             parent: None,
+            repo_digests: existing
+                .as_ref()
+                .map(|m| m.repo_digests.clone())
+                .unwrap_or_default(),
+            platform: existing.as_ref().and_then(|m| m.platform.clone()),
+            blob_digests: existing
+                .as_ref()
+                .filter(|m| m.layers == layers)
+                .map(|m| m.blob_digests.clone())
+                .unwrap_or_default(),
+            manifest_digest: existing.as_ref().and_then(|m| m.manifest_digest.clone()),
+            index_digest: existing.as_ref().and_then(|m| m.index_digest.clone()),
         };
 
         let metadata_path = self.paths.image_metadata(image_id);
@@ -203,13 +291,31 @@ impl ImageStore {
 
     /// Load image config
     pub fn load_config(&self, image_id: &str) -> Result<ImageConfig> {
+        match self.config_bytes(image_id)? {
+            Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            None => Ok(ImageConfig::default()),
+        }
+    }
+
+    /// Read the stored config of an image as raw bytes
+    ///
+    /// The config blob the metadata refers to is preferred, falling back to
+    /// the config file in the image directory.
+    pub fn config_bytes(&self, image_id: &str) -> Result<Option<Vec<u8>>> {
+        let blob = self
+            .load_metadata(image_id)
+            .ok()
+            .and_then(|m| m.digest)
+            .filter(|digest| self.paths.blob(digest).exists());
+        if let Some(digest) = blob {
+            return BlobStore::new(&self.paths).read(&digest).map(Some);
+        }
+
         let config_path = self.paths.image_config(image_id);
         if !config_path.exists() {
-            return Ok(ImageConfig::default());
+            return Ok(None);
         }
-        let config_json = fs::read_to_string(&config_path)?;
-        let config: ImageConfig = serde_json::from_str(&config_json)?;
-        Ok(config)
+        Ok(Some(fs::read(&config_path)?))
     }
 
     /// Save image config
@@ -240,8 +346,91 @@ impl ImageStore {
         Ok(())
     }
 
+    /// Record that an image was pulled from a repository by a manifest digest
+    pub fn add_repo_digest(&self, image_id: &str, repository: &str, digest: &str) -> Result<()> {
+        let repo_digest = format!("{}@{}", repository, digest);
+
+        let mut metadata = self.load_metadata(image_id)?;
+        if !metadata.repo_digests.contains(&repo_digest) {
+            metadata.repo_digests.push(repo_digest.clone());
+            metadata.repo_digests.sort();
+        }
+
+        let metadata_path = self.paths.image_metadata(image_id);
+        let metadata_json = serde_json::to_string_pretty(&metadata)?;
+        fs::write(&metadata_path, metadata_json)?;
+
+        let mut index = self.load_index().unwrap_or_default();
+        index.digests.insert(repo_digest, image_id.to_string());
+        self.save_index(&index)?;
+
+        Ok(())
+    }
+
+    /// Record the platform an image was built for
+    pub fn set_platform(&self, image_id: &str, platform: &Platform) -> Result<()> {
+        let mut metadata = self.load_metadata(image_id)?;
+        metadata.platform = Some(platform.to_string());
+
+        let metadata_path = self.paths.image_metadata(image_id);
+        let metadata_json = serde_json::to_string_pretty(&metadata)?;
+        fs::write(&metadata_path, metadata_json)?;
+        Ok(())
+    }
+
+    /// Record the compressed blob digests an image's layers were pulled as
+    pub fn set_blob_digests(&self, image_id: &str, digests: &[String]) -> Result<()> {
+        let mut metadata = self.load_metadata(image_id)?;
+        metadata.blob_digests = digests.to_vec();
+
+        let metadata_path = self.paths.image_metadata(image_id);
+        let metadata_json = serde_json::to_string_pretty(&metadata)?;
+        fs::write(&metadata_path, metadata_json)?;
+        Ok(())
+    }
+
+    /// Record the manifest an image was pulled or loaded as, and the index it
+    /// was picked from
+    pub fn set_manifest(
+        &self,
+        image_id: &str,
+        manifest_digest: &str,
+        index_digest: Option<&str>,
+    ) -> Result<()> {
+        let mut metadata = self.load_metadata(image_id)?;
+        metadata.manifest_digest = Some(manifest_digest.to_string());
+        metadata.index_digest = index_digest.map(String::from);
+
+        let metadata_path = self.paths.image_metadata(image_id);
+        let metadata_json = serde_json::to_string_pretty(&metadata)?;
+        fs::write(&metadata_path, metadata_json)?;
+        Ok(())
+    }
+
+    /// List the tags of a repository as (tag, image ID) pairs
+    pub fn list_tags(&self, repository: &str) -> Result<Vec<(String, String)>> {
+        let index = self.load_index()?;
+        let prefix = format!("{}:", repository);
+
+        let mut tags: Vec<(String, String)> = index
+            .tags
+            .iter()
+            .filter_map(|(key, id)| {
+                key.strip_prefix(&prefix)
+                    .filter(|tag| !tag.contains('/'))
+                    .map(|tag| (tag.to_string(), id.clone()))
+            })
+            .collect();
+        tags.sort();
+
+        Ok(tags)
+    }
+
     /// Remove an image
-    pub fn remove(&self, image_id: &str, prune_layers: bool) -> Result<()> {
+    ///
+    /// With `prune_layers`, the image's layers that no other image or container
+    /// uses are deleted too. Returns the number of bytes reclaimed.
+    pub fn remove(&self, image_id: &str, prune_layers: bool) -> Result<u64> {
         let metadata = self.load_metadata(image_id)?;
 
         // Remove image directory
@@ -250,18 +439,6 @@ impl ImageStore {
             fs::remove_dir_all(&image_dir)?;
         }
 
-        // Optionally remove layers
-        if prune_layers {
-            for layer in &metadata.layers {
-                let layer_dir = self.paths.layer_dir(layer);
-                if layer_dir.exists() {
-                    // Only remove if no other image uses this layer
-                    // For simplicity, we'll skip this check for now
-                    // In a production implementation, we'd reference count layers
-                }
-            }
-        }
-
         // Update index
         let mut index = self.load_index().unwrap_or_default();
         if let (Some(repo), Some(tag)) = (&metadata.repository, &metadata.tag) {
@@ -270,9 +447,17 @@ impl ImageStore {
         }
         let short_id = &image_id[..12.min(image_id.len())];
         index.short_ids.remove(short_id);
+        index.digests.retain(|_, id| id != image_id);
         self.save_index(&index)?;
 
-        Ok(())
+        // Layers and blobs are only removed once no image references them
+        if prune_layers {
+            let layers = LayerManager::new(&self.paths).remove_unreferenced(&metadata.layers)?;
+            let blobs = BlobStore::new(&self.paths).remove_unreferenced(&metadata.blobs())?;
+            return Ok(layers + blobs);
+        }
+
+        Ok(0)
     }
 
     /// List all images
@@ -347,4 +532,82 @@ mod tests {
         let found = store.find("abc123456789");
         assert_eq!(found, Some("abc123456789".to_string()));
     }
+
+    #[test]
+    fn test_list_tags() {
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let store = ImageStore::new(&paths).unwrap();
+        store
+            .store("aaa111111111", Some("myapp"), Some("v2"), None, &[], 0)
+            .unwrap();
+        store
+            .store("bbb222222222", Some("myapp"), Some("v1"), None, &[], 0)
+            .unwrap();
+        store
+            .store("ccc333333333", Some("myapp/sub"), Some("v1"), None, &[], 0)
+            .unwrap();
+
+        let tags = store.list_tags("myapp").unwrap();
+        assert_eq!(
+            tags,
+            vec![
+                ("v1".to_string(), "bbb222222222".to_string()),
+                ("v2".to_string(), "aaa111111111".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_repo_digests() {
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        paths.ensure_directories().unwrap();
+
+        let store = ImageStore::new(&paths).unwrap();
+        let digest = format!("sha256:{}", "ab".repeat(32));
+        store
+            .store("abc123456789", Some("library/alpine"), None, None, &[], 0)
+            .unwrap();
+        store
+            .add_repo_digest("abc123456789", "library/alpine", &digest)
+            .unwrap();
+        store
+            .add_repo_digest("abc123456789", "library/alpine", &digest)
+            .unwrap();
+
+        let metadata = store.load_metadata("abc123456789").unwrap();
+        assert_eq!(
+            metadata.repo_digests,
+            vec![format!("library/alpine@{}", digest)]
+        );
+
+        let reference = ImageReference::parse(&format!("alpine@{}", digest)).unwrap();
+        assert_eq!(
+            store.find_image(&reference),
+            Some("abc123456789".to_string())
+        );
+        assert_eq!(
+            store.find(&format!("alpine@{}", digest)),
+            Some("abc123456789".to_string())
+        );
+
+        // Storing the image again keeps its repo digests
+        store
+            .store("abc123456789", None, None, None, &[], 0)
+            .unwrap();
+        assert_eq!(
+            store
+                .load_metadata("abc123456789")
+                .unwrap()
+                .repo_digests
+                .len(),
+            1
+        );
+
+        store.remove("abc123456789", false).unwrap();
+        assert_eq!(store.find_image(&reference), None);
+    }
 }
@@ -0,0 +1,170 @@
+//! Local manifest lists, assembled and annotated before they are pushed
+
+use crate::image::oci::{media_types, ImageIndex, ImageReference, ManifestDescriptor};
+use crate::storage::paths::DarkerPaths;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// A manifest list stored under the darker root
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestList {
+    /// Reference the list is pushed to
+    pub name: String,
+    /// Image manifests in the list, in the order they were added
+    pub entries: Vec<ManifestListEntry>,
+}
+
+/// An image manifest in a local manifest list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestListEntry {
+    /// Image reference the manifest was added as
+    pub source: String,
+    /// Local image the manifest is pushed from, or None if it's already in a registry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_id: Option<String>,
+    /// Descriptor of the manifest in the index, with its platform
+    pub descriptor: ManifestDescriptor,
+}
+
+impl ManifestList {
+    /// Create an empty manifest list
+    pub fn new(reference: &ImageReference) -> Self {
+        Self {
+            name: reference.full_name(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Add an entry, replacing any entry for the same manifest
+    pub fn add(&mut self, entry: ManifestListEntry) {
+        match self
+            .entries
+            .iter_mut()
+            .find(|e| e.descriptor.digest == entry.descriptor.digest)
+        {
+            Some(existing) => *existing = entry,
+            None => self.entries.push(entry),
+        }
+    }
+
+    /// Find an entry by the reference it was added as, or by manifest digest
+    pub fn find_mut(&mut self, source_or_digest: &str) -> Option<&mut ManifestListEntry> {
+        let source = ImageReference::parse(source_or_digest)
+            .map(|r| r.full_name())
+            .ok();
+        self.entries.iter_mut().find(|e| {
+            e.descriptor.digest == source_or_digest
+                || e.source == source_or_digest
+                || source.as_deref() == Some(e.source.as_str())
+        })
+    }
+
+    /// Build the OCI image index for the list
+    pub fn index(&self) -> ImageIndex {
+        ImageIndex {
+            schema_version: 2,
+            media_type: Some(media_types::OCI_IMAGE_INDEX.to_string()),
+            manifests: self.entries.iter().map(|e| e.descriptor.clone()).collect(),
+            annotations: None,
+        }
+    }
+}
+
+/// Manages manifest lists under the darker root
+pub struct ManifestListStore {
+    paths: DarkerPaths,
+}
+
+impl ManifestListStore {
+    /// Create a new manifest list store
+    pub fn new(paths: &DarkerPaths) -> Result<Self> {
+        Ok(Self {
+            paths: paths.clone(),
+        })
+    }
+
+    /// Load the manifest list for a reference, if one exists
+    pub fn load(&self, reference: &ImageReference) -> Result<Option<ManifestList>> {
+        let path = self.paths.manifest_list(&reference.full_name());
+        if !path.exists() {
+            return Ok(None);
+        }
+        let json = fs::read_to_string(&path)?;
+        let list: ManifestList = serde_json::from_str(&json)?;
+        Ok(Some(list))
+    }
+
+    /// Save a manifest list
+    pub fn save(&self, list: &ManifestList) -> Result<()> {
+        fs::create_dir_all(self.paths.manifests_dir())?;
+        let json = serde_json::to_string_pretty(list)?;
+        fs::write(self.paths.manifest_list(&list.name), json)?;
+        Ok(())
+    }
+
+    /// Remove the manifest list for a reference, returning whether it existed
+    pub fn remove(&self, reference: &ImageReference) -> Result<bool> {
+        let path = self.paths.manifest_list(&reference.full_name());
+        if !path.exists() {
+            return Ok(false);
+        }
+        fs::remove_file(path)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::oci::Platform;
+    use tempfile::TempDir;
+
+    fn entry(source: &str, digest: &str, arch: &str) -> ManifestListEntry {
+        ManifestListEntry {
+            source: source.to_string(),
+            image_id: None,
+            descriptor: ManifestDescriptor {
+                media_type: media_types::OCI_IMAGE_MANIFEST.to_string(),
+                digest: digest.to_string(),
+                size: 100,
+                platform: Some(Platform::new("linux", arch, None)),
+                annotations: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_manifest_list_store() {
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        let store = ManifestListStore::new(&paths).unwrap();
+
+        let reference = ImageReference::parse("ghcr.io/org/app:1.0").unwrap();
+        assert!(store.load(&reference).unwrap().is_none());
+
+        let mut list = ManifestList::new(&reference);
+        list.add(entry("ghcr.io/org/app:1.0-amd64", "sha256:aa", "amd64"));
+        list.add(entry("ghcr.io/org/app:1.0-arm64", "sha256:bb", "arm64"));
+        list.add(entry("ghcr.io/org/app:1.0-arm64-v2", "sha256:bb", "arm64"));
+        store.save(&list).unwrap();
+
+        let mut loaded = store.load(&reference).unwrap().unwrap();
+        assert_eq!(loaded.name, "ghcr.io/org/app:1.0");
+        assert_eq!(loaded.entries.len(), 2);
+        assert!(loaded.find_mut("sha256:aa").is_some());
+        assert!(loaded.find_mut("ghcr.io/org/app:1.0-arm64-v2").is_some());
+        assert!(loaded.find_mut("ghcr.io/org/app:1.0-arm64").is_none());
+
+        let index = loaded.index();
+        assert_eq!(
+            index.media_type.as_deref(),
+            Some(media_types::OCI_IMAGE_INDEX)
+        );
+        let digests: Vec<&str> = index.manifests.iter().map(|m| m.digest.as_str()).collect();
+        assert_eq!(digests, vec!["sha256:aa", "sha256:bb"]);
+
+        assert!(store.remove(&reference).unwrap());
+        assert!(!store.remove(&reference).unwrap());
+    }
+}
@@ -1,5 +1,10 @@
 //! Storage module for persistent state management
 
+pub mod blobs;
+pub mod build_cache;
 pub mod containers;
+pub mod credentials;
 pub mod images;
+pub mod manifest_lists;
 pub mod paths;
+pub mod registries;
@@ -41,6 +41,7 @@ impl DarkerPaths {
         std::fs::create_dir_all(self.containers_dir())?;
         std::fs::create_dir_all(self.images_dir())?;
         std::fs::create_dir_all(self.layers_dir())?;
+        std::fs::create_dir_all(self.blobs_dir().join("sha256"))?;
         std::fs::create_dir_all(self.volumes_dir())?;
         std::fs::create_dir_all(self.tmp_dir())?;
         Ok(())
@@ -131,6 +132,27 @@ impl DarkerPaths {
         self.layer_dir(layer_sha).join("extracted")
     }
 
+    /// Directory of content-addressed blobs, kept as they were received
+    pub fn blobs_dir(&self) -> PathBuf {
+        self.root.join("blobs")
+    }
+
+    /// Blob file for a digest such as "sha256:<hex>"
+    pub fn blob(&self, digest: &str) -> PathBuf {
+        let (algorithm, hex) = digest.split_once(':').unwrap_or(("sha256", digest));
+        self.blobs_dir().join(algorithm).join(hex)
+    }
+
+    /// Directory of cached build steps
+    pub fn build_cache_dir(&self) -> PathBuf {
+        self.root.join("build-cache")
+    }
+
+    /// Cache entry file for a build step's key
+    pub fn build_cache_entry(&self, key: &str) -> PathBuf {
+        self.build_cache_dir().join(format!("{}.json", key))
+    }
+
     /// Directory containing volumes
     pub fn volumes_dir(&self) -> PathBuf {
         self.root.join("volumes")
@@ -155,6 +177,30 @@ impl DarkerPaths {
     pub fn container_index(&self) -> PathBuf {
         self.root.join("containers.json")
     }
+
+    /// Registry credentials file
+    pub fn auth_config(&self) -> PathBuf {
+        self.root.join("auth.json")
+    }
+
+    /// Registry mirror and connection settings file
+    pub fn registries_config(&self) -> PathBuf {
+        self.root.join("registries.json")
+    }
+
+    /// Directory containing local manifest lists
+    pub fn manifests_dir(&self) -> PathBuf {
+        self.root.join("manifests")
+    }
+
+    /// Manifest list file for a reference
+    pub fn manifest_list(&self, name: &str) -> PathBuf {
+        let file_name: String = name
+            .chars()
+            .map(|c| if matches!(c, '/' | ':' | '@') { '_' } else { c })
+            .collect();
+        self.manifests_dir().join(format!("{}.json", file_name))
+    }
 }
 
 #[cfg(test)]
@@ -171,6 +217,10 @@ mod tests {
         assert_eq!(paths.containers_dir(), tmp.path().join("containers"));
         assert_eq!(paths.images_dir(), tmp.path().join("images"));
         assert_eq!(paths.volumes_dir(), tmp.path().join("volumes"));
+        assert_eq!(
+            paths.blob("sha256:abc"),
+            tmp.path().join("blobs").join("sha256").join("abc")
+        );
     }
 
     #[test]
@@ -0,0 +1,217 @@
+//! Registry mirror and connection settings
+//!
+//! Read from `registries.json` under the darker root, for example:
+//!
+//! ```json
+//! {
+//!   "registries": {
+//!     "docker.io": { "mirrors": ["mirror.corp.example", "http://10.0.0.5:5000"] },
+//!     "registry.corp.example:5000": { "plain_http": true },
+//!     "harbor.corp.example": { "ca_file": "/etc/ssl/corp-ca.pem" },
+//!     "lab.example": { "skip_verify": true }
+//!   }
+//! }
+//! ```
+
+use crate::image::oci::registry_api_url;
+use crate::storage::credentials::normalize_registry;
+use crate::storage::paths::DarkerPaths;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Settings for all configured registries
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RegistriesConfig {
+    /// Settings keyed by registry host
+    #[serde(default)]
+    pub registries: HashMap<String, RegistrySettings>,
+}
+
+/// Settings for a single registry host
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RegistrySettings {
+    /// Mirrors to pull from before the registry itself, in order
+    ///
+    /// Entries are a host, optionally followed by a namespace path that is
+    /// prepended to repositories, or a full `http://` or `https://` URL.
+    pub mirrors: Vec<String>,
+    /// Use plain HTTP instead of HTTPS
+    pub plain_http: bool,
+    /// Don't verify the registry's TLS certificate
+    pub skip_verify: bool,
+    /// PEM file with additional CA certificates to trust
+    pub ca_file: Option<PathBuf>,
+}
+
+/// Where to send registry API requests for a host or mirror
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegistryLocation {
+    /// Registry host, used to look up credentials and TLS settings
+    pub host: String,
+    /// Base URL of the registry API
+    pub url: String,
+    /// Namespace prepended to repository names
+    pub namespace: Option<String>,
+}
+
+impl RegistriesConfig {
+    /// Load the registry configuration, which is empty if the file doesn't exist
+    pub fn load(paths: &DarkerPaths) -> Result<Self> {
+        let path = paths.registries_config();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let json = fs::read_to_string(&path)?;
+        let config: RegistriesConfig = serde_json::from_str(&json)?;
+        Ok(config)
+    }
+
+    /// Get the settings for a registry host
+    pub fn settings(&self, registry: &str) -> RegistrySettings {
+        let registry = normalize_registry(registry);
+        self.registries
+            .iter()
+            .find(|(host, _)| normalize_registry(host) == registry)
+            .map(|(_, settings)| settings.clone())
+            .unwrap_or_default()
+    }
+
+    /// Get the location of a registry itself
+    pub fn location(&self, registry: &str) -> RegistryLocation {
+        let host = normalize_registry(registry);
+        let url = if self.settings(&host).plain_http {
+            format!("http://{}", host)
+        } else {
+            registry_api_url(&host)
+        };
+        RegistryLocation {
+            host,
+            url,
+            namespace: None,
+        }
+    }
+
+    /// Get the locations of a registry's mirrors, in the order to try them
+    pub fn mirror_locations(&self, registry: &str) -> Vec<RegistryLocation> {
+        self.settings(registry)
+            .mirrors
+            .iter()
+            .map(|mirror| self.mirror_location(mirror))
+            .collect()
+    }
+
+    /// Get the location of a mirror entry
+    fn mirror_location(&self, mirror: &str) -> RegistryLocation {
+        let mirror = mirror.trim().trim_end_matches('/');
+        let (scheme, rest) = match mirror.split_once("://") {
+            Some((scheme, rest)) => (Some(scheme), rest),
+            None => (None, mirror),
+        };
+        let (host, namespace) = match rest.split_once('/') {
+            Some((host, namespace)) => (host, Some(namespace.to_string())),
+            None => (rest, None),
+        };
+
+        let mut location = self.location(host);
+        if let Some(scheme) = scheme {
+            location.url = format!("{}://{}", scheme, host);
+        }
+        location.namespace = namespace.filter(|n| !n.is_empty());
+        location
+    }
+}
+
+impl RegistryLocation {
+    /// Repository path at this location
+    pub fn repository(&self, repository: &str) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{}/{}", namespace, repository),
+            None => repository.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_load_registries_config() {
+        let tmp = TempDir::new().unwrap();
+        let paths = DarkerPaths::with_root(tmp.path());
+        assert!(RegistriesConfig::load(&paths)
+            .unwrap()
+            .registries
+            .is_empty());
+
+        fs::write(
+            paths.registries_config(),
+            r#"{"registries": {"index.docker.io": {"mirrors": ["mirror.example"]},
+                "corp.example:5000": {"plain_http": true, "ca_file": "/tmp/ca.pem"}}}"#,
+        )
+        .unwrap();
+        let config = RegistriesConfig::load(&paths).unwrap();
+
+        assert_eq!(config.settings("docker.io").mirrors, vec!["mirror.example"]);
+        let corp = config.settings("corp.example:5000");
+        assert!(corp.plain_http);
+        assert!(!corp.skip_verify);
+        assert_eq!(corp.ca_file, Some(PathBuf::from("/tmp/ca.pem")));
+        assert_eq!(config.settings("ghcr.io"), RegistrySettings::default());
+    }
+
+    #[test]
+    fn test_mirror_locations() {
+        let mut config = RegistriesConfig::default();
+        config.registries.insert(
+            "docker.io".to_string(),
+            RegistrySettings {
+                mirrors: vec![
+                    "mirror.example".to_string(),
+                    "http://10.0.0.5:5000/".to_string(),
+                    "harbor.example/hub-proxy".to_string(),
+                ],
+                ..Default::default()
+            },
+        );
+        config.registries.insert(
+            "harbor.example".to_string(),
+            RegistrySettings {
+                plain_http: true,
+                ..Default::default()
+            },
+        );
+
+        let locations = config.mirror_locations("docker.io");
+        let urls: Vec<&str> = locations.iter().map(|l| l.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://mirror.example",
+                "http://10.0.0.5:5000",
+                "http://harbor.example",
+            ]
+        );
+        assert_eq!(locations[2].host, "harbor.example");
+        assert_eq!(
+            locations[2].repository("library/alpine"),
+            "hub-proxy/library/alpine"
+        );
+        assert_eq!(locations[0].repository("library/alpine"), "library/alpine");
+        assert!(config.mirror_locations("ghcr.io").is_empty());
+
+        assert_eq!(
+            config.location("docker.io").url,
+            "https://registry-1.docker.io"
+        );
+        assert_eq!(
+            config.location("harbor.example").url,
+            "http://harbor.example"
+        );
+    }
+}